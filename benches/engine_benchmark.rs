@@ -1,8 +1,10 @@
 use clap::ValueEnum;
 use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
-use kvs::{Engine, Storage, StoreTrait};
+use kvs::{Compression, Engine, KvStore, KvStoreOptions, Storage, StoreTrait};
 use once_cell::sync::Lazy;
 use rand::{Rng, SeedableRng, distributions::Alphanumeric, rngs::SmallRng};
+use std::ops::Bound;
+use std::path::Path;
 use tempfile::TempDir;
 
 const NUM_VALS: usize = 10;
@@ -11,6 +13,9 @@ const KEY_SEED: u64 = 757;
 const VALUE_SIZE_SEED: u64 = 2041;
 const VALUE_SEED: u64 = 1024;
 const READ_SEED: u64 = 999;
+const SCAN_KEYS: usize = 10_000;
+const OVERWRITE_KEYS: usize = 1_000;
+const OVERWRITES: usize = 50_000;
 
 fn get_size(seed: u64) -> [usize; NUM_VALS] {
     let mut r: SmallRng = SeedableRng::seed_from_u64(seed);
@@ -34,8 +39,43 @@ fn get_vals(seed: u64, size: &[usize]) -> Vec<String> {
 static KEY_SIZES: Lazy<[usize; NUM_VALS]> = Lazy::new(|| get_size(KEY_SIZE_SEED));
 static VAL_SIZES: Lazy<[usize; NUM_VALS]> = Lazy::new(|| get_size(VALUE_SIZE_SEED));
 
+// JSON documents, which unlike the random values above compress well
+fn get_docs(seed: u64, size: &[usize]) -> Vec<String> {
+    let mut r: SmallRng = SeedableRng::seed_from_u64(seed);
+    let mut res = vec![];
+    for s in size {
+        let mut doc = String::from("[");
+        while doc.len() < *s {
+            let name: String = r.sample_iter(&Alphanumeric).take(8).collect();
+            doc.push_str(&format!(
+                r#"{{"id":{},"name":"{}","active":{},"tags":["alpha","beta"]}},"#,
+                r.gen_range(0, 1_000_000),
+                name,
+                r.gen_range(0, 2) == 1
+            ));
+        }
+        doc.pop();
+        doc.push(']');
+        res.push(doc);
+    }
+    res
+}
+
 static KEYS: Lazy<Vec<String>> = Lazy::new(|| get_vals(KEY_SEED, &*KEY_SIZES));
 static VALS: Lazy<Vec<String>> = Lazy::new(|| get_vals(VALUE_SEED, &*VAL_SIZES));
+static DOCS: Lazy<Vec<String>> = Lazy::new(|| get_docs(VALUE_SEED, &*VAL_SIZES));
+
+const COMPRESSIONS: [Compression; 3] = [Compression::None, Compression::Lz4, Compression::Zstd];
+
+fn open_compressed(compression: Compression) -> (KvStore, TempDir) {
+    let tempdir = TempDir::new_in("/tmp").unwrap();
+    let options = KvStoreOptions {
+        compression,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(tempdir.path().to_path_buf(), options).unwrap();
+    (store, tempdir)
+}
 
 fn bench_write(c: &mut Criterion) {
     println!("Running bench_write");
@@ -111,5 +151,150 @@ fn bench_read(c: &mut Criterion) {
     }
 }
 
-criterion_group!(engine, bench_write, bench_read);
+fn bench_compression(c: &mut Criterion) {
+    let mut bench_compression = c.benchmark_group("bench_compression");
+    let keys = &KEYS;
+    let docs = &DOCS;
+
+    for compression in COMPRESSIONS {
+        let name = format!("{:?}", compression).to_lowercase();
+        bench_compression.bench_function(format!("kvs-{}-write", name), |b| {
+            b.iter_batched(
+                || open_compressed(compression),
+                |(kv, _tempdir)| {
+                    for i in 0..NUM_VALS {
+                        kv.set(keys[i].clone(), docs[i].clone()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        bench_compression.bench_function(format!("kvs-{}-read", name), |b| {
+            b.iter_batched(
+                || {
+                    let (kv, tempdir) = open_compressed(compression);
+                    for i in 0..NUM_VALS {
+                        kv.set(keys[i].clone(), docs[i].clone()).unwrap();
+                    }
+                    (kv, tempdir)
+                },
+                |(kv, _tempdir)| {
+                    let mut r: SmallRng = SeedableRng::seed_from_u64(READ_SEED);
+                    for _ in 0..1000 {
+                        let index = r.gen_range(0, NUM_VALS);
+                        let key = keys[index].to_owned();
+                        assert_eq!(Some(docs[index].clone()), kv.get(key).unwrap());
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+// A store holding `SCAN_KEYS` small pairs, under keys that sort by number
+fn open_filled(engine: Engine) -> (Storage, TempDir) {
+    let tempdir = TempDir::new_in("/tmp").unwrap();
+    let storage = Storage::build(tempdir.path().to_path_buf(), engine).unwrap();
+    for i in 0..SCAN_KEYS {
+        storage
+            .set(format!("key{:06}", i), format!("value{}", i))
+            .unwrap();
+    }
+    (storage, tempdir)
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let mut bench_scan = c.benchmark_group("bench_scan");
+    for engine in Engine::value_variants() {
+        let (kv, _tempdir) = open_filled(*engine);
+        bench_scan.bench_function(format!("{}-scan-100", engine), |b| {
+            let mut r: SmallRng = SeedableRng::seed_from_u64(READ_SEED);
+            b.iter(|| {
+                let start = r.gen_range(0, SCAN_KEYS - 100);
+                let range = (
+                    Bound::Included(format!("key{:06}", start)),
+                    Bound::Excluded(format!("key{:06}", start + 100)),
+                );
+                let mut found = 0;
+                kv.scan_range(range, &mut |_, _| {
+                    found += 1;
+                    Ok(())
+                })
+                .unwrap();
+                assert_eq!(found, 100);
+            })
+        });
+    }
+}
+
+// Bytes this process has handed to `write` so far; Linux only
+fn bytes_written() -> Option<u64> {
+    let io = std::fs::read_to_string("/proc/self/io").ok()?;
+    io.lines()
+        .find_map(|line| line.strip_prefix("wchar:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn overwrite(dir: &Path, engine: Engine) -> Storage {
+    let storage = Storage::build(dir.to_path_buf(), engine).unwrap();
+    let value = "x".repeat(100);
+    for i in 0..OVERWRITES {
+        storage
+            .set(format!("key{:06}", i % OVERWRITE_KEYS), value.clone())
+            .unwrap();
+    }
+    storage
+}
+
+// Overwrites a small set of keys many times, the workload where
+// compaction rewrites the most; prints bytes written per byte set before
+// timing it
+fn bench_overwrite(c: &mut Criterion) {
+    let mut bench_overwrite = c.benchmark_group("bench_overwrite");
+    bench_overwrite.sample_size(10);
+    let user_bytes = (OVERWRITES * (9 + 100)) as f64;
+    for engine in Engine::value_variants() {
+        let tempdir = TempDir::new_in("/tmp").unwrap();
+        let before = bytes_written();
+        let storage = overwrite(tempdir.path(), *engine);
+        if let Storage::Lsm(store) = &storage {
+            let stats = store.stats().unwrap();
+            println!(
+                "lsm: tables per level {:?}, {:.2} bytes written per byte set by its own count",
+                stats.levels,
+                stats.write_amplification()
+            );
+        }
+        drop(storage);
+        if let (Some(before), Some(after)) = (before, bytes_written()) {
+            println!(
+                "{}: {:.2} bytes written per byte set",
+                engine,
+                (after - before) as f64 / user_bytes
+            );
+        }
+
+        bench_overwrite.bench_function(format!("{}-overwrite", engine), |b| {
+            b.iter_batched(
+                || TempDir::new_in("/tmp").unwrap(),
+                |tempdir| {
+                    overwrite(tempdir.path(), *engine);
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+}
+
+criterion_group!(
+    engine,
+    bench_write,
+    bench_read,
+    bench_compression,
+    bench_scan,
+    bench_overwrite
+);
 criterion_main!(engine);
@@ -2,7 +2,7 @@ use clap::ValueEnum;
 use criterion::{Criterion, Throughput, criterion_group, criterion_main};
 use kvs::{
     Client, ClientTrait, Engine, PoolType, RayonThreadPool, Request, Response, Server, ServerTrait,
-    ThreadPoolTrait,
+    ServerType, Storage, StoreTrait, ThreadPoolTrait,
 };
 use once_cell::sync::Lazy;
 use rand::{Rng, thread_rng};
@@ -66,8 +66,15 @@ fn bench_write(c: &mut Criterion) {
                 let unique_path = tempdir.path().join(engine.to_string());
                 std::fs::create_dir_all(&unique_path).unwrap();
 
-                let mut server =
-                    Server::build(addr, *engine, *pool, *num_threads as u32, unique_path).unwrap();
+                let mut server = Server::build(
+                    ServerType::Sync,
+                    addr,
+                    *engine,
+                    *pool,
+                    *num_threads as u32,
+                    unique_path,
+                )
+                .unwrap();
                 let server_shutdown = server.shutdown();
                 let server_handle = std::thread::spawn(move || {
                     server.run().unwrap();
@@ -122,8 +129,7 @@ fn bench_write(c: &mut Criterion) {
                     });
                 });
                 // Server shutdown
-                server_shutdown.send(()).unwrap();
-                Client::connect(addr).unwrap();
+                server_shutdown.shutdown();
                 server_handle.join().expect("Server thread panicked");
                 println!("Finished: {}", &write_id);
             }
@@ -149,8 +155,15 @@ fn bench_read(c: &mut Criterion) {
                 let unique_path = tempdir.path().join(engine.to_string());
                 std::fs::create_dir_all(&unique_path).unwrap();
 
-                let mut server =
-                    Server::build(addr, *engine, *pool, *num_threads as u32, unique_path).unwrap();
+                let mut server = Server::build(
+                    ServerType::Sync,
+                    addr,
+                    *engine,
+                    *pool,
+                    *num_threads as u32,
+                    unique_path,
+                )
+                .unwrap();
                 let server_shutdown = server.shutdown();
                 let server_handle = std::thread::spawn(move || {
                     server.run().unwrap();
@@ -224,8 +237,7 @@ fn bench_read(c: &mut Criterion) {
                 });
 
                 // Server shutdown
-                server_shutdown.send(()).unwrap();
-                Client::connect(addr).unwrap();
+                server_shutdown.shutdown();
                 server_handle.join().unwrap();
                 println!("Finished: {}", &read_id);
             }
@@ -233,5 +245,46 @@ fn bench_read(c: &mut Criterion) {
     }
 }
 
-criterion_group!(threadpool, bench_write, bench_read);
+// Reads straight against a shared engine, without the network in the way, to
+// show how reads scale once workers no longer serialize on a store lock
+fn bench_engine_read(c: &mut Criterion) {
+    println!("Running bench_engine_read");
+    let thread_counts = [1, 2, 4, 8];
+    for engine in Engine::value_variants() {
+        let mut bench_engine_read = c.benchmark_group("bench_engine_read");
+
+        let tempdir = TempDir::new_in("/tmp").unwrap();
+        let unique_path = tempdir.path().join(engine.to_string());
+        std::fs::create_dir_all(&unique_path).unwrap();
+
+        let storage = Storage::build(unique_path, *engine).unwrap();
+        for i in 0..NUM_VALS {
+            storage.set(KEYS[i].clone(), VALS[i].clone()).unwrap();
+        }
+
+        for num_threads in thread_counts.iter() {
+            let read_id = format!("{}-{}-engine-read", engine, num_threads);
+
+            bench_engine_read.throughput(Throughput::Elements(NUM_VALS as u64));
+            bench_engine_read.sample_size(10);
+            bench_engine_read.bench_with_input(&read_id, num_threads, |b, num_threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for t in 0..*num_threads {
+                            let storage = storage.clone();
+                            scope.spawn(move || {
+                                for i in (t..NUM_VALS).step_by(*num_threads) {
+                                    let value = storage.get(KEYS[i].clone()).unwrap();
+                                    assert_eq!(value.as_ref(), Some(&VALS[i]));
+                                }
+                            });
+                        }
+                    });
+                });
+            });
+        }
+    }
+}
+
+criterion_group!(threadpool, bench_write, bench_read, bench_engine_read);
 criterion_main!(threadpool);
@@ -0,0 +1,560 @@
+use clap::{Arg, ArgAction, ArgMatches, Command, arg, value_parser};
+use kvs::{
+    Client, ClientTrait, ClientType, DumpFormat, EncryptionKey, Engine, Entry, Keyring, KvStore,
+    KvStoreOptions, LogInspector, Request, Response, Result, SEGMENT_VERSION, Storage,
+    StorageConfig, StoreTrait,
+};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use tracing::Level;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+fn cli() -> Command {
+    Command::new("kvs-admin")
+        .about("Maintenance tasks for a kvs store")
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("backup")
+                .about("Snapshot a running server's store into a new directory on its host")
+                .arg(
+                    arg!(<DEST> "The backup directory, relative to the server's server.backup_root")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--addr <ADDR> "The server address")
+                        .value_parser(value_parser!(SocketAddr))
+                        .num_args(1)
+                        .default_value(DEFAULT_ADDRESS),
+                )
+                .arg(
+                    arg!(--client <CLIENT> "The client implementation")
+                        .value_parser(value_parser!(ClientType))
+                        .num_args(1)
+                        .default_value("sync"),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restore a backup into an empty data directory; the server must be stopped")
+                .arg(arg!(<SRC> "The backup directory").value_parser(value_parser!(PathBuf)))
+                .arg(
+                    arg!(--"data-dir" <DIR> "The data directory to restore into")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Move a data directory to another engine; the server must be stopped")
+                .arg(
+                    arg!(--"data-dir" <DIR> "The data directory to migrate")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!(--to <ENGINE> "The engine to migrate to")
+                        .value_parser(value_parser!(Engine))
+                        .required(true),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Dump every pair in a data directory; the server must be stopped")
+                .arg(
+                    arg!(--"data-dir" <DIR> "The data directory to export")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!(--format <FORMAT> "The dump format")
+                        .value_parser(value_parser!(DumpFormat))
+                        .default_value("json"),
+                )
+                .arg(
+                    arg!(--output <FILE> "Where to write the dump [default: stdout]")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Load a dump of either format into a data directory; the server must be stopped")
+                .arg(
+                    arg!(--"data-dir" <DIR> "The data directory to import into")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!(--engine <ENGINE> "The engine for a new data directory")
+                        .value_parser(value_parser!(Engine))
+                        .default_value("kvs"),
+                )
+                .arg(
+                    arg!(--input <FILE> "Where to read the dump [default: stdin]")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("List a kvs data directory's segments with live and stale entries")
+                .arg(data_dir_arg())
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("Print every entry in a kvs data directory's segments with its offset")
+                .arg(data_dir_arg())
+                .arg(
+                    arg!(--segment <ID> "Only this segment")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the entry framing and checksums of a kvs data directory's segments")
+                .arg(data_dir_arg())
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("repair")
+                .about("Cut damaged segments back to their last whole entry; the server must be stopped")
+                .arg(data_dir_arg())
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("Rewrite segments from older formats in the current one; the server must be stopped")
+                .arg(data_dir_arg())
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("collect-blobs")
+                .about("Move live values out of blob files holding dead ones and delete those files; the server must be stopped")
+                .arg(data_dir_arg())
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("keygen")
+                .about("Print a new random key for storage.encryption_key_file"),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Summarize a kvs data directory's log")
+                .arg(data_dir_arg())
+                .arg(key_file_arg())
+                .arg_required_else_help(true),
+        )
+}
+
+fn data_dir_arg() -> Arg {
+    arg!(--"data-dir" <DIR> "The stopped server's data directory")
+        .value_parser(value_parser!(PathBuf))
+        .required(true)
+}
+
+fn key_file_arg() -> Arg {
+    arg!(--"key-file" <FILE> "A key the segments are encrypted with; repeat for rotated-out keys after the current one")
+        .value_parser(value_parser!(PathBuf))
+        .action(ArgAction::Append)
+}
+
+// The first key is the current one
+fn keyring(matches: &ArgMatches) -> Result<Option<Keyring>> {
+    let mut paths = matches
+        .get_many::<PathBuf>("key-file")
+        .into_iter()
+        .flatten();
+    let Some(current) = paths.next() else {
+        return Ok(None);
+    };
+    let mut keys = Keyring::new(EncryptionKey::from_file(current)?);
+    for path in paths {
+        keys = keys.retire(EncryptionKey::from_file(path)?);
+    }
+    Ok(Some(keys))
+}
+
+// Segment files only exist for the kvs engine
+fn require_kvs(dir: &Path) -> Result<()> {
+    match Engine::detect(dir)? {
+        Some(Engine::Kvs) => Ok(()),
+        Some(engine) => {
+            eprintln!(
+                "Error: {} uses the {} engine, which has no segment files",
+                dir.display(),
+                engine
+            );
+            exit(1);
+        }
+        None => {
+            eprintln!("Error: {} is not a data directory", dir.display());
+            exit(1);
+        }
+    }
+}
+
+fn inspector(dir: &Path, matches: &ArgMatches) -> Result<LogInspector> {
+    require_kvs(dir)?;
+    match keyring(matches)? {
+        Some(keys) => LogInspector::open_with_keys(dir, keys),
+        None => LogInspector::open(dir),
+    }
+}
+
+// Long values are cut short so one entry stays on one line
+fn preview(value: &str) -> String {
+    const MAX_CHARS: usize = 60;
+    match value.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{:?}... ({} bytes)", &value[..end], value.len()),
+        None => format!("{:?}", value),
+    }
+}
+
+// Opens a stopped server's data directory with the engine that wrote it,
+// or `engine` for a new one
+fn open(dir: &Path, engine: Option<Engine>) -> Result<Storage> {
+    let engine = match (Engine::detect(dir)?, engine) {
+        (Some(engine), _) | (None, Some(engine)) => engine,
+        (None, None) => {
+            eprintln!("Error: {} is not a data directory", dir.display());
+            exit(1);
+        }
+    };
+    fs::create_dir_all(dir)?;
+    Storage::build(dir.to_path_buf(), engine)
+}
+
+// Exporting only reads, so it can share the directory with other readers;
+// sled has no read-only mode
+fn open_for_export(dir: &Path, keys: Option<Keyring>) -> Result<Storage> {
+    match Engine::detect(dir)? {
+        Some(Engine::Sled) | None => open(dir, None),
+        Some(_) => Storage::open_read_only_with_keys(dir.to_path_buf(), keys),
+    }
+}
+
+// Opens the store for writing, with the keys given; segments under a
+// rotated-out key are readable, and rewritten under the current one
+fn open_for_rewrite(dir: &Path, matches: &ArgMatches) -> Result<KvStore> {
+    match keyring(matches)? {
+        Some(keys) => KvStore::open_with_keys(dir.to_path_buf(), KvStoreOptions::default(), keys),
+        None => KvStore::open(dir.to_path_buf()),
+    }
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(Level::WARN)
+        .init();
+
+    let matches = cli().get_matches();
+
+    match matches.subcommand() {
+        Some(("backup", matches)) => {
+            let dest = matches.get_one::<PathBuf>("DEST").expect("Required");
+            let addr = matches.get_one::<SocketAddr>("addr").expect("Required");
+            let client = matches.get_one::<ClientType>("client").expect("Required");
+
+            // The server resolves the path under its `server.backup_root`
+            let mut client = Client::build(*client, *addr)?;
+            let request = Request::Backup {
+                dest: dest.to_string_lossy().into_owned(),
+            };
+
+            match client.send(request)? {
+                Response::Ok => println!("Backed up {} to {}", addr, dest.display()),
+                Response::Error(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+                response => {
+                    eprintln!("Unexpected response: {:?}", response);
+                    exit(1);
+                }
+            }
+        }
+        Some(("restore", matches)) => {
+            let src = matches.get_one::<PathBuf>("SRC").expect("Required");
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+
+            match Storage::restore(src, dir) {
+                Ok(engine) => println!("Restored {} backup into {}", engine, dir.display()),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            }
+        }
+        Some(("migrate", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            let engine = matches.get_one::<Engine>("to").expect("Required");
+
+            let config = StorageConfig {
+                engine: *engine,
+                ..StorageConfig::default()
+            };
+            match Storage::migrate(dir, &config) {
+                Ok(migration) => {
+                    println!(
+                        "Migrated {} keys from {} to {} (checksum {:016x})",
+                        migration.keys, migration.from, migration.to, migration.checksum
+                    );
+                    println!("Previous data kept in {}", migration.previous.display());
+                }
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            }
+        }
+        Some(("export", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            let format = matches.get_one::<DumpFormat>("format").expect("Required");
+
+            let store = match keyring(matches).and_then(|keys| open_for_export(dir, keys)) {
+                Ok(store) => store,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            };
+            let mut output: Box<dyn Write> = match matches.get_one::<PathBuf>("output") {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+            let count = store.export(*format, &mut output)?;
+            eprintln!("Exported {} keys", count);
+        }
+        Some(("import", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            let engine = matches.get_one::<Engine>("engine").expect("Required");
+
+            let store = open(dir, Some(*engine))?;
+            let mut input: Box<dyn BufRead> = match matches.get_one::<PathBuf>("input") {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(io::stdin().lock()),
+            };
+            let count = match store.import(&mut input) {
+                Ok(count) => count,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            };
+            store.flush()?;
+            // The memory engine only keeps what is in its dump
+            if let Storage::Memory(store) = &store {
+                store.save(dir)?;
+            }
+            println!("Imported {} keys", count);
+        }
+        Some(("inspect", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+
+            println!(
+                "{:<12} {:>4} {:>8} {:>12} {:>9} {:>9} {:>9} {:>9} {:>9}",
+                "SEGMENT", "VER", "KEY", "BYTES", "ENTRIES", "SETS", "REMOVES", "LIVE", "STALE"
+            );
+            for info in inspector(dir, matches)?.segments()? {
+                println!(
+                    "{:<12} {:>4} {:>8} {:>12} {:>9} {:>9} {:>9} {:>9} {:>9}",
+                    format!("{}.log", info.file_id),
+                    info.version,
+                    info.key_id
+                        .map_or("-".to_owned(), |id| format!("{:08x}", id)),
+                    info.size,
+                    info.entries,
+                    info.sets,
+                    info.removes,
+                    info.live,
+                    info.stale
+                );
+                if let Some(damage) = info.damage {
+                    println!("  damaged at offset {}: {}", damage.offset, damage.reason);
+                }
+            }
+        }
+        Some(("dump", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            let inspector = inspector(dir, matches)?;
+            let file_ids: Vec<u64> = match matches.get_one::<u64>("segment") {
+                Some(file_id) => vec![*file_id],
+                None => inspector.file_ids().to_vec(),
+            };
+
+            let mut out = BufWriter::new(io::stdout().lock());
+            for file_id in file_ids {
+                let damage = inspector.walk(file_id, &mut |offset, length, entry| {
+                    let at = format!("{}.log:{}", file_id, offset);
+                    match entry {
+                        Entry::Set { key, value } => writeln!(
+                            out,
+                            "{:<16} {:>6}  SET    {:?} = {}",
+                            at,
+                            length,
+                            key,
+                            preview(&value)
+                        )?,
+                        Entry::Remove { key } => {
+                            writeln!(out, "{:<16} {:>6}  REMOVE {:?}", at, length, key)?
+                        }
+                        Entry::Blob { key, blob } => writeln!(
+                            out,
+                            "{:<16} {:>6}  BLOB   {:?} -> {}.blob:{} ({} bytes)",
+                            at, length, key, blob.file_id, blob.offset, blob.length
+                        )?,
+                    }
+                    Ok(())
+                })?;
+                if let Some(damage) = damage {
+                    writeln!(
+                        out,
+                        "{}.log:{} DAMAGED {}",
+                        file_id, damage.offset, damage.reason
+                    )?;
+                }
+            }
+            out.flush()?;
+        }
+        Some(("verify", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            let inspector = inspector(dir, matches)?;
+            let problems = inspector.verify()?;
+            let framing_only = inspector.framing_only()?;
+            if !framing_only.is_empty() {
+                let names: Vec<String> = framing_only
+                    .iter()
+                    .map(|id| format!("{}.log", id))
+                    .collect();
+                println!(
+                    "Note: {} predate entry checksums, so only their framing was checked; \
+                     `kvs-admin upgrade` rewrites them with checksums",
+                    names.join(", ")
+                );
+            }
+            if problems.is_empty() {
+                println!("OK: {} segments intact", inspector.file_ids().len());
+                return Ok(());
+            }
+
+            let active = inspector.file_ids().last().copied();
+            for (file_id, damage) in problems {
+                // A crash mid-append leaves a partial entry at the very end
+                let hint = match Some(file_id) == active {
+                    true => " (end of the active segment: likely an interrupted write)",
+                    false => "",
+                };
+                println!(
+                    "{}.log: offset {}: {}{}",
+                    file_id, damage.offset, damage.reason, hint
+                );
+            }
+            exit(1);
+        }
+        Some(("repair", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            require_kvs(dir)?;
+
+            let repaired = keyring(matches).and_then(|keys| match keys {
+                Some(keys) => KvStore::repair_with_keys(dir, &keys),
+                None => KvStore::repair(dir),
+            });
+            let repairs = match repaired {
+                Ok(repairs) => repairs,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            };
+            if repairs.is_empty() {
+                println!("Nothing to repair");
+            }
+            for repair in repairs {
+                println!(
+                    "{}.log: cut at offset {} ({}), dropped {} bytes",
+                    repair.file_id, repair.offset, repair.reason, repair.lost_bytes
+                );
+                if let Some(original) = repair.quarantined {
+                    println!("  original moved to {}", original.display());
+                }
+            }
+        }
+        Some(("upgrade", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            require_kvs(dir)?;
+
+            let upgraded = open_for_rewrite(dir, matches).and_then(|store| store.upgrade());
+            match upgraded {
+                Ok(0) => println!("Every segment is already up to date"),
+                Ok(count) => println!(
+                    "Rewrote {} segments into format version {}",
+                    count, SEGMENT_VERSION
+                ),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            }
+        }
+        Some(("collect-blobs", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            require_kvs(dir)?;
+
+            match open_for_rewrite(dir, matches).and_then(|store| store.collect_blobs()) {
+                Ok(0) => println!("No blob file holds dead values"),
+                Ok(freed) => println!("Freed {} bytes of blob files", freed),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    exit(1);
+                }
+            }
+        }
+        Some(("keygen", _)) => {
+            let (_, hex) = EncryptionKey::generate();
+            println!("{}", hex);
+        }
+        Some(("stats", matches)) => {
+            let dir = matches.get_one::<PathBuf>("data-dir").expect("Required");
+            let stats = inspector(dir, matches)?.stats()?;
+
+            let amplification = match stats.live_bytes {
+                0 => 0.0,
+                live => stats.bytes as f64 / live as f64,
+            };
+            println!("segments             {}", stats.segments);
+            println!("bytes                {}", stats.bytes);
+            println!("entries              {}", stats.entries);
+            println!("live keys            {}", stats.live_keys);
+            println!("live bytes           {}", stats.live_bytes);
+            println!("stale bytes          {}", stats.bytes - stats.live_bytes);
+            println!("key bytes            {}", stats.key_bytes);
+            println!("value bytes          {}", stats.value_bytes);
+            println!("space amplification  {:.2}x", amplification);
+            println!("blob files           {}", stats.blob_files);
+            println!("blob bytes           {}", stats.blob_bytes);
+            println!("live blob bytes      {}", stats.live_blob_bytes);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use clap::{Command, arg, value_parser};
-use kvs::{Client, ClientTrait, Request, Response, Result};
+use kvs::{Client, ClientTrait, ClientType, Request, Response, Result};
 use std::net::SocketAddr;
 use std::process::exit;
 use tracing::Level;
@@ -24,6 +24,12 @@ fn cli() -> Command {
                         .num_args(1)
                         .default_value(DEFAULT_ADDRESS),
                 )
+                .arg(
+                    arg!(--client <CLIENT> "The client implementation")
+                        .value_parser(value_parser!(ClientType))
+                        .num_args(1)
+                        .default_value("sync"),
+                )
                 .arg_required_else_help(true),
         )
         .subcommand(
@@ -36,6 +42,12 @@ fn cli() -> Command {
                         .num_args(1)
                         .default_value(DEFAULT_ADDRESS),
                 )
+                .arg(
+                    arg!(--client <CLIENT> "The client implementation")
+                        .value_parser(value_parser!(ClientType))
+                        .num_args(1)
+                        .default_value("sync"),
+                )
                 .arg_required_else_help(true),
         )
         .subcommand(
@@ -48,6 +60,12 @@ fn cli() -> Command {
                         .num_args(1)
                         .default_value(DEFAULT_ADDRESS),
                 )
+                .arg(
+                    arg!(--client <CLIENT> "The client implementation")
+                        .value_parser(value_parser!(ClientType))
+                        .num_args(1)
+                        .default_value("sync"),
+                )
                 .arg_required_else_help(true),
         )
 }
@@ -66,8 +84,9 @@ fn main() -> Result<()> {
             let key = matches.get_one::<String>("KEY").expect("Required");
             let value = matches.get_one::<String>("VALUE").expect("Required");
             let addr = matches.get_one::<SocketAddr>("addr").expect("Required");
+            let client = matches.get_one::<ClientType>("client").expect("Required");
 
-            let mut client = Client::connect(*addr)?;
+            let mut client = Client::build(*client, *addr)?;
             let request: Request = Request::Set {
                 key: key.clone(),
                 value: value.clone(),
@@ -90,8 +109,9 @@ fn main() -> Result<()> {
         Some(("get", matches)) => {
             let key = matches.get_one::<String>("KEY").expect("Required");
             let addr = matches.get_one::<SocketAddr>("addr").expect("Required");
+            let client = matches.get_one::<ClientType>("client").expect("Required");
 
-            let mut client = Client::connect(*addr)?;
+            let mut client = Client::build(*client, *addr)?;
             let request: Request = Request::Get { key: key.clone() };
 
             let response = client.send(request)?;
@@ -111,8 +131,9 @@ fn main() -> Result<()> {
         Some(("rm", matches)) => {
             let key = matches.get_one::<String>("KEY").expect("Required");
             let addr = matches.get_one::<SocketAddr>("addr").expect("Required");
+            let client = matches.get_one::<ClientType>("client").expect("Required");
 
-            let mut client = Client::connect(*addr)?;
+            let mut client = Client::build(*client, *addr)?;
             let request: Request = Request::Remove { key: key.clone() };
 
             let response = client.send(request)?;
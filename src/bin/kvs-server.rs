@@ -1,8 +1,16 @@
-use clap::{Command, arg, value_parser};
-use kvs::{Engine, PoolType, Result, Server, ServerTrait};
-use std::env::current_dir;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, arg, value_parser};
+use kvs::{
+    Config, Engine, LogHandle, PoolType, ReloadHandle, Result, Server, ServerTrait, ServerType,
+    ShutdownHandle, init_logging,
+};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::net::SocketAddr;
-use tracing::{Level, info};
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread;
+use tracing::{error, info, warn};
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
 
@@ -21,27 +29,161 @@ fn cli() -> Command {
                 .value_parser(value_parser!(Engine))
                 .default_value("kvs"),
         )
+        .arg(
+            arg!(--server <SERVER> "The server implementation")
+                .value_parser(value_parser!(ServerType))
+                .default_value("sync"),
+        )
+        .arg(
+            arg!(--pool <POOL> "The thread pool used by the sync server")
+                .value_parser(value_parser!(PoolType))
+                .default_value("queue"),
+        )
+        .arg(
+            arg!(--threads <THREADS> "Worker threads [default: available parallelism]")
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            arg!(--"data-dir" <DIR> "Directory holding the store [default: current directory]")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--config <FILE> "TOML config file, reread on SIGHUP; command line flags take precedence")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--cluster "Run as a raft cluster node, with members from [cluster] in the config")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"node-id" <ID> "This node's id in [cluster]")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"print-config" "Print the effective configuration and exit")
+                .action(ArgAction::SetTrue),
+        )
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .pretty()
-        .with_max_level(Level::DEBUG)
-        .init();
+// Flags given on the command line win over the environment and the config
+// file; flag defaults only apply when neither of those sets the key
+fn override_with<T: Clone + Send + Sync + 'static>(matches: &ArgMatches, id: &str, key: &mut T) {
+    if matches.value_source(id) == Some(ValueSource::CommandLine)
+        && let Some(value) = matches.get_one::<T>(id)
+    {
+        *key = value.clone();
+    }
+}
 
+fn load_config(matches: &ArgMatches) -> Result<Config> {
+    let path = matches.get_one::<PathBuf>("config").map(PathBuf::as_path);
+    let mut config = Config::load(path)?;
+
+    override_with(matches, "addr", &mut config.server.addr);
+    override_with(matches, "engine", &mut config.storage.engine);
+    override_with(matches, "server", &mut config.server.kind);
+    override_with(matches, "pool", &mut config.server.pool);
+    if let Some(threads) = matches.get_one::<u32>("threads") {
+        config.server.threads = Some(*threads);
+    }
+    if let Some(dir_path) = matches.get_one::<PathBuf>("data-dir") {
+        config.server.data_dir = Some(dir_path.clone());
+    }
+    if matches.get_flag("cluster") {
+        config.cluster.enabled = true;
+    }
+    override_with(matches, "node-id", &mut config.cluster.id);
+
+    config.validate()?;
+    Ok(config)
+}
+
+fn main() {
     let matches = cli().get_matches();
-    let addr = matches.get_one::<SocketAddr>("addr").expect("Required");
-    let engine = matches.get_one::<Engine>("engine").expect("Required");
-    let pool = PoolType::Queue;
-    let threads = 5;
-    let dir_path = current_dir()?;
+
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            exit(1);
+        }
+    };
+
+    if matches.get_flag("print-config") {
+        match config.to_toml() {
+            Ok(toml) => print!("{}", toml),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    let logging = init_logging(&config.logging);
+    for name in Config::ignored_env_vars() {
+        warn!("Ignoring {}: it names no config key", name);
+    }
+
+    if let Err(err) = run(&config, matches, logging) {
+        error!("{}", err);
+        exit(1);
+    }
+}
+
+fn run(config: &Config, matches: ArgMatches, logging: LogHandle) -> Result<()> {
+    let dir_path = config.server.data_dir()?;
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Storage engine: {}", engine.to_string());
-    info!("Listening on {}", addr);
+    info!("Storage engine: {}", config.storage.engine.to_string());
+    info!("Server type: {}", config.server.kind.to_string());
+    info!(
+        "Thread pool: {} with {} threads",
+        config.server.pool.to_string(),
+        config.server.worker_threads()?
+    );
+    info!("Data directory: {}", dir_path.display());
+    info!("Listening on {}", config.server.addr);
+    if let Some(node) = config.cluster.node().filter(|_| config.cluster.enabled) {
+        info!(
+            "Cluster node {} of {}, raft on {}",
+            node.id,
+            config.cluster.nodes.len(),
+            node.raft_addr
+        );
+    }
+
+    std::fs::create_dir_all(&dir_path)?;
+
+    let mut server = Server::from_config(config)?;
+    let reload = server.reload().with_logging(logging);
+    handle_signals(server.shutdown(), reload, matches)?;
+    server.run()?;
+
+    Ok(())
+}
+
+// SIGHUP rereads the config file and environment, keeping the command line
+// overrides; SIGINT and SIGTERM stop the server
+fn handle_signals(
+    shutdown: ShutdownHandle,
+    reload: ReloadHandle,
+    matches: ArgMatches,
+) -> Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM])?;
 
-    Server::build(*addr, *engine, pool, threads, dir_path)?.run()?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            info!("Received signal {}", signal);
+            if signal != SIGHUP {
+                shutdown.shutdown();
+                break;
+            }
+            if let Err(err) = load_config(&matches).and_then(|config| reload.reload(&config)) {
+                error!("Config reload failed, keeping the current config: {}", err);
+            }
+        }
+    });
 
     Ok(())
 }
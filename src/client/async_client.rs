@@ -0,0 +1,30 @@
+use crate::protocols::{read_frame_async, write_frame_async};
+use crate::{KvsError, Protocol, Request, Response, Result};
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tracing::info;
+
+pub struct AsyncKvsClient {
+    stream: TcpStream,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(addr: SocketAddr) -> Result<AsyncKvsClient> {
+        let stream = TcpStream::connect(addr).await?;
+
+        info!("Server connection");
+        Ok(AsyncKvsClient { stream })
+    }
+
+    pub async fn send(&mut self, request: Request) -> Result<Response> {
+        let protocol = Protocol::build();
+        let encoded = protocol.encode_request(&request);
+
+        write_frame_async(&mut self.stream, &encoded).await?;
+        let buf = read_frame_async(&mut self.stream, usize::MAX)
+            .await?
+            .ok_or_else(|| KvsError::Protocol("Connection closed by server".into()))?;
+
+        protocol.decode_response(&buf)
+    }
+}
@@ -1,24 +1,33 @@
-use crate::{Request, Response, Result};
+use crate::{ClientConfig, Request, Response, Result};
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
 use std::net::SocketAddr;
+use tokio::runtime::{Builder, Runtime};
 
+mod async_client;
+mod sharded;
 mod sync_client;
+pub use async_client::AsyncKvsClient;
+pub use sharded::{DEFAULT_VNODES, HashRing, ShardedClient};
 pub use sync_client::KvsClient;
 
 pub trait ClientTrait {
     fn send(&mut self, request: Request) -> Result<Response>;
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ClientType {
     Sync,
+    Async,
 }
 
 impl Display for ClientType {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let s = match self {
             ClientType::Sync => "sync",
+            ClientType::Async => "async",
         };
         write!(f, "{}", s)
     }
@@ -26,27 +35,37 @@ impl Display for ClientType {
 
 pub enum Client {
     Sync(KvsClient),
+    // The runtime drives the async client from synchronous callers
+    Async(Runtime, AsyncKvsClient),
 }
 
 impl Client {
     pub fn connect(addr: SocketAddr) -> Result<Client> {
-        //  let config = Config::from_file("../config/config.toml")?;
-        //  match config.client {
-        //      ClientConfig::Sync => {
-        //          let client = sync_client::KvsClient::connect(addr)?;
-        //          Ok(Box::new(client))
-        //      }
-        //  }
-
         let client = sync_client::KvsClient::connect(addr)?;
         Ok(Client::Sync(client))
     }
+
+    pub fn from_config(config: &ClientConfig) -> Result<Client> {
+        Client::build(config.kind, config.addr)
+    }
+
+    pub fn build(client: ClientType, addr: SocketAddr) -> Result<Client> {
+        match client {
+            ClientType::Sync => Client::connect(addr),
+            ClientType::Async => {
+                let runtime = Builder::new_current_thread().enable_all().build()?;
+                let client = runtime.block_on(AsyncKvsClient::connect(addr))?;
+                Ok(Client::Async(runtime, client))
+            }
+        }
+    }
 }
 
 impl ClientTrait for Client {
     fn send(&mut self, request: Request) -> Result<Response> {
         match self {
             Client::Sync(client) => client.send(request),
+            Client::Async(runtime, client) => runtime.block_on(client.send(request)),
         }
     }
 }
@@ -0,0 +1,258 @@
+use crate::common::hash64;
+use crate::{Client, ClientTrait, ClientType, KvsError, Request, Response, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::thread;
+
+/// Points each server gets on the ring per unit of weight
+pub const DEFAULT_VNODES: u32 = 160;
+
+/// Consistent-hash ring mapping keys to servers.
+///
+/// Every server owns `weight * vnodes` points on the ring and a key belongs
+/// to the first point at or after its hash. Adding or removing a server only
+/// moves the keys next to that server's points.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    vnodes: u32,
+    ring: BTreeMap<u64, SocketAddr>,
+    weights: HashMap<SocketAddr, u32>,
+}
+
+impl HashRing {
+    pub fn new(vnodes: u32) -> HashRing {
+        HashRing {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+            weights: HashMap::new(),
+        }
+    }
+
+    /// Adds a server, or changes its weight if it is already on the ring.
+    ///
+    /// A weight of 0 would leave the server on the ring without any keys,
+    /// so it is refused along with one too large to place.
+    pub fn add(&mut self, addr: SocketAddr, weight: u32) -> Result<()> {
+        if weight == 0 {
+            return Err(KvsError::Protocol(format!(
+                "{addr} needs a weight of at least 1"
+            )));
+        }
+        let points = weight.checked_mul(self.vnodes).ok_or_else(|| {
+            KvsError::Protocol(format!(
+                "Weight {weight} for {addr} is too large for {} points per unit",
+                self.vnodes
+            ))
+        })?;
+        self.remove(addr);
+        for point in 0..points {
+            // A taken point stays with its owner, so the ring does not
+            // depend on insertion order
+            self.ring
+                .entry(hash64(format!("{addr}#{point}").as_bytes()))
+                .or_insert(addr);
+        }
+        self.weights.insert(addr, weight);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        if self.weights.remove(&addr).is_some() {
+            self.ring.retain(|_, owner| *owner != addr);
+        }
+    }
+
+    /// The server owning `key`, if the ring has any.
+    pub fn node(&self, key: &str) -> Option<SocketAddr> {
+        let hash = hash64(key.as_bytes());
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Servers with their weights.
+    pub fn nodes(&self) -> impl Iterator<Item = (SocketAddr, u32)> + '_ {
+        self.weights.iter().map(|(addr, weight)| (*addr, *weight))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+}
+
+impl Default for HashRing {
+    fn default() -> HashRing {
+        HashRing::new(DEFAULT_VNODES)
+    }
+}
+
+/// Client spreading keys over several independent servers.
+///
+/// Keys are routed with a `HashRing`, so no proxy is needed. Multi-key
+/// operations are split per server and sent to all of them at once.
+///
+/// # Example
+///
+/// ```no_run
+/// use kvs::{ClientTrait, Request, Result, ShardedClient};
+///
+/// fn main() -> Result<()> {
+///     let mut client = ShardedClient::connect(&[
+///         "127.0.0.1:4000".parse().unwrap(),
+///         "127.0.0.1:4001".parse().unwrap(),
+///     ])?;
+///
+///     client.mset(vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())])?;
+///     assert_eq!(client.mget(vec!["a".to_owned(), "b".to_owned()])?.len(), 2);
+///
+///     client.send(Request::Remove { key: "a".to_owned() })?;
+///     Ok(())
+/// }
+/// ```
+pub struct ShardedClient {
+    kind: ClientType,
+    ring: HashRing,
+}
+
+impl ShardedClient {
+    /// Sync clients for equally weighted servers.
+    pub fn connect(addrs: &[SocketAddr]) -> Result<ShardedClient> {
+        let nodes: Vec<(SocketAddr, u32)> = addrs.iter().map(|addr| (*addr, 1)).collect();
+        ShardedClient::build(ClientType::Sync, &nodes)
+    }
+
+    pub fn build(kind: ClientType, nodes: &[(SocketAddr, u32)]) -> Result<ShardedClient> {
+        let mut ring = HashRing::default();
+        for (addr, weight) in nodes {
+            ring.add(*addr, *weight)?;
+        }
+        ShardedClient::with_ring(kind, ring)
+    }
+
+    pub fn with_ring(kind: ClientType, ring: HashRing) -> Result<ShardedClient> {
+        if ring.is_empty() {
+            return Err(KvsError::Protocol("No servers to shard across".into()));
+        }
+        Ok(ShardedClient { kind, ring })
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Routes keys to a new server. Keys already stored elsewhere are not
+    /// copied over; the ring keeps the set that moves small.
+    pub fn add_node(&mut self, addr: SocketAddr, weight: u32) -> Result<()> {
+        self.ring.add(addr, weight)
+    }
+
+    pub fn remove_node(&mut self, addr: SocketAddr) -> Result<()> {
+        if self.ring.nodes().count() == 1 && self.ring.weights.contains_key(&addr) {
+            return Err(KvsError::Protocol("Cannot remove the last server".into()));
+        }
+        self.ring.remove(addr);
+        Ok(())
+    }
+
+    /// The server that owns `key`.
+    pub fn node_for(&self, key: &str) -> Result<SocketAddr> {
+        self.ring
+            .node(key)
+            .ok_or_else(|| KvsError::Protocol("No servers to shard across".into()))
+    }
+
+    /// Values for `keys`, in the same order.
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let requests = keys.into_iter().map(|key| Request::Get { key }).collect();
+
+        self.fan_out(requests)?
+            .into_iter()
+            .map(|response| match response {
+                Response::Value(value) => Ok(Some(value)),
+                Response::NotFound => Ok(None),
+                Response::Error(err) => Err(KvsError::Protocol(err)),
+                Response::Ok => Err(KvsError::Protocol("Unexpected OK for GET".into())),
+            })
+            .collect()
+    }
+
+    /// Sets every pair. Servers apply their share independently, so a
+    /// failure can leave some pairs set.
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let requests = pairs
+            .into_iter()
+            .map(|(key, value)| Request::Set { key, value })
+            .collect();
+
+        for response in self.fan_out(requests)? {
+            if let Response::Error(err) = response {
+                return Err(KvsError::Protocol(err));
+            }
+        }
+        Ok(())
+    }
+
+    // One thread per server, each sending its share in order; responses come
+    // back in request order
+    fn fan_out(&self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let mut groups: HashMap<SocketAddr, Vec<(usize, Request)>> = HashMap::new();
+        for (i, request) in requests.into_iter().enumerate() {
+            let addr = self.node_for(key(&request)?)?;
+            groups.entry(addr).or_default().push((i, request));
+        }
+
+        let kind = self.kind;
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .map(|(addr, group)| {
+                    scope.spawn(move || {
+                        group
+                            .into_iter()
+                            .map(|(i, request)| Ok((i, send(kind, addr, request)?)))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .map_err(|_| KvsError::Concurrency("Fan-out thread panicked".into()))?
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut responses: Vec<(usize, Response)> = results.into_iter().flatten().collect();
+        responses.sort_unstable_by_key(|(i, _)| *i);
+        Ok(responses
+            .into_iter()
+            .map(|(_, response)| response)
+            .collect())
+    }
+}
+
+impl ClientTrait for ShardedClient {
+    fn send(&mut self, request: Request) -> Result<Response> {
+        let addr = self.node_for(key(&request)?)?;
+        send(self.kind, addr, request)
+    }
+}
+
+fn key(request: &Request) -> Result<&str> {
+    match request {
+        Request::Get { key } | Request::Set { key, .. } | Request::Remove { key } => Ok(key),
+        Request::Backup { .. } => Err(KvsError::Protocol(
+            "BACKUP is per server, send it to each one directly".into(),
+        )),
+    }
+}
+
+// The sync server answers one request per connection, so every request
+// gets its own
+fn send(kind: ClientType, addr: SocketAddr, request: Request) -> Result<Response> {
+    Client::build(kind, addr)?.send(request)
+}
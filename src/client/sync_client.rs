@@ -1,8 +1,12 @@
-use crate::{ClientTrait, Protocol, Request, Response, Result};
-use std::io::{Read, Write};
+use crate::protocols::{MOVED, read_frame, write_frame};
+use crate::{ClientTrait, KvsError, Protocol, Request, Response, Result};
 use std::net::{SocketAddr, TcpStream};
 use tracing::info;
 
+// Cluster followers point at the leader; more hops than this means the
+// cluster is still electing one
+const MAX_REDIRECTS: usize = 3;
+
 pub struct KvsClient {
     stream: TcpStream,
 }
@@ -21,15 +25,28 @@ impl ClientTrait for KvsClient {
         let protocol = Protocol::build();
         let encoded = protocol.encode_request(&request);
 
-        self.stream.write_all(&encoded)?;
-        self.stream.flush()?;
-
-        let mut buf = vec![0u8; 1024]; // Allocate a buffer to read into
-        let n = self.stream.read(&mut buf)?;
-        buf.truncate(n); // Keep only the actual bytes read
-
-        let response = protocol.decode_response(&buf)?;
-
-        Ok(response)
+        for _ in 0..=MAX_REDIRECTS {
+            write_frame(&mut self.stream, &encoded)?;
+            let buf = read_frame(&mut self.stream, usize::MAX)?
+                .ok_or_else(|| KvsError::Protocol("Connection closed by server".into()))?;
+
+            let response = protocol.decode_response(&buf)?;
+
+            // Follow a cluster node's redirect to the leader
+            let leader = match &response {
+                Response::Error(err) => err.strip_prefix(MOVED),
+                _ => None,
+            };
+            let Some(leader) = leader else {
+                return Ok(response);
+            };
+            let addr: SocketAddr = leader
+                .parse()
+                .map_err(|_| KvsError::Protocol(format!("Invalid redirect: {}", leader)))?;
+            info!("Redirected to leader {}", addr);
+            self.stream = TcpStream::connect(addr)?;
+        }
+
+        Err(KvsError::Protocol("Too many redirects".into()))
     }
 }
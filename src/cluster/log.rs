@@ -0,0 +1,137 @@
+use super::rpc::{Command, LogEntry};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+/// What a node must remember across restarts besides the log itself
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+    /// Entries up to here are applied to the store and dropped from the log
+    pub snapshot_index: u64,
+    pub snapshot_term: u64,
+}
+
+// A snapshot being received from the leader, one command per line
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+
+/// The raft log and hard state in `<data_dir>/raft`
+pub struct RaftLog {
+    dir: PathBuf,
+    file: File,
+}
+
+impl RaftLog {
+    pub fn open(dir: PathBuf) -> Result<(RaftLog, HardState, Vec<LogEntry>)> {
+        fs::create_dir_all(&dir)?;
+
+        let state_path = dir.join("state.json");
+        let state: HardState = match state_path.exists() {
+            true => serde_json::from_slice(&fs::read(&state_path)?)?,
+            false => HardState::default(),
+        };
+
+        // A torn write can leave a partial last line; everything before it
+        // was synced, and the tail is refetched from the leader
+        let log_path = dir.join("log.jsonl");
+        let mut entries: Vec<LogEntry> = vec![];
+        if log_path.exists() {
+            for line in BufReader::new(File::open(&log_path)?).lines() {
+                let Ok(entry) = serde_json::from_str::<LogEntry>(&line?) else {
+                    break;
+                };
+                // Compaction saves the state before rewriting the log, so
+                // the file may still start with entries now in the snapshot
+                if entry.index <= state.snapshot_index {
+                    continue;
+                }
+                // Truncation rewrites the file, so entries are contiguous
+                entries.push(entry);
+            }
+        }
+
+        let mut log = RaftLog {
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_path)?,
+            dir,
+        };
+        log.rewrite(&entries)?;
+
+        Ok((log, state, entries))
+    }
+
+    pub fn save_state(&self, state: &HardState) -> Result<()> {
+        let tmp_path = self.dir.join("state.json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(state)?)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, self.dir.join("state.json"))?;
+        Ok(())
+    }
+
+    pub fn append(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let mut buffer = vec![];
+        for entry in entries {
+            serde_json::to_writer(&mut buffer, entry)?;
+            buffer.push(b'\n');
+        }
+        self.file.write_all(&buffer)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the file's contents, after truncation or compaction
+    pub fn rewrite(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let tmp_path = self.dir.join("log.jsonl.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for entry in entries {
+            serde_json::to_writer(&mut tmp, entry)?;
+            tmp.write_all(b"\n")?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join("log.jsonl"))?;
+
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join("log.jsonl"))?;
+        Ok(())
+    }
+
+    /// Starts staging a snapshot, dropping any partly received one
+    pub fn start_snapshot(&self) -> Result<()> {
+        File::create(self.dir.join(SNAPSHOT_FILE))?;
+        Ok(())
+    }
+
+    /// Adds a chunk to the snapshot being staged. It is not synced: a
+    /// snapshot cut short by a restart is sent again from the start.
+    pub fn stage_snapshot(&self, commands: &[Command]) -> Result<()> {
+        let mut buffer = vec![];
+        for command in commands {
+            serde_json::to_writer(&mut buffer, command)?;
+            buffer.push(b'\n');
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(SNAPSHOT_FILE))?
+            .write_all(&buffer)?;
+        Ok(())
+    }
+
+    /// The staged snapshot's commands, read as they are used
+    pub fn staged_snapshot(&self) -> Result<impl Iterator<Item = Result<Command>> + use<>> {
+        let file = File::open(self.dir.join(SNAPSHOT_FILE))?;
+        Ok(BufReader::new(file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?)))
+    }
+
+    pub fn drop_snapshot(&self) -> Result<()> {
+        fs::remove_file(self.dir.join(SNAPSHOT_FILE))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+//! Raft replicated cluster mode.
+//!
+//! Every node keeps a raft log of `Set`/`Remove` commands and applies
+//! committed entries to its local `KvStore`. Only the leader answers
+//! clients; other nodes reply with `MOVED <leader address>`, which
+//! `KvsClient` follows. Before a read the leader checks with a quorum that
+//! it still leads, so a deposed leader never serves stale values.
+//!
+//! Applied entries are dropped from the log once the store has flushed
+//! them, so the store doubles as the raft snapshot, and a node too far
+//! behind is streamed the store's own log in chunks instead of the entries.
+//!
+//! Nodes talk over their own `raft_addr` with one JSON message per
+//! connection, which keeps the transport simple at the cost of a connect per
+//! message.
+
+use crate::protocols::MOVED;
+use crate::storage::backup_dest;
+use crate::{Config, KvStore, KvsError, Request, Response, Result, Storage, StoreTrait};
+use raft::{HEARTBEAT_INTERVAL, Node, Role};
+use rpc::{Command, Message};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::error;
+
+mod log;
+mod raft;
+mod rpc;
+
+// How often timers are checked
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+// How long a write waits to commit, or a read for a quorum to confirm the
+// leader, before the client is told to retry
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest `limits.max_request_bytes` a cluster node accepts, so that every
+/// entry fits in a raft message
+pub(crate) const MAX_REQUEST_BYTES: usize = 8 * 1024 * 1024;
+// Answer while no leader is known
+const NO_LEADER: &str = "NOLEADER election in progress, retry";
+
+/// Handle to this node's raft state, shared by the server's workers.
+#[derive(Clone)]
+pub struct Cluster {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    node: Mutex<Node>,
+    // Signalled whenever entries are applied or the role changes
+    changed: Condvar,
+    // Client addresses by node id, for redirects
+    addrs: HashMap<u64, SocketAddr>,
+    backup_root: Option<PathBuf>,
+}
+
+/// Starts raft for `config.cluster`, or returns `None` when it is disabled.
+pub(crate) fn start(
+    config: &Config,
+    store: &Storage,
+    shutdown: watch::Receiver<bool>,
+) -> Result<Option<Cluster>> {
+    if !config.cluster.enabled {
+        return Ok(None);
+    }
+    let Storage::Kvs(store) = store else {
+        return Err(KvsError::Config {
+            key: "storage.engine".into(),
+            message: "a cluster node must use the kvs engine".into(),
+        });
+    };
+    Cluster::start(config, store.clone(), shutdown).map(Some)
+}
+
+impl Cluster {
+    fn start(config: &Config, store: KvStore, shutdown: watch::Receiver<bool>) -> Result<Cluster> {
+        let cluster = &config.cluster;
+        let node = cluster.node().ok_or_else(|| KvsError::Config {
+            key: "cluster.id".into(),
+            message: "not listed in cluster.nodes".into(),
+        })?;
+
+        let peers = cluster
+            .nodes
+            .iter()
+            .filter(|peer| peer.id != node.id)
+            .map(|peer| (peer.id, peer.raft_addr))
+            .collect();
+        let addrs = cluster
+            .nodes
+            .iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+
+        let (raft_log, state, entries) =
+            log::RaftLog::open(config.server.data_dir()?.join("raft"))?;
+        let node_state = Node::new(
+            node.id,
+            peers,
+            raft_log,
+            state,
+            entries,
+            store,
+            cluster.snapshot_entries,
+        );
+
+        let cluster = Cluster {
+            shared: Arc::new(Shared {
+                node: Mutex::new(node_state),
+                changed: Condvar::new(),
+                addrs,
+                backup_root: config.server.backup_root.clone(),
+            }),
+        };
+
+        let listener = cluster.clone();
+        rpc::listen(
+            node.raft_addr,
+            move |message| listener.receive(message),
+            shutdown.clone(),
+        )?;
+
+        let ticker = cluster.clone();
+        thread::spawn(move || {
+            while !*shutdown.borrow() {
+                thread::sleep(TICK_INTERVAL);
+                if let Err(e) = ticker.tick() {
+                    error!("Raft tick failed: {}", e);
+                }
+            }
+        });
+
+        Ok(cluster)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Node>> {
+        self.shared.node.lock().map_err(|_| KvsError::LockPoisoned)
+    }
+
+    // Messages go out on their own threads so a slow peer never holds the lock
+    fn tick(&self) -> Result<()> {
+        let messages = self.lock()?.tick()?;
+        self.shared.changed.notify_all();
+
+        for (id, message) in messages {
+            let cluster = self.clone();
+            thread::spawn(move || {
+                let Ok(node) = cluster.lock() else {
+                    return;
+                };
+                let Some(addr) = node.peers.get(&id).map(|peer| peer.raft_addr) else {
+                    return;
+                };
+                drop(node);
+
+                let reply = rpc::call(addr, &message).ok();
+                if let Ok(mut node) = cluster.lock()
+                    && let Err(e) = node.handle_reply(id, &message, reply)
+                {
+                    error!("Failed to handle reply from node {}: {}", id, e);
+                }
+                cluster.shared.changed.notify_all();
+            });
+        }
+        Ok(())
+    }
+
+    fn receive(&self, message: Message) -> Message {
+        let reply = match self.lock() {
+            Ok(mut node) => node.handle(message),
+            Err(e) => Err(e),
+        };
+        self.shared.changed.notify_all();
+        reply.unwrap_or_else(|e| {
+            error!("Failed to handle raft message: {}", e);
+            // Refusing with term 0 is ignored by the sender
+            Message::Appended {
+                term: 0,
+                success: false,
+                match_index: 0,
+            }
+        })
+    }
+
+    /// The leader's client address, if one is known.
+    pub fn leader(&self) -> Option<SocketAddr> {
+        let leader = self.lock().ok()?.leader?;
+        self.shared.addrs.get(&leader).copied()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock().is_ok_and(|node| node.role == Role::Leader)
+    }
+
+    /// Serves a client request: reads from the leader's store once a
+    /// majority confirms it still leads, writes once a majority has logged
+    /// them. Other nodes redirect to the leader.
+    pub fn handle(&self, store: &Storage, request: Request) -> Response {
+        let command = match request {
+            Request::Get { key } => {
+                match self.confirm_leadership() {
+                    Ok(true) => {}
+                    Ok(false) => return self.redirect(),
+                    Err(e) => return Response::Error(e.to_string()),
+                }
+                return match store.get(key) {
+                    Ok(Some(value)) => Response::Value(value),
+                    Ok(None) => Response::NotFound,
+                    Err(e) => Response::Error(e.to_string()),
+                };
+            }
+            Request::Set { key, value } => Command::Set { key, value },
+            Request::Remove { key } => Command::Remove { key },
+            // Every node can snapshot its own store
+            Request::Backup { dest } => {
+                let root = self.shared.backup_root.as_deref();
+                return match backup_dest(root, &dest).and_then(|dest| store.backup(&dest)) {
+                    Ok(()) => Response::Ok,
+                    Err(e) => Response::Error(e.to_string()),
+                };
+            }
+        };
+
+        match self.replicate(command) {
+            Ok(Some(response)) => response,
+            Ok(None) => self.redirect(),
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+
+    // Raft's read index: a read sees every write acknowledged before it once
+    // this term has committed an entry, which carries the earlier terms'
+    // entries with it, and a quorum has answered messages sent after the
+    // read arrived, so no newer leader can have committed anything yet.
+    // `false` when this node is not, or stopped being, the leader.
+    fn confirm_leadership(&self) -> Result<bool> {
+        let mut node = self.lock()?;
+        let term = node.term();
+        let since = Instant::now();
+        node.heartbeat_now();
+
+        let deadline = since + COMMIT_TIMEOUT;
+        loop {
+            if node.role != Role::Leader || node.term() != term {
+                return Ok(false);
+            }
+            if node.confirmed_since(since)
+                && node.term_at(node.commit_index) == Some(term)
+                && node.last_applied >= node.commit_index
+            {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Err(KvsError::Concurrency(
+                    "leadership not confirmed in time".into(),
+                ));
+            }
+            node = self
+                .shared
+                .changed
+                .wait_timeout(node, HEARTBEAT_INTERVAL)
+                .map_err(|_| KvsError::LockPoisoned)?
+                .0;
+        }
+    }
+
+    // `None` when this node is not, or stopped being, the leader
+    fn replicate(&self, command: Command) -> Result<Option<Response>> {
+        let mut node = self.lock()?;
+        let term = node.term();
+        let Some(index) = node.propose(command)? else {
+            return Ok(None);
+        };
+        self.shared.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if let Some(Some(_)) = node.pending.get(&index) {
+                let response = node.pending.remove(&index).flatten();
+                // Applied at our index, but possibly another leader's entry
+                return match node.term_at(index) {
+                    Some(t) if t == term => Ok(response),
+                    Some(_) => Ok(None),
+                    None => Ok(response),
+                };
+            }
+            let now = Instant::now();
+            if node.role != Role::Leader || node.term() != term || now >= deadline {
+                node.pending.remove(&index);
+                return match now >= deadline {
+                    true => Err(KvsError::Concurrency("write not committed in time".into())),
+                    false => Ok(None),
+                };
+            }
+            node = self
+                .shared
+                .changed
+                .wait_timeout(node, HEARTBEAT_INTERVAL)
+                .map_err(|_| KvsError::LockPoisoned)?
+                .0;
+        }
+    }
+
+    fn redirect(&self) -> Response {
+        match self.leader() {
+            Some(addr) if !self.is_leader() => Response::Error(format!("{MOVED}{addr}")),
+            _ => Response::Error(NO_LEADER.to_string()),
+        }
+    }
+}
@@ -0,0 +1,692 @@
+use super::log::{HardState, RaftLog};
+use super::rpc::{Command, LogEntry, Message};
+use crate::{Entry, KvStore, KvsError, LogPosition, Response, Result, StoreTrait};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+const ELECTION_TIMEOUT_MS: std::ops::Range<u64> = 300..600;
+// Store log bytes per snapshot chunk
+const SNAPSHOT_CHUNK_BYTES: u64 = 1024 * 1024;
+// Key and value bytes after which a batch of entries takes no more
+const APPEND_BATCH_BYTES: u64 = 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub struct Peer {
+    pub raft_addr: SocketAddr,
+    next_index: u64,
+    match_index: u64,
+    in_flight: bool,
+    last_sent: Option<Instant>,
+    // When the latest message the peer answered in this term was sent
+    acked: Option<Instant>,
+    // A read is waiting on the peer's next answer, so it is sent at once
+    heartbeat: bool,
+    // Progress of the snapshot being streamed to the peer
+    snapshot: Option<SnapshotStream>,
+}
+
+// The store's log is sent from `from` as chunk `chunk`; `next` is where the
+// chunk after it starts once this one is acknowledged
+#[derive(Default)]
+struct SnapshotStream {
+    chunk: u64,
+    from: LogPosition,
+    next: LogPosition,
+}
+
+/// One member's view of the cluster. All methods run under the cluster lock,
+/// and none of them touch the network: messages to send are returned instead.
+pub struct Node {
+    pub id: u64,
+    pub role: Role,
+    pub leader: Option<u64>,
+    pub peers: HashMap<u64, Peer>,
+    state: HardState,
+    log: Vec<LogEntry>,
+    raft_log: RaftLog,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    votes: HashSet<u64>,
+    election_deadline: Instant,
+    store: KvStore,
+    snapshot_entries: u64,
+    // The chunk expected next of the snapshot being received
+    snapshot_chunk: Option<u64>,
+    /// Results for proposals a client is waiting on, by log index
+    pub pending: HashMap<u64, Option<Response>>,
+}
+
+impl Node {
+    pub fn new(
+        id: u64,
+        peers: HashMap<u64, SocketAddr>,
+        raft_log: RaftLog,
+        state: HardState,
+        log: Vec<LogEntry>,
+        store: KvStore,
+        snapshot_entries: u64,
+    ) -> Node {
+        let peers = peers
+            .into_iter()
+            .map(|(id, raft_addr)| {
+                let peer = Peer {
+                    raft_addr,
+                    next_index: 1,
+                    match_index: 0,
+                    in_flight: false,
+                    last_sent: None,
+                    acked: None,
+                    heartbeat: false,
+                    snapshot: None,
+                };
+                (id, peer)
+            })
+            .collect();
+
+        // The store already holds everything up to the snapshot; later
+        // entries are applied again once the leader reports them committed
+        let applied = state.snapshot_index;
+
+        Node {
+            id,
+            role: Role::Follower,
+            leader: None,
+            peers,
+            state,
+            log,
+            raft_log,
+            commit_index: applied,
+            last_applied: applied,
+            votes: HashSet::new(),
+            election_deadline: election_deadline(),
+            store,
+            snapshot_entries,
+            snapshot_chunk: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.state.snapshot_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    /// `None` for entries dropped into the snapshot or not yet received
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            return Some(self.state.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        let offset = index.checked_sub(self.state.snapshot_index + 1)?;
+        self.log.get(usize::try_from(offset).ok()?)
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    /// Whether a quorum, this node included, has answered messages sent at
+    /// or after `since`, so no other leader had been elected by then
+    pub fn confirmed_since(&self, since: Instant) -> bool {
+        let acked = self
+            .peers
+            .values()
+            .filter(|peer| peer.acked.is_some_and(|sent| sent >= since))
+            .count();
+        acked + 1 >= self.quorum()
+    }
+
+    /// Has the next tick heartbeat every peer, rather than when one is due.
+    pub fn heartbeat_now(&mut self) {
+        for peer in self.peers.values_mut() {
+            peer.heartbeat = true;
+        }
+    }
+
+    /// Appends a client command if this node leads, returning its index.
+    pub fn propose(&mut self, command: Command) -> Result<Option<u64>> {
+        if self.role != Role::Leader {
+            return Ok(None);
+        }
+        let index = self.append(command)?;
+        self.pending.insert(index, None);
+        // A single node cluster commits on its own
+        self.advance_commit()?;
+        Ok(Some(index))
+    }
+
+    fn append(&mut self, command: Command) -> Result<u64> {
+        let entry = LogEntry {
+            term: self.state.term,
+            index: self.last_index() + 1,
+            command,
+        };
+        self.raft_log.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        Ok(self.last_index())
+    }
+
+    /// Timer work: start elections, and as leader keep followers fed.
+    pub fn tick(&mut self) -> Result<Vec<(u64, Message)>> {
+        let now = Instant::now();
+
+        if self.role != Role::Leader {
+            if now < self.election_deadline {
+                return Ok(vec![]);
+            }
+            return self.start_election();
+        }
+
+        let ids: Vec<u64> = self.peers.keys().copied().collect();
+        let mut messages = vec![];
+        for id in ids {
+            let last_index = self.last_index();
+            let peer = &self.peers[&id];
+            let behind = peer.next_index <= last_index;
+            let heartbeat_due = peer.heartbeat
+                || peer
+                    .last_sent
+                    .is_none_or(|sent| now.duration_since(sent) >= HEARTBEAT_INTERVAL);
+            if peer.in_flight || !(behind || heartbeat_due) {
+                continue;
+            }
+
+            let message = self.replicate_to(id)?;
+            let peer = self.peers.get_mut(&id).expect("peer exists");
+            peer.in_flight = true;
+            peer.heartbeat = false;
+            peer.last_sent = Some(now);
+            messages.push((id, message));
+        }
+        Ok(messages)
+    }
+
+    fn replicate_to(&mut self, id: u64) -> Result<Message> {
+        let next_index = self.peers[&id].next_index;
+
+        // Entries the peer needs were compacted away, so send the state
+        if next_index <= self.state.snapshot_index {
+            return self.snapshot_chunk(id);
+        }
+
+        // A batch always has room for one entry, however large, so it stays
+        // under `MAX_MESSAGE_BYTES` as long as entries stay under the
+        // cluster's request limit
+        let prev_log_index = next_index - 1;
+        let mut bytes = 0;
+        let entries = (next_index..=self.last_index())
+            .filter_map(|index| self.entry(index).cloned())
+            .take(256)
+            .take_while(|entry| {
+                let room = bytes < APPEND_BATCH_BYTES;
+                bytes += entry.command.size();
+                room
+            })
+            .collect();
+        Ok(Message::AppendEntries {
+            term: self.state.term,
+            leader: self.id,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+            entries,
+            leader_commit: self.commit_index,
+        })
+    }
+
+    // The store holds everything applied, so its own log is replayed to the
+    // peer a chunk at a time. The stream continues where the last chunk the
+    // peer acknowledged ended, or starts over if compaction removed that
+    // point. The last chunk has no commands, and is read under the same lock
+    // as `last_applied`, so the peer's replay stands exactly there.
+    fn snapshot_chunk(&mut self, id: u64) -> Result<Message> {
+        let last_term = self.term_at(self.last_applied).unwrap_or(0);
+        let peer = self.peers.get_mut(&id).expect("peer exists");
+        let stream = peer.snapshot.get_or_insert_with(SnapshotStream::default);
+        let batch = self.store.read_log(stream.from, SNAPSHOT_CHUNK_BYTES)?;
+        if batch.reset {
+            stream.chunk = 0;
+        }
+        stream.next = batch.next;
+
+        let commands = batch
+            .entries
+            .into_iter()
+            .map(|entry| match entry {
+                Entry::Set { key, value } => Ok(Command::Set { key, value }),
+                Entry::Remove { key } => Ok(Command::Remove { key }),
+                // `read_log` resolves pointers to their values
+                Entry::Blob { .. } => Err(KvsError::CorruptedLog),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Message::InstallSnapshot {
+            term: self.state.term,
+            leader: self.id,
+            last_index: self.last_applied,
+            last_term,
+            chunk: stream.chunk,
+            done: commands.is_empty(),
+            commands,
+        })
+    }
+
+    fn start_election(&mut self) -> Result<Vec<(u64, Message)>> {
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.state.term += 1;
+        self.state.voted_for = Some(self.id);
+        self.raft_log.save_state(&self.state)?;
+        self.votes = HashSet::from([self.id]);
+        self.election_deadline = election_deadline();
+        info!(
+            "Node {} starting election for term {}",
+            self.id, self.state.term
+        );
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader()?;
+            return Ok(vec![]);
+        }
+
+        let message = |_| Message::RequestVote {
+            term: self.state.term,
+            candidate: self.id,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        Ok(self.peers.keys().map(|id| (*id, message(id))).collect())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!(
+            "Node {} became leader for term {}",
+            self.id, self.state.term
+        );
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next_index = self.last_index() + 1;
+        for peer in self.peers.values_mut() {
+            peer.next_index = next_index;
+            peer.match_index = 0;
+            peer.last_sent = None;
+            peer.acked = None;
+            peer.snapshot = None;
+        }
+        // Entries from earlier terms only commit along with one from this term
+        self.append(Command::Noop)?;
+        self.advance_commit()
+    }
+
+    fn step_down(&mut self, term: u64, leader: Option<u64>) -> Result<()> {
+        if term > self.state.term {
+            self.state.term = term;
+            self.state.voted_for = None;
+            self.raft_log.save_state(&self.state)?;
+        }
+        if self.role == Role::Leader {
+            info!("Node {} stepping down in term {}", self.id, term);
+        }
+        self.role = Role::Follower;
+        if leader.is_some() {
+            self.leader = leader;
+        }
+        Ok(())
+    }
+
+    /// Answers a message from another node.
+    pub fn handle(&mut self, message: Message) -> Result<Message> {
+        match message {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > self.state.term {
+                    self.step_down(term, None)?;
+                    self.leader = None;
+                }
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.state.term
+                    && self.state.voted_for.is_none_or(|id| id == candidate)
+                    && up_to_date;
+                if granted {
+                    self.state.voted_for = Some(candidate);
+                    self.raft_log.save_state(&self.state)?;
+                    self.election_deadline = election_deadline();
+                }
+                Ok(Message::Vote {
+                    term: self.state.term,
+                    granted,
+                })
+            }
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let reply = |node: &Node, success, match_index| Message::Appended {
+                    term: node.state.term,
+                    success,
+                    match_index,
+                };
+                if term < self.state.term {
+                    return Ok(reply(self, false, 0));
+                }
+                self.step_down(term, Some(leader))?;
+                self.election_deadline = election_deadline();
+
+                if prev_log_index > self.last_index() {
+                    return Ok(reply(self, false, self.last_index()));
+                }
+                // Anything up to the snapshot is committed, so it matches
+                if prev_log_index > self.state.snapshot_index
+                    && self.term_at(prev_log_index) != Some(prev_log_term)
+                {
+                    return Ok(reply(self, false, prev_log_index - 1));
+                }
+
+                let match_index = prev_log_index + entries.len() as u64;
+                self.merge(entries)?;
+                if leader_commit > self.commit_index {
+                    self.commit_index = leader_commit.min(match_index.max(self.commit_index));
+                    self.apply()?;
+                }
+                Ok(reply(self, true, match_index))
+            }
+            Message::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                chunk,
+                commands,
+                done,
+            } => {
+                let reply = |node: &Node, accepted| Message::Installed {
+                    term: node.state.term,
+                    accepted,
+                };
+                if term < self.state.term {
+                    return Ok(reply(self, false));
+                }
+                self.step_down(term, Some(leader))?;
+                self.election_deadline = election_deadline();
+
+                // Chunks are staged on disk until the last one arrives, so
+                // the store is never left holding half a snapshot
+                match self.snapshot_chunk {
+                    _ if chunk == 0 => self.raft_log.start_snapshot()?,
+                    Some(next) if chunk == next => {}
+                    // Sent again because our answer was lost
+                    Some(next) if chunk < next => return Ok(reply(self, true)),
+                    _ => {
+                        self.snapshot_chunk = None;
+                        return Ok(reply(self, false));
+                    }
+                }
+                self.raft_log.stage_snapshot(&commands)?;
+                self.snapshot_chunk = Some(chunk + 1);
+
+                if done {
+                    if last_index > self.commit_index {
+                        self.install(last_index, last_term)?;
+                    }
+                    self.raft_log.drop_snapshot()?;
+                }
+                Ok(reply(self, true))
+            }
+            other => Err(KvsError::Protocol(format!(
+                "unexpected raft message {:?}",
+                other
+            ))),
+        }
+    }
+
+    // Appends the leader's entries, dropping any conflicting suffix of ours
+    fn merge(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        let mut new = vec![];
+        for entry in entries {
+            if entry.index <= self.state.snapshot_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term && new.is_empty() => continue,
+                Some(_) if new.is_empty() => {
+                    let keep = (entry.index - self.state.snapshot_index - 1) as usize;
+                    warn!(
+                        "Node {} dropping {} conflicting entries from {}",
+                        self.id,
+                        self.log.len() - keep,
+                        entry.index
+                    );
+                    self.log.truncate(keep);
+                    self.raft_log.rewrite(&self.log)?;
+                    new.push(entry);
+                }
+                _ => new.push(entry),
+            }
+        }
+        if !new.is_empty() {
+            self.raft_log.append(&new)?;
+            self.log.extend(new);
+        }
+        Ok(())
+    }
+
+    fn install(&mut self, last_index: u64, last_term: u64) -> Result<()> {
+        info!("Node {} installing snapshot at {}", self.id, last_index);
+        self.store.clear()?;
+        for command in self.raft_log.staged_snapshot()? {
+            match command? {
+                Command::Set { key, value } => self.store.set(key, value)?,
+                Command::Remove { key } => match self.store.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+                Command::Noop => {}
+            }
+        }
+        self.store.flush()?;
+
+        self.state.snapshot_index = last_index;
+        self.state.snapshot_term = last_term;
+        self.raft_log.save_state(&self.state)?;
+        self.log.clear();
+        self.raft_log.rewrite(&self.log)?;
+        self.commit_index = last_index;
+        self.last_applied = last_index;
+        Ok(())
+    }
+
+    /// Takes in a peer's answer to `request`.
+    pub fn handle_reply(
+        &mut self,
+        id: u64,
+        request: &Message,
+        reply: Option<Message>,
+    ) -> Result<()> {
+        if let Some(peer) = self.peers.get_mut(&id)
+            && !matches!(request, Message::RequestVote { .. })
+        {
+            peer.in_flight = false;
+        }
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+
+        let term = match &reply {
+            Message::Vote { term, .. }
+            | Message::Appended { term, .. }
+            | Message::Installed { term, .. } => *term,
+            _ => return Ok(()),
+        };
+        if term > self.state.term {
+            self.step_down(term, None)?;
+            self.leader = None;
+            return Ok(());
+        }
+        if term < self.state.term {
+            return Ok(());
+        }
+        // Only one message is in flight per peer, so this answers the last
+        // one sent
+        if self.role == Role::Leader
+            && !matches!(reply, Message::Vote { .. })
+            && let Some(peer) = self.peers.get_mut(&id)
+        {
+            peer.acked = peer.last_sent;
+        }
+
+        match (reply, request) {
+            (Message::Vote { granted: true, .. }, _) if self.role == Role::Candidate => {
+                self.votes.insert(id);
+                if self.votes.len() >= self.quorum() {
+                    self.become_leader()?;
+                }
+            }
+            (
+                Message::Appended {
+                    success,
+                    match_index,
+                    ..
+                },
+                _,
+            ) if self.role == Role::Leader => {
+                let last_index = self.last_index();
+                let peer = self.peers.get_mut(&id).expect("peer exists");
+                if success {
+                    peer.match_index = peer.match_index.max(match_index);
+                    peer.next_index = peer.match_index + 1;
+                    self.advance_commit()?;
+                } else {
+                    peer.next_index = (peer.next_index - 1)
+                        .min(match_index + 1)
+                        .clamp(1, last_index + 1);
+                }
+            }
+            (
+                Message::Installed { accepted, .. },
+                Message::InstallSnapshot {
+                    last_index,
+                    chunk,
+                    done,
+                    ..
+                },
+            ) if self.role == Role::Leader => {
+                let peer = self.peers.get_mut(&id).expect("peer exists");
+                match (accepted, *done) {
+                    // Out of order for the peer, so the stream starts over
+                    (false, _) => peer.snapshot = None,
+                    (true, false) => {
+                        if let Some(stream) = &mut peer.snapshot
+                            && stream.chunk == *chunk
+                        {
+                            stream.chunk += 1;
+                            stream.from = stream.next;
+                        }
+                    }
+                    (true, true) => {
+                        peer.snapshot = None;
+                        peer.match_index = peer.match_index.max(*last_index);
+                        peer.next_index = peer.match_index + 1;
+                        self.advance_commit()?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Commit the highest entry of this term that a majority holds
+    fn advance_commit(&mut self) -> Result<()> {
+        let mut matched: Vec<u64> = self.peers.values().map(|peer| peer.match_index).collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = matched[self.quorum() - 1];
+
+        if majority > self.commit_index && self.term_at(majority) == Some(self.state.term) {
+            self.commit_index = majority;
+            self.apply()?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let command = match self.entry(index) {
+                Some(entry) => entry.command.clone(),
+                None => break,
+            };
+
+            let response = match command {
+                Command::Noop => Response::Ok,
+                Command::Set { key, value } => {
+                    self.store.set(key, value)?;
+                    Response::Ok
+                }
+                Command::Remove { key } => match self.store.remove(key) {
+                    Ok(()) => Response::Ok,
+                    Err(KvsError::KeyNotFound) => Response::NotFound,
+                    Err(e) => return Err(e),
+                },
+            };
+            if let Some(pending) = self.pending.get_mut(&index) {
+                *pending = Some(response);
+            }
+            self.last_applied = index;
+        }
+
+        self.compact()
+    }
+
+    // Applied entries live on in the store, so the log can drop them once
+    // the store has them on disk. KvStore compacts its own log as it goes.
+    fn compact(&mut self) -> Result<()> {
+        let applied = self.last_applied - self.state.snapshot_index;
+        if applied < self.snapshot_entries {
+            return Ok(());
+        }
+
+        self.store.flush()?;
+        let snapshot_term = self.term_at(self.last_applied).unwrap_or(0);
+        self.log.drain(..applied as usize);
+        self.state.snapshot_index = self.last_applied;
+        self.state.snapshot_term = snapshot_term;
+        // The state goes first: a log still holding older entries is fine
+        self.raft_log.save_state(&self.state)?;
+        self.raft_log.rewrite(&self.log)?;
+        Ok(())
+    }
+}
+
+fn election_deadline() -> Instant {
+    Instant::now() + Duration::from_millis(fastrand::u64(ELECTION_TIMEOUT_MS))
+}
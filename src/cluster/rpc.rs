@@ -0,0 +1,166 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::error;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(10);
+// Longest message read from a peer. Batches of entries and snapshot chunks
+// stay well under it, even with JSON escaping every byte of a value.
+pub const MAX_MESSAGE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A state machine command carried by the log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Appended by a new leader so entries from earlier terms commit
+    Noop,
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+impl Command {
+    /// Bytes of key and value carried
+    pub fn size(&self) -> u64 {
+        match self {
+            Command::Noop => 0,
+            Command::Set { key, value } => (key.len() + value.len()) as u64,
+            Command::Remove { key } => key.len() as u64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        candidate: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        leader: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// On failure `match_index` is a hint of where the follower's log ends
+    Appended {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// One chunk of the leader's state, for nodes too far behind for the
+    /// entries it still keeps. The chunks replay the leader's store log in
+    /// order from chunk 0; the last, `done`, has no commands and leaves the
+    /// state as of `last_index`.
+    InstallSnapshot {
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        chunk: u64,
+        commands: Vec<Command>,
+        done: bool,
+    },
+    /// Not `accepted` when the chunk is out of order, so the leader starts
+    /// the snapshot over
+    Installed {
+        term: u64,
+        accepted: bool,
+    },
+}
+
+/// Sends one message and waits for the reply.
+pub fn call(addr: SocketAddr, message: &Message) -> Result<Message> {
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    send(&mut stream, message)?;
+    receive(&mut BufReader::new(stream))
+}
+
+/// Answers messages arriving on `addr` with `handle` until shutdown.
+pub fn listen<F>(addr: SocketAddr, handle: F, shutdown: watch::Receiver<bool>) -> Result<()>
+where
+    F: Fn(Message) -> Message + Clone + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+
+    thread::spawn(move || {
+        while !*shutdown.borrow() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let handle = handle.clone();
+                    thread::spawn(move || {
+                        let _ = answer(stream, handle);
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) => {
+                    error!("Raft listener failed: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn answer<F: Fn(Message) -> Message>(stream: TcpStream, handle: F) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let message = receive(&mut BufReader::new(stream))?;
+    send(&mut writer, &handle(message))
+}
+
+// One JSON document per line
+fn send(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Anyone who can reach `raft_addr` can send, so a line is only buffered up
+// to the limit
+fn receive(reader: &mut BufReader<TcpStream>) -> Result<Message> {
+    let mut line = String::new();
+    match reader.take(MAX_MESSAGE_BYTES).read_line(&mut line)? {
+        0 => return Err(KvsError::Protocol("connection closed".into())),
+        _ if !line.ends_with('\n') && line.len() as u64 == MAX_MESSAGE_BYTES => {
+            return Err(KvsError::Protocol(format!(
+                "raft message exceeds the limit of {} bytes",
+                MAX_MESSAGE_BYTES
+            )));
+        }
+        _ => {}
+    }
+    Ok(serde_json::from_str(&line)?)
+}
@@ -1,3 +1,7 @@
+// `failure_derive` expands into impls nested inside an anonymous const.
+#![allow(non_local_definitions)]
+
+use crate::Engine;
 use failure::Fail;
 
 #[derive(Fail, Debug)]
@@ -41,8 +45,17 @@ pub enum KvsError {
     #[fail(display = "Unexpected log entry or command: {}", _0)]
     UnexpectedCommand(String),
 
-    #[fail(display = "Engine mismatch error")]
-    WrongEngine,
+    #[fail(
+        display = "Engine mismatch: the directory holds {} data, not {}",
+        found, wanted
+    )]
+    WrongEngine { found: Engine, wanted: Engine },
+
+    #[fail(
+        display = "Unsupported format version {}; this build reads up to {}",
+        found, supported
+    )]
+    FormatVersion { found: u32, supported: u32 },
 
     #[fail(display = "Concurrency error: {}", _0)]
     Concurrency(String),
@@ -58,6 +71,33 @@ pub enum KvsError {
 
     #[fail(display = "Lock poison error")]
     LockPoisoned,
+
+    #[fail(display = "Replication error: {}", _0)]
+    Replication(String),
+
+    #[fail(display = "Backup error: {}", _0)]
+    Backup(String),
+
+    #[fail(display = "Migration error: {}", _0)]
+    Migration(String),
+
+    #[fail(display = "Dump error: {}", _0)]
+    Dump(String),
+
+    #[fail(display = "Data directory locked: {}", _0)]
+    DirectoryLocked(String),
+
+    #[fail(display = "Store was opened read-only")]
+    ReadOnly,
+
+    #[fail(display = "Unsupported: {}", _0)]
+    Unsupported(String),
+
+    #[fail(display = "Encryption error: {}", _0)]
+    Encryption(String),
+
+    #[fail(display = "Invalid config key `{}`: {}", key, message)]
+    Config { key: String, message: String },
 }
 
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -121,45 +161,3 @@ impl From<&str> for KvsError {
         KvsError::Protocol(s.to_string())
     }
 }
-
-impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, sled::Db>>> for KvsError {
-    fn from(err: std::sync::PoisonError<std::sync::MutexGuard<'_, sled::Db>>) -> Self {
-        KvsError::LockError(err.to_string())
-    }
-}
-
-impl
-    From<
-        std::sync::PoisonError<
-            std::sync::MutexGuard<'_, std::collections::HashMap<String, String>>,
-        >,
-    > for KvsError
-{
-    fn from(
-        err: std::sync::PoisonError<
-            std::sync::MutexGuard<'_, std::collections::HashMap<String, String>>,
-        >,
-    ) -> Self {
-        KvsError::LockError(err.to_string())
-    }
-}
-
-impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, std::io::BufReader<std::fs::File>>>>
-    for KvsError
-{
-    fn from(
-        err: std::sync::PoisonError<std::sync::MutexGuard<'_, std::io::BufReader<std::fs::File>>>,
-    ) -> Self {
-        KvsError::LockError(err.to_string())
-    }
-}
-
-impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, std::io::BufWriter<std::fs::File>>>>
-    for KvsError
-{
-    fn from(
-        err: std::sync::PoisonError<std::sync::MutexGuard<'_, std::io::BufWriter<std::fs::File>>>,
-    ) -> Self {
-        KvsError::LockError(err.to_string())
-    }
-}
@@ -0,0 +1,15 @@
+/// 64-bit hash that is stable across runs and platforms, unlike
+/// `DefaultHasher`.
+///
+/// FNV-1a, finished with the splitmix64 mixer so nearby inputs such as
+/// `addr#1` and `addr#2` land far apart.
+pub(crate) fn hash64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -1,29 +1,63 @@
-use tracing_log::LogTracer;
-use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use crate::{KvsError, LogFormat, LoggingConfig, Result};
+use tracing::warn;
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
-pub fn init_logging() {
-    // Capture logs from crates still using the `log` crate
-    LogTracer::init().expect("Failed to set logger");
+/// Changes the log filter of the running process, e.g. on a config reload.
+#[derive(Clone, Debug)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogHandle {
+    /// Swaps in `config.level`, unless RUST_LOG pins the filter.
+    pub fn reload(&self, config: &LoggingConfig) -> Result<()> {
+        if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+            warn!(
+                "{} is set, ignoring logging.level = {:?}",
+                EnvFilter::DEFAULT_ENV,
+                config.level
+            );
+            return Ok(());
+        }
+
+        let filter = EnvFilter::try_new(&config.level).map_err(|err| KvsError::Config {
+            key: "logging.level".to_string(),
+            message: err.to_string(),
+        })?;
+        self.filter.reload(filter).map_err(|err| KvsError::Config {
+            key: "logging.level".to_string(),
+            message: err.to_string(),
+        })
+    }
+}
 
-    // Set up environment filter, defaulting to "info" if not set
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+pub fn init_logging(config: &LoggingConfig) -> LogHandle {
+    // RUST_LOG still wins over the configured level when set
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let (env_filter, filter) = reload::Layer::new(env_filter);
 
-    // Decide between pretty (dev) or JSON (prod) based on env or feature
+    // Logs go to stderr so they never mix with command output
     let fmt_layer = fmt::layer()
+        .with_writer(std::io::stderr)
         .with_target(true)
         .with_thread_ids(true)
         .with_thread_names(true);
 
-    // Change this condition as needed for runtime detection
-    #[cfg(debug_assertions)]
-    let fmt_layer = fmt_layer.pretty();
-
-    #[cfg(not(debug_assertions))]
-    let fmt_layer = fmt_layer.json();
+    let fmt_layer = match config.format {
+        LogFormat::Pretty => fmt_layer.pretty().boxed(),
+        LogFormat::Compact => fmt_layer.compact().boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
 
+    // `try_init` also bridges records from crates still using `log`
     tracing_subscriber::registry()
         .with(env_filter)
         .with(fmt_layer)
         .try_init()
         .expect("Failed to set global default subscriber");
+
+    LogHandle { filter }
 }
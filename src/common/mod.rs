@@ -1,5 +1,7 @@
 mod error;
+mod hash;
 mod logging;
 
 pub use error::{KvsError, Result};
-pub use logging::init_logging;
+pub(crate) use hash::hash64;
+pub use logging::{LogHandle, init_logging};
@@ -1,52 +1,573 @@
-use crate::common::Result;
-use serde::Deserialize;
+use crate::cluster;
+use crate::common::{KvsError, Result};
+use crate::replication;
+use crate::{
+    ClientType, Compression, EncryptionKey, Engine, IndexMode, Keyring, PoolType, Role, ServerType,
+};
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
 
-#[derive(Deserialize)]
+/// Prefix of environment variables that override config keys.
+///
+/// `KVS_<SECTION>_<KEY>` sets `<key>` in `[<section>]`, so `KVS_SERVER_ADDR`
+/// overrides `server.addr` and `KVS_LIMITS_MAX_CONNECTIONS` overrides
+/// `limits.max_connections`.
+pub const ENV_PREFIX: &str = "KVS_";
+
+/// Settings for the whole binary, read from a TOML file.
+///
+/// Every section and key has a default, so a file only needs to name what it
+/// changes.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub storage: StorageConfig,
     pub server: ServerConfig,
+    pub storage: StorageConfig,
     pub client: ClientConfig,
     pub protocol: ProtocolConfig,
     pub serialization: SerializationConfig,
+    pub limits: LimitsConfig,
+    pub logging: LoggingConfig,
+    pub tls: TlsConfig,
+    pub replication: ReplicationConfig,
+    pub cluster: ClusterConfig,
 }
 
-impl Config {
-    pub fn from_file(path: &str) -> Result<Self> {
-        let contents = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&contents)?;
-        Ok(config)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub kind: ServerType,
+    pub pool: PoolType,
+    /// Worker threads; available parallelism when unset
+    pub threads: Option<u32>,
+    /// Store directory; the working directory when unset
+    pub data_dir: Option<PathBuf>,
+    /// Directory `BACKUP` requests write under; refused when unset
+    pub backup_root: Option<PathBuf>,
+    pub shutdown_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: DEFAULT_ADDRESS.parse().expect("valid default address"),
+            kind: ServerType::Sync,
+            pool: PoolType::Queue,
+            threads: None,
+            data_dir: None,
+            backup_root: None,
+            shutdown_timeout_ms: 5000,
+        }
     }
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum StorageConfig {
-    Kvs,
-    Sled,
-    Memory,
+impl ServerConfig {
+    pub fn worker_threads(&self) -> Result<u32> {
+        match self.threads {
+            Some(threads) => Ok(threads),
+            None => Ok(std::thread::available_parallelism()?.get().try_into()?),
+        }
+    }
+
+    pub fn data_dir(&self) -> Result<PathBuf> {
+        match &self.data_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(std::env::current_dir()?),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum ServerConfig {
-    Sync,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub engine: Engine,
+    /// KvStore: roll over to a new segment file past this size
+    pub max_segment_bytes: u64,
+    /// KvStore: compact once the log grows past this size
+    pub compaction_threshold_bytes: u64,
+    /// KvStore: codec for new values
+    pub compression: Compression,
+    /// KvStore: values shorter than this are not compressed
+    pub compression_threshold_bytes: u64,
+    /// KvStore: values at least this long go to blob files
+    pub blob_threshold_bytes: u64,
+    /// KvStore: collect blob files once this many bytes in them are dead
+    pub blob_gc_threshold_bytes: u64,
+    /// KvStore: keep the index of keys in memory or in files next to the
+    /// segments
+    pub index: IndexMode,
+    /// KvStore: file holding the hex key entries are encrypted with
+    pub encryption_key_file: Option<PathBuf>,
+    /// KvStore: environment variable holding that key instead
+    pub encryption_key_env: Option<String>,
+    /// KvStore: files holding rotated-out keys, still needed for segments
+    /// compaction has not rewritten yet
+    pub old_encryption_key_files: Vec<PathBuf>,
+    /// sled: page cache size
+    pub sled_cache_bytes: u64,
+    /// LSM: flush the memtable to a table past this size
+    pub lsm_memtable_bytes: u64,
+    /// LSM: compaction starts a new table past this size
+    pub lsm_table_bytes: u64,
+    /// B+tree: bytes of pages the buffer pool keeps in memory
+    pub btree_cache_bytes: u64,
+    /// B+tree: checkpoint once the write-ahead log grows past this size
+    pub btree_checkpoint_bytes: u64,
+    /// memory: log writes to an append-only file and snapshot the map, so
+    /// they survive a restart
+    pub memory_persistent: bool,
+    /// memory: snapshot once the append-only file grows past this size
+    pub memory_snapshot_bytes: u64,
+    /// memory: snapshot at the first write this long after the last one
+    pub memory_snapshot_interval_ms: u64,
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum ClientConfig {
-    Sync,
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            engine: Engine::Kvs,
+            max_segment_bytes: 4 * 1024 * 1024,
+            compaction_threshold_bytes: 1024 * 1024,
+            compression: Compression::None,
+            compression_threshold_bytes: 512,
+            blob_threshold_bytes: 64 * 1024,
+            blob_gc_threshold_bytes: 16 * 1024 * 1024,
+            index: IndexMode::Memory,
+            encryption_key_file: None,
+            encryption_key_env: None,
+            old_encryption_key_files: vec![],
+            sled_cache_bytes: 64 * 1024 * 1024,
+            lsm_memtable_bytes: 4 * 1024 * 1024,
+            lsm_table_bytes: 2 * 1024 * 1024,
+            btree_cache_bytes: 8 * 1024 * 1024,
+            btree_checkpoint_bytes: 16 * 1024 * 1024,
+            memory_persistent: false,
+            memory_snapshot_bytes: 64 * 1024 * 1024,
+            memory_snapshot_interval_ms: 300_000,
+        }
+    }
+}
+
+impl StorageConfig {
+    /// The keys named by the encryption settings, if any
+    pub fn keyring(&self) -> Result<Option<Keyring>> {
+        let current = match (&self.encryption_key_file, &self.encryption_key_env) {
+            (Some(path), _) => EncryptionKey::from_file(path)?,
+            (None, Some(var)) => EncryptionKey::from_env(var)?,
+            (None, None) => return Ok(None),
+        };
+        let mut keys = Keyring::new(current);
+        for path in &self.old_encryption_key_files {
+            keys = keys.retire(EncryptionKey::from_file(path)?);
+        }
+        Ok(Some(keys))
+    }
+
+    fn encrypted(&self) -> bool {
+        self.encryption_key_file.is_some() || self.encryption_key_env.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub addr: SocketAddr,
+    pub kind: ClientType,
 }
 
-#[derive(Deserialize)]
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            addr: DEFAULT_ADDRESS.parse().expect("valid default address"),
+            kind: ClientType::Sync,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    pub kind: ProtocolKind,
+}
+
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
-pub enum ProtocolConfig {
-    Result,
+pub enum ProtocolKind {
+    #[default]
+    Resp,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerializationConfig {
+    pub kind: SerializationKind,
+}
+
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
-pub enum SerializationConfig {
+pub enum SerializationKind {
+    #[default]
     Binary,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Connections beyond this are closed as soon as they are accepted
+    pub max_connections: usize,
+    /// Largest request accepted, going by the length in its frame header
+    pub max_request_bytes: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_connections: 10_000,
+            max_request_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `EnvFilter` directive, e.g. `info` or `kvs=debug,sled=warn`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Serving TLS is not supported yet, so turning it on fails validation
+    pub enabled: bool,
+    /// PEM certificate chain, required when enabled
+    pub cert: Option<PathBuf>,
+    /// PEM private key for `cert`, required when enabled
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    pub role: Role,
+    /// Primary: where replicas connect to follow the log
+    pub listen_addr: Option<SocketAddr>,
+    /// Replica: the primary's `listen_addr`
+    pub primary_addr: Option<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub enabled: bool,
+    /// This node's entry in `nodes`
+    pub id: u64,
+    /// Every member, this node included
+    pub nodes: Vec<ClusterNode>,
+    /// Applied log entries kept before the log is cut back to a snapshot
+    pub snapshot_entries: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> ClusterConfig {
+        ClusterConfig {
+            enabled: false,
+            id: 1,
+            nodes: vec![],
+            snapshot_entries: 1000,
+        }
+    }
+}
+
+impl ClusterConfig {
+    pub fn node(&self) -> Option<&ClusterNode> {
+        self.nodes.iter().find(|node| node.id == self.id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterNode {
+    pub id: u64,
+    /// Where clients reach the node, its `server.addr`
+    pub addr: SocketAddr,
+    /// Where the other nodes reach it
+    pub raft_addr: SocketAddr,
+}
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+impl Config {
+    /// Reads a config file without environment overrides.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let table = read_table(path.as_ref())?;
+        Config::from_table(table)
+    }
+
+    /// Reads the config file, if any, then applies `KVS_*` environment
+    /// overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => read_table(path)?,
+            None => toml::Table::new(),
+        };
+        apply_env(&mut table, std::env::vars())?;
+        Config::from_table(table)
+    }
+
+    /// `KVS_*` environment variables that `load` skips because they name no
+    /// config key.
+    pub fn ignored_env_vars() -> Vec<String> {
+        ignored_env_vars(std::env::vars())
+    }
+
+    pub(crate) fn from_table(table: toml::Table) -> Result<Self> {
+        let config: Config =
+            serde_path_to_error::deserialize(toml::Value::Table(table)).map_err(|err| {
+                KvsError::Config {
+                    key: err.path().to_string(),
+                    message: err.inner().to_string(),
+                }
+            })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Renders the config as TOML, e.g. for `--print-config`.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).map_err(|err| KvsError::Config {
+            key: ".".to_string(),
+            message: err.to_string(),
+        })
+    }
+
+    /// Checks values that parse but make no sense.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |key: &str, message: &str| {
+            Err(KvsError::Config {
+                key: key.to_string(),
+                message: message.to_string(),
+            })
+        };
+
+        if self.server.threads == Some(0) {
+            return invalid("server.threads", "must be at least 1");
+        }
+        if self.storage.max_segment_bytes == 0 {
+            return invalid("storage.max_segment_bytes", "must be greater than 0");
+        }
+        if self.storage.compaction_threshold_bytes == 0 {
+            return invalid(
+                "storage.compaction_threshold_bytes",
+                "must be greater than 0",
+            );
+        }
+        if self.storage.encryption_key_file.is_some() && self.storage.encryption_key_env.is_some() {
+            return invalid(
+                "storage.encryption_key_env",
+                "conflicts with storage.encryption_key_file",
+            );
+        }
+        if !self.storage.old_encryption_key_files.is_empty() && !self.storage.encrypted() {
+            return invalid(
+                "storage.old_encryption_key_files",
+                "needs a current key in storage.encryption_key_file or storage.encryption_key_env",
+            );
+        }
+        if self.storage.encrypted() && self.storage.engine != Engine::Kvs {
+            return invalid("storage.engine", "only the kvs engine supports encryption");
+        }
+        if self.limits.max_connections == 0 {
+            return invalid("limits.max_connections", "must be at least 1");
+        }
+        if self.limits.max_request_bytes < 16 {
+            return invalid("limits.max_request_bytes", "must be at least 16");
+        }
+        if let Err(err) = EnvFilter::try_new(&self.logging.level) {
+            return invalid("logging.level", &err.to_string());
+        }
+        match self.replication.role {
+            Role::Standalone => {}
+            Role::Primary => {
+                if self.replication.listen_addr.is_none() {
+                    return invalid("replication.listen_addr", "required for a primary");
+                }
+                // Replicas follow the segment log, which only KvStore has
+                if self.storage.engine != Engine::Kvs {
+                    return invalid("storage.engine", "a primary must use the kvs engine");
+                }
+            }
+            Role::Replica => {
+                if self.replication.primary_addr.is_none() {
+                    return invalid("replication.primary_addr", "required for a replica");
+                }
+            }
+        }
+        if self.replication.role != Role::Standalone
+            && self.limits.max_request_bytes > replication::MAX_REQUEST_BYTES
+        {
+            return invalid(
+                "limits.max_request_bytes",
+                &format!(
+                    "must be at most {} when replicating, where entries travel in batches",
+                    replication::MAX_REQUEST_BYTES
+                ),
+            );
+        }
+        if self.cluster.enabled {
+            let Some(node) = self.cluster.node() else {
+                return invalid("cluster.id", "not listed in cluster.nodes");
+            };
+            if node.addr != self.server.addr {
+                return invalid(
+                    "cluster.nodes",
+                    &format!("node {} addr differs from server.addr", node.id),
+                );
+            }
+            for (i, node) in self.cluster.nodes.iter().enumerate() {
+                if self.cluster.nodes[..i]
+                    .iter()
+                    .any(|other| other.id == node.id)
+                {
+                    return invalid(
+                        "cluster.nodes",
+                        &format!("node {} is listed twice", node.id),
+                    );
+                }
+            }
+            if self.cluster.snapshot_entries == 0 {
+                return invalid("cluster.snapshot_entries", "must be at least 1");
+            }
+            if self.limits.max_request_bytes > cluster::MAX_REQUEST_BYTES {
+                return invalid(
+                    "limits.max_request_bytes",
+                    &format!(
+                        "must be at most {} in a cluster, where entries travel in raft messages",
+                        cluster::MAX_REQUEST_BYTES
+                    ),
+                );
+            }
+            // Snapshots are read back from the segment log
+            if self.storage.engine != Engine::Kvs {
+                return invalid("storage.engine", "a cluster node must use the kvs engine");
+            }
+            if self.replication.role != Role::Standalone {
+                return invalid("replication.role", "clusters replicate through raft");
+            }
+        }
+        if self.tls.enabled {
+            for (key, path) in [("tls.cert", &self.tls.cert), ("tls.key", &self.tls.key)] {
+                match path {
+                    None => return invalid(key, "required when tls.enabled is true"),
+                    Some(path) if !path.is_file() => {
+                        return invalid(key, &format!("{} is not a file", path.display()));
+                    }
+                    Some(_) => {}
+                }
+            }
+            return invalid("tls.enabled", "serving TLS is not supported yet");
+        }
+        Ok(())
+    }
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let contents = fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+/// Applies `KVS_<SECTION>_<KEY>` variables on top of the file's table.
+///
+/// Values are parsed as TOML first, so `KVS_SERVER_THREADS=4` is an integer,
+/// and fall back to plain strings, so `KVS_SERVER_ADDR=127.0.0.1:4000` needs
+/// no quoting. Variables that name no config key, like `KVS_FAULT_SEED`, are
+/// skipped; `Config::ignored_env_vars` lists them.
+pub fn apply_env(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    for (name, raw) in vars {
+        let Some((section, key, value)) = env_override(&name, raw) else {
+            continue;
+        };
+
+        let section_table = table
+            .entry(section.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        match section_table {
+            toml::Value::Table(section_table) => {
+                section_table.insert(key, value);
+            }
+            _ => {
+                return Err(KvsError::Config {
+                    key: section,
+                    message: format!("{name} overrides a key in a section that is not a table"),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn ignored_env_vars(vars: impl Iterator<Item = (String, String)>) -> Vec<String> {
+    vars.filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .filter(|(name, raw)| env_override(name, raw.clone()).is_none())
+        .map(|(name, _)| name)
+        .collect()
+}
+
+// The section, key and value a variable sets, if it names a config key
+fn env_override(name: &str, raw: String) -> Option<(String, String, toml::Value)> {
+    let rest = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+    let (section, key) = rest.split_once('_')?;
+
+    let value = match toml::from_str::<toml::Table>(&format!("v = {raw}")) {
+        Ok(mut parsed) => parsed.remove("v").unwrap_or(toml::Value::String(raw)),
+        Err(_) => toml::Value::String(raw),
+    };
+
+    // Every struct denies unknown fields, so a key the schema lacks fails as
+    // unknown right at the section or key. A known key with a bad value fails
+    // otherwise and is kept for `from_table` to report.
+    let mut section_table = toml::Table::new();
+    section_table.insert(key.to_string(), value.clone());
+    let mut probe = toml::Table::new();
+    probe.insert(section.to_string(), toml::Value::Table(section_table));
+    if let Err(err) = serde_path_to_error::deserialize::<_, Config>(toml::Value::Table(probe)) {
+        let path = err.path().to_string();
+        let unknown = err.inner().to_string().contains("unknown field");
+        if unknown && (path == section || path == format!("{section}.{key}")) {
+            return None;
+        }
+    }
+
+    Some((section.to_string(), key.to_string(), value))
+}
@@ -1,18 +1,39 @@
-pub use client::{Client, ClientTrait};
-pub use common::{KvsError, Result, init_logging};
-pub use config::{ClientConfig, Config, SerializationConfig, ServerConfig};
-pub use protocols::{Protocol, Request, Response};
+pub use client::{
+    AsyncKvsClient, Client, ClientTrait, ClientType, DEFAULT_VNODES, HashRing, KvsClient,
+    ShardedClient,
+};
+pub use cluster::Cluster;
+pub use common::{KvsError, LogHandle, Result, init_logging};
+pub use config::{
+    ClientConfig, ClusterConfig, ClusterNode, Config, LimitsConfig, LogFormat, LoggingConfig,
+    ProtocolConfig, ProtocolKind, ReplicationConfig, SerializationConfig, SerializationKind,
+    ServerConfig, StorageConfig, TlsConfig,
+};
+pub use protocols::{Protocol, ProtocolTrait, Request, Response};
+pub use replication::{Role, load_position};
 pub use serialization::{Serialization, SerializationTrait};
-pub use server::{Server, ServerTrait};
-pub use storage::{Engine, KvMemory, KvSled, KvStore, Storage, StoreTrait};
+pub use server::{
+    AsyncServer, ReloadHandle, SHUTDOWN_TIMEOUT, Server, ServerTrait, ServerType, ShutdownHandle,
+    SyncServer,
+};
+pub use storage::{
+    AsyncStore, BTreeOptions, BTreeStats, BTreeStore, BlobRef, CORRUPT_DIR, Compression, Damage,
+    DumpFormat, EncryptionKey, Engine, Entry, FORMAT_VERSION, Fault, FaultInjector, IndexMode,
+    KEY_LEN, Keyring, KvMemory, KvMemoryOptions, KvSled, KvStore, KvStoreOptions, LOCK_FILE,
+    LogBatch, LogInspector, LogPosition, LogStats, LsmOptions, LsmStats, LsmStore, MANIFEST_FILE,
+    Manifest, ManifestOptions, Migration, Repair, SEGMENT_VERSION, SegmentInfo, Storage,
+    StoreTrait,
+};
 pub use threadpool::{
     NaiveThreadPool, PoolType, QueueThreadPool, RayonThreadPool, ThreadPool, ThreadPoolTrait,
 };
 
 mod client;
+mod cluster;
 mod common;
 mod config;
 mod protocols;
+mod replication;
 mod serialization;
 mod server;
 mod storage;
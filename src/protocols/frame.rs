@@ -0,0 +1,88 @@
+use crate::{KvsError, Result};
+use std::io::{self, ErrorKind, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Every request and response goes over the wire as `[length u32 BE]`
+// followed by that many bytes, so a message may arrive in any number of
+// reads.
+const HEADER_LEN: usize = 4;
+
+/// Writes `payload` as one frame
+pub(crate) fn write_frame(stream: &mut impl Write, payload: &[u8]) -> Result<()> {
+    stream.write_all(&header(payload)?)?;
+    stream.write_all(payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads one frame of at most `max_bytes`, or `None` if the peer hung up
+/// before sending one
+pub(crate) fn read_frame(stream: &mut impl Read, max_bytes: usize) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match stream.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(KvsError::Protocol("connection closed mid-frame".into())),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(header) as u64;
+    if len > max_bytes as u64 {
+        // Skipped rather than left unread, so the peer is not reset before
+        // it reads the refusal
+        io::copy(&mut stream.take(len), &mut io::sink())?;
+        return Err(too_long(len, max_bytes));
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Like `write_frame`, on an async stream
+pub(crate) async fn write_frame_async(
+    stream: &mut (impl AsyncWrite + Unpin),
+    payload: &[u8],
+) -> Result<()> {
+    stream.write_all(&header(payload)?).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Like `read_frame`, on an async stream
+pub(crate) async fn read_frame_async(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match stream.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(KvsError::Protocol("connection closed mid-frame".into())),
+            n => filled += n,
+        }
+    }
+    let len = u32::from_be_bytes(header) as u64;
+    if len > max_bytes as u64 {
+        tokio::io::copy(&mut stream.take(len), &mut tokio::io::sink()).await?;
+        return Err(too_long(len, max_bytes));
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+fn header(payload: &[u8]) -> Result<[u8; HEADER_LEN]> {
+    Ok(u32::try_from(payload.len())?.to_be_bytes())
+}
+
+fn too_long(len: u64, max_bytes: usize) -> KvsError {
+    KvsError::Protocol(format!(
+        "message of {} bytes exceeds the limit of {}",
+        len, max_bytes
+    ))
+}
@@ -1,12 +1,30 @@
-use crate::Result;
+use crate::{ProtocolConfig, ProtocolKind, Result};
 
+mod frame;
 mod resp;
 
+pub(crate) use frame::{read_frame, read_frame_async, write_frame, write_frame_async};
+
+/// Prefix of the error a cluster follower answers with, followed by the
+/// leader's address
+pub(crate) const MOVED: &str = "MOVED ";
+
 #[derive(Debug)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Snapshot the store into `dest`, a directory on the server's host
+    Backup {
+        dest: String,
+    },
 }
 
 #[derive(Debug)]
@@ -17,7 +35,7 @@ pub enum Response {
     Error(String),
 }
 
-pub trait ProtocolTrait {
+pub trait ProtocolTrait: Send + Sync {
     fn encode_request(&self, req: &Request) -> Vec<u8>;
     fn decode_request(&self, data: &[u8]) -> Result<Request>;
 
@@ -37,7 +55,12 @@ pub struct Protocol;
 
 impl Protocol {
     pub fn build() -> Box<dyn ProtocolTrait> {
-        // let _config = Config::from_file("../config/config.toml");
-        Box::new(resp::RespProtocol)
+        Protocol::from_config(&ProtocolConfig::default())
+    }
+
+    pub fn from_config(config: &ProtocolConfig) -> Box<dyn ProtocolTrait> {
+        match config.kind {
+            ProtocolKind::Resp => Box::new(resp::RespProtocol),
+        }
     }
 }
@@ -10,6 +10,7 @@ impl ProtocolTrait for RespProtocol {
             Request::Set { key, value } => serialize("SET", &[key, value]),
             Request::Get { key } => serialize("GET", &[key]),
             Request::Remove { key } => serialize("REMOVE", &[key]),
+            Request::Backup { dest } => serialize("BACKUP", &[dest]),
         }
         .into_bytes()
     }
@@ -27,6 +28,9 @@ impl ProtocolTrait for RespProtocol {
             ["REMOVE", key] => Ok(Request::Remove {
                 key: key.to_string(),
             }),
+            ["BACKUP", dest] => Ok(Request::Backup {
+                dest: dest.to_string(),
+            }),
             _ => Err("Invalid request format".into()),
         }
     }
@@ -0,0 +1,167 @@
+//! Primary/replica replication by shipping the KvStore segment log.
+//!
+//! A replica connects to the primary's replication address and sends the
+//! `LogPosition` it has applied up to. The primary answers with a stream of
+//! batches, each holding whole log entries in their on-disk encoding and the
+//! position following them. Idle primaries send empty batches as heartbeats.
+//!
+//! Compaction deletes the segments the replica's position points into. The
+//! primary then restarts from its oldest segment with `reset` set, and the
+//! replica replays the log over the store it has, so it keeps serving every
+//! key while it resyncs. Once the replay reaches the end of the primary's log,
+//! the replica removes the keys it did not set, which the primary no longer
+//! has.
+
+use crate::{Config, Entry, KvsError, LogBatch, LogPosition, Result, Storage};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::io::{Read, Write};
+use std::time::Duration;
+use tokio::sync::watch;
+
+mod primary;
+mod replica;
+
+pub use replica::load_position;
+
+// How often an idle primary checks its log for new entries
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// Sent by idle primaries so replicas notice a dead connection
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// Upper bound for the entries in one batch
+const MAX_BATCH_BYTES: u64 = 256 * 1024;
+/// Largest `limits.max_request_bytes` a primary or replica accepts, so that
+/// every entry fits in a batch a replica will read
+pub(crate) const MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+// Longest batch payload a replica reads. A batch takes entries until it holds
+// MAX_BATCH_BYTES, so the last one may cross it by up to a request.
+const MAX_PAYLOAD_BYTES: u64 = MAX_BATCH_BYTES + MAX_REQUEST_BYTES as u64;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// No replication
+    #[default]
+    Standalone,
+    /// Ships its log to replicas
+    Primary,
+    /// Follows a primary and only serves reads
+    Replica,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Role::Standalone => "standalone",
+            Role::Primary => "primary",
+            Role::Replica => "replica",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Starts the background threads for `config.replication.role`. They stop
+/// once `shutdown` flips to true.
+pub(crate) fn start(
+    config: &Config,
+    store: &Storage,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let replication = &config.replication;
+
+    match (replication.role, store) {
+        (Role::Standalone, _) => {}
+        (Role::Primary, Storage::Kvs(store)) => {
+            let addr = replication
+                .listen_addr
+                .ok_or_else(|| KvsError::Replication("no listen_addr for primary".into()))?;
+            primary::spawn(addr, store.clone(), shutdown)?;
+        }
+        (Role::Primary, _) => {
+            return Err(KvsError::Replication(
+                "a primary must use the kvs engine".into(),
+            ));
+        }
+        (Role::Replica, store) => {
+            let addr = replication
+                .primary_addr
+                .ok_or_else(|| KvsError::Replication("no primary_addr for replica".into()))?;
+            replica::spawn(addr, store.clone(), config.server.data_dir()?, shutdown);
+        }
+    }
+
+    Ok(())
+}
+
+fn write_position(stream: &mut impl Write, position: LogPosition) -> Result<()> {
+    stream.write_all(&position.file_id.to_le_bytes())?;
+    stream.write_all(&position.offset.to_le_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_position(stream: &mut impl Read) -> Result<LogPosition> {
+    Ok(LogPosition {
+        file_id: read_u64(stream)?,
+        offset: read_u64(stream)?,
+    })
+}
+
+// [reset u8][at_end u8][file_id u64][offset u64][payload length u64][entries]
+fn write_batch(stream: &mut impl Write, batch: &LogBatch) -> Result<()> {
+    let payload: Vec<u8> = batch.entries.iter().flat_map(Entry::serialize).collect();
+
+    stream.write_all(&[batch.reset as u8, batch.at_end as u8])?;
+    stream.write_all(&batch.next.file_id.to_le_bytes())?;
+    stream.write_all(&batch.next.offset.to_le_bytes())?;
+    stream.write_all(&(payload.len() as u64).to_le_bytes())?;
+    stream.write_all(&payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_batch(stream: &mut impl Read) -> Result<LogBatch> {
+    let mut flags = [0u8; 2];
+    stream.read_exact(&mut flags)?;
+    let next = read_position(stream)?;
+    let length = read_u64(stream)?;
+    if length > MAX_PAYLOAD_BYTES {
+        return Err(KvsError::CorruptedLog);
+    }
+
+    let mut payload = vec![0u8; length.try_into()?];
+    stream.read_exact(&mut payload)?;
+
+    let mut entries = vec![];
+    let mut rest = payload.as_slice();
+    while !rest.is_empty() {
+        let length = entry_length(rest).ok_or(KvsError::CorruptedLog)?;
+        entries.push(Entry::deserialize(&rest[..length])?);
+        rest = &rest[length..];
+    }
+
+    Ok(LogBatch {
+        reset: flags[0] != 0,
+        entries,
+        next,
+        at_end: flags[1] != 0,
+    })
+}
+
+// The length of the entry `bytes` starts with, if they hold all of it
+fn entry_length(bytes: &[u8]) -> Option<usize> {
+    let key_size = u64::from_le_bytes(bytes.get(0..8)?.try_into().unwrap());
+    let value_size = u64::from_le_bytes(bytes.get(8..16)?.try_into().unwrap());
+    let length = key_size.checked_add(value_size)?.checked_add(16)?;
+    match length <= bytes.len() as u64 {
+        true => Some(length as usize),
+        false => None,
+    }
+}
+
+fn read_u64(stream: &mut impl Read) -> Result<u64> {
+    let mut buffer = [0u8; 8];
+    stream.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
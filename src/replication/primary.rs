@@ -0,0 +1,90 @@
+use super::{HEARTBEAT_INTERVAL, MAX_BATCH_BYTES, POLL_INTERVAL, read_position, write_batch};
+use crate::{KvStore, LogBatch, Result};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+// The listener is polled rather than blocking in `accept` so it notices
+// shutdown without a wake-up connection
+pub(super) fn spawn(
+    addr: SocketAddr,
+    store: KvStore,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    info!("Shipping the log to replicas on {}", addr);
+
+    thread::spawn(move || {
+        while !*shutdown.borrow() {
+            match listener.accept() {
+                Ok((stream, replica)) => {
+                    let store = store.clone();
+                    let shutdown = shutdown.clone();
+                    thread::spawn(move || {
+                        info!("Replica {} connected", replica);
+                        match serve_replica(stream, &store, &shutdown) {
+                            Ok(()) => info!("Replica {} disconnected", replica),
+                            Err(e) => warn!("Replica {} disconnected: {}", replica, e),
+                        }
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    error!("Replication listener failed: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn serve_replica(
+    mut stream: TcpStream,
+    store: &KvStore,
+    shutdown: &watch::Receiver<bool>,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+
+    let mut position = read_position(&mut stream)?;
+    let mut last_sent = Instant::now();
+
+    while !*shutdown.borrow() {
+        let batch = store.read_log(position, MAX_BATCH_BYTES)?;
+
+        if !batch.reset && batch.entries.is_empty() && batch.next == position {
+            if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                write_batch(
+                    &mut stream,
+                    &LogBatch {
+                        reset: false,
+                        entries: vec![],
+                        next: position,
+                        at_end: true,
+                    },
+                )?;
+                last_sent = Instant::now();
+            }
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        if batch.reset {
+            warn!(
+                "Log position {}:{} was compacted away, resyncing replica from {}:{}",
+                position.file_id, position.offset, batch.next.file_id, 0
+            );
+        }
+        write_batch(&mut stream, &batch)?;
+        last_sent = Instant::now();
+        position = batch.next;
+    }
+
+    Ok(())
+}
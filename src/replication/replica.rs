@@ -0,0 +1,146 @@
+use super::{HEARTBEAT_INTERVAL, read_batch, write_position};
+use crate::{Entry, KvsError, LogPosition, Result, Storage, StoreTrait};
+use std::collections::HashSet;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+const POSITION_FILE: &str = "replica.pos";
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The primary's log position a replica in `dir_path` has applied up to.
+pub fn load_position(dir_path: &Path) -> Result<LogPosition> {
+    let path = dir_path.join(POSITION_FILE);
+    if !path.exists() {
+        return Ok(LogPosition::default());
+    }
+
+    let contents = fs::read_to_string(&path)?;
+    let mut parts = contents.split_whitespace().map(str::parse::<u64>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(file_id)), Some(Ok(offset))) => Ok(LogPosition { file_id, offset }),
+        _ => Err(KvsError::Replication(format!(
+            "invalid position file {}",
+            path.display()
+        ))),
+    }
+}
+
+// Written after the entries it covers, and atomically, so a restarted replica
+// at worst replays entries it already applied
+fn save_position(dir_path: &Path, position: LogPosition) -> Result<()> {
+    let tmp_path = dir_path.join(format!("{POSITION_FILE}.tmp"));
+    fs::write(
+        &tmp_path,
+        format!("{} {}\n", position.file_id, position.offset),
+    )?;
+    fs::rename(tmp_path, dir_path.join(POSITION_FILE))?;
+    Ok(())
+}
+
+pub(super) fn spawn(
+    primary: SocketAddr,
+    store: Storage,
+    dir_path: PathBuf,
+    shutdown: watch::Receiver<bool>,
+) {
+    info!("Replicating from {}", primary);
+
+    thread::spawn(move || {
+        while !*shutdown.borrow() {
+            if let Err(e) = follow(primary, &store, &dir_path, &shutdown) {
+                warn!("Replication from {} interrupted: {}", primary, e);
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    });
+}
+
+fn follow(
+    primary: SocketAddr,
+    store: &Storage,
+    dir_path: &Path,
+    shutdown: &watch::Receiver<bool>,
+) -> Result<()> {
+    let mut position = load_position(dir_path)?;
+
+    let mut stream = TcpStream::connect_timeout(&primary, RETRY_INTERVAL)?;
+    // Heartbeats arrive every second, so silence means the primary is gone
+    stream.set_read_timeout(Some(HEARTBEAT_INTERVAL * 5))?;
+    stream.set_nodelay(true)?;
+    write_position(&mut stream, position)?;
+    info!(
+        "Following {} from {}:{}",
+        primary, position.file_id, position.offset
+    );
+
+    // Keys set since a resync began, kept until it reaches the end of the log
+    let mut resync: Option<HashSet<String>> = None;
+    while !*shutdown.borrow() {
+        let batch = read_batch(&mut stream)?;
+
+        if batch.reset {
+            warn!("Primary compacted past this replica's position, replaying its log");
+            resync = Some(HashSet::new());
+        }
+        for entry in batch.entries {
+            if let Some(seen) = &mut resync
+                && let Entry::Set { key, .. } = &entry
+            {
+                seen.insert(key.clone());
+            }
+            apply(store, entry)?;
+        }
+        if batch.at_end
+            && let Some(seen) = resync.take()
+        {
+            let removed = sweep(store, &seen)?;
+            info!("Resync done, removed {} keys the primary no longer has", removed);
+        }
+        // A position inside a resync is not saved, so a replica restarted in
+        // the middle of one starts it over and still sweeps
+        if resync.is_none() && batch.next != position {
+            position = batch.next;
+            save_position(dir_path, position)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Remove every key not in `seen`, returning how many
+fn sweep(store: &Storage, seen: &HashSet<String>) -> Result<usize> {
+    let mut stale = vec![];
+    store.scan(&mut |key, _| {
+        if !seen.contains(&key) {
+            stale.push(key);
+        }
+        Ok(())
+    })?;
+
+    let removed = stale.len();
+    for key in stale {
+        apply(store, Entry::Remove { key })?;
+    }
+    Ok(removed)
+}
+
+fn apply(store: &Storage, entry: Entry) -> Result<()> {
+    match entry {
+        Entry::Set { key, value } => store.set(key, value),
+        // Replayed entries may remove a key that is already gone
+        Entry::Remove { key } => match store.remove(key) {
+            Err(KvsError::KeyNotFound) | Ok(()) => Ok(()),
+            Err(e) => Err(e),
+        },
+        // The primary resolves pointers before shipping its log
+        Entry::Blob { key, .. } => Err(KvsError::Replication(format!(
+            "received a blob pointer for {:?} instead of its value",
+            key
+        ))),
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{Config, Result, SerializationConfig};
+use crate::{Config, Result, SerializationKind};
 
 mod binary;
 
@@ -8,8 +8,8 @@ pub struct Serialization;
 
 impl Serialization {
     pub fn build(config: &Config) -> Result<Box<dyn SerializationTrait>> {
-        match config.serialization {
-            SerializationConfig::Binary => Ok(Box::new(binary::Binary)),
+        match config.serialization.kind {
+            SerializationKind::Binary => Ok(Box::new(binary::Binary)),
         }
     }
 }
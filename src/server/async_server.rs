@@ -0,0 +1,245 @@
+use super::READ_ONLY;
+use super::reload::ReloadHandle;
+use super::shutdown::ShutdownHandle;
+use super::too_many_connections;
+use crate::cluster::{self, Cluster};
+use crate::protocols::{read_frame_async, write_frame_async};
+use crate::storage::backup_dest;
+use crate::{
+    AsyncStore, Config, KvsError, Protocol, ProtocolConfig, Request, Response, Result, Role,
+    ServerTrait, Storage,
+};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{error, info, warn};
+
+pub struct AsyncServer {
+    /// The bound address, with the port the system chose if 0 was asked for
+    pub addr: SocketAddr,
+    /// Bound in `new` and handed to the runtime by `run`
+    pub listener: Option<std::net::TcpListener>,
+    pub store: AsyncStore<Storage>,
+    pub runtime: Runtime,
+    pub shutdown: ShutdownHandle,
+    pub shutdown_rx: watch::Receiver<bool>,
+    pub reload: ReloadHandle,
+    pub settings: watch::Receiver<Config>,
+    pub protocol: ProtocolConfig,
+    /// Replicas take their writes from the primary only
+    pub read_only: bool,
+    /// Set in cluster mode, where requests go through raft
+    pub cluster: Option<Cluster>,
+    pub backup_root: Option<PathBuf>,
+}
+
+impl AsyncServer {
+    pub fn new(config: &Config, store: Storage) -> Result<AsyncServer> {
+        let listener = std::net::TcpListener::bind(config.server.addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        // The runtime's worker count is fixed, so no server settings are live
+        let (reload, settings) = ReloadHandle::new(config, store.clone(), &[]);
+        let store = AsyncStore::new(store);
+
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(config.server.worker_threads()?.try_into()?)
+            .enable_all()
+            .build()?;
+
+        let (shutdown, shutdown_rx) = ShutdownHandle::new(addr);
+        let cluster = cluster::start(config, store.inner(), shutdown.subscribe())?;
+
+        Ok(AsyncServer {
+            addr,
+            listener: Some(listener),
+            store,
+            runtime,
+            shutdown,
+            shutdown_rx,
+            reload,
+            settings,
+            protocol: config.protocol.clone(),
+            read_only: config.replication.role == Role::Replica,
+            cluster,
+            backup_root: config.server.backup_root.clone(),
+        })
+    }
+}
+
+impl ServerTrait for AsyncServer {
+    fn run(&mut self) -> Result<()> {
+        info!("Async server starting at {}", self.addr);
+
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let connection_rx = self.shutdown_rx.clone();
+        let addr = self.addr;
+        let bound = self.listener.take();
+        let store = self.store.clone();
+        let settings = self.settings.clone();
+        let protocol = self.protocol.clone();
+        let read_only = self.read_only;
+        let cluster = self.cluster.clone();
+        let backup_root = self.backup_root.clone();
+
+        self.runtime.block_on(async move {
+            let listener = match bound {
+                Some(listener) => TcpListener::from_std(listener)?,
+                None => TcpListener::bind(addr).await?,
+            };
+            let mut connections = JoinSet::new();
+
+            // `wait_for` also sees a shutdown requested before the bind
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.wait_for(|stopped| *stopped) => break,
+                    accepted = listener.accept() => {
+                        let (mut stream, _) = accepted?;
+                        let limits = settings.borrow().limits.clone();
+                        if connections.len() >= limits.max_connections {
+                            warn!("Connection limit of {} reached", limits.max_connections);
+                            let refusal = too_many_connections(
+                                Protocol::from_config(&protocol).as_ref(),
+                                limits.max_connections,
+                            );
+                            // Not tracked, so it cannot hold up the accept loop or shutdown
+                            tokio::spawn(async move {
+                                let _ = write_frame_async(&mut stream, &refusal).await;
+                            });
+                            continue;
+                        }
+                        let connection = Connection {
+                            store: store.clone(),
+                            protocol: protocol.clone(),
+                            max_request_bytes: limits.max_request_bytes,
+                            read_only,
+                            cluster: cluster.clone(),
+                            backup_root: backup_root.clone(),
+                            shutdown_rx: connection_rx.clone(),
+                        };
+                        connections.spawn(async move {
+                            if let Err(e) = connection.serve(stream).await {
+                                error!("Failed to handle connection: {}", e);
+                            }
+                        });
+                    }
+                    // Reap finished connections so the set does not grow unbounded
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                }
+            }
+
+            info!("Shutting down server");
+
+            // Stop accepting before draining so no new work arrives
+            drop(listener);
+
+            let shutdown_timeout = settings.borrow().server.shutdown_timeout();
+            let drain = async { while connections.join_next().await.is_some() {} };
+            if time::timeout(shutdown_timeout, drain).await.is_err() {
+                warn!(
+                    "Shutdown deadline reached with {} connections open",
+                    connections.len()
+                );
+                connections.shutdown().await;
+            }
+
+            store.flush().await?;
+
+            info!("Server stopped");
+
+            Ok::<(), KvsError>(())
+        })?;
+
+        Ok(())
+    }
+    fn shutdown(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    fn reload(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+}
+
+// Connections stay open so a client can send many requests; an idle
+// connection only costs a parked task rather than a pool thread. On shutdown
+// idle connections close at once, while a request already read is answered.
+struct Connection {
+    store: AsyncStore<Storage>,
+    protocol: ProtocolConfig,
+    max_request_bytes: usize,
+    read_only: bool,
+    cluster: Option<Cluster>,
+    backup_root: Option<PathBuf>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl Connection {
+    async fn serve(mut self, mut stream: TcpStream) -> Result<()> {
+        let store = &self.store;
+        let protocol = Protocol::from_config(&self.protocol);
+
+        loop {
+            // Nothing is allocated for a request until its header arrives
+            let read = tokio::select! {
+                read = read_frame_async(&mut stream, self.max_request_bytes) => read,
+                _ = self.shutdown_rx.wait_for(|stopped| *stopped) => return Ok(()),
+            };
+            let buffer = match read {
+                Ok(Some(buffer)) => buffer,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    let refusal = protocol.encode_response(&Response::Error(e.to_string()));
+                    let _ = write_frame_async(&mut stream, &refusal).await;
+                    return Err(e);
+                }
+            };
+
+            let request = protocol.decode_request(&buffer)?;
+
+            let response: Response = match request {
+                // Raft waits on a condvar, so it runs off the async workers
+                request if let Some(cluster) = self.cluster.clone() => {
+                    let store = store.inner().clone();
+                    tokio::task::spawn_blocking(move || cluster.handle(&store, request))
+                        .await
+                        .map_err(|e| KvsError::Concurrency(e.to_string()))?
+                }
+                Request::Set { .. } | Request::Remove { .. } if self.read_only => {
+                    Response::Error(READ_ONLY.to_string())
+                }
+                // Engine errors are answered, so the connection stays open
+                Request::Set { key, value } => match store.set(key, value).await {
+                    Ok(()) => Response::Ok,
+                    Err(e) => Response::Error(e.to_string()),
+                },
+                Request::Get { key } => match store.get(key).await {
+                    Ok(Some(value)) => Response::Value(value),
+                    Ok(None) => Response::NotFound,
+                    Err(e) => Response::Error(e.to_string()),
+                },
+                Request::Remove { key } => match store.remove(key).await {
+                    Ok(_) => Response::Ok,
+                    Err(_) => Response::NotFound,
+                },
+                Request::Backup { dest } => {
+                    let backup = match backup_dest(self.backup_root.as_deref(), &dest) {
+                        Ok(dest) => store.backup(dest).await,
+                        Err(e) => Err(e),
+                    };
+                    match backup {
+                        Ok(()) => Response::Ok,
+                        Err(e) => Response::Error(e.to_string()),
+                    }
+                }
+            };
+
+            let encoded = protocol.encode_response(&response);
+
+            write_frame_async(&mut stream, &encoded).await?;
+        }
+    }
+}
@@ -1,27 +1,49 @@
-use crate::{Engine, PoolType, Result};
+use crate::replication;
+use crate::{Config, Engine, PoolType, ProtocolTrait, Response, Result, Storage};
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::mpsc::Sender;
 
+mod async_server;
+mod reload;
+mod shutdown;
 mod sync_server;
+pub use async_server::AsyncServer;
+pub use reload::ReloadHandle;
+pub use shutdown::{SHUTDOWN_TIMEOUT, ShutdownHandle};
 pub use sync_server::SyncServer;
 
+// Answer to writes sent to a replica
+pub(crate) const READ_ONLY: &str = "READONLY writes go to the primary";
+
+// Answer to a connection over `limits.max_connections`, before it is closed
+fn too_many_connections(protocol: &dyn ProtocolTrait, max_connections: usize) -> Vec<u8> {
+    let error = format!("BUSY connection limit of {} reached", max_connections);
+    protocol.encode_response(&Response::Error(error))
+}
+
 pub trait ServerTrait {
+    /// Serve until shut down, then drain in-flight requests and flush the store.
     fn run(&mut self) -> Result<()>;
-    fn shutdown(&self) -> Sender<()>;
+    fn shutdown(&self) -> ShutdownHandle;
+    /// Handle for applying a changed config while the server runs.
+    fn reload(&self) -> ReloadHandle;
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ServerType {
     Sync,
+    Async,
 }
 
 impl Display for ServerType {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let s = match self {
             ServerType::Sync => "sync",
+            ServerType::Async => "async",
         };
         write!(f, "{}", s)
     }
@@ -29,25 +51,48 @@ impl Display for ServerType {
 
 pub enum Server {
     Sync(SyncServer),
+    Async(AsyncServer),
 }
 
 impl Server {
     pub fn build(
+        server: ServerType,
         addr: SocketAddr,
         engine: Engine,
         pool: PoolType,
         threads: u32,
         dir_path: PathBuf,
     ) -> Result<Server> {
-        // let config = Config::from_file("../config/config.toml")?;
+        let mut config = Config::default();
+        config.server.kind = server;
+        config.server.addr = addr;
+        config.server.pool = pool;
+        config.server.threads = Some(threads);
+        config.server.data_dir = Some(dir_path);
+        config.storage.engine = engine;
 
-        // match config.server {
-        //     ServerConfig::Sync => Ok(Box::new(sync_server::SyncServer::new(addr, engine)?)),
-        // }
+        Server::from_config(&config)
+    }
 
-        let server = SyncServer::new(addr, engine, pool, threads, dir_path)?;
+    pub fn from_config(config: &Config) -> Result<Server> {
+        let store = Storage::open(config.server.data_dir()?, &config.storage)?;
 
-        Ok(Server::Sync(server))
+        let server = match config.server.kind {
+            ServerType::Sync => Server::Sync(SyncServer::new(config, store.clone())?),
+            // Tokio schedules connections itself, so the pool type does not apply
+            ServerType::Async => Server::Async(AsyncServer::new(config, store.clone())?),
+        };
+        replication::start(config, &store, server.shutdown().subscribe())?;
+
+        Ok(server)
+    }
+
+    /// The address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Server::Sync(server) => server.addr,
+            Server::Async(server) => server.addr,
+        }
     }
 }
 
@@ -55,11 +100,19 @@ impl ServerTrait for Server {
     fn run(&mut self) -> Result<()> {
         match self {
             Server::Sync(server) => server.run(),
+            Server::Async(server) => server.run(),
         }
     }
-    fn shutdown(&self) -> Sender<()> {
+    fn shutdown(&self) -> ShutdownHandle {
         match self {
             Server::Sync(server) => server.shutdown(),
+            Server::Async(server) => server.shutdown(),
+        }
+    }
+    fn reload(&self) -> ReloadHandle {
+        match self {
+            Server::Sync(server) => server.reload(),
+            Server::Async(server) => server.reload(),
         }
     }
 }
@@ -0,0 +1,152 @@
+use crate::common::LogHandle;
+use crate::{Config, KvsError, Result, Storage};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+// Settings every server applies without a restart. Servers add their own,
+// e.g. the sync server can swap its thread pool.
+const LIVE_KEYS: &[&str] = &[
+    "server.shutdown_timeout_ms",
+    "storage.max_segment_bytes",
+    "storage.compaction_threshold_bytes",
+    "storage.compression",
+    "storage.compression_threshold_bytes",
+    "storage.blob_threshold_bytes",
+    "storage.blob_gc_threshold_bytes",
+    "limits.max_connections",
+    "limits.max_request_bytes",
+    "logging.level",
+];
+
+/// Cloneable handle used to apply a new config to a running server.
+///
+/// Settings that can change live are applied at once; the rest are logged
+/// and keep their old value until the server restarts.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    current: Arc<Mutex<Config>>,
+    tx: Arc<watch::Sender<Config>>,
+    store: Storage,
+    server_keys: &'static [&'static str],
+    logging: Option<LogHandle>,
+}
+
+impl ReloadHandle {
+    pub(crate) fn new(
+        config: &Config,
+        store: Storage,
+        server_keys: &'static [&'static str],
+    ) -> (ReloadHandle, watch::Receiver<Config>) {
+        let (tx, rx) = watch::channel(config.clone());
+        let handle = ReloadHandle {
+            current: Arc::new(Mutex::new(config.clone())),
+            tx: Arc::new(tx),
+            store,
+            server_keys,
+            logging: None,
+        };
+        (handle, rx)
+    }
+
+    /// Also applies `logging.level` to the process-wide log filter.
+    pub fn with_logging(mut self, logging: LogHandle) -> ReloadHandle {
+        self.logging = Some(logging);
+        self
+    }
+
+    /// The config the server is running with.
+    pub fn current(&self) -> Result<Config> {
+        Ok(self
+            .current
+            .lock()
+            .map_err(|_| KvsError::LockPoisoned)?
+            .clone())
+    }
+
+    /// Applies `config` and returns the changed keys that need a restart.
+    pub fn reload(&self, config: &Config) -> Result<Vec<String>> {
+        config.validate()?;
+
+        let mut current = self.current.lock().map_err(|_| KvsError::LockPoisoned)?;
+        let old = sections(&current)?;
+        let new = sections(config)?;
+
+        let mut merged = old.clone();
+        let mut applied = 0;
+        let mut restart = vec![];
+
+        for (section, new_keys) in &new {
+            // Client settings are not used by the server
+            if section == "client" {
+                continue;
+            }
+            let (Some(old_keys), Some(merged_keys)) = (old.get(section), merged.get_mut(section))
+            else {
+                continue;
+            };
+            let added = new_keys.keys().filter(|key| !old_keys.contains_key(*key));
+
+            for key in old_keys.keys().chain(added) {
+                let (before, after) = (old_keys.get(key), new_keys.get(key));
+                if before == after {
+                    continue;
+                }
+
+                let name = format!("{section}.{key}");
+                if LIVE_KEYS.contains(&name.as_str()) || self.server_keys.contains(&name.as_str()) {
+                    info!("Reloaded {}: {} -> {}", name, show(before), show(after));
+                    match after {
+                        Some(value) => merged_keys.insert(key.clone(), value.clone()),
+                        None => merged_keys.remove(key),
+                    };
+                    applied += 1;
+                } else {
+                    warn!("{} changed but requires a restart", name);
+                    restart.push(name);
+                }
+            }
+        }
+
+        let merged = Config::from_table(
+            merged
+                .into_iter()
+                .map(|(section, keys)| (section, toml::Value::Table(keys)))
+                .collect(),
+        )?;
+
+        if let Some(logging) = &self.logging
+            && merged.logging.level != current.logging.level
+        {
+            logging.reload(&merged.logging)?;
+        }
+        self.store.reconfigure(&merged.storage);
+        self.tx.send_replace(merged.clone());
+        *current = merged;
+
+        info!(
+            "Configuration reloaded: {} applied, {} waiting for a restart",
+            applied,
+            restart.len()
+        );
+
+        Ok(restart)
+    }
+}
+
+// Configs are compared as TOML so every key is covered without listing them
+fn sections(config: &Config) -> Result<BTreeMap<String, toml::Table>> {
+    let table: toml::Table = toml::from_str(&config.to_toml()?)?;
+    Ok(table
+        .into_iter()
+        .map(|(section, value)| match value {
+            toml::Value::Table(keys) => (section, keys),
+            _ => (section, toml::Table::new()),
+        })
+        .collect())
+}
+
+fn show(value: Option<&toml::Value>) -> String {
+    value.map_or_else(|| "unset".to_string(), toml::Value::to_string)
+}
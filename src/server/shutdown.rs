@@ -0,0 +1,45 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::info;
+
+/// How long a stopping server waits for in-flight requests before giving up.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cloneable handle used to stop a running server from another thread.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    addr: SocketAddr,
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(addr: SocketAddr) -> (ShutdownHandle, watch::Receiver<bool>) {
+        let (tx, rx) = watch::channel(false);
+        let handle = ShutdownHandle {
+            addr,
+            tx: Arc::new(tx),
+        };
+        (handle, rx)
+    }
+
+    /// Signals the server to stop accepting connections.
+    pub fn shutdown(&self) {
+        info!("Shutdown requested");
+        self.tx.send_replace(true);
+
+        // A blocking `accept` only returns when a client arrives, so connect
+        // once to wake it up. The server drops this connection unanswered.
+        let _ = TcpStream::connect_timeout(&self.addr, Duration::from_millis(100));
+    }
+
+    // For background work that has to stop along with the server
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+}
@@ -1,106 +1,315 @@
+use super::READ_ONLY;
+use super::reload::ReloadHandle;
+use super::shutdown::ShutdownHandle;
+use super::too_many_connections;
+use crate::cluster::{self, Cluster};
+use crate::protocols::{read_frame, write_frame};
+use crate::storage::backup_dest;
 use crate::{
-    Engine, KvsError, PoolType, Protocol, Request, Response, Result, ServerTrait, Storage,
-    StoreTrait, ThreadPool,
+    Config, KvsError, PoolType, Protocol, ProtocolConfig, ProtocolTrait, Request, Response, Result,
+    Role, ServerTrait, Storage, StoreTrait, ThreadPool,
 };
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tracing::{error, info};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
 
 pub struct SyncServer {
+    /// The bound address, with the port the system chose if 0 was asked for
     pub addr: SocketAddr,
-    pub store: Arc<Mutex<Storage>>,
+    /// Bound in `new`, so the shutdown handle can reach the server on `addr`
+    pub listener: Option<TcpListener>,
+    pub store: Storage,
     pub pool: ThreadPool,
-    pub shutdown_tx: Sender<()>,
-    pub shutdown_rx: Receiver<()>,
+    pub pool_size: (PoolType, u32),
+    pub shutdown: ShutdownHandle,
+    pub shutdown_rx: watch::Receiver<bool>,
+    pub reload: ReloadHandle,
+    pub settings: watch::Receiver<Config>,
+    pub connections: Arc<Connections>,
+    pub protocol: ProtocolConfig,
+    /// Replicas take their writes from the primary only
+    pub read_only: bool,
+    /// Set in cluster mode, where requests go through raft
+    pub cluster: Option<Cluster>,
+    pub backup_root: Option<PathBuf>,
 }
 
+// The pool is rebuilt when these change
+const SERVER_KEYS: &[&str] = &["server.pool", "server.threads"];
+
 impl SyncServer {
-    pub fn new(
-        addr: SocketAddr,
-        engine: Engine,
-        pool: PoolType,
-        num_threads: u32,
-        dir_path: PathBuf,
-    ) -> Result<SyncServer> {
-        let store = Arc::new(Mutex::new(Storage::build(dir_path, engine)?));
+    pub fn new(config: &Config, store: Storage) -> Result<SyncServer> {
+        let listener = TcpListener::bind(config.server.addr)?;
+        let addr = listener.local_addr()?;
 
-        let pool = ThreadPool::run(pool, num_threads)?;
+        let pool_size = (config.server.pool, config.server.worker_threads()?);
+        let pool = ThreadPool::run(pool_size.0, pool_size.1)?;
 
-        let (shutdown_tx, shutdown_rx) = channel::<()>();
+        let (shutdown, shutdown_rx) = ShutdownHandle::new(addr);
+        let (reload, settings) = ReloadHandle::new(config, store.clone(), SERVER_KEYS);
+        let cluster = cluster::start(config, &store, shutdown.subscribe())?;
 
         Ok(SyncServer {
             addr,
+            listener: Some(listener),
             store,
             pool,
-            shutdown_tx,
+            pool_size,
+            shutdown,
             shutdown_rx,
+            reload,
+            settings,
+            connections: Arc::new(Connections::default()),
+            protocol: config.protocol.clone(),
+            read_only: config.replication.role == Role::Replica,
+            cluster,
+            backup_root: config.server.backup_root.clone(),
         })
     }
+
+    // Jobs already queued keep running on the old pool, which is dropped on
+    // its own thread since dropping a pool waits for its workers
+    fn resize_pool(&mut self) -> Result<()> {
+        let server = self.settings.borrow_and_update().server.clone();
+        let pool_size = (server.pool, server.worker_threads()?);
+        if pool_size == self.pool_size {
+            return Ok(());
+        }
+
+        let pool = ThreadPool::run(pool_size.0, pool_size.1)?;
+        let old = std::mem::replace(&mut self.pool, pool);
+        thread::spawn(move || drop(old));
+        self.pool_size = pool_size;
+
+        info!(
+            "Thread pool: {} with {} threads",
+            pool_size.0.to_string(),
+            pool_size.1
+        );
+        Ok(())
+    }
+
+    // Wait for requests already handed to the pool, up to the deadline
+    fn drain(&self) {
+        let deadline = Instant::now() + self.settings.borrow().server.shutdown_timeout();
+
+        // Connections that have not sent a request yet would block a worker
+        // until the deadline, so close them straight away
+        self.connections.close_idle();
+
+        while self.connections.in_flight() > 0 {
+            if Instant::now() >= deadline {
+                warn!(
+                    "Shutdown deadline reached with {} requests in flight",
+                    self.connections.in_flight()
+                );
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
 }
 impl ServerTrait for SyncServer {
     fn run(&mut self) -> Result<()> {
         info!("Server starting at {}", self.addr);
 
-        let listener = TcpListener::bind(self.addr)?;
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => TcpListener::bind(self.addr)?,
+        };
 
-        for stream in listener.incoming() {
-            if self.shutdown_rx.try_recv().is_ok() {
-                println!("Shutting down server");
+        // Checked before every accept: shutdown may have been requested before
+        // the listener was bound, in which case no wake-up connection arrives
+        while !*self.shutdown_rx.borrow() {
+            let (stream, _) = listener.accept()?;
+            if *self.shutdown_rx.borrow() {
                 break;
             }
-            let stream = stream?;
-            let store = Arc::clone(&self.store);
+            if self.settings.has_changed().unwrap_or(false) {
+                self.resize_pool()?;
+            }
+            let limits = self.settings.borrow().limits.clone();
+            if self.connections.in_flight() >= limits.max_connections {
+                warn!("Connection limit of {} reached", limits.max_connections);
+                let protocol = Protocol::from_config(&self.protocol);
+                let refusal = too_many_connections(protocol.as_ref(), limits.max_connections);
+                let _ = write_frame(&mut (&stream), &refusal);
+                continue;
+            }
+            // Engines are cheap handles over shared state, so each job gets its own
+            let store = self.store.clone();
+            let connection = Connections::register(&self.connections, &stream)?;
+            let protocol = Protocol::from_config(&self.protocol);
+            let max_request_bytes = limits.max_request_bytes;
+            let read_only = self.read_only;
+            let cluster = self.cluster.clone();
+            let backup_root = self.backup_root.clone();
             self.pool.spawn(move || {
-                if let Err(e) = handle_connecton(stream, store) {
+                let request = handle_connecton(
+                    stream,
+                    &store,
+                    &connection,
+                    protocol,
+                    max_request_bytes,
+                    Routing {
+                        read_only,
+                        cluster: cluster.as_ref(),
+                        backup_root: backup_root.as_deref(),
+                    },
+                );
+                if let Err(e) = request {
                     error!("Failed to handle connection: {}", e);
                 }
             });
         }
 
-        println!("Shutting down.");
+        info!("Shutting down server");
+
+        // Stop accepting before draining so no new work arrives
+        drop(listener);
+        self.drain();
+
+        self.store.flush()?;
+
+        info!("Server stopped");
 
         Ok(())
     }
-    fn shutdown(&self) -> Sender<()> {
-        self.shutdown_tx.clone()
+    fn shutdown(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    fn reload(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+}
+
+// Tracks accepted connections so shutdown can tell idle ones, still waiting
+// for a request, from ones whose request is being served
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    in_flight: AtomicUsize,
+    idle: Mutex<HashMap<u64, TcpStream>>,
+}
+
+impl Connections {
+    fn register(connections: &Arc<Connections>, stream: &TcpStream) -> Result<Connection> {
+        let id = connections.next_id.fetch_add(1, Ordering::SeqCst);
+        connections
+            .idle
+            .lock()
+            .map_err(|_| KvsError::LockPoisoned)?
+            .insert(id, stream.try_clone()?);
+        connections.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        Ok(Connection {
+            id,
+            connections: Arc::clone(connections),
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn close_idle(&self) {
+        if let Ok(mut idle) = self.idle.lock() {
+            for (_, stream) in idle.drain() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+// Counts the connection as in flight until dropped, even if the job panics
+struct Connection {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Connection {
+    // Once a request has been read it is served to completion
+    fn busy(&self) {
+        if let Ok(mut idle) = self.connections.idle.lock() {
+            idle.remove(&self.id);
+        }
     }
 }
-fn handle_connecton(mut stream: TcpStream, store: Arc<Mutex<Storage>>) -> Result<()> {
-    let mut buffer = vec![0u8; 1024]; // Allocate 1 KB
-    let bytes_read = stream.read(&mut buffer)?;
-    buffer.truncate(bytes_read);
 
-    let protocol = Protocol::build();
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.busy();
+        self.connections.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Server settings that decide how a request is answered
+struct Routing<'a> {
+    read_only: bool,
+    cluster: Option<&'a Cluster>,
+    backup_root: Option<&'a Path>,
+}
+
+fn handle_connecton(
+    mut stream: TcpStream,
+    store: &Storage,
+    connection: &Connection,
+    protocol: Box<dyn ProtocolTrait>,
+    max_request_bytes: usize,
+    routing: Routing,
+) -> Result<()> {
+    let buffer = match read_frame(&mut stream, max_request_bytes) {
+        Ok(Some(buffer)) => buffer,
+        // Client hung up, or shutdown closed the connection before a request
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            let refusal = protocol.encode_response(&Response::Error(e.to_string()));
+            let _ = write_frame(&mut stream, &refusal);
+            return Err(e);
+        }
+    };
+    connection.busy();
+
     let request = protocol.decode_request(&buffer)?;
 
-    let store = store.lock().map_err(|_| KvsError::LockPoisoned)?;
     let response: Response = match request {
-        Request::Set { key, value } => {
-            store.set(key, value)?;
-            Response::Ok
+        request if let Some(cluster) = routing.cluster => cluster.handle(store, request),
+        Request::Set { .. } | Request::Remove { .. } if routing.read_only => {
+            Response::Error(READ_ONLY.to_string())
         }
-        Request::Get { key } => match store.get(key)? {
-            Some(value) => {
+        Request::Set { key, value } => match store.set(key, value) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Get { key } => match store.get(key) {
+            Ok(Some(value)) => {
                 info!("Get Value: {}", value);
                 Response::Value(value)
             }
-            None => Response::NotFound,
+            Ok(None) => Response::NotFound,
+            Err(e) => Response::Error(e.to_string()),
         },
         Request::Remove { key } => match store.remove(key) {
             Ok(_) => Response::Ok,
             Err(_) => Response::NotFound,
         },
+        Request::Backup { dest } => {
+            match backup_dest(routing.backup_root, &dest).and_then(|dest| store.backup(&dest)) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Error(e.to_string()),
+            }
+        }
     };
 
     let encoded = protocol.encode_response(&response);
 
     info!("Encoded Server: {:?}", encoded);
 
-    stream.write_all(&encoded)?;
-    stream.flush()?;
-
-    Ok(())
+    write_frame(&mut stream, &encoded)
 }
@@ -0,0 +1,64 @@
+use crate::{KvsError, Result, StoreTrait};
+use std::path::PathBuf;
+use tokio::task;
+
+/// Async adapter over any `StoreTrait` engine.
+///
+/// Engine calls block on disk I/O, so each one is moved onto Tokio's blocking
+/// thread pool instead of running on a runtime worker.
+#[derive(Clone)]
+pub struct AsyncStore<S: StoreTrait> {
+    store: S,
+}
+
+impl<S: StoreTrait> AsyncStore<S> {
+    /// Wraps a blocking store.
+    pub fn new(store: S) -> AsyncStore<S> {
+        AsyncStore { store }
+    }
+
+    /// Returns the wrapped blocking store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// get the value of the given string key
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let store = self.store.clone();
+        blocking(move || store.get(key)).await
+    }
+
+    /// set the value of the string key
+    pub async fn set(&self, key: String, val: String) -> Result<()> {
+        let store = self.store.clone();
+        blocking(move || store.set(key, val)).await
+    }
+
+    /// remove the value of the key
+    pub async fn remove(&self, key: String) -> Result<()> {
+        let store = self.store.clone();
+        blocking(move || store.remove(key)).await
+    }
+
+    /// flush buffered writes to durable storage
+    pub async fn flush(&self) -> Result<()> {
+        let store = self.store.clone();
+        blocking(move || store.flush()).await
+    }
+
+    /// write a consistent snapshot into the new or empty directory `dest`
+    pub async fn backup(&self, dest: PathBuf) -> Result<()> {
+        let store = self.store.clone();
+        blocking(move || store.backup(&dest)).await
+    }
+}
+
+async fn blocking<F, T>(job: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(job)
+        .await
+        .map_err(|err| KvsError::Concurrency(err.to_string()))?
+}
@@ -0,0 +1,79 @@
+// About a 1% false positive rate
+const BITS_PER_KEY: u64 = 10;
+const HASHES: u64 = 7;
+
+/// Says whether a key may be in a set, so lookups skip most files without
+/// reading them
+///
+/// Filters are written to disk, so keys are hashed with FNV-1a rather than
+/// `DefaultHasher`, whose output may change between Rust releases.
+#[derive(Debug, Clone)]
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    /// An empty filter sized for `keys` keys
+    pub(crate) fn new(keys: u64) -> Bloom {
+        let bits = (keys * BITS_PER_KEY).max(64);
+        Bloom {
+            bits: vec![0; bits.div_ceil(64) as usize],
+        }
+    }
+
+    pub(crate) fn hash(key: &str) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in key.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        // FNV mixes its high bits poorly; finish as splitmix64 does
+        hash ^= hash >> 30;
+        hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash ^= hash >> 27;
+        hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^ (hash >> 31)
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        self.insert_hash(Bloom::hash(key));
+    }
+
+    /// Like `insert`, for a key hashed by `Bloom::hash` earlier
+    pub(crate) fn insert_hash(&mut self, hash: u64) {
+        for bit in self.probes(hash) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.probes(Bloom::hash(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.bits
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    /// The filter `to_bytes` wrote, or `None` if `bytes` cannot be one
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Bloom> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(8) {
+            return None;
+        }
+        let bits = bytes
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Some(Bloom { bits })
+    }
+
+    // Double hashing: two halves of one hash make every probe
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> + use<> {
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
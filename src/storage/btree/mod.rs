@@ -0,0 +1,7 @@
+mod page;
+mod pool;
+mod store;
+mod tree;
+mod wal;
+
+pub use store::{BTreeOptions, BTreeStats, BTreeStore};
@@ -0,0 +1,318 @@
+use crate::{KvsError, Result};
+
+/// Every page, the meta page included, is this size
+pub(super) const PAGE_SIZE: usize = 4096;
+
+pub(super) type PageId = u64;
+
+/// Page 0 describes the tree
+pub(super) const META_PAGE: PageId = 0;
+
+const MAGIC: [u8; 8] = *b"KVSBTREE";
+const VERSION: u32 = 1;
+
+// `[crc u32][kind u8]`, the crc covering the rest of the page
+const HEADER: usize = 5;
+
+const META: u8 = 1;
+const LEAF: u8 = 2;
+const INTERNAL: u8 = 3;
+const OVERFLOW: u8 = 4;
+const FREE: u8 = 5;
+
+/// Longer keys and values go to a chain of overflow pages, so a node page
+/// always holds at least three cells and splits into two that fit
+pub(super) const INLINE_MAX: usize = 512;
+
+// After the header and the cell count
+const NODE_CAPACITY: usize = PAGE_SIZE - HEADER - 2;
+
+// After the header, the next page and the bytes used
+pub(super) const OVERFLOW_CAPACITY: usize = PAGE_SIZE - HEADER - 12;
+
+/// Where the tree starts and how far the file goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Meta {
+    pub(super) root: PageId,
+    /// Pages allocated, free ones included; the next new page gets this id
+    pub(super) pages: u64,
+    /// First page of the free list, 0 when it is empty
+    pub(super) free: PageId,
+}
+
+/// A key or value as a cell holds it
+#[derive(Debug, Clone)]
+pub(super) enum Field {
+    Inline(String),
+    /// Stored in the chain of overflow pages starting at `first`
+    Overflow {
+        first: PageId,
+        len: u32,
+    },
+}
+
+impl Field {
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Field::Inline(text) => 5 + text.len(),
+            Field::Overflow { .. } => 13,
+        }
+    }
+}
+
+/// A key in a node, in full, with the overflow chain holding it if it is
+/// too long to sit in the page
+#[derive(Debug, Clone)]
+pub(super) struct Key {
+    pub(super) text: String,
+    pub(super) chain: Option<PageId>,
+}
+
+impl Key {
+    fn field(&self) -> Field {
+        match self.chain {
+            Some(first) => Field::Overflow {
+                first,
+                len: self.text.len() as u32,
+            },
+            None => Field::Inline(self.text.clone()),
+        }
+    }
+
+    pub(super) fn size(&self) -> usize {
+        match self.chain {
+            Some(_) => 13,
+            None => 5 + self.text.len(),
+        }
+    }
+}
+
+/// A decoded tree page
+///
+/// A leaf holds sorted keys and their values. An internal node holds one
+/// more child than keys; child `i` holds the keys from `keys[i - 1]` up to
+/// but not including `keys[i]`.
+#[derive(Debug, Clone)]
+pub(super) enum Node {
+    Leaf {
+        keys: Vec<Key>,
+        values: Vec<Field>,
+    },
+    Internal {
+        keys: Vec<Key>,
+        children: Vec<PageId>,
+    },
+}
+
+impl Node {
+    pub(super) fn empty_leaf() -> Node {
+        Node::Leaf {
+            keys: vec![],
+            values: vec![],
+        }
+    }
+
+    /// Bytes of cells, which must fit in `NODE_CAPACITY`
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Node::Leaf { keys, values } => {
+                keys.iter().map(Key::size).sum::<usize>()
+                    + values.iter().map(Field::size).sum::<usize>()
+            }
+            Node::Internal { keys, children } => {
+                keys.iter().map(Key::size).sum::<usize>() + 8 * children.len()
+            }
+        }
+    }
+
+    pub(super) fn fits(&self) -> bool {
+        self.size() <= NODE_CAPACITY
+    }
+}
+
+/// A node page as read, its keys not yet fetched from overflow pages
+#[derive(Debug)]
+pub(super) enum RawNode {
+    Leaf(Vec<(Field, Field)>),
+    Internal(Vec<Field>, Vec<PageId>),
+}
+
+fn blank(kind: u8) -> Box<[u8]> {
+    let mut page = vec![0; PAGE_SIZE].into_boxed_slice();
+    page[4] = kind;
+    page
+}
+
+/// Fills in the checksum of a page about to be written
+pub(super) fn seal(page: &mut [u8]) {
+    let crc = crc32fast::hash(&page[4..]);
+    page[..4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Whether a page read back is the one written
+pub(super) fn verify(page: &[u8]) -> bool {
+    page.len() == PAGE_SIZE && crc32fast::hash(&page[4..]).to_le_bytes() == page[..4]
+}
+
+pub(super) fn encode_meta(meta: &Meta) -> Box<[u8]> {
+    let mut page = blank(META);
+    let mut out = &mut page[HEADER..];
+    put(&mut out, &MAGIC);
+    put(&mut out, &VERSION.to_le_bytes());
+    put(&mut out, &meta.root.to_le_bytes());
+    put(&mut out, &meta.pages.to_le_bytes());
+    put(&mut out, &meta.free.to_le_bytes());
+    page
+}
+
+pub(super) fn decode_meta(page: &[u8]) -> Result<Meta> {
+    let mut input = body(page, META)?;
+    if take(&mut input, 8)? != MAGIC || u32::from_le_bytes(array(&mut input)?) != VERSION {
+        return Err(KvsError::CorruptedLog);
+    }
+    Ok(Meta {
+        root: u64::from_le_bytes(array(&mut input)?),
+        pages: u64::from_le_bytes(array(&mut input)?),
+        free: u64::from_le_bytes(array(&mut input)?),
+    })
+}
+
+/// Encodes a node that fits in a page and whose long keys have chains
+pub(super) fn encode_node(node: &Node) -> Box<[u8]> {
+    debug_assert!(node.fits());
+    let (mut page, count) = match node {
+        Node::Leaf { keys, .. } => (blank(LEAF), keys.len()),
+        Node::Internal { keys, .. } => (blank(INTERNAL), keys.len()),
+    };
+    let mut out = &mut page[HEADER..];
+    put(&mut out, &(count as u16).to_le_bytes());
+    match node {
+        Node::Leaf { keys, values } => {
+            for (key, value) in keys.iter().zip(values) {
+                put_field(&mut out, &key.field());
+                put_field(&mut out, value);
+            }
+        }
+        Node::Internal { keys, children } => {
+            put(&mut out, &children[0].to_le_bytes());
+            for (key, child) in keys.iter().zip(&children[1..]) {
+                put_field(&mut out, &key.field());
+                put(&mut out, &child.to_le_bytes());
+            }
+        }
+    }
+    page
+}
+
+pub(super) fn decode_node(page: &[u8]) -> Result<RawNode> {
+    let kind = page.get(4).copied().ok_or(KvsError::CorruptedLog)?;
+    let mut input = body(page, kind)?;
+    let count = u16::from_le_bytes(array(&mut input)?) as usize;
+    match kind {
+        LEAF => {
+            let mut cells = Vec::with_capacity(count);
+            for _ in 0..count {
+                cells.push((take_field(&mut input)?, take_field(&mut input)?));
+            }
+            Ok(RawNode::Leaf(cells))
+        }
+        INTERNAL => {
+            let mut keys = Vec::with_capacity(count);
+            let mut children = vec![u64::from_le_bytes(array(&mut input)?)];
+            for _ in 0..count {
+                keys.push(take_field(&mut input)?);
+                children.push(u64::from_le_bytes(array(&mut input)?));
+            }
+            Ok(RawNode::Internal(keys, children))
+        }
+        _ => Err(KvsError::CorruptedLog),
+    }
+}
+
+/// One page of an overflow chain; `next` is 0 on the last
+pub(super) fn encode_overflow(next: PageId, bytes: &[u8]) -> Box<[u8]> {
+    debug_assert!(bytes.len() <= OVERFLOW_CAPACITY);
+    let mut page = blank(OVERFLOW);
+    let mut out = &mut page[HEADER..];
+    put(&mut out, &next.to_le_bytes());
+    put(&mut out, &(bytes.len() as u32).to_le_bytes());
+    put(&mut out, bytes);
+    page
+}
+
+/// The next page of the chain and this page's bytes
+pub(super) fn decode_overflow(page: &[u8]) -> Result<(PageId, &[u8])> {
+    let mut input = body(page, OVERFLOW)?;
+    let next = u64::from_le_bytes(array(&mut input)?);
+    let len = u32::from_le_bytes(array(&mut input)?) as usize;
+    Ok((next, take(&mut input, len)?))
+}
+
+/// A page on the free list; `next` is 0 on the last
+pub(super) fn encode_free(next: PageId) -> Box<[u8]> {
+    let mut page = blank(FREE);
+    page[HEADER..HEADER + 8].copy_from_slice(&next.to_le_bytes());
+    page
+}
+
+pub(super) fn decode_free(page: &[u8]) -> Result<PageId> {
+    let mut input = body(page, FREE)?;
+    Ok(u64::from_le_bytes(array(&mut input)?))
+}
+
+// The page past its header, if it is of `kind`
+fn body(page: &[u8], kind: u8) -> Result<&[u8]> {
+    match page.get(4) {
+        Some(&found) if found == kind && page.len() == PAGE_SIZE => Ok(&page[HEADER..]),
+        _ => Err(KvsError::CorruptedLog),
+    }
+}
+
+fn put(out: &mut &mut [u8], bytes: &[u8]) {
+    let (head, tail) = std::mem::take(out).split_at_mut(bytes.len());
+    head.copy_from_slice(bytes);
+    *out = tail;
+}
+
+fn put_field(out: &mut &mut [u8], field: &Field) {
+    match field {
+        Field::Inline(text) => {
+            put(out, &[0]);
+            put(out, &(text.len() as u32).to_le_bytes());
+            put(out, text.as_bytes());
+        }
+        Field::Overflow { first, len } => {
+            put(out, &[1]);
+            put(out, &len.to_le_bytes());
+            put(out, &first.to_le_bytes());
+        }
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(KvsError::CorruptedLog);
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+fn array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N]> {
+    Ok(take(input, N)?.try_into().unwrap())
+}
+
+fn take_field(input: &mut &[u8]) -> Result<Field> {
+    let [tag] = array(input)?;
+    let len = u32::from_le_bytes(array(input)?);
+    match tag {
+        0 => Ok(Field::Inline(String::from_utf8(
+            take(input, len as usize)?.to_vec(),
+        )?)),
+        1 => Ok(Field::Overflow {
+            first: u64::from_le_bytes(array(input)?),
+            len,
+        }),
+        _ => Err(KvsError::CorruptedLog),
+    }
+}
@@ -0,0 +1,228 @@
+use super::page::{self, PAGE_SIZE, PageId};
+use super::wal::{Images, Wal};
+use crate::{KvsError, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+#[derive(Debug)]
+struct Frame {
+    page: Box<[u8]>,
+    // Newer than the page file; its image is in the log unless the write
+    // in progress changed it
+    dirty: bool,
+    // Tick of the last use, its key in `lru`
+    used: u64,
+}
+
+/// Counts of page lookups since the pool was created
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct PoolStats {
+    pub(super) hits: u64,
+    pub(super) misses: u64,
+    pub(super) evictions: u64,
+}
+
+/// Caches up to `capacity` pages of the page file, evicting the least
+/// recently used
+///
+/// Changes stay in the pool until a write commits them to the log, and
+/// reach the page file only on eviction or at a checkpoint, each time
+/// after the log is on disk. The pages a write in progress changed are not
+/// evicted, so an aborted write leaves nothing behind.
+#[derive(Debug)]
+pub(super) struct Pool {
+    file: File,
+    // None when read-only
+    wal: Option<Wal>,
+    // A read-only pool's view of the log it may not replay into the file
+    images: Images,
+    capacity: usize,
+    frames: HashMap<PageId, Frame>,
+    lru: BTreeMap<u64, PageId>,
+    tick: u64,
+    // What each page changed by the write in progress held before it, if
+    // it was cached
+    undo: HashMap<PageId, Option<Frame>>,
+    stats: PoolStats,
+}
+
+impl Pool {
+    pub(super) fn new(file: File, wal: Option<Wal>, images: Images, capacity: usize) -> Pool {
+        Pool {
+            file,
+            wal,
+            images,
+            capacity,
+            frames: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            undo: HashMap::new(),
+            stats: PoolStats::default(),
+        }
+    }
+
+    pub(super) fn stats(&self) -> PoolStats {
+        self.stats
+    }
+
+    /// Bytes logged since the last checkpoint
+    pub(super) fn wal_size(&self) -> u64 {
+        self.wal.as_ref().map_or(0, Wal::size)
+    }
+
+    pub(super) fn read(&mut self, id: PageId) -> Result<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            self.stats.hits += 1;
+            self.lru.remove(&frame.used);
+            self.lru.insert(tick, id);
+            frame.used = tick;
+        } else {
+            self.stats.misses += 1;
+            let page = match self.images.get(&id) {
+                Some(image) => image.clone(),
+                None => {
+                    let mut page = vec![0; PAGE_SIZE].into_boxed_slice();
+                    self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+                    self.file.read_exact(&mut page)?;
+                    page
+                }
+            };
+            if !page::verify(&page) {
+                return Err(KvsError::CorruptedLog);
+            }
+            self.evict(1)?;
+            self.lru.insert(tick, id);
+            self.frames.insert(
+                id,
+                Frame {
+                    page,
+                    dirty: false,
+                    used: tick,
+                },
+            );
+        }
+        Ok(&self.frames[&id].page)
+    }
+
+    /// Replaces page `id` as part of the write in progress
+    pub(super) fn write(&mut self, id: PageId, mut page: Box<[u8]>) -> Result<()> {
+        if self.wal.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        page::seal(&mut page);
+        self.tick += 1;
+        let before = self.frames.remove(&id);
+        if let Some(before) = &before {
+            self.lru.remove(&before.used);
+        }
+        self.undo.entry(id).or_insert(before);
+        self.evict(1)?;
+        self.lru.insert(self.tick, id);
+        self.frames.insert(
+            id,
+            Frame {
+                page,
+                dirty: true,
+                used: self.tick,
+            },
+        );
+        Ok(())
+    }
+
+    /// Logs the pages the write in progress changed, making it durable
+    /// once the log is synced
+    pub(super) fn commit(&mut self) -> Result<()> {
+        if self.undo.is_empty() {
+            return Ok(());
+        }
+        let mut ids: Vec<PageId> = self.undo.keys().copied().collect();
+        ids.sort_unstable();
+        let pages: Vec<(PageId, &[u8])> = ids
+            .iter()
+            .map(|id| (*id, &self.frames[id].page[..]))
+            .collect();
+        let wal = self.wal.as_mut().ok_or(KvsError::ReadOnly)?;
+        wal.append(&pages)?;
+        self.undo.clear();
+        self.evict(0)
+    }
+
+    /// Puts back what the pages changed by the write in progress held
+    pub(super) fn abort(&mut self) {
+        for (id, before) in self.undo.drain() {
+            if let Some(frame) = self.frames.remove(&id) {
+                self.lru.remove(&frame.used);
+            }
+            if let Some(before) = before {
+                self.lru.insert(before.used, id);
+                self.frames.insert(id, before);
+            }
+        }
+    }
+
+    pub(super) fn sync(&mut self) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes every dirty page back and empties the log
+    pub(super) fn checkpoint(&mut self) -> Result<()> {
+        debug_assert!(self.undo.is_empty());
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.sync()?;
+        let mut dirty: Vec<PageId> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(id, _)| *id)
+            .collect();
+        dirty.sort_unstable();
+        for id in dirty {
+            let frame = self.frames.get_mut(&id).expect("listed above");
+            self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+            self.file.write_all(&frame.page)?;
+            frame.dirty = false;
+        }
+        self.file.sync_all()?;
+        wal.reset()
+    }
+
+    /// Drops every cached page; the file is about to be replaced
+    pub(super) fn discard(&mut self) -> &mut File {
+        self.frames.clear();
+        self.lru.clear();
+        self.undo.clear();
+        &mut self.file
+    }
+
+    // Makes room for `room` more pages, writing dirty ones back; pages the
+    // write in progress changed stay, even past capacity
+    fn evict(&mut self, room: usize) -> Result<()> {
+        while self.frames.len() + room > self.capacity {
+            let Some((&used, &id)) = self.lru.iter().find(|(_, id)| !self.undo.contains_key(id))
+            else {
+                return Ok(());
+            };
+            let frame = &self.frames[&id];
+            if frame.dirty {
+                // The log must be on disk before the page it redoes
+                if let Some(wal) = &mut self.wal {
+                    wal.sync()?;
+                }
+                self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+                self.file.write_all(&frame.page)?;
+            }
+            self.lru.remove(&used);
+            self.frames.remove(&id);
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+}
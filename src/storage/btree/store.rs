@@ -0,0 +1,219 @@
+use super::page::PAGE_SIZE;
+use super::tree::{PAGE_FILE, Tree, before_end};
+use super::wal::WAL_FILE;
+use crate::storage::lock::DirLock;
+use crate::storage::start_backup;
+use crate::{Engine, KvsError, Result, StoreTrait};
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const CACHE_BYTES: u64 = 8 * 1024 * 1024; // 8 MB
+const CHECKPOINT_BYTES: u64 = 16 * 1024 * 1024; // 16 MB
+
+// Enough for a write that splits every level of a deep tree
+const MIN_CACHE_PAGES: usize = 64;
+
+/// Tuning knobs for `BTreeStore`
+#[derive(Debug, Clone, Copy)]
+pub struct BTreeOptions {
+    /// Bytes of pages the buffer pool keeps in memory
+    pub cache_bytes: u64,
+    /// Write every changed page back and empty the log once it grows past
+    /// this size
+    pub checkpoint_bytes: u64,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> BTreeOptions {
+        BTreeOptions {
+            cache_bytes: CACHE_BYTES,
+            checkpoint_bytes: CHECKPOINT_BYTES,
+        }
+    }
+}
+
+/// The shape of a `BTreeStore`, and how its buffer pool has fared since it
+/// was opened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BTreeStats {
+    /// Pages in the page file, free ones included
+    pub pages: u64,
+    /// Pages waiting on the free list to be reused
+    pub free_pages: u64,
+    /// Levels from the root down to the leaves
+    pub depth: usize,
+    /// Page lookups the buffer pool answered from memory
+    pub cache_hits: u64,
+    /// Page lookups that read the page file
+    pub cache_misses: u64,
+    /// Pages evicted to make room for others
+    pub evictions: u64,
+    /// Bytes in the write-ahead log since the last checkpoint
+    pub wal_bytes: u64,
+    /// Checkpoints taken since the store was opened
+    pub checkpoints: u64,
+}
+
+/// A B+tree of string key/value pairs in fixed-size pages
+///
+/// Pages are cached in a buffer pool that evicts the least recently used.
+/// Each write logs the whole of every page it changed to a write-ahead log
+/// before returning, and changed pages reach the page file on eviction or
+/// at a checkpoint, once the log holding them is on disk. Opening the store
+/// replays the log, so a crash loses at most the writes since the last
+/// `flush`. Keys and values too long for a page go to overflow pages.
+///
+/// # Example
+///
+/// ```
+/// use tempfile::TempDir;
+/// use kvs::{BTreeStore, Result, StoreTrait};
+/// use std::ops::Bound;
+///
+/// fn main() -> Result<()> {
+///     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+///     let store = BTreeStore::open(temp_dir.path().to_path_buf())?;
+///
+///     store.set("b".to_string(), "2".to_string())?;
+///     store.set("a".to_string(), "1".to_string())?;
+///     let mut keys = vec![];
+///     store.scan_range(
+///         (Bound::Excluded("a".to_string()), Bound::Unbounded),
+///         &mut |key, _| Ok(keys.push(key)),
+///     )?;
+///     assert_eq!(keys, vec!["b"]);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct BTreeStore {
+    dir: PathBuf,
+    // Reads take it too, as they move pages in the pool
+    tree: Arc<Mutex<Tree>>,
+    _lock: Arc<DirLock>,
+}
+
+impl BTreeStore {
+    /// Create a B+tree store
+    pub fn open(dir_path: PathBuf) -> Result<BTreeStore> {
+        BTreeStore::open_with_options(dir_path, BTreeOptions::default())
+    }
+    /// Create a B+tree store with non-default options
+    pub fn open_with_options(dir_path: PathBuf, options: BTreeOptions) -> Result<BTreeStore> {
+        BTreeStore::open_inner(dir_path, options, false)
+    }
+    /// Open an existing store for reading only
+    ///
+    /// Like `KvStore::open_read_only`, readers share the directory with each
+    /// other but not with a writer. The log is read but not replayed into
+    /// the page file.
+    pub fn open_read_only(dir_path: PathBuf) -> Result<BTreeStore> {
+        BTreeStore::open_inner(dir_path, BTreeOptions::default(), true)
+    }
+    fn open_inner(dir_path: PathBuf, options: BTreeOptions, read_only: bool) -> Result<BTreeStore> {
+        let lock = match read_only {
+            true => DirLock::shared(&dir_path)?,
+            false => DirLock::exclusive(&dir_path)?,
+        };
+        let cache_pages = ((options.cache_bytes / PAGE_SIZE as u64) as usize).max(MIN_CACHE_PAGES);
+        let tree = Tree::open(&dir_path, cache_pages, options.checkpoint_bytes, read_only)?;
+        Ok(BTreeStore {
+            dir: dir_path,
+            tree: Arc::new(Mutex::new(tree)),
+            _lock: Arc::new(lock),
+        })
+    }
+    /// Pages, tree depth and buffer pool counts
+    pub fn stats(&self) -> Result<BTreeStats> {
+        let mut tree = self.tree()?;
+        let meta = tree.meta();
+        let pool = tree.pool_stats();
+        Ok(BTreeStats {
+            pages: meta.pages,
+            free_pages: tree.free_pages()?,
+            depth: tree.depth()?,
+            cache_hits: pool.hits,
+            cache_misses: pool.misses,
+            evictions: pool.evictions,
+            wal_bytes: tree.wal_size(),
+            checkpoints: tree.checkpoints,
+        })
+    }
+    /// Write every changed page back to the page file and empty the log,
+    /// so a restart has nothing to replay
+    pub fn checkpoint(&self) -> Result<()> {
+        self.tree()?.checkpoint()
+    }
+    /// Remove every key
+    pub fn clear(&self) -> Result<()> {
+        self.tree()?.clear()
+    }
+
+    fn tree(&self) -> Result<MutexGuard<'_, Tree>> {
+        self.tree.lock().map_err(|_| KvsError::LockPoisoned)
+    }
+}
+
+impl StoreTrait for BTreeStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.tree()?.set(&key, &value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.tree()?.get(&key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.tree()?.remove(&key)? {
+            true => Ok(()),
+            false => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.tree()?.sync()
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.scan_range((Bound::Unbounded, Bound::Unbounded), visit)
+    }
+
+    /// Walks the leaves in order, a leaf at a time, without holding the
+    /// tree while `visit` runs
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        visit: &mut dyn FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        let mut leaf = range.clone();
+        loop {
+            let (pairs, next) = self.tree()?.scan_leaf(&leaf)?;
+            for (key, value) in pairs {
+                visit(key, value)?;
+            }
+            match next {
+                Some(next) if before_end(&range.1, &next) => {
+                    leaf.0 = Bound::Included(next);
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Checkpoints, then copies the page file into `dest`; a read-only
+    /// store copies the log alongside, as it cannot checkpoint
+    fn backup(&self, dest: &Path) -> Result<()> {
+        start_backup(dest, Engine::BTree)?;
+        let mut tree = self.tree()?;
+        tree.checkpoint()?;
+        fs::copy(self.dir.join(PAGE_FILE), dest.join(PAGE_FILE))?;
+        match fs::copy(self.dir.join(WAL_FILE), dest.join(WAL_FILE)) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
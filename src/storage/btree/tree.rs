@@ -0,0 +1,588 @@
+use super::page::{
+    self, Field, INLINE_MAX, Key, META_PAGE, Meta, Node, OVERFLOW_CAPACITY, PAGE_SIZE, PageId,
+    RawNode,
+};
+use super::pool::{Pool, PoolStats};
+use super::wal::{self, Images, Wal};
+use crate::{KvsError, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+pub(super) const PAGE_FILE: &str = "btree.db";
+
+// Deeper than any tree of 2^64 pages; a walk past it has met a cycle
+const MAX_DEPTH: usize = 64;
+
+type Pair = (String, String);
+
+// A separator key and the new right sibling it leads to
+type Split = (Key, PageId);
+
+const FRESH: Meta = Meta {
+    root: 1,
+    pages: 2,
+    free: 0,
+};
+
+/// The tree's pages and the operations on them
+///
+/// Every write runs in `atomically`: it changes pages in the pool, then
+/// commits them to the log as one record, or puts them back on error.
+/// Nodes are not merged as they shrink; a node is freed once empty.
+#[derive(Debug)]
+pub(super) struct Tree {
+    pool: Pool,
+    meta: Meta,
+    // As of the last commit
+    committed: Meta,
+    checkpoint_bytes: u64,
+    pub(super) checkpoints: u64,
+}
+
+impl Tree {
+    /// Opens the tree in `dir`, first redoing whatever its log holds; a
+    /// reader keeps the log's pages in the pool rather than write them
+    pub(super) fn open(
+        dir: &Path,
+        cache_pages: usize,
+        checkpoint_bytes: u64,
+        read_only: bool,
+    ) -> Result<Tree> {
+        let images = wal::replay(dir)?;
+        let (pool, fresh) = match read_only {
+            true => {
+                let file = File::open(dir.join(PAGE_FILE))?;
+                let fresh = file.metadata()?.len() == 0 && images.is_empty();
+                (Pool::new(file, None, images, cache_pages), fresh)
+            }
+            false => {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(dir.join(PAGE_FILE))?;
+                redo(&mut file, &images)?;
+                let fresh = file.metadata()?.len() == 0;
+                let wal = Wal::create(dir)?;
+                (
+                    Pool::new(file, Some(wal), Images::new(), cache_pages),
+                    fresh,
+                )
+            }
+        };
+
+        let mut tree = Tree {
+            pool,
+            meta: FRESH,
+            committed: FRESH,
+            checkpoint_bytes,
+            checkpoints: 0,
+        };
+        match (fresh, read_only) {
+            (true, false) => tree.init()?,
+            // Only a writer that crashed while creating it leaves this
+            (true, true) => return Err(KvsError::CorruptedLog),
+            (false, _) => {
+                tree.meta = page::decode_meta(tree.pool.read(META_PAGE)?)?;
+                tree.committed = tree.meta;
+            }
+        }
+        Ok(tree)
+    }
+
+    pub(super) fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let mut id = self.meta.root;
+        for _ in 0..MAX_DEPTH {
+            match self.node(id)? {
+                Node::Leaf { keys, values } => {
+                    return match keys.binary_search_by(|found| found.text.as_str().cmp(key)) {
+                        Ok(i) => Ok(Some(self.read_field(&values[i])?)),
+                        Err(_) => Ok(None),
+                    };
+                }
+                Node::Internal { keys, children } => {
+                    id = children[keys.partition_point(|found| found.text.as_str() <= key)];
+                }
+            }
+        }
+        Err(KvsError::CorruptedLog)
+    }
+
+    pub(super) fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.atomically(|tree| {
+            let value = tree.write_field(value)?;
+            let root = tree.meta.root;
+            let (old, split) = tree.insert(root, key, value)?;
+            if let Some((separator, right)) = split {
+                // The tree grows a level
+                let new_root = tree.alloc()?;
+                let node = Node::Internal {
+                    keys: vec![separator],
+                    children: vec![root, right],
+                };
+                tree.write_node(new_root, &node)?;
+                tree.meta.root = new_root;
+            }
+            match old {
+                Some(old) => tree.free_field(&old),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Whether `key` was there to remove
+    pub(super) fn remove(&mut self, key: &str) -> Result<bool> {
+        self.atomically(|tree| {
+            let root = tree.meta.root;
+            let Some((value, emptied)) = tree.delete(root, key)? else {
+                return Ok(false);
+            };
+            tree.free_field(&value)?;
+            if emptied {
+                tree.write_node(root, &Node::empty_leaf())?;
+            }
+            // A root left with one child hands over to it
+            loop {
+                let root = tree.meta.root;
+                let child = match page::decode_node(tree.pool.read(root)?)? {
+                    RawNode::Internal(_, children) if children.len() == 1 => children[0],
+                    _ => break,
+                };
+                tree.meta.root = child;
+                tree.free_page(root)?;
+            }
+            Ok(true)
+        })
+    }
+
+    /// The pairs in `range` of the leaf where it starts, and the smallest
+    /// key the next leaf may hold, if there is a next leaf
+    pub(super) fn scan_leaf(
+        &mut self,
+        range: &(Bound<String>, Bound<String>),
+    ) -> Result<(Vec<Pair>, Option<String>)> {
+        let mut id = self.meta.root;
+        let mut next = None;
+        for _ in 0..MAX_DEPTH {
+            match self.node(id)? {
+                Node::Internal { mut keys, children } => {
+                    let i = match &range.0 {
+                        Bound::Included(start) | Bound::Excluded(start) => {
+                            keys.partition_point(|found| found.text <= *start)
+                        }
+                        Bound::Unbounded => 0,
+                    };
+                    if i < keys.len() {
+                        next = Some(keys.swap_remove(i).text);
+                    }
+                    id = children[i];
+                }
+                Node::Leaf { keys, values } => {
+                    let mut pairs = vec![];
+                    for (key, value) in keys.into_iter().zip(values) {
+                        if !before_end(&range.1, &key.text) {
+                            return Ok((pairs, None));
+                        }
+                        if range.contains(&key.text) {
+                            pairs.push((key.text, self.read_field(&value)?));
+                        }
+                    }
+                    return Ok((pairs, next));
+                }
+            }
+        }
+        Err(KvsError::CorruptedLog)
+    }
+
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.pool.sync()
+    }
+
+    /// Writes every change back to the page file and empties the log
+    pub(super) fn checkpoint(&mut self) -> Result<()> {
+        self.pool.checkpoint()?;
+        self.checkpoints += 1;
+        Ok(())
+    }
+
+    /// Starts over with an empty page file
+    pub(super) fn clear(&mut self) -> Result<()> {
+        // Checkpointed first, so a crash part way leaves the old tree or
+        // none, never the file without the log's pages
+        self.checkpoint()?;
+        let file = self.pool.discard();
+        file.set_len(0)?;
+        file.sync_all()?;
+        self.init()
+    }
+
+    pub(super) fn meta(&self) -> Meta {
+        self.meta
+    }
+
+    pub(super) fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    pub(super) fn wal_size(&self) -> u64 {
+        self.pool.wal_size()
+    }
+
+    /// Levels from the root down to the leaves
+    pub(super) fn depth(&mut self) -> Result<usize> {
+        let mut id = self.meta.root;
+        for depth in 1..=MAX_DEPTH {
+            match page::decode_node(self.pool.read(id)?)? {
+                RawNode::Leaf(_) => return Ok(depth),
+                RawNode::Internal(_, children) => id = children[0],
+            }
+        }
+        Err(KvsError::CorruptedLog)
+    }
+
+    /// Pages on the free list
+    pub(super) fn free_pages(&mut self) -> Result<u64> {
+        let mut count = 0;
+        let mut id = self.meta.free;
+        while id != 0 {
+            if count >= self.meta.pages {
+                return Err(KvsError::CorruptedLog);
+            }
+            id = page::decode_free(self.pool.read(id)?)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // An empty leaf as the root, checkpointed so the page file is whole
+    fn init(&mut self) -> Result<()> {
+        self.meta = FRESH;
+        self.committed = Meta {
+            root: 0,
+            pages: 0,
+            free: 0,
+        };
+        self.atomically(|tree| tree.write_node(FRESH.root, &Node::empty_leaf()))?;
+        self.checkpoint()
+    }
+
+    fn atomically<T>(&mut self, write: impl FnOnce(&mut Tree) -> Result<T>) -> Result<T> {
+        let result = write(self).and_then(|value| {
+            self.commit()?;
+            Ok(value)
+        });
+        match result {
+            Ok(value) => {
+                if self.pool.wal_size() >= self.checkpoint_bytes {
+                    self.checkpoint()?;
+                }
+                Ok(value)
+            }
+            Err(e) => {
+                self.pool.abort();
+                self.meta = self.committed;
+                Err(e)
+            }
+        }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.meta != self.committed {
+            self.pool.write(META_PAGE, page::encode_meta(&self.meta))?;
+        }
+        self.pool.commit()?;
+        self.committed = self.meta;
+        Ok(())
+    }
+
+    // Returns the value `key` had, and the separator and page of a new
+    // right sibling if the node split
+    fn insert(
+        &mut self,
+        id: PageId,
+        key: &str,
+        value: Field,
+    ) -> Result<(Option<Field>, Option<Split>)> {
+        let mut node = self.node(id)?;
+        let old = match &mut node {
+            Node::Leaf { keys, values } => {
+                match keys.binary_search_by(|found| found.text.as_str().cmp(key)) {
+                    Ok(i) => Some(std::mem::replace(&mut values[i], value)),
+                    Err(i) => {
+                        keys.insert(i, self.own_key(key.to_owned())?);
+                        values.insert(i, value);
+                        None
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|found| found.text.as_str() <= key);
+                let (old, split) = self.insert(children[i], key, value)?;
+                let Some((separator, right)) = split else {
+                    return Ok((old, None));
+                };
+                keys.insert(i, separator);
+                children.insert(i + 1, right);
+                old
+            }
+        };
+        Ok((old, self.store_node(id, node)?))
+    }
+
+    // Returns the value removed and whether the node is left empty, for
+    // the caller to free
+    fn delete(&mut self, id: PageId, key: &str) -> Result<Option<(Field, bool)>> {
+        let mut node = self.node(id)?;
+        let value = match &mut node {
+            Node::Leaf { keys, values } => {
+                let Ok(i) = keys.binary_search_by(|found| found.text.as_str().cmp(key)) else {
+                    return Ok(None);
+                };
+                self.free_key(keys.remove(i))?;
+                values.remove(i)
+            }
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|found| found.text.as_str() <= key);
+                let Some((value, emptied)) = self.delete(children[i], key)? else {
+                    return Ok(None);
+                };
+                if !emptied {
+                    return Ok(Some((value, false)));
+                }
+                // Either neighbouring separator will do; the keys between
+                // them now route to the remaining neighbour
+                self.free_page(children.remove(i))?;
+                if !keys.is_empty() {
+                    self.free_key(keys.remove(i.saturating_sub(1)))?;
+                }
+                value
+            }
+        };
+        let emptied = match &node {
+            Node::Leaf { keys, .. } => keys.is_empty(),
+            Node::Internal { children, .. } => children.is_empty(),
+        };
+        // The caller frees an empty node, or makes an empty root a leaf
+        if !emptied {
+            self.write_node(id, &node)?;
+        }
+        Ok(Some((value, emptied)))
+    }
+
+    // Writes `node` to page `id`, splitting off a right half to a new page
+    // if it does not fit
+    fn store_node(&mut self, id: PageId, mut node: Node) -> Result<Option<Split>> {
+        if node.fits() {
+            self.write_node(id, &node)?;
+            return Ok(None);
+        }
+        let (separator, right) = match &mut node {
+            Node::Leaf { keys, values } => {
+                let sizes: Vec<usize> = keys
+                    .iter()
+                    .zip(values.iter())
+                    .map(|(key, value)| key.size() + value.size())
+                    .collect();
+                let cut = half(&sizes).clamp(1, keys.len() - 1);
+                let right = Node::Leaf {
+                    keys: keys.split_off(cut),
+                    values: values.split_off(cut),
+                };
+                let Node::Leaf {
+                    keys: right_keys, ..
+                } = &right
+                else {
+                    unreachable!()
+                };
+                // The separator gets its own copy, as each node frees its
+                // own keys
+                (self.own_key(right_keys[0].text.clone())?, right)
+            }
+            Node::Internal { keys, children } => {
+                let sizes: Vec<usize> = keys.iter().map(|key| key.size() + 8).collect();
+                let cut = half(&sizes).min(keys.len() - 1);
+                let right = Node::Internal {
+                    keys: keys.split_off(cut + 1),
+                    children: children.split_off(cut + 1),
+                };
+                // The middle key moves up
+                (keys.pop().expect("cut leaves it on the left"), right)
+            }
+        };
+        let right_id = self.alloc()?;
+        self.write_node(id, &node)?;
+        self.write_node(right_id, &right)?;
+        Ok(Some((separator, right_id)))
+    }
+
+    fn node(&mut self, id: PageId) -> Result<Node> {
+        match page::decode_node(self.pool.read(id)?)? {
+            RawNode::Leaf(cells) => {
+                let mut keys = Vec::with_capacity(cells.len());
+                let mut values = Vec::with_capacity(cells.len());
+                for (key, value) in cells {
+                    keys.push(self.key(key)?);
+                    values.push(value);
+                }
+                Ok(Node::Leaf { keys, values })
+            }
+            RawNode::Internal(fields, children) => {
+                let mut keys = Vec::with_capacity(fields.len());
+                for field in fields {
+                    keys.push(self.key(field)?);
+                }
+                Ok(Node::Internal { keys, children })
+            }
+        }
+    }
+
+    fn write_node(&mut self, id: PageId, node: &Node) -> Result<()> {
+        self.pool.write(id, page::encode_node(node))
+    }
+
+    fn key(&mut self, field: Field) -> Result<Key> {
+        match field {
+            Field::Inline(text) => Ok(Key { text, chain: None }),
+            Field::Overflow { first, .. } => Ok(Key {
+                text: self.read_field(&field)?,
+                chain: Some(first),
+            }),
+        }
+    }
+
+    // A key for a node, written to an overflow chain if it is long
+    fn own_key(&mut self, text: String) -> Result<Key> {
+        let chain = match self.write_field(&text)? {
+            Field::Overflow { first, .. } => Some(first),
+            Field::Inline(_) => None,
+        };
+        Ok(Key { text, chain })
+    }
+
+    fn free_key(&mut self, key: Key) -> Result<()> {
+        match key.chain {
+            Some(first) => self.free_field(&Field::Overflow {
+                first,
+                len: key.text.len() as u32,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn read_field(&mut self, field: &Field) -> Result<String> {
+        let (first, len) = match field {
+            Field::Inline(text) => return Ok(text.clone()),
+            Field::Overflow { first, len } => (*first, *len as usize),
+        };
+        let mut bytes = vec![];
+        let mut id = first;
+        while bytes.len() < len {
+            if id == 0 {
+                return Err(KvsError::CorruptedLog);
+            }
+            let (next, chunk) = page::decode_overflow(self.pool.read(id)?)?;
+            if chunk.is_empty() {
+                return Err(KvsError::CorruptedLog);
+            }
+            bytes.extend_from_slice(chunk);
+            id = next;
+        }
+        if bytes.len() != len {
+            return Err(KvsError::CorruptedLog);
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    fn write_field(&mut self, text: &str) -> Result<Field> {
+        if text.len() <= INLINE_MAX {
+            return Ok(Field::Inline(text.to_owned()));
+        }
+        let len = u32::try_from(text.len())?;
+        let chunks: Vec<&[u8]> = text.as_bytes().chunks(OVERFLOW_CAPACITY).collect();
+        let ids = chunks
+            .iter()
+            .map(|_| self.alloc())
+            .collect::<Result<Vec<PageId>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = ids.get(i + 1).copied().unwrap_or(0);
+            self.pool
+                .write(ids[i], page::encode_overflow(next, chunk))?;
+        }
+        Ok(Field::Overflow { first: ids[0], len })
+    }
+
+    fn free_field(&mut self, field: &Field) -> Result<()> {
+        let Field::Overflow { first, len } = *field else {
+            return Ok(());
+        };
+        let (mut id, mut left) = (first, len as usize);
+        while left > 0 {
+            let (next, chunk) = page::decode_overflow(self.pool.read(id)?)?;
+            if chunk.is_empty() || chunk.len() > left {
+                return Err(KvsError::CorruptedLog);
+            }
+            left -= chunk.len();
+            self.free_page(id)?;
+            id = next;
+        }
+        Ok(())
+    }
+
+    fn alloc(&mut self) -> Result<PageId> {
+        if self.meta.free != 0 {
+            let id = self.meta.free;
+            self.meta.free = page::decode_free(self.pool.read(id)?)?;
+            return Ok(id);
+        }
+        let id = self.meta.pages;
+        self.meta.pages += 1;
+        Ok(id)
+    }
+
+    fn free_page(&mut self, id: PageId) -> Result<()> {
+        self.pool.write(id, page::encode_free(self.meta.free))?;
+        self.meta.free = id;
+        Ok(())
+    }
+}
+
+/// Whether `key` comes before `end`
+pub(super) fn before_end(end: &Bound<String>, key: &str) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_str(),
+        Bound::Excluded(end) => key < end.as_str(),
+        Bound::Unbounded => true,
+    }
+}
+
+// The first cut that leaves at least half the bytes on the left
+fn half(sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    let mut left = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        left += size;
+        if left * 2 >= total {
+            return i + 1;
+        }
+    }
+    sizes.len()
+}
+
+// Writes the log's page images into the page file, which then no longer
+// needs the log
+fn redo(file: &mut File, images: &Images) -> Result<()> {
+    if images.is_empty() {
+        return Ok(());
+    }
+    let mut ids: Vec<&PageId> = images.keys().collect();
+    ids.sort_unstable();
+    for id in ids {
+        file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        file.write_all(&images[id])?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
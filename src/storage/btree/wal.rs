@@ -0,0 +1,132 @@
+use super::page::{PAGE_SIZE, PageId};
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use tracing::warn;
+
+pub(super) const WAL_FILE: &str = "btree.wal";
+
+// `[crc u32][pages u32]`, the crc covering the pages that follow
+const RECORD_HEADER: usize = 8;
+
+/// Pages an unfinished write-ahead log holds, newest image of each
+pub(super) type Images = HashMap<PageId, Box<[u8]>>;
+
+/// The write-ahead log of page images
+///
+/// Each write to the tree appends one record holding the whole of every
+/// page it changed, so replaying the log over the page file redoes every
+/// write since the last checkpoint, and repairs a page a crash tore part
+/// way through being written back.
+#[derive(Debug)]
+pub(super) struct Wal {
+    file: File,
+    size: u64,
+    synced: bool,
+}
+
+impl Wal {
+    /// Continues the log in `dir` after a replay has checkpointed it, so it
+    /// starts out empty
+    pub(super) fn create(dir: &Path) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(dir.join(WAL_FILE))?;
+        file.sync_all()?;
+        Ok(Wal {
+            file,
+            size: 0,
+            synced: true,
+        })
+    }
+
+    /// Bytes written since the last checkpoint
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Appends one write's pages; on failure the log is cut back to where
+    /// it was, so a later record does not follow a torn one
+    pub(super) fn append(&mut self, pages: &[(PageId, &[u8])]) -> Result<()> {
+        let mut record = vec![0; RECORD_HEADER];
+        record[4..8].copy_from_slice(&(pages.len() as u32).to_le_bytes());
+        for (id, page) in pages {
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(page);
+        }
+        let crc = crc32fast::hash(&record[4..]);
+        record[..4].copy_from_slice(&crc.to_le_bytes());
+
+        if let Err(e) = self.file.write_all(&record) {
+            self.file.set_len(self.size)?;
+            self.file.seek(SeekFrom::Start(self.size))?;
+            return Err(e.into());
+        }
+        self.size += record.len() as u64;
+        self.synced = false;
+        Ok(())
+    }
+
+    pub(super) fn sync(&mut self) -> Result<()> {
+        if !self.synced {
+            self.file.sync_data()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /// Empties the log once a checkpoint has put its pages in the page file
+    pub(super) fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        self.size = 0;
+        self.synced = true;
+        Ok(())
+    }
+}
+
+/// The page images the log in `dir` holds, up to any record a crash tore
+pub(super) fn replay(dir: &Path) -> Result<Images> {
+    let bytes = match fs::read(dir.join(WAL_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Images::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut images = Images::new();
+    let mut offset = 0;
+    while let Some(length) = record_len(&bytes[offset..]) {
+        let record = &bytes[offset..offset + length];
+        for image in record[RECORD_HEADER..].chunks_exact(8 + PAGE_SIZE) {
+            let id = u64::from_le_bytes(image[..8].try_into().unwrap());
+            images.insert(id, image[8..].into());
+        }
+        offset += length;
+    }
+    if offset < bytes.len() {
+        warn!(
+            "{} ends in a torn record at offset {}: dropped bytes {}..{}",
+            WAL_FILE,
+            offset,
+            offset,
+            bytes.len()
+        );
+    }
+    Ok(images)
+}
+
+// The length of the whole, intact record at the start of `bytes`
+fn record_len(bytes: &[u8]) -> Option<usize> {
+    let header = bytes.get(..RECORD_HEADER)?;
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let pages = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let length = pages
+        .checked_mul(8 + PAGE_SIZE)?
+        .checked_add(RECORD_HEADER)?;
+    let record = bytes.get(..length)?;
+    (crc32fast::hash(&record[4..]) == crc).then_some(length)
+}
@@ -0,0 +1,173 @@
+//! Portable dumps of a store's pairs, independent of the engine.
+//!
+//! JSON lines hold one `{"key":…,"value":…}` object per line and are meant
+//! for reading and editing by hand. The binary format starts with
+//! `KVSDUMP1`, then one `[key len u64][value len u64][key][value][crc32 u32]`
+//! record per pair, and ends with a `u64::MAX` marker followed by the record
+//! count, so a corrupted or truncated dump is caught on import.
+//!
+//! Both directions stream, one pair at a time.
+
+use super::StoreTrait;
+use crate::{KvsError, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::io::{BufRead, Read, Write};
+
+const MAGIC: &[u8; 8] = b"KVSDUMP1";
+// A key length no real record has, marking the trailer
+const END: u64 = u64::MAX;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpFormat {
+    Json,
+    Binary,
+}
+
+impl Display for DumpFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            DumpFormat::Json => "json",
+            DumpFormat::Binary => "binary",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+pub(super) fn export<S: StoreTrait>(
+    store: &S,
+    format: DumpFormat,
+    writer: &mut dyn Write,
+) -> Result<u64> {
+    let mut count: u64 = 0;
+    if format == DumpFormat::Binary {
+        writer.write_all(MAGIC)?;
+    }
+
+    store.scan(&mut |key, value| {
+        match format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut *writer, &Pair { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                writer.write_all(&(key.len() as u64).to_le_bytes())?;
+                writer.write_all(&(value.len() as u64).to_le_bytes())?;
+                writer.write_all(key.as_bytes())?;
+                writer.write_all(value.as_bytes())?;
+                writer.write_all(&checksum(key.as_bytes(), value.as_bytes()).to_le_bytes())?;
+            }
+        }
+        count += 1;
+        Ok(())
+    })?;
+
+    if format == DumpFormat::Binary {
+        writer.write_all(&END.to_le_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+pub(super) fn import<S: StoreTrait>(store: &S, reader: &mut dyn BufRead) -> Result<u64> {
+    match reader.fill_buf()?.starts_with(MAGIC) {
+        true => import_binary(store, reader),
+        false => import_json(store, reader),
+    }
+}
+
+fn import_json<S: StoreTrait>(store: &S, reader: &mut dyn BufRead) -> Result<u64> {
+    let mut count = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let pair: Pair = serde_json::from_str(&line)
+            .map_err(|e| KvsError::Dump(format!("line {}: {}", number + 1, e)))?;
+        store.set(pair.key, pair.value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn import_binary<S: StoreTrait>(store: &S, reader: &mut dyn BufRead) -> Result<u64> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+
+    let mut count = 0;
+    loop {
+        let key_size = read_u64(reader, count)?;
+        if key_size == END {
+            break;
+        }
+        let value_size = read_u64(reader, count)?;
+
+        let key = read_bytes(reader, key_size, count)?;
+        let value = read_bytes(reader, value_size, count)?;
+
+        let mut crc = [0u8; 4];
+        reader.read_exact(&mut crc).map_err(|_| truncated(count))?;
+        if u32::from_le_bytes(crc) != checksum(&key, &value) {
+            return Err(KvsError::Dump(format!(
+                "checksum mismatch in record {}",
+                count + 1
+            )));
+        }
+
+        let invalid = |_| KvsError::Dump(format!("invalid UTF-8 in record {}", count + 1));
+        let key = String::from_utf8(key).map_err(invalid)?;
+        let value = String::from_utf8(value).map_err(invalid)?;
+
+        store.set(key, value)?;
+        count += 1;
+    }
+
+    let expected = read_u64(reader, count)?;
+    if expected != count {
+        return Err(KvsError::Dump(format!(
+            "dump ends after {} records but says it holds {}",
+            count, expected
+        )));
+    }
+    Ok(count)
+}
+
+fn checksum(key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+fn read_u64(reader: &mut dyn BufRead, count: u64) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|_| truncated(count))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut dyn BufRead, size: u64, count: u64) -> Result<Vec<u8>> {
+    // The length is not trusted until the checksum is, so read through
+    // `take` rather than allocating it up front
+    let mut bytes = Vec::new();
+    (&mut *reader).take(size).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != size {
+        return Err(truncated(count));
+    }
+    Ok(bytes)
+}
+
+fn truncated(count: u64) -> KvsError {
+    KvsError::Dump(format!("dump is truncated after {} records", count))
+}
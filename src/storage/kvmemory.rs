@@ -1,30 +1,292 @@
-use crate::{Result, StoreTrait};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use super::lock::DirLock;
+use super::start_backup;
+use crate::{Engine, Entry, KvsError, Result, StoreTrait};
+use dashmap::DashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// Snapshot written by `backup` and by a persistent store, and loaded when a
+// store opens a directory holding one
+const DUMP_FILE: &str = "memory.dump";
+// Writes since the snapshot, replayed over it on open
+const AOF_FILE: &str = "memory.aof";
+
+const SNAPSHOT_BYTES: u64 = 64 * 1024 * 1024; // 64 MB
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Tuning knobs for `KvMemory`
+#[derive(Debug, Clone, Copy)]
+pub struct KvMemoryOptions {
+    /// Append every write to a file replayed on open, so writes survive a
+    /// restart
+    pub persistent: bool,
+    /// Snapshot once the append-only file grows past this size
+    pub snapshot_bytes: u64,
+    /// Snapshot at the first write this long after the last snapshot
+    pub snapshot_interval: Duration,
+}
+
+impl Default for KvMemoryOptions {
+    fn default() -> KvMemoryOptions {
+        KvMemoryOptions {
+            persistent: false,
+            snapshot_bytes: SNAPSHOT_BYTES,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+        }
+    }
+}
+
+/// The append-only file of a persistent store
+///
+/// Each record is `[crc u32]` followed by an `Entry::Set` or
+/// `Entry::Remove` as framed in a segment.
+#[derive(Debug)]
+struct Aof {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    snapshot_bytes: u64,
+    snapshot_interval: Duration,
+    snapshot_at: Instant,
+}
+
+impl Aof {
+    /// Appends `entry`, cutting a partial record back off on failure
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let serialized = entry.serialize();
+        let mut record = Vec::with_capacity(4 + serialized.len());
+        record.extend_from_slice(&crc32fast::hash(&serialized).to_le_bytes());
+        record.extend_from_slice(&serialized);
+        if let Err(e) = self.file.write_all(&record) {
+            self.file.set_len(self.size)?;
+            self.file.seek(SeekFrom::Start(self.size))?;
+            return Err(e.into());
+        }
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    fn due(&self) -> bool {
+        self.size >= self.snapshot_bytes
+            || (self.size > 0 && self.snapshot_at.elapsed() >= self.snapshot_interval)
+    }
+
+    /// Replaces the snapshot with `map` and empties the file. The caller
+    /// holds off writes until it returns.
+    ///
+    /// A crash between the two leaves writes the new snapshot already holds
+    /// in the file; replaying them over it changes nothing.
+    fn snapshot(&mut self, map: &DashMap<String, String>) -> Result<()> {
+        write_dump(&self.dir, map)?;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        self.size = 0;
+        self.snapshot_at = Instant::now();
+        Ok(())
+    }
+}
 
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+/// Key/value pairs are stored in a `DashMap` in memory. By default nothing
+/// is persisted: a store loaded from a directory starts from the dump there
+/// and its writes are lost on restart.
+///
+/// With `KvMemoryOptions::persistent`, every write is also appended to a
+/// file in the directory before it is applied, and the whole map is
+/// snapshotted into the dump from time to time, emptying that file. Opening
+/// the directory loads the snapshot and replays the file over it, so a
+/// crash loses at most the writes since the last `flush`. Writes wait while
+/// a snapshot is taken; reads do not.
 ///
 /// Example:
 ///
 /// ```rust
-/// # use kvs::KvMemory;
-/// let mut store = KvMemory::new();
-/// store.set("key".to_owned(), "value".to_owned());
-/// let val = store.get("key".to_owned());
+/// # use kvs::{KvMemory, StoreTrait};
+/// let store = KvMemory::new();
+/// store.set("key".to_owned(), "value".to_owned()).unwrap();
+/// let val = store.get("key".to_owned()).unwrap();
 /// assert_eq!(val, Some("value".to_owned()));
 /// ```
 #[derive(Default, Clone, Debug)]
 pub struct KvMemory {
-    map: Arc<Mutex<HashMap<String, String>>>,
+    map: Arc<DashMap<String, String>>,
+    read_only: bool,
+    // Writes hold it while they log and apply, so the file and the map
+    // agree on their order
+    aof: Option<Arc<Mutex<Aof>>>,
+    // Held while loaded from a directory
+    _lock: Option<Arc<DirLock>>,
 }
 
 impl KvMemory {
     /// Creates a `KvStore`.
     pub fn new() -> KvMemory {
-        KvMemory {
-            map: Arc::new(Mutex::new(HashMap::new())),
+        KvMemory::default()
+    }
+
+    /// Creates a `KvMemory` holding the dump restored into `dir`, if any.
+    ///
+    /// Writes are not persisted, so every start begins from what `dir`
+    /// last held. `dir` stays locked until the store is dropped.
+    pub fn load(dir: &Path) -> Result<KvMemory> {
+        KvMemory::load_with_options(dir, KvMemoryOptions::default())
+    }
+
+    /// Like `load`, persisting writes to `dir` if `options` ask for it.
+    pub fn load_with_options(dir: &Path, options: KvMemoryOptions) -> Result<KvMemory> {
+        let (mut store, whole) = KvMemory::load_inner(dir, DirLock::exclusive(dir)?, false)?;
+        if !options.persistent {
+            return Ok(store);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(AOF_FILE))?;
+        let size = file.metadata()?.len();
+        if whole < size {
+            warn!(
+                "{} ends in a torn record at offset {}: dropped bytes {}..{}",
+                AOF_FILE, whole, whole, size
+            );
+            file.set_len(whole)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(whole))?;
+        store.aof = Some(Arc::new(Mutex::new(Aof {
+            dir: dir.to_path_buf(),
+            file,
+            size: whole,
+            snapshot_bytes: options.snapshot_bytes,
+            snapshot_interval: options.snapshot_interval,
+            snapshot_at: Instant::now(),
+        })));
+        Ok(store)
+    }
+
+    /// Like `load`, but shares `dir` with other readers and refuses writes.
+    pub fn load_read_only(dir: &Path) -> Result<KvMemory> {
+        KvMemory::load_inner(dir, DirLock::shared(dir)?, true).map(|(store, _)| store)
+    }
+
+    // Loads the dump and replays the append-only file over it, returning
+    // how many bytes of the file hold whole records
+    fn load_inner(dir: &Path, lock: DirLock, read_only: bool) -> Result<(KvMemory, u64)> {
+        let store = KvMemory {
+            read_only,
+            _lock: Some(Arc::new(lock)),
+            ..KvMemory::default()
+        };
+        if let Some(bytes) = read_if_exists(&dir.join(DUMP_FILE))? {
+            // The dump is a run of `Entry::Set` records, as in a segment
+            let mut rest = bytes.as_slice();
+            while !rest.is_empty() {
+                let length = entry_length(rest).ok_or(KvsError::CorruptedLog)?;
+                if let Entry::Set { key, value } = Entry::deserialize(&rest[..length])? {
+                    store.map.insert(key, value);
+                }
+                rest = &rest[length..];
+            }
+        }
+
+        let mut whole = 0;
+        if let Some(bytes) = read_if_exists(&dir.join(AOF_FILE))? {
+            // Whatever follows the last whole record is a torn write, as
+            // nothing is appended after it
+            while let Some(length) = bytes.get(whole + 4..).and_then(entry_length) {
+                let crc = u32::from_le_bytes(bytes[whole..whole + 4].try_into().unwrap());
+                let serialized = &bytes[whole + 4..whole + 4 + length];
+                if crc32fast::hash(serialized) != crc {
+                    break;
+                }
+                store.apply(Entry::deserialize(serialized)?);
+                whole += 4 + length;
+            }
+        }
+        Ok((store, whole as u64))
+    }
+
+    /// Removes every key.
+    pub fn clear(&self) -> Result<()> {
+        self.check_writable()?;
+        match self.aof()? {
+            Some(mut aof) => {
+                self.map.clear();
+                aof.snapshot(&self.map)
+            }
+            None => {
+                self.map.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes every pair to the dump in `dir`, which the next `load` of
+    /// `dir` starts from.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        // Holding off writes makes a persistent store's dump a consistent one
+        let _aof = self.aof()?;
+        write_dump(dir, &self.map)
+    }
+
+    /// Snapshots a persistent store now rather than when it is due, leaving
+    /// nothing to replay on open. Does nothing otherwise.
+    pub fn snapshot(&self) -> Result<()> {
+        match self.aof()? {
+            Some(mut aof) => aof.snapshot(&self.map),
+            None => Ok(()),
+        }
+    }
+
+    // Logs `entry` if the store is persistent, then applies it
+    fn write(&self, entry: Entry) -> Result<()> {
+        self.check_writable()?;
+        let Some(mut aof) = self.aof()? else {
+            self.apply(entry);
+            return Ok(());
+        };
+        if let Entry::Set { value, .. } = &entry {
+            // It would replay as a removal
+            if value.is_empty() {
+                return Err(KvsError::EmptyValue);
+            }
+        }
+        aof.append(&entry)?;
+        self.apply(entry);
+        match aof.due() {
+            true => aof.snapshot(&self.map),
+            false => Ok(()),
+        }
+    }
+
+    fn apply(&self, entry: Entry) {
+        match entry {
+            Entry::Set { key, value } => {
+                self.map.insert(key, value);
+            }
+            Entry::Remove { key } | Entry::Blob { key, .. } => {
+                self.map.remove(&key);
+            }
+        }
+    }
+
+    fn aof(&self) -> Result<Option<MutexGuard<'_, Aof>>> {
+        self.aof
+            .as_ref()
+            .map(|aof| aof.lock().map_err(|_| KvsError::LockPoisoned))
+            .transpose()
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(KvsError::ReadOnly),
+            false => Ok(()),
         }
     }
 }
@@ -33,20 +295,77 @@ impl StoreTrait for KvMemory {
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.map.lock()?.insert(key, value);
-        Ok(())
+        self.write(Entry::Set { key, value })
     }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self.map.lock()?.get(&key).cloned())
+        Ok(self.map.get(&key).map(|value| value.clone()))
     }
 
     /// Remove a given key.
     fn remove(&self, key: String) -> Result<()> {
-        self.map.lock()?.remove(&key);
+        self.write(Entry::Remove { key })
+    }
+
+    /// Syncs the append-only file of a persistent store.
+    fn flush(&self) -> Result<()> {
+        if let Some(aof) = self.aof()? {
+            aof.file.sync_data()?;
+        }
         Ok(())
     }
+
+    /// Visits pairs while holding a read lock on their shard.
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        for pair in self.map.iter() {
+            visit(pair.key().clone(), pair.value().clone())?;
+        }
+        Ok(())
+    }
+
+    /// Dumps every pair into `dest`. Without persistence each key is read
+    /// once, so a write racing the dump may or may not be included.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        start_backup(dest, Engine::Memory)?;
+        self.save(dest)
+    }
+}
+
+// Writes the dump in `dir` next to it and moves it into place, so a crash
+// leaves the previous one
+fn write_dump(dir: &Path, map: &DashMap<String, String>) -> Result<()> {
+    let staging = dir.join(format!("{}.tmp", DUMP_FILE));
+    let mut dump = BufWriter::new(File::create(&staging)?);
+    for pair in map.iter() {
+        let entry = Entry::Set {
+            key: pair.key().clone(),
+            value: pair.value().clone(),
+        };
+        dump.write_all(&entry.serialize())?;
+    }
+    dump.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&staging, dir.join(DUMP_FILE))?;
+    Ok(())
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The length of the entry `bytes` starts with, if they hold all of it
+fn entry_length(bytes: &[u8]) -> Option<usize> {
+    let key_size = u64::from_le_bytes(bytes.get(0..8)?.try_into().unwrap());
+    let value_size = u64::from_le_bytes(bytes.get(8..16)?.try_into().unwrap());
+    let length = key_size.checked_add(value_size)?.checked_add(16)?;
+    match length <= bytes.len() as u64 {
+        true => Some(length as usize),
+        false => None,
+    }
 }
@@ -1,33 +1,42 @@
-use super::StoreTrait;
-use crate::{KvsError, Result};
-use sled::{Db, Tree};
-use std::sync::{Arc, Mutex};
+use super::{StoreTrait, start_backup};
+use crate::{Engine, KvsError, Result};
+use sled::Db;
+use std::ops::Bound;
+use std::path::Path;
 
 /// Wrapper of `sled::Db`
+///
+/// `sled::Db` is a cheaply cloneable handle that is safe to share between
+/// threads, so no extra locking is needed here.
 #[derive(Clone)]
 pub struct KvSled {
-    db: Arc<Mutex<Db>>,
+    db: Db,
 }
 
 impl KvSled {
     /// Creates a `KvSled` from `sled::Db`.
     pub fn new(db: Db) -> Self {
-        KvSled {
-            db: Arc::new(Mutex::new(db)),
-        }
+        KvSled { db }
+    }
+
+    /// Removes every key.
+    pub fn clear(&self) -> Result<()> {
+        self.db.clear()?;
+        self.db.flush()?;
+        Ok(())
     }
 }
 
 impl StoreTrait for KvSled {
     fn set(&self, key: String, value: String) -> Result<()> {
-        let tree = self.db.lock()?;
+        let tree = &self.db;
         tree.insert(key, value.into_bytes()).map(|_| ())?;
         tree.flush()?;
         Ok(())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        let tree = self.db.lock()?;
+        let tree = &self.db;
         Ok(tree
             .get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
@@ -36,9 +45,49 @@ impl StoreTrait for KvSled {
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        let tree = self.db.lock()?;
+        let tree = &self.db;
         tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
         tree.flush()?;
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        for pair in self.db.iter() {
+            let (key, value) = pair?;
+            visit(
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        visit: &mut dyn FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        for pair in self.db.range(range) {
+            let (key, value) = pair?;
+            visit(
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Exports every tree into a fresh database at `dest`.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        start_backup(dest, Engine::Sled)?;
+        let backup = sled::Config::new().path(dest).open()?;
+        backup.import(self.db.export());
+        backup.flush()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,188 @@
+use crate::{KvsError, Result};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Bytes in an `EncryptionKey`
+pub const KEY_LEN: usize = 32;
+
+/// Random bytes in each encrypted segment's header, shared by the nonces
+/// of all its entries
+pub(super) const SALT_LEN: usize = 16;
+
+// Each entry carries the rest of its nonce
+const NONCE_TAIL_LEN: usize = 8;
+
+/// A key for encrypting segment entries with XChaCha20-Poly1305
+///
+/// Keys are written as 64 hex digits. Segment headers name the key by a
+/// short id derived from it, never by the key itself.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: u32,
+    cipher: XChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    /// A key from raw bytes
+    pub fn new(bytes: [u8; KEY_LEN]) -> EncryptionKey {
+        let digest = Sha256::new()
+            .chain_update(b"kvs segment key id")
+            .chain_update(bytes)
+            .finalize();
+        // Id 0 marks a segment that is not encrypted
+        let id = u32::from_be_bytes(digest[..4].try_into().unwrap()).max(1);
+        EncryptionKey {
+            id,
+            cipher: XChaCha20Poly1305::new(&bytes.into()),
+        }
+    }
+
+    /// A key from its hex form; surrounding whitespace is ignored
+    pub fn from_hex(text: &str) -> Result<EncryptionKey> {
+        let mut bytes = [0; KEY_LEN];
+        hex::decode_to_slice(text.trim(), &mut bytes).map_err(|e| {
+            KvsError::Encryption(format!("a key must be {} hex digits: {}", KEY_LEN * 2, e))
+        })?;
+        Ok(EncryptionKey::new(bytes))
+    }
+
+    /// The key in the file at `path`
+    pub fn from_file(path: &Path) -> Result<EncryptionKey> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            KvsError::Encryption(format!("cannot read key file {}: {}", path.display(), e))
+        })?;
+        EncryptionKey::from_hex(&text)
+    }
+
+    /// The key in the environment variable `var`
+    pub fn from_env(var: &str) -> Result<EncryptionKey> {
+        let text = std::env::var(var)
+            .map_err(|e| KvsError::Encryption(format!("cannot read key from ${}: {}", var, e)))?;
+        EncryptionKey::from_hex(&text)
+    }
+
+    /// A new random key, with its hex form for safekeeping
+    pub fn generate() -> (EncryptionKey, String) {
+        let mut bytes = [0; KEY_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        (EncryptionKey::new(bytes), hex::encode(bytes))
+    }
+
+    /// The id segment headers record for this key
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// Never prints the key
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({:08x})", self.id)
+    }
+}
+
+/// The key new segments are encrypted with, plus retired keys that older
+/// segments may still need
+///
+/// To rotate, make the new key current and retire the old one; compaction
+/// rewrites every live entry under the current key, after which the old key
+/// is no longer needed. `KvStore::upgrade` does that at once.
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    current: EncryptionKey,
+    retired: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    /// A keyring encrypting with `current`
+    pub fn new(current: EncryptionKey) -> Keyring {
+        Keyring {
+            current,
+            retired: vec![],
+        }
+    }
+
+    /// Also decrypt segments written under `key`
+    pub fn retire(mut self, key: EncryptionKey) -> Keyring {
+        self.retired.push(key);
+        self
+    }
+
+    /// The key new segments are encrypted with
+    pub fn current(&self) -> &EncryptionKey {
+        &self.current
+    }
+
+    pub(super) fn get(&self, id: u32) -> Option<&EncryptionKey> {
+        std::iter::once(&self.current)
+            .chain(&self.retired)
+            .find(|key| key.id == id)
+    }
+}
+
+/// Seals and opens the entries of one segment
+#[derive(Debug, Clone)]
+pub(super) struct SegmentCipher {
+    key: EncryptionKey,
+    salt: [u8; SALT_LEN],
+}
+
+impl SegmentCipher {
+    pub(super) fn new(key: EncryptionKey, salt: [u8; SALT_LEN]) -> SegmentCipher {
+        SegmentCipher { key, salt }
+    }
+
+    // `[nonce tail][ciphertext and tag]`; the tail is random, so an entry
+    // rewritten at the offset of a torn one never reuses its nonce
+    pub(super) fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut tail = [0; NONCE_TAIL_LEN];
+        OsRng.fill_bytes(&mut tail);
+        let ciphertext = self
+            .key
+            .cipher
+            .encrypt(&self.nonce(&tail), plaintext)
+            .expect("encrypting into memory cannot fail");
+
+        let mut sealed = Vec::with_capacity(NONCE_TAIL_LEN + ciphertext.len());
+        sealed.extend_from_slice(&tail);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub(super) fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted entry failed authentication",
+            )
+        };
+        if sealed.len() < NONCE_TAIL_LEN {
+            return Err(invalid());
+        }
+        let (tail, ciphertext) = sealed.split_at(NONCE_TAIL_LEN);
+        self.key
+            .cipher
+            .decrypt(&self.nonce(tail), ciphertext)
+            .map_err(|_| invalid())
+    }
+
+    fn nonce(&self, tail: &[u8]) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..SALT_LEN].copy_from_slice(&self.salt);
+        nonce[SALT_LEN..].copy_from_slice(tail);
+        nonce
+    }
+}
+
+/// Fresh random salt for a new encrypted segment
+pub(super) fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
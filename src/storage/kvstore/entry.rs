@@ -1,22 +1,180 @@
-use std::io::Read;
+use super::crypto::SegmentCipher;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+// The top two bits of an entry's value size name the codec its value was
+// stored with, and the bit below them marks a blob pointer; sizes never
+// come near them
+const CODEC_SHIFT: u32 = 62;
+const BLOB_BIT: u64 = 1 << 61;
+const SIZE_MASK: u64 = BLOB_BIT - 1;
+
+// `[file_id u64][offset u64][length u64]`
+const BLOB_REF_LEN: usize = 24;
+
+// In place of a codec: the value is a whole encrypted entry, and the key
+// is empty
+const SEALED_FLAG: u64 = 3;
+
+/// How `KvStore` compresses values it writes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Values are stored as given
+    #[default]
+    None,
+    /// Fast, with a modest ratio
+    Lz4,
+    /// Slower, with a better ratio
+    Zstd,
+}
+
+impl Compression {
+    pub(super) fn flag(self) -> u64 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub(super) fn from_flag(flag: u64) -> io::Result<Compression> {
+        match flag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression flag {}", flag),
+            )),
+        }
+    }
+
+    // None when compression would not save anything
+    fn compress(self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(bytes),
+            Compression::Zstd => {
+                zstd::bulk::compress(bytes, zstd::DEFAULT_COMPRESSION_LEVEL).ok()?
+            }
+        };
+        Some(compressed).filter(|compressed| compressed.len() < bytes.len())
+    }
+
+    fn decompress(self, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} value does not decompress: {}", self, e),
+            )
+        };
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Lz4 => {
+                lz4_flex::decompress_size_prepended(&bytes).map_err(|e| invalid(&e))
+            }
+            Compression::Zstd => zstd::stream::decode_all(&bytes[..]).map_err(|e| invalid(&e)),
+        }
+    }
+}
+
+/// Key and value sizes from the first 16 bytes of an entry, the value size
+/// as stored
+pub fn entry_sizes(header: &[u8]) -> (u64, u64) {
+    let key_size = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let value_size = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    (key_size, value_size & SIZE_MASK)
+}
+
+/// Where a value moved out of the segment log lives: the record `length`
+/// bytes long at `offset` in blob file `N.blob`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobRef {
+    /// Blob file id, `N` in `N.blob`
+    pub file_id: u64,
+    /// Byte offset of the record
+    pub offset: u64,
+    /// Bytes in the record
+    pub length: u64,
+}
+
+impl BlobRef {
+    fn serialize(&self) -> [u8; BLOB_REF_LEN] {
+        let mut bytes = [0; BLOB_REF_LEN];
+        bytes[0..8].copy_from_slice(&self.file_id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    fn deserialize(bytes: &[u8]) -> io::Result<BlobRef> {
+        if bytes.len() != BLOB_REF_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "a blob pointer is {} bytes, not {}",
+                    BLOB_REF_LEN,
+                    bytes.len()
+                ),
+            ));
+        }
+        let field = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Ok(BlobRef {
+            file_id: field(0),
+            offset: field(8),
+            length: field(16),
+        })
+    }
+}
 
 #[derive(Debug)]
 pub enum Entry {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// A `Set` whose value went to a blob file; only segments hold these,
+    /// and `KvStore::read_log` hands out the `Set` instead
+    Blob {
+        key: String,
+        blob: BlobRef,
+    },
 }
 
 impl Entry {
     pub fn serialize(&self) -> Vec<u8> {
-        let (key_bytes, value_bytes) = match self {
-            Entry::Set { key, value } => (key.as_bytes(), value.as_bytes()),
-            Entry::Remove { key } => (key.as_bytes(), &[][..]),
+        self.serialize_with(Compression::None, u64::MAX)
+    }
+    /// Like `serialize`, compressing a value of at least `threshold` bytes
+    /// when that makes it smaller
+    pub fn serialize_with(&self, compression: Compression, threshold: u64) -> Vec<u8> {
+        let pointer;
+        let (key_bytes, value_bytes, blob_bit) = match self {
+            Entry::Set { key, value } => (key.as_bytes(), value.as_bytes(), 0),
+            Entry::Remove { key } => (key.as_bytes(), &[][..], 0),
+            Entry::Blob { key, blob } => {
+                pointer = blob.serialize();
+                (key.as_bytes(), &pointer[..], BLOB_BIT)
+            }
+        };
+
+        let compressed = match blob_bit == 0 && value_bytes.len() as u64 >= threshold {
+            true => compression.compress(value_bytes),
+            false => None,
+        };
+        let (value_bytes, flag) = match &compressed {
+            Some(compressed) => (&compressed[..], compression.flag()),
+            None => (value_bytes, 0),
         };
 
         // key_size
         let key_size = key_bytes.len().to_le_bytes();
-        // value_size
-        let value_size = value_bytes.len().to_le_bytes();
+        // value_size, with the codec in its top bits
+        let value_size = (value_bytes.len() as u64 | blob_bit | flag << CODEC_SHIFT).to_le_bytes();
 
         // [ksz, vsz, key, value]
         let mut buffer: Vec<u8> = Vec::with_capacity(16 + key_bytes.len() + value_bytes.len());
@@ -30,7 +188,20 @@ impl Entry {
 
         buffer
     }
-    pub fn deserialize(mut bytes: &[u8]) -> std::io::Result<Self> {
+    pub fn deserialize(bytes: &[u8]) -> io::Result<Self> {
+        Entry::decode(bytes, None)
+    }
+    // Frames an encrypted entry; `serialized` is its plaintext form
+    pub(super) fn seal(serialized: &[u8], cipher: &SegmentCipher) -> Vec<u8> {
+        let sealed = cipher.seal(serialized);
+        let mut buffer = Vec::with_capacity(16 + sealed.len());
+        buffer.extend_from_slice(&0u64.to_le_bytes());
+        buffer.extend_from_slice(&(sealed.len() as u64 | SEALED_FLAG << CODEC_SHIFT).to_le_bytes());
+        buffer.extend_from_slice(&sealed);
+        buffer
+    }
+    // Encrypted entries need the cipher of the segment they were read from
+    pub(super) fn decode(mut bytes: &[u8], cipher: Option<&SegmentCipher>) -> io::Result<Self> {
         use std::io::{Error, ErrorKind};
 
         let mut ksz_buf = [0u8; 8];
@@ -41,6 +212,9 @@ impl Entry {
 
         let key_size = u64::from_le_bytes(ksz_buf);
         let value_size = u64::from_le_bytes(vsz_buf);
+        let flag = value_size >> CODEC_SHIFT;
+        let blob_bit = value_size & BLOB_BIT;
+        let value_size = value_size & SIZE_MASK;
 
         let mut key_bytes = vec![0; key_size as usize];
         let mut value_bytes = vec![0; value_size as usize];
@@ -48,8 +222,20 @@ impl Entry {
         bytes.read_exact(&mut key_bytes)?;
         bytes.read_exact(&mut value_bytes)?;
 
+        if flag == SEALED_FLAG {
+            let cipher = cipher.ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "Encrypted entry without a key")
+            })?;
+            return Entry::decode(&cipher.open(&value_bytes)?, None);
+        }
+        let value_bytes = Compression::from_flag(flag)?.decompress(value_bytes)?;
+
         let key = String::from_utf8(key_bytes)
             .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UTF-8 in key"))?;
+        if blob_bit != 0 {
+            let blob = BlobRef::deserialize(&value_bytes)?;
+            return Ok(Entry::Blob { key, blob });
+        }
         let value = String::from_utf8(value_bytes)
             .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid UTF-8 in value"))?;
 
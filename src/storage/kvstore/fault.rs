@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A failure for `FaultInjector` to inject into segment file I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The write that crosses the next `n` bytes stops there and fails;
+    /// later writes succeed again
+    WriteFailsAfter(u64),
+    /// Once `n` more bytes are written, every write fails with
+    /// `StorageFull` until the fault is cleared
+    NoSpaceAfter(u64),
+    /// The next `n` fsyncs fail without making anything durable
+    SyncFails(u32),
+    /// Reads return at most this many bytes per call
+    ShortReads(usize),
+    /// The write that crosses the next `n` bytes stops there, and from then
+    /// on every write, fsync and truncation fails, as if the machine lost
+    /// power; follow with `crash`
+    PowerCutAfter(u64),
+}
+
+/// Injects faults into a `KvStore`'s segment files, for crash testing
+///
+/// Every clone shares the same faults. The injector also remembers how much
+/// of each segment was made durable by fsync, so `crash` can simulate a
+/// power cut.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    write_fails_at: Option<u64>,
+    no_space_at: Option<u64>,
+    sync_failures: u32,
+    short_reads: Option<usize>,
+    power_cut_at: Option<u64>,
+    powered_off: bool,
+    // Durable length of each segment written through the injector
+    synced: HashMap<u64, u64>,
+}
+
+impl FaultInjector {
+    /// An injector with no faults armed
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    /// Arms `fault`, replacing an earlier fault of the same kind
+    pub fn inject(&self, fault: Fault) {
+        let mut state = self.state();
+        match fault {
+            Fault::WriteFailsAfter(n) => state.write_fails_at = Some(n),
+            Fault::NoSpaceAfter(n) => state.no_space_at = Some(n),
+            Fault::SyncFails(n) => state.sync_failures = n,
+            Fault::ShortReads(n) => state.short_reads = Some(n.max(1)),
+            Fault::PowerCutAfter(n) => state.power_cut_at = Some(n),
+        }
+    }
+
+    /// Disarms every fault
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.write_fails_at = None;
+        state.no_space_at = None;
+        state.sync_failures = 0;
+        state.short_reads = None;
+        state.power_cut_at = None;
+        state.powered_off = false;
+    }
+
+    /// Simulates losing power: each segment written through the injector
+    /// keeps what was fsynced and a random part of what came after it
+    ///
+    /// The store must be dropped first.
+    pub fn crash(&self, dir_path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        for (&file_id, &synced) in &state.synced {
+            let path = dir_path.join(format!("{file_id}.log"));
+            let size = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                // Compaction removed it
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if size > synced {
+                let file = fs::OpenOptions::new().write(true).open(&path)?;
+                file.set_len(fastrand::u64(synced..=size))?;
+            }
+        }
+        state.synced.clear();
+        state.power_cut_at = None;
+        state.powered_off = false;
+        Ok(())
+    }
+
+    // Writes all of `buffer` unless a fault stops it part way, in which
+    // case the bytes before the fault stay in the file
+    pub(super) fn write(&self, file: &mut File, buffer: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_power()?;
+        let len = buffer.len() as u64;
+
+        let mut allowed = len;
+        let mut error = None;
+        if let Some(at) = state.power_cut_at {
+            if at < allowed {
+                allowed = at;
+                error = Some(power_cut());
+                state.powered_off = true;
+            }
+            state.power_cut_at = at.checked_sub(len);
+        }
+        if let Some(at) = state.no_space_at {
+            if at < allowed {
+                allowed = at;
+                error = Some(io::Error::from(io::ErrorKind::StorageFull));
+            }
+            state.no_space_at = Some(at.saturating_sub(len));
+        }
+        if let Some(at) = state.write_fails_at {
+            if at < allowed {
+                allowed = at;
+                error = Some(io::Error::other("injected write failure"));
+            }
+            state.write_fails_at = at.checked_sub(len);
+        }
+
+        file.write_all(&buffer[..allowed as usize])?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn sync(&self, file: &File, file_id: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_power()?;
+        if state.sync_failures > 0 {
+            state.sync_failures -= 1;
+            return Err(io::Error::other("injected fsync failure"));
+        }
+        file.sync_data()?;
+        state.synced.insert(file_id, file.metadata()?.len());
+        Ok(())
+    }
+
+    // Starts tracking a segment opened with `len` bytes already durable
+    pub(super) fn track(&self, file_id: u64, len: u64) {
+        self.state().synced.insert(file_id, len);
+    }
+
+    pub(super) fn set_len(&self, file: &File, file_id: u64, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_power()?;
+        file.set_len(len)?;
+        let synced = state.synced.entry(file_id).or_insert(len);
+        *synced = (*synced).min(len);
+        Ok(())
+    }
+
+    // Largest read a single call may return
+    pub(super) fn read_limit(&self) -> Option<usize> {
+        self.state().short_reads
+    }
+
+    // A test that panicked while holding the lock should not hide the
+    // faults from the rest of the run
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl FaultState {
+    fn check_power(&self) -> io::Result<()> {
+        match self.powered_off {
+            true => Err(power_cut()),
+            false => Ok(()),
+        }
+    }
+}
+
+fn power_cut() -> io::Error {
+    io::Error::other("injected power cut")
+}
@@ -0,0 +1,220 @@
+use super::crypto::Keyring;
+use super::entry::Entry;
+use super::segment::{
+    CHECKSUM_LEN, Damage, Replay, SegmentReader, blob_ids, blob_path, segment_ids,
+};
+use super::store::CommandPos;
+use crate::Result;
+use dashmap::DashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Read-only view of a stopped `KvStore`'s segment files
+///
+/// Unlike `KvStore::open`, nothing is created or changed in the directory.
+#[derive(Debug)]
+pub struct LogInspector {
+    dir: PathBuf,
+    file_ids: Vec<u64>,
+    keys: Option<Keyring>,
+}
+
+/// Summary of one segment file
+#[derive(Debug, Clone, Default)]
+pub struct SegmentInfo {
+    /// Segment file id, `N` in `N.log`
+    pub file_id: u64,
+    /// File size in bytes
+    pub size: u64,
+    /// Segment layout version; 0 for segments written before headers
+    pub version: u32,
+    /// Seconds since the Unix epoch, when the header records it
+    pub created_at: Option<u64>,
+    /// Id of the key entries are encrypted with, if they are
+    pub key_id: Option<u32>,
+    /// Whether entries end in a checksum, which segments from version 5 on
+    /// do; in older ones only the framing can be checked
+    pub checksummed: bool,
+    /// Entries before any damage
+    pub entries: u64,
+    /// Of which `Set`, counting those whose value is in a blob file
+    pub sets: u64,
+    /// Of which `Remove`
+    pub removes: u64,
+    /// Entries the index ends up pointing at
+    pub live: u64,
+    /// Entries compaction would drop: overwritten or removed values, and
+    /// the removals themselves
+    pub stale: u64,
+    /// First framing or checksum problem, if any; later bytes are not read
+    pub damage: Option<Damage>,
+}
+
+/// Totals over the whole log
+#[derive(Debug, Clone, Default)]
+pub struct LogStats {
+    /// Segment files
+    pub segments: u64,
+    /// Bytes on disk
+    pub bytes: u64,
+    /// Entries in every segment
+    pub entries: u64,
+    /// Keys with a value
+    pub live_keys: u64,
+    /// Bytes of the entries holding those values
+    pub live_bytes: u64,
+    /// Key bytes of live keys
+    pub key_bytes: u64,
+    /// Value bytes of live keys, as stored after any compression; a value
+    /// in a blob file counts as the size of its pointer
+    pub value_bytes: u64,
+    /// Blob files
+    pub blob_files: u64,
+    /// Bytes in blob files
+    pub blob_bytes: u64,
+    /// Bytes of the blob records live keys point at
+    pub live_blob_bytes: u64,
+}
+
+impl LogInspector {
+    /// Lists the segments in `dir`
+    pub fn open(dir: &Path) -> Result<LogInspector> {
+        Ok(LogInspector {
+            dir: dir.to_path_buf(),
+            file_ids: segment_ids(dir)?,
+            keys: None,
+        })
+    }
+
+    /// Lists the segments in `dir`, which may be encrypted with `keys`
+    pub fn open_with_keys(dir: &Path, keys: Keyring) -> Result<LogInspector> {
+        Ok(LogInspector {
+            keys: Some(keys),
+            ..LogInspector::open(dir)?
+        })
+    }
+
+    /// Segment ids, oldest first
+    pub fn file_ids(&self) -> &[u64] {
+        &self.file_ids
+    }
+
+    /// Every segment, with live and stale counts from replaying the log
+    /// into an index as `KvStore::open` does
+    pub fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        let (infos, _) = self.replay()?;
+        Ok(infos)
+    }
+
+    /// Calls `visit` with the offset, length and entry of everything in
+    /// segment `file_id` up to the first damage, which is returned
+    pub fn walk(
+        &self,
+        file_id: u64,
+        visit: &mut dyn FnMut(u64, u64, Entry) -> Result<()>,
+    ) -> Result<Option<Damage>> {
+        SegmentReader::open(&self.dir, file_id, None, self.keys.as_ref())?.walk(visit)
+    }
+
+    /// Problems by segment id; empty when every segment is whole
+    ///
+    /// Every segment is checked for framing, and the entries of those from
+    /// version 5 on against their checksums too. In older segments a flipped
+    /// bit in a key or value is only caught if it breaks the framing or the
+    /// entry's encoding; `KvStore::upgrade` rewrites them with checksums.
+    pub fn verify(&self) -> Result<Vec<(u64, Damage)>> {
+        let mut problems = vec![];
+        for &file_id in &self.file_ids {
+            if let Some(damage) = self.walk(file_id, &mut |_, _, _| Ok(()))? {
+                problems.push((file_id, damage));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Ids of the segments whose entries have no checksums, so that
+    /// `verify` can only check their framing
+    pub fn framing_only(&self) -> Result<Vec<u64>> {
+        let mut file_ids = vec![];
+        for &file_id in &self.file_ids {
+            let reader = SegmentReader::open(&self.dir, file_id, None, self.keys.as_ref())?;
+            if !reader.header.checksummed() {
+                file_ids.push(file_id);
+            }
+        }
+        Ok(file_ids)
+    }
+
+    /// Totals over every segment
+    pub fn stats(&self) -> Result<LogStats> {
+        let (infos, index) = self.replay()?;
+        let mut stats = LogStats {
+            segments: infos.len() as u64,
+            ..LogStats::default()
+        };
+        for info in &infos {
+            stats.bytes += info.size;
+            stats.entries += info.entries;
+        }
+        for pos in index.iter() {
+            let key_bytes = pos.key().len() as u64;
+            let trailer = infos
+                .iter()
+                .find(|info| info.file_id == pos.file_id && info.checksummed)
+                .map_or(0, |_| CHECKSUM_LEN);
+            stats.live_keys += 1;
+            stats.live_bytes += pos.length;
+            stats.key_bytes += key_bytes;
+            stats.value_bytes += pos.length - 16 - key_bytes - trailer;
+            stats.live_blob_bytes += pos.blob.map_or(0, |blob| blob.length);
+        }
+        for file_id in blob_ids(&self.dir)? {
+            stats.blob_files += 1;
+            stats.blob_bytes += fs::metadata(blob_path(&self.dir, file_id))?.len();
+        }
+        Ok(stats)
+    }
+
+    fn replay(&self) -> Result<(Vec<SegmentInfo>, DashMap<String, CommandPos>)> {
+        let mut index = DashMap::new();
+        let mut infos = vec![];
+
+        for &file_id in &self.file_ids {
+            let reader = SegmentReader::open(&self.dir, file_id, None, self.keys.as_ref())?;
+            reader.index(&mut index, &mut Replay::default())?;
+
+            let mut info = SegmentInfo {
+                file_id,
+                size: reader.size,
+                version: reader.header.version,
+                created_at: Some(reader.header.created_at).filter(|_| reader.header.version > 0),
+                key_id: Some(reader.header.key_id).filter(|&key_id| key_id != 0),
+                checksummed: reader.header.checksummed(),
+                ..SegmentInfo::default()
+            };
+            let (mut entries, mut sets, mut removes) = (0, 0, 0);
+            info.damage = reader.walk(&mut |_, _, entry| {
+                entries += 1;
+                match entry {
+                    Entry::Set { .. } | Entry::Blob { .. } => sets += 1,
+                    Entry::Remove { .. } => removes += 1,
+                }
+                Ok(())
+            })?;
+            info.entries = entries;
+            info.sets = sets;
+            info.removes = removes;
+            infos.push(info);
+        }
+
+        for pos in index.iter() {
+            if let Some(info) = infos.iter_mut().find(|info| info.file_id == pos.file_id) {
+                info.live += 1;
+            }
+        }
+        for info in &mut infos {
+            info.stale = info.entries - info.live;
+        }
+        Ok((infos, index))
+    }
+}
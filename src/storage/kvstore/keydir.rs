@@ -0,0 +1,587 @@
+use super::entry::{BlobRef, Entry};
+use super::segment::{Replay, SegmentReader, index_path, read_exact_at};
+use super::store::CommandPos;
+use crate::storage::bloom::Bloom;
+use crate::{KvsError, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+const INDEX_MAGIC: [u8; 8] = *b"KVSINDEX";
+const INDEX_VERSION: u32 = 1;
+
+// `[magic][version u32][reserved u32][records u64][values u64]
+// [blob garbage u64][last blob id u64]`
+const INDEX_HEADER_LEN: u64 = 48;
+
+// Records per block; only the first key of each block stays in memory
+const BLOCK_RECORDS: u64 = 16;
+
+// Record tags
+const TAG_REMOVED: u8 = 0;
+const TAG_VALUE: u8 = 1;
+const TAG_BLOB: u8 = 2;
+
+/// How `KvStore` keeps track of where each key's latest entry is
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexMode {
+    /// Every key in a hash map: fastest, but memory grows with the keyspace
+    #[default]
+    Memory,
+    /// Only keys written to the active segment are held in memory. Each
+    /// sealed segment gets a sorted `N.idx` file, of which just a bloom
+    /// filter and one key per block stay in memory.
+    Disk,
+}
+
+/// A segment's own view of its keys: the last entry for each, `None` for
+/// a removal
+#[derive(Debug, Default)]
+struct SegmentKeys {
+    records: HashMap<String, Option<CommandPos>>,
+    // `Set` entries, counting those overwritten within the segment
+    values: u64,
+    blob_garbage: u64,
+    last_blob_id: u64,
+}
+
+impl SegmentKeys {
+    fn scan(reader: &SegmentReader) -> Result<SegmentKeys> {
+        let mut keys = SegmentKeys::default();
+        // Like `SegmentReader::index`, whatever follows damage is ignored;
+        // only a store opened for writing indexes on disk, and it has repaired
+        // damage already
+        reader.walk(&mut |offset, length, entry| {
+            let pos = |blob| CommandPos {
+                file_id: reader.file_id,
+                offset,
+                length,
+                blob,
+            };
+            match entry {
+                Entry::Set { key, .. } => keys.insert(key, Some(pos(None))),
+                Entry::Blob { key, blob } => keys.insert(key, Some(pos(Some(blob)))),
+                Entry::Remove { key } => keys.insert(key, None),
+            };
+            Ok(())
+        })?;
+        Ok(keys)
+    }
+
+    fn insert(&mut self, key: String, record: Option<CommandPos>) -> Option<CommandPos> {
+        if let Some(pos) = &record {
+            self.values += 1;
+            if let Some(blob) = pos.blob {
+                self.last_blob_id = self.last_blob_id.max(blob.file_id);
+            }
+        }
+        let replaced = self.records.insert(key, record).flatten();
+        if let Some(blob) = replaced.as_ref().and_then(|pos| pos.blob) {
+            self.blob_garbage += blob.length;
+        }
+        replaced
+    }
+
+    fn sorted(&self) -> Vec<(String, Option<CommandPos>)> {
+        let sorted: BTreeMap<_, _> = self.records.iter().collect();
+        sorted
+            .into_iter()
+            .map(|(key, record)| (key.clone(), record.clone()))
+            .collect()
+    }
+}
+
+/// Where each live key's latest entry is
+#[derive(Debug)]
+pub(super) enum KeyDir {
+    Memory(DashMap<String, CommandPos>),
+    Disk(DiskKeyDir),
+}
+
+impl KeyDir {
+    pub(super) fn mode(&self) -> IndexMode {
+        match self {
+            KeyDir::Memory(_) => IndexMode::Memory,
+            KeyDir::Disk(_) => IndexMode::Disk,
+        }
+    }
+
+    pub(super) fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            KeyDir::Memory(index) => Ok(index.get(key).map(|pos| pos.clone())),
+            KeyDir::Disk(index) => index.get(key),
+        }
+    }
+
+    /// Points `key` at `pos`, returning where it pointed before
+    pub(super) fn insert(&self, key: String, pos: CommandPos) -> Result<Option<CommandPos>> {
+        match self {
+            KeyDir::Memory(index) => Ok(index.insert(key, pos)),
+            KeyDir::Disk(index) => index.insert(key, Some(pos)),
+        }
+    }
+
+    /// Forgets `key`, returning where it pointed
+    pub(super) fn remove(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            KeyDir::Memory(index) => Ok(index.remove(key).map(|(_, pos)| pos)),
+            KeyDir::Disk(index) => index.insert(key.to_owned(), None),
+        }
+    }
+
+    pub(super) fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Visits every live key; `visit` may change the index as it goes
+    pub(super) fn for_each(
+        &self,
+        visit: &mut dyn FnMut(String, CommandPos) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            KeyDir::Memory(index) => {
+                let live: Vec<(String, CommandPos)> = index
+                    .iter()
+                    .map(|pos| (pos.key().clone(), pos.clone()))
+                    .collect();
+                for (key, pos) in live {
+                    visit(key, pos)?;
+                }
+                Ok(())
+            }
+            KeyDir::Disk(index) => {
+                index.merge(&mut |key, mut records| match records.swap_remove(0) {
+                    Some(pos) => visit(key, pos),
+                    None => Ok(()),
+                })
+            }
+        }
+    }
+
+    /// Called once `file_id` will take no more writes
+    pub(super) fn seal(&self, file_id: u64) -> Result<()> {
+        match self {
+            KeyDir::Memory(_) => Ok(()),
+            KeyDir::Disk(index) => index.seal(file_id),
+        }
+    }
+
+    /// Called once compaction has replaced the segments `file_ids`
+    pub(super) fn forget(&self, file_ids: &[u64]) -> Result<()> {
+        match self {
+            KeyDir::Memory(_) => Ok(()),
+            KeyDir::Disk(index) => {
+                let mut sealed = index.sealed.write().map_err(|_| KvsError::LockPoisoned)?;
+                sealed.retain(|segment| !file_ids.contains(&segment.file_id));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct DiskKeyDir {
+    dir: PathBuf,
+    active: RwLock<SegmentKeys>,
+    // Newest first
+    sealed: RwLock<Vec<Arc<SegmentIndex>>>,
+}
+
+impl DiskKeyDir {
+    /// Indexes `readers`: every segment but `active` is sealed, and gets an
+    /// index file unless it has one already
+    pub(super) fn open(
+        dir: &Path,
+        readers: &DashMap<String, SegmentReader>,
+        active: u64,
+        replay: &mut Replay,
+    ) -> Result<DiskKeyDir> {
+        let mut file_ids: Vec<u64> = readers
+            .iter()
+            .filter_map(|reader| reader.key().parse().ok())
+            .collect();
+        file_ids.sort_unstable();
+
+        let mut sealed = vec![];
+        let mut active_keys = SegmentKeys::default();
+        for file_id in file_ids {
+            let reader = readers
+                .get(&file_id.to_string())
+                .ok_or(KvsError::FileNotFound)?;
+            if file_id == active {
+                // Written to after its index would have been
+                let _ = fs::remove_file(index_path(dir, file_id));
+                active_keys = SegmentKeys::scan(&reader)?;
+                continue;
+            }
+            let index = match SegmentIndex::open(dir, file_id) {
+                Ok(index) => index,
+                // Never written, or cut short by a crash
+                Err(_) => SegmentIndex::write(dir, file_id, &SegmentKeys::scan(&reader)?)?,
+            };
+            sealed.insert(0, Arc::new(index));
+        }
+
+        let index = DiskKeyDir {
+            dir: dir.to_path_buf(),
+            active: RwLock::new(active_keys),
+            sealed: RwLock::new(sealed),
+        };
+
+        // What `SegmentReader::index` would have counted, from one pass over
+        // the sorted runs
+        let mut values = 0;
+        {
+            let active = index.active.read().map_err(|_| KvsError::LockPoisoned)?;
+            values += active.values;
+            replay.blob_garbage += active.blob_garbage;
+            replay.last_blob_id = replay.last_blob_id.max(active.last_blob_id);
+        }
+        for segment in index.sealed()? {
+            values += segment.values;
+            replay.blob_garbage += segment.blob_garbage;
+            replay.last_blob_id = replay.last_blob_id.max(segment.last_blob_id);
+        }
+        let mut live = 0;
+        index.merge(&mut |_, records| {
+            live += u64::from(records[0].is_some());
+            for pos in records[1..].iter().flatten() {
+                replay.blob_garbage += pos.blob.map_or(0, |blob| blob.length);
+            }
+            Ok(())
+        })?;
+        replay.stale_entries += values.saturating_sub(live);
+
+        Ok(index)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        {
+            let active = self.active.read().map_err(|_| KvsError::LockPoisoned)?;
+            if let Some(record) = active.records.get(key) {
+                return Ok(record.clone());
+            }
+        }
+        for segment in self.sealed()? {
+            if let Some(record) = segment.get(key)? {
+                return Ok(record);
+            }
+        }
+        Ok(None)
+    }
+
+    // A removal is kept as `None`, hiding the key in older segments
+    fn insert(&self, key: String, record: Option<CommandPos>) -> Result<Option<CommandPos>> {
+        let before = self.get(&key)?;
+        if before.is_none() && record.is_none() {
+            return Ok(None);
+        }
+        let mut active = self.active.write().map_err(|_| KvsError::LockPoisoned)?;
+        active.insert(key, record);
+        Ok(before)
+    }
+
+    fn seal(&self, file_id: u64) -> Result<()> {
+        let index = {
+            let active = self.active.read().map_err(|_| KvsError::LockPoisoned)?;
+            SegmentIndex::write(&self.dir, file_id, &active)?
+        };
+        // Sealed first, so a reader never finds the keys in neither place
+        self.sealed
+            .write()
+            .map_err(|_| KvsError::LockPoisoned)?
+            .insert(0, Arc::new(index));
+        *self.active.write().map_err(|_| KvsError::LockPoisoned)? = SegmentKeys::default();
+        Ok(())
+    }
+
+    fn sealed(&self) -> Result<Vec<Arc<SegmentIndex>>> {
+        Ok(self
+            .sealed
+            .read()
+            .map_err(|_| KvsError::LockPoisoned)?
+            .clone())
+    }
+
+    // Calls `visit` once per key, in key order, with its records newest
+    // first. Works on a snapshot, holding one record per segment in memory.
+    fn merge(
+        &self,
+        visit: &mut dyn FnMut(String, Vec<Option<CommandPos>>) -> Result<()>,
+    ) -> Result<()> {
+        let active = self
+            .active
+            .read()
+            .map_err(|_| KvsError::LockPoisoned)?
+            .sorted();
+        let mut runs: Vec<Box<dyn Iterator<Item = Result<Record>>>> =
+            vec![Box::new(active.into_iter().map(Ok))];
+        for segment in self.sealed()? {
+            runs.push(Box::new(segment.records()?));
+        }
+
+        let mut heads: Vec<Option<Record>> = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::new();
+        for (run, records) in runs.iter_mut().enumerate() {
+            let head = records.next().transpose()?;
+            if let Some((key, _)) = &head {
+                heap.push(Reverse((key.clone(), run)));
+            }
+            heads.push(head);
+        }
+
+        // Ties pop in run order, which is newest first
+        while let Some(Reverse((key, run))) = heap.pop() {
+            let mut records = vec![];
+            let mut next = Some(run);
+            while let Some(run) = next {
+                let (_, record) = heads[run].take().expect("every run in the heap has a head");
+                records.push(record);
+                heads[run] = runs[run].next().transpose()?;
+                if let Some((key, _)) = &heads[run] {
+                    heap.push(Reverse((key.clone(), run)));
+                }
+                next = match heap.peek() {
+                    Some(Reverse((next_key, next_run))) if *next_key == key => {
+                        let next_run = *next_run;
+                        heap.pop();
+                        Some(next_run)
+                    }
+                    _ => None,
+                };
+            }
+            visit(key, records)?;
+        }
+        Ok(())
+    }
+}
+
+type Record = (String, Option<CommandPos>);
+
+/// The sorted index file of one sealed segment, with its bloom filter and
+/// the first key of each block in memory
+#[derive(Debug)]
+struct SegmentIndex {
+    file_id: u64,
+    path: PathBuf,
+    file: File,
+    values: u64,
+    blob_garbage: u64,
+    last_blob_id: u64,
+    bloom: Bloom,
+    // First key, offset and length of each block of records
+    blocks: Vec<(String, u64, u64)>,
+}
+
+impl SegmentIndex {
+    // Written beside the segment and renamed into place, so an index file
+    // is either whole or missing
+    fn write(dir: &Path, file_id: u64, keys: &SegmentKeys) -> Result<SegmentIndex> {
+        let path = index_path(dir, file_id);
+        let temp = path.with_extension("idx.tmp");
+        let records = keys.sorted();
+
+        let mut out = BufWriter::new(File::create(&temp)?);
+        out.write_all(&INDEX_MAGIC)?;
+        out.write_all(&INDEX_VERSION.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        for field in [
+            records.len() as u64,
+            keys.values,
+            keys.blob_garbage,
+            keys.last_blob_id,
+        ] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        for (key, record) in &records {
+            write_record(&mut out, key, record.as_ref())?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp, &path)?;
+
+        SegmentIndex::open(dir, file_id)
+    }
+
+    fn open(dir: &Path, file_id: u64) -> Result<SegmentIndex> {
+        let path = index_path(dir, file_id);
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let mut input = BufReader::new(file.try_clone()?);
+
+        let mut header = [0; INDEX_HEADER_LEN as usize];
+        input.read_exact(&mut header)?;
+        let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if header[..8] != INDEX_MAGIC || version != INDEX_VERSION {
+            return Err(KvsError::CorruptedLog);
+        }
+        let (count, values, blob_garbage, last_blob_id) =
+            (field(16), field(24), field(32), field(40));
+
+        let mut bloom = Bloom::new(count);
+        let mut blocks: Vec<(String, u64, u64)> = vec![];
+        let mut offset = INDEX_HEADER_LEN;
+        for i in 0..count {
+            let (key, _, length) = read_record(&mut input)?;
+            bloom.insert(&key);
+            if i % BLOCK_RECORDS == 0 {
+                blocks.push((key, offset, 0));
+            }
+            offset += length;
+            if let Some(block) = blocks.last_mut() {
+                block.2 = offset - block.1;
+            }
+        }
+        if offset != size {
+            return Err(KvsError::CorruptedLog);
+        }
+
+        Ok(SegmentIndex {
+            file_id,
+            path,
+            file,
+            values,
+            blob_garbage,
+            last_blob_id,
+            bloom,
+            blocks,
+        })
+    }
+
+    // `Some(None)` when the segment removed the key
+    fn get(&self, key: &str) -> Result<Option<Option<CommandPos>>> {
+        if !self.bloom.contains(key) {
+            return Ok(None);
+        }
+        let block = self
+            .blocks
+            .partition_point(|(first, _, _)| first.as_str() <= key);
+        let Some((_, offset, length)) = block.checked_sub(1).map(|block| &self.blocks[block])
+        else {
+            return Ok(None);
+        };
+
+        let mut buffer = vec![0; *length as usize];
+        read_exact_at(&self.file, &mut buffer, *offset)?;
+        let mut input = &buffer[..];
+        while !input.is_empty() {
+            let (found, record, _) = read_record(&mut input)?;
+            match found.as_str().cmp(key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return Ok(Some(record)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    // Reads its own handle, so walks do not disturb lookups
+    fn records(&self) -> Result<impl Iterator<Item = Result<Record>> + use<>> {
+        let mut input = BufReader::new(File::open(&self.path)?);
+        input.read_exact(&mut [0; INDEX_HEADER_LEN as usize])?;
+        let mut remaining = self
+            .blocks
+            .last()
+            .map_or(0, |(_, offset, length)| offset + length - INDEX_HEADER_LEN);
+        Ok(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            Some(match read_record(&mut input) {
+                Ok((key, record, length)) => {
+                    remaining -= length;
+                    Ok((key, record))
+                }
+                Err(e) => {
+                    remaining = 0;
+                    Err(e.into())
+                }
+            })
+        }))
+    }
+}
+
+// `[key size u64][key][tag u8]`, then for a value `[segment u64][offset u64]
+// [length u64]` and for a blob pointer also `[blob file u64][blob offset u64]
+// [blob length u64]`
+fn write_record(out: &mut impl Write, key: &str, record: Option<&CommandPos>) -> io::Result<()> {
+    out.write_all(&(key.len() as u64).to_le_bytes())?;
+    out.write_all(key.as_bytes())?;
+    let Some(pos) = record else {
+        return out.write_all(&[TAG_REMOVED]);
+    };
+    match pos.blob {
+        Some(_) => out.write_all(&[TAG_BLOB])?,
+        None => out.write_all(&[TAG_VALUE])?,
+    }
+    out.write_all(&pos.file_id.to_le_bytes())?;
+    out.write_all(&pos.offset.to_le_bytes())?;
+    out.write_all(&pos.length.to_le_bytes())?;
+    if let Some(blob) = pos.blob {
+        out.write_all(&blob.file_id.to_le_bytes())?;
+        out.write_all(&blob.offset.to_le_bytes())?;
+        out.write_all(&blob.length.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// The record and its length in bytes
+fn read_record(input: &mut impl Read) -> io::Result<(String, Option<CommandPos>, u64)> {
+    let read_u64 = |input: &mut dyn Read| -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        input.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+    let key_size = read_u64(input)?;
+    // Not trusted with an allocation up front: the file may be damaged
+    let mut key = vec![];
+    input.take(key_size).read_to_end(&mut key)?;
+    if key.len() as u64 != key_size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let key = String::from_utf8(key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 in key"))?;
+    let mut tag = [0; 1];
+    input.read_exact(&mut tag)?;
+
+    let mut length = 8 + key_size + 1;
+    let record = match tag[0] {
+        TAG_REMOVED => None,
+        TAG_VALUE | TAG_BLOB => {
+            let file_id = read_u64(input)?;
+            let offset = read_u64(input)?;
+            let entry_length = read_u64(input)?;
+            length += 24;
+            let blob = match tag[0] {
+                TAG_BLOB => {
+                    length += 24;
+                    Some(BlobRef {
+                        file_id: read_u64(input)?,
+                        offset: read_u64(input)?,
+                        length: read_u64(input)?,
+                    })
+                }
+                _ => None,
+            };
+            Some(CommandPos {
+                file_id,
+                offset,
+                length: entry_length,
+                blob,
+            })
+        }
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown index record tag {}", tag),
+            ));
+        }
+    };
+    Ok((key, record, length))
+}
@@ -1,5 +1,19 @@
+mod crypto;
 mod entry;
+mod fault;
+mod inspect;
+mod keydir;
+mod recovery;
 mod segment;
 mod store;
 
-pub use store::KvStore;
+pub use crypto::{EncryptionKey, KEY_LEN, Keyring};
+pub(crate) use entry::entry_sizes;
+pub use entry::{BlobRef, Compression, Entry};
+pub use fault::{Fault, FaultInjector};
+pub use inspect::{LogInspector, LogStats, SegmentInfo};
+pub use keydir::IndexMode;
+pub use recovery::{CORRUPT_DIR, Repair};
+pub(crate) use segment::read_exact_at;
+pub use segment::{Damage, SEGMENT_VERSION};
+pub use store::{KvStore, KvStoreOptions, LogBatch, LogPosition};
@@ -0,0 +1,119 @@
+use super::crypto::Keyring;
+use super::segment::{SegmentReader, index_path, segment_ids};
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Where damaged sealed segments are moved, whole, before being rebuilt
+pub const CORRUPT_DIR: &str = "corrupt";
+
+/// A damaged segment cut back to its last whole entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// Segment file id, `N` in `N.log`
+    pub file_id: u64,
+    /// Where the damage started; the segment now ends here, holding the
+    /// bytes in front of it unchanged
+    pub offset: u64,
+    /// Bytes dropped from the end of the segment
+    pub lost_bytes: u64,
+    /// What was wrong at `offset`
+    pub reason: String,
+    /// The damaged file itself, moved aside for sealed segments only: a torn
+    /// tail on the active segment is the expected result of a crash
+    pub quarantined: Option<PathBuf>,
+}
+
+// Every entry a segment holds past its first damage is unreachable, and the
+// writer would append after the garbage, so each damaged segment is cut back
+// to the whole entries in front of it. A sealed segment is never changed in
+// place: it is moved into `corrupt/` and a new file holding its valid prefix
+// takes its name.
+pub(super) fn recover(dir_path: &Path, keys: Option<&Keyring>) -> Result<Vec<Repair>> {
+    let file_ids = segment_ids(dir_path)?;
+    let active = file_ids.last().copied();
+    let mut repairs = vec![];
+
+    for file_id in file_ids {
+        let reader = SegmentReader::open(dir_path, file_id, None, keys)?;
+        let Some(damage) = reader.walk(&mut |_, _, _| Ok(()))? else {
+            continue;
+        };
+        let size = reader.size;
+        drop(reader);
+
+        let path = dir_path.join(format!("{file_id}.log"));
+        let quarantined = match Some(file_id) == active {
+            true => {
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(damage.offset)?;
+                file.sync_all()?;
+                None
+            }
+            false => Some(quarantine(dir_path, &path, file_id, damage.offset)?),
+        };
+
+        // Would point at the dropped entries; rebuilt on the next open
+        match fs::remove_file(index_path(dir_path, file_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let repair = Repair {
+            file_id,
+            offset: damage.offset,
+            lost_bytes: size - damage.offset,
+            reason: damage.reason,
+            quarantined,
+        };
+        match &repair.quarantined {
+            Some(copy) => warn!(
+                "Segment {}.log damaged at offset {} ({}): dropped bytes {}..{}, original moved to {}",
+                file_id,
+                repair.offset,
+                repair.reason,
+                repair.offset,
+                size,
+                copy.display()
+            ),
+            None => warn!(
+                "Active segment {}.log ends in a torn entry at offset {} ({}): dropped bytes {}..{}",
+                file_id, repair.offset, repair.reason, repair.offset, size
+            ),
+        }
+        repairs.push(repair);
+    }
+
+    Ok(repairs)
+}
+
+// Moves the segment into `corrupt/`, never over an earlier one, leaving a
+// rebuilt copy of its first `valid` bytes in its place. The rebuilt file is
+// written beside the segment and renamed over it, and the original is linked
+// into `corrupt/` first, so a crash leaves either name whole.
+fn quarantine(dir_path: &Path, path: &Path, file_id: u64, valid: u64) -> Result<PathBuf> {
+    let corrupt = dir_path.join(CORRUPT_DIR);
+    fs::create_dir_all(&corrupt)?;
+
+    let mut target = corrupt.join(format!("{file_id}.log"));
+    let mut n = 1;
+    while target.exists() {
+        target = corrupt.join(format!("{file_id}.log.{n}"));
+        n += 1;
+    }
+
+    let temp = path.with_extension("log.tmp");
+    let mut rebuilt = File::create(&temp)?;
+    io::copy(&mut File::open(path)?.take(valid), &mut rebuilt)?;
+    rebuilt.sync_all()?;
+
+    if fs::hard_link(path, &target).is_err() {
+        // e.g. the filesystem has no hard links
+        fs::copy(path, &target)?;
+    }
+    fs::rename(&temp, path)?;
+    File::open(dir_path)?.sync_all()?;
+    Ok(target)
+}
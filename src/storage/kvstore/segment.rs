@@ -1,25 +1,248 @@
-use super::entry::Entry;
+use super::crypto::{EncryptionKey, Keyring, SALT_LEN, SegmentCipher, random_salt};
+use super::entry::{Compression, Entry, entry_sizes};
+use super::fault::FaultInjector;
 use super::store::CommandPos;
 use crate::{KvsError, Result};
 use dashmap::DashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
-use tracing::{error, info};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Segment layout this build writes; version 0 segments have no header,
+/// from version 2 on a value may be compressed, from version 3 on entries
+/// may be encrypted, from version 4 on an entry may point into a blob file,
+/// and from version 5 on every entry ends in a checksum
+pub const SEGMENT_VERSION: u32 = 5;
+
+// The first version whose entries end in `[crc u32]`, taken over the entry
+// bytes in front of it as written, i.e. compressed or encrypted
+const CHECKSUM_VERSION: u32 = 5;
+pub(super) const CHECKSUM_LEN: u64 = 4;
+
+const SEGMENT_MAGIC: [u8; 8] = *b"KVSSEGMT";
+
+// `[magic][version u32][key id u32][created_at u64]`, then for an
+// encrypted segment `[salt]`
+const HEADER_LEN: u64 = 24;
+
+// Segments hold the log; blob files hold the values too large for it, laid
+// out the same way
+const LOG_EXT: &str = "log";
+const BLOB_EXT: &str = "blob";
+
+/// Ids of the `N.log` segment files in `dir_path`, oldest first
+pub fn segment_ids(dir_path: &Path) -> Result<Vec<u64>> {
+    file_ids(dir_path, LOG_EXT)
+}
+
+/// Ids of the `N.blob` files in `dir_path`, oldest first
+pub fn blob_ids(dir_path: &Path) -> Result<Vec<u64>> {
+    file_ids(dir_path, BLOB_EXT)
+}
+
+/// Path of blob file `file_id`
+pub fn blob_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("{file_id}.{BLOB_EXT}"))
+}
+
+/// Path of the sorted index of segment `file_id`, kept when the store
+/// indexes on disk
+pub fn index_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("{file_id}.idx"))
+}
+
+fn file_ids(dir_path: &Path, ext: &str) -> Result<Vec<u64>> {
+    let mut file_ids = fs::read_dir(dir_path)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+
+            if path.extension()?.to_str()? != ext {
+                return None;
+            }
+
+            let stem_str = path.file_stem()?.to_str()?;
+            stem_str.parse::<u64>().ok()
+        })
+        .collect::<Vec<u64>>();
+
+    file_ids.sort_unstable();
+    Ok(file_ids)
+}
+
+/// What a segment says about itself before its first entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    /// Layout of the entries that follow
+    pub version: u32,
+    /// Seconds since the Unix epoch; 0 for version 0, which did not say
+    pub created_at: u64,
+    /// Id of the key entries are encrypted with; 0 when they are not
+    pub key_id: u32,
+    salt: [u8; SALT_LEN],
+}
+
+impl SegmentHeader {
+    fn new(key: Option<&EncryptionKey>) -> SegmentHeader {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        SegmentHeader {
+            version: SEGMENT_VERSION,
+            created_at,
+            key_id: key.map_or(0, EncryptionKey::id),
+            salt: match key {
+                Some(_) => random_salt(),
+                None => [0; SALT_LEN],
+            },
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.data_start() as usize);
+        buffer.extend_from_slice(&SEGMENT_MAGIC);
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&self.key_id.to_le_bytes());
+        buffer.extend_from_slice(&self.created_at.to_le_bytes());
+        if self.key_id != 0 {
+            buffer.extend_from_slice(&self.salt);
+        }
+        buffer
+    }
+
+    /// Whether entries end in a checksum; older segments can only be
+    /// checked for framing, so a flipped bit in a key or value goes unseen
+    pub fn checksummed(&self) -> bool {
+        self.version >= CHECKSUM_VERSION
+    }
+
+    // Bytes every entry carries past its key and value
+    pub(super) fn trailer_len(&self) -> u64 {
+        match self.checksummed() {
+            true => CHECKSUM_LEN,
+            false => 0,
+        }
+    }
+
+    /// Offset of the first entry
+    pub fn data_start(&self) -> u64 {
+        match (self.version, self.key_id) {
+            (0, _) => 0,
+            (_, 0) => HEADER_LEN,
+            _ => HEADER_LEN + SALT_LEN as u64,
+        }
+    }
+
+    // A segment that does not start with the magic predates headers; one
+    // cut off inside its header is damaged at offset 0
+    fn read(file: &File, size: u64) -> Result<(SegmentHeader, Option<Damage>)> {
+        let mut prefix = vec![0; size.min(HEADER_LEN + SALT_LEN as u64) as usize];
+        read_exact_at(file, &mut prefix, 0)?;
+
+        let magic_len = prefix.len().min(SEGMENT_MAGIC.len());
+        if prefix.is_empty() || prefix[..magic_len] != SEGMENT_MAGIC[..magic_len] {
+            let legacy = SegmentHeader {
+                version: 0,
+                created_at: 0,
+                key_id: 0,
+                salt: [0; SALT_LEN],
+            };
+            return Ok((legacy, None));
+        }
+        let torn = || {
+            let damage = Damage {
+                offset: 0,
+                reason: format!("{} bytes are too short for a segment header", size),
+            };
+            Ok((SegmentHeader::new(None), Some(damage)))
+        };
+        if size < HEADER_LEN {
+            return torn();
+        }
+
+        let version = u32::from_le_bytes(prefix[8..12].try_into().unwrap());
+        if version == 0 || version > SEGMENT_VERSION {
+            return Err(KvsError::FormatVersion {
+                found: version,
+                supported: SEGMENT_VERSION,
+            });
+        }
+        let mut header = SegmentHeader {
+            version,
+            created_at: u64::from_le_bytes(prefix[16..24].try_into().unwrap()),
+            key_id: u32::from_le_bytes(prefix[12..16].try_into().unwrap()),
+            salt: [0; SALT_LEN],
+        };
+        if header.key_id != 0 {
+            if size < header.data_start() {
+                return torn();
+            }
+            header.salt.copy_from_slice(&prefix[HEADER_LEN as usize..]);
+        }
+        Ok((header, None))
+    }
+}
+
+/// Where a segment's framing stops making sense
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    /// Byte offset where an entry should start
+    pub offset: u64,
+    /// What is wrong there
+    pub reason: String,
+}
+
+/// What replaying segments into an index found
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// Entries compaction would drop
+    pub stale_entries: u64,
+    /// Bytes of the blob records stale entries pointed at
+    pub blob_garbage: u64,
+    /// Highest blob file id any entry points at, stale ones included
+    pub last_blob_id: u64,
+}
 
 #[derive(Debug)]
 pub struct SegmentReader {
     pub file_id: u64,
-    pub reader: BufReader<File>,
+    pub file: File,
     pub size: u64,
+    pub header: SegmentHeader,
+    header_damage: Option<Damage>,
+    faults: Option<FaultInjector>,
+    pub(super) cipher: Option<SegmentCipher>,
 }
 
 impl SegmentReader {
-    pub fn open(dir_path: &Path, file_id: u64) -> Result<SegmentReader> {
+    pub fn open(
+        dir_path: &Path,
+        file_id: u64,
+        faults: Option<FaultInjector>,
+        keys: Option<&Keyring>,
+    ) -> Result<SegmentReader> {
+        SegmentReader::open_file(dir_path, file_id, LOG_EXT, faults, keys)
+    }
+    /// Opens blob file `file_id`; blob files do not go through the fault
+    /// injector
+    pub fn open_blob(
+        dir_path: &Path,
+        file_id: u64,
+        keys: Option<&Keyring>,
+    ) -> Result<SegmentReader> {
+        SegmentReader::open_file(dir_path, file_id, BLOB_EXT, None, keys)
+    }
+    fn open_file(
+        dir_path: &Path,
+        file_id: u64,
+        ext: &str,
+        faults: Option<FaultInjector>,
+        keys: Option<&Keyring>,
+    ) -> Result<SegmentReader> {
         // Create empty file
-        let file_name = format!("{file_id}.log");
+        let file_name = format!("{file_id}.{ext}");
         let path = dir_path.join(file_name);
 
         // Reader handle
@@ -28,97 +251,164 @@ impl SegmentReader {
         // File size
         let size = reader_file.metadata()?.len();
 
+        let (header, header_damage) = SegmentHeader::read(&reader_file, size)?;
+
+        // Without the key nothing in the segment can be checked, so it is
+        // not opened at all rather than taken for damage
+        let cipher = match header.key_id {
+            0 => None,
+            key_id => {
+                let key = keys.and_then(|keys| keys.get(key_id)).ok_or_else(|| {
+                    KvsError::Encryption(format!(
+                        "{}.{} is encrypted with key {:08x}, which was not given",
+                        file_id, ext, key_id
+                    ))
+                })?;
+                Some(SegmentCipher::new(key.clone(), header.salt))
+            }
+        };
+
         // Create Segment
         Ok(SegmentReader {
             file_id,
-            reader: BufReader::new(reader_file),
+            file: reader_file,
             size,
+            header,
+            header_damage,
+            faults,
+            cipher,
         })
     }
-    pub fn read(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
+    /// The entry `length` bytes long at `offset`, checked against its
+    /// checksum and decrypted if need be
+    pub fn read_entry(&self, offset: u64, length: u64) -> Result<Entry> {
+        Ok(self.decode(&self.read(offset, length)?)?)
+    }
+    fn decode(&self, bytes: &[u8]) -> io::Result<Entry> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let body = match self.header.checksummed() {
+            true => {
+                let (body, trailer) = bytes
+                    .split_at_checked(bytes.len().saturating_sub(CHECKSUM_LEN as usize))
+                    .filter(|(_, trailer)| trailer.len() == CHECKSUM_LEN as usize)
+                    .ok_or_else(|| {
+                        invalid(format!("{} bytes are too short for an entry", bytes.len()))
+                    })?;
+                let stored = u32::from_le_bytes(trailer.try_into().unwrap());
+                let computed = crc32fast::hash(body);
+                if stored != computed {
+                    return Err(invalid(format!(
+                        "entry checksum {:08x} does not match its contents ({:08x})",
+                        stored, computed
+                    )));
+                }
+                body
+            }
+            false => bytes,
+        };
+        Entry::decode(body, self.cipher.as_ref())
+    }
+    pub fn read(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
         // Get key-value at a given offset (provided by index)
 
         let mut buffer = vec![0; length as usize];
 
-        // Find file offset
-        self.reader.seek(SeekFrom::Start(offset))?;
-
-        // Read bytes
-        self.reader.read_exact(&mut buffer)?;
+        // Positional read leaves the shared file cursor alone, so readers
+        // on other threads do not have to take turns
+        match self.faults.as_ref().and_then(FaultInjector::read_limit) {
+            Some(limit) => {
+                for (i, chunk) in buffer.chunks_mut(limit).enumerate() {
+                    read_exact_at(&self.file, chunk, offset + (i * limit) as u64)?;
+                }
+            }
+            None => read_exact_at(&self.file, &mut buffer, offset)?,
+        }
 
         // Deserialise value
         // Return value
         Ok(buffer)
     }
-    pub fn index(&mut self, index: &mut DashMap<String, CommandPos>) -> Result<u64> {
-        let mut stale_entries = 0;
-        let mut read_offset = 0;
-
-        let file_ref = self.reader.get_ref();
-        let metadata = file_ref.metadata()?;
-        let current_size = metadata.len();
-
-        // loop through file
-        while read_offset + 8 + 8 <= current_size {
-            // Calculate size of entry
-            let size_buffer = match self.read(read_offset, 16) {
-                Ok(e) => e,
-                Err(_) => break,
+    // Follows the `[ksz][vsz][key][value]` framing, with its `[crc]` from
+    // version 5 on, from the start of the file, stopping at the first damage
+    pub fn walk(
+        &self,
+        visit: &mut dyn FnMut(u64, u64, Entry) -> Result<()>,
+    ) -> Result<Option<Damage>> {
+        if let Some(damage) = &self.header_damage {
+            return Ok(Some(damage.clone()));
+        }
+        let size = self.file.metadata()?.len();
+        let mut offset = self.header.data_start();
+
+        while offset < size {
+            let damage = |reason: String| Ok(Some(Damage { offset, reason }));
+
+            let remaining = size - offset;
+            if remaining < 16 {
+                return damage(format!(
+                    "{} trailing bytes are too short for an entry header",
+                    remaining
+                ));
+            }
+            let (key_size, value_size) = entry_sizes(&self.read(offset, 16)?);
+
+            let trailer = self.header.trailer_len();
+            let length = match key_size
+                .checked_add(value_size)
+                .and_then(|body| body.checked_add(trailer))
+            {
+                Some(body) if body <= remaining - 16 => 16 + body,
+                _ => {
+                    return damage(format!(
+                        "entry claims a {} byte key and {} byte value but only {} bytes remain",
+                        key_size,
+                        value_size,
+                        remaining - 16
+                    ));
+                }
             };
 
-            let key_size = u64::from_le_bytes(size_buffer[0..8].try_into().unwrap());
-            let value_size = u64::from_le_bytes(size_buffer[8..16].try_into().unwrap());
-
-            let entry_len = 16 + key_size + value_size;
-
-            // Read buffer (slightly larger than Entry)
-            let buffer = match self.read(read_offset, entry_len) {
-                Ok(buf) => buf,
-                Err(_) => break, // incomplete or corrupted entry
+            let entry = match self.decode(&self.read(offset, length)?) {
+                Ok(entry) => entry,
+                Err(e) => return damage(e.to_string()),
             };
+            visit(offset, length, entry)?;
+            offset += length;
+        }
 
-            // Deserialize Entry
-            let slice = buffer.as_slice();
-            let entry = match Entry::deserialize(slice) {
-                Ok(e) => e,
-                Err(_) => break,
+        Ok(None)
+    }
+    /// Replays the segment into `index`, adding what it finds to `replay`.
+    ///
+    /// Entries from the first damage on are left out, and the damage is
+    /// returned. A store opened for writing repairs damage beforehand, but a
+    /// read-only one cannot.
+    pub fn index(
+        &self,
+        index: &mut DashMap<String, CommandPos>,
+        replay: &mut Replay,
+    ) -> Result<Option<Damage>> {
+        self.walk(&mut |offset, length, entry| {
+            let pos = |blob| CommandPos {
+                file_id: self.file_id,
+                offset,
+                length,
+                blob,
             };
-
-            // Size of Entry
-            let mut consumed = 8u64 + 8u64; // ksz + vsz
-
-            match &entry {
-                Entry::Set { key, value } => {
-                    let entry_length = key.len() as u64 + value.len() as u64;
-                    consumed += entry_length;
-
-                    if index
-                        .insert(
-                            key.clone(),
-                            CommandPos {
-                                file_id: self.file_id,
-                                offset: read_offset,
-                                length: consumed,
-                            },
-                        )
-                        .is_some()
-                    {
-                        stale_entries += 1;
-                    }
-                }
-                Entry::Remove { key } => {
-                    consumed += key.len() as u64;
-                    if index.remove(key).is_some() {
-                        stale_entries += 1;
-                    }
+            let replaced = match entry {
+                Entry::Set { key, .. } => index.insert(key, pos(None)),
+                Entry::Blob { key, blob } => {
+                    replay.last_blob_id = replay.last_blob_id.max(blob.file_id);
+                    index.insert(key, pos(Some(blob)))
                 }
+                Entry::Remove { key } => index.remove(&key).map(|(_, pos)| pos),
+            };
+            if let Some(replaced) = replaced {
+                replay.stale_entries += 1;
+                replay.blob_garbage += replaced.blob.map_or(0, |blob| blob.length);
             }
-
-            // Update read offset
-            read_offset += consumed;
-        }
-
-        Ok(stale_entries)
+            Ok(())
+        })
     }
 }
 
@@ -127,13 +417,43 @@ pub struct SegmentWriter {
     pub file_id: u64,
     pub offset: AtomicU64,
     pub size: AtomicU64,
-    pub writer: BufWriter<File>,
+    /// Codec for values of at least `compression_threshold` bytes
+    pub compression: Compression,
+    pub compression_threshold: u64,
+    file: File,
+    faults: Option<FaultInjector>,
+    cipher: Option<SegmentCipher>,
+    checksummed: bool,
+    // A failed entry is still in the file past `offset`
+    torn: bool,
 }
 
 impl SegmentWriter {
-    pub fn new(dir_path: &Path, file_id: u64) -> Result<SegmentWriter> {
+    pub fn new(
+        dir_path: &Path,
+        file_id: u64,
+        faults: Option<FaultInjector>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SegmentWriter> {
+        SegmentWriter::create(dir_path, file_id, LOG_EXT, faults, key)
+    }
+    /// Starts blob file `file_id`
+    pub fn new_blob(
+        dir_path: &Path,
+        file_id: u64,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SegmentWriter> {
+        SegmentWriter::create(dir_path, file_id, BLOB_EXT, None, key)
+    }
+    fn create(
+        dir_path: &Path,
+        file_id: u64,
+        ext: &str,
+        faults: Option<FaultInjector>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SegmentWriter> {
         // Create empty file
-        let file_name = format!("{file_id}.log");
+        let file_name = format!("{file_id}.{ext}");
         let path = dir_path.join(file_name);
 
         // Write handle
@@ -141,20 +461,44 @@ impl SegmentWriter {
             .write(true)
             .create_new(true) // Fail if file exists
             .open(&path)?;
+        if let Some(faults) = &faults {
+            faults.track(file_id, 0);
+        }
 
-        // File size
-        let metadata = writer_file.metadata()?;
-        let size = metadata.len();
-
-        // Create Segment
-        Ok(SegmentWriter {
+        let mut writer = SegmentWriter {
             file_id,
-            offset: AtomicU64::new(size),
-            size: AtomicU64::new(size),
-            writer: BufWriter::new(writer_file),
-        })
+            offset: AtomicU64::new(0),
+            size: AtomicU64::new(0),
+            compression: Compression::None,
+            compression_threshold: 0,
+            file: writer_file,
+            faults,
+            cipher: None,
+            checksummed: true,
+            torn: false,
+        };
+        // A segment without its header would be taken for version 0, so a
+        // failed one is not left behind
+        let header = SegmentHeader::new(key);
+        if let Some(key) = key {
+            writer.cipher = Some(SegmentCipher::new(key.clone(), header.salt));
+        }
+        if let Err(e) = writer.start(header) {
+            drop(writer);
+            fs::remove_file(&path)?;
+            return Err(e);
+        }
+        Ok(writer)
     }
-    pub fn open(dir_path: &Path, file_id: u64) -> Result<SegmentWriter> {
+    /// Appends to an existing segment in the layout of the `header` its
+    /// reader found, encrypting with the reader's `cipher`
+    pub fn open(
+        dir_path: &Path,
+        file_id: u64,
+        faults: Option<FaultInjector>,
+        header: SegmentHeader,
+        cipher: Option<SegmentCipher>,
+    ) -> Result<SegmentWriter> {
         // Create empty file
         let file_name = format!("{file_id}.log");
         let path = dir_path.join(file_name);
@@ -164,32 +508,64 @@ impl SegmentWriter {
 
         // Seek the writer to the end of file
         let size = writer_file.seek(SeekFrom::End(0))?;
+        if let Some(faults) = &faults {
+            faults.track(file_id, size);
+        }
 
-        // Create Segment
-        Ok(SegmentWriter {
+        // Create Segment. Entries are appended to an older segment in its
+        // own layout, without checksums before version 5.
+        let mut writer = SegmentWriter {
             file_id,
             offset: AtomicU64::new(size),
             size: AtomicU64::new(size),
-            writer: BufWriter::new(writer_file),
-        })
+            compression: Compression::None,
+            compression_threshold: 0,
+            file: writer_file,
+            faults,
+            cipher,
+            checksummed: header.checksummed() || size == 0,
+            torn: false,
+        };
+        // Created, but the header never made it
+        if size == 0 {
+            writer.start(SegmentHeader::new(None))?;
+        }
+        Ok(writer)
     }
     pub fn append(&mut self, entry: Entry) -> Result<CommandPos> {
         // Add key-value and return offset for index
 
-        if let Entry::Set { ref value, .. } = entry {
-            if value.is_empty() {
-                return Err(KvsError::EmptyValue);
-            }
+        if let Entry::Set { ref value, .. } = entry
+            && value.is_empty()
+        {
+            return Err(KvsError::EmptyValue);
         }
         // Current Segment Offset
         let cur_offset = self.offset.load(Ordering::Acquire);
+        if self.torn {
+            self.rewind(cur_offset)?;
+        }
 
-        let buffer = entry.serialize();
+        let blob = match &entry {
+            Entry::Blob { blob, .. } => Some(*blob),
+            _ => None,
+        };
+        let mut buffer = entry.serialize_with(self.compression, self.compression_threshold);
+        if let Some(cipher) = &self.cipher {
+            buffer = Entry::seal(&buffer, cipher);
+        }
+        if self.checksummed {
+            let crc = crc32fast::hash(&buffer);
+            buffer.extend_from_slice(&crc.to_le_bytes());
+        }
 
-        // Write to file
-        self.writer.write_all(&buffer)?;
-        self.writer.flush()?;
-        self.writer.get_ref().sync_data()?;
+        // Write to file; a failed entry is never acknowledged, so whatever
+        // part of it reached the file is taken back
+        if let Err(e) = self.write_durably(&buffer) {
+            self.torn = true;
+            self.rewind(cur_offset)?;
+            return Err(e.into());
+        }
 
         // Update segment offset
         self.offset
@@ -204,211 +580,74 @@ impl SegmentWriter {
             file_id: self.file_id,
             offset: cur_offset,
             length: buffer.len() as u64,
+            blob,
         })
     }
+    pub fn sync(&mut self) -> Result<()> {
+        match &self.faults {
+            Some(faults) => faults.sync(&self.file, self.file_id)?,
+            None => self.file.sync_all()?,
+        }
+        Ok(())
+    }
     pub fn size(&self) -> Result<u64> {
         // Measure if compaction is needed
-        let file_ref = self.writer.get_ref();
-        let metadata = file_ref.metadata()?;
+        let metadata = self.file.metadata()?;
         let size = metadata.len();
         Ok(size)
     }
-}
-
-// -------------------------------------------------------- //
-
-#[derive(Debug, Clone)]
-pub enum SegmentStatus {
-    Active, // Currently being written to
-    Sealed, // Closed to writes, may be compacted
-    // Compacting, // Being compacted into a new segment
-    Archived, // Fully compacted, no longer in use
-}
-
-#[derive(Debug, Clone)]
-pub struct Segment {
-    pub file_id: Arc<AtomicU64>,
-    pub offset: Arc<AtomicU64>,
-    pub size: Arc<AtomicU64>,
-    pub status: Arc<RwLock<SegmentStatus>>,
-    pub reader: Arc<Mutex<BufReader<File>>>,
-    pub writer: Arc<Mutex<BufWriter<File>>>,
-}
-
-impl Segment {
-    pub fn new(dir_path: &Path, file_id: u64) -> Result<Segment> {
-        // Create empty file
-        let file_name = format!("{file_id}.log");
-        let path = dir_path.join(file_name);
-
-        // Write handle
-        let writer_file = OpenOptions::new()
-            .write(true)
-            .create_new(true) // Fail if file exists
-            .open(&path)?;
-
-        // Reader handle
-        let reader_file = OpenOptions::new().read(true).open(&path)?;
-
-        // File size
-        let metadata = writer_file.metadata()?;
-        let size = metadata.len();
-
-        // Create Segment
-        Ok(Segment {
-            file_id: Arc::new(AtomicU64::new(file_id)),
-            offset: Arc::new(AtomicU64::new(size)),
-            size: Arc::new(AtomicU64::new(size)),
-            status: Arc::new(RwLock::new(SegmentStatus::Active)),
-            reader: Arc::new(Mutex::new(BufReader::new(reader_file))),
-            writer: Arc::new(Mutex::new(BufWriter::new(writer_file))),
-        })
-    }
-    pub fn open(dir_path: &Path, file_id: u64, status: SegmentStatus) -> Result<Segment> {
-        // Create empty file
-        let file_name = format!("{file_id}.log");
-        let path = dir_path.join(file_name);
-
-        // Write handle
-        let mut writer_file = OpenOptions::new().write(true).open(&path)?;
-
-        // Reader handle
-        let reader_file = OpenOptions::new().read(true).open(&path)?;
-
-        // Seek the writer to the end of file
-        let size = writer_file.seek(SeekFrom::End(0))?;
-
-        // Create Segment
-        Ok(Segment {
-            file_id: Arc::new(AtomicU64::new(file_id)),
-            offset: Arc::new(AtomicU64::new(size)),
-            size: Arc::new(AtomicU64::new(size)),
-            status: Arc::new(RwLock::new(status)),
-            reader: Arc::new(Mutex::new(BufReader::new(reader_file))),
-            writer: Arc::new(Mutex::new(BufWriter::new(writer_file))),
-        })
+    fn start(&mut self, header: SegmentHeader) -> Result<()> {
+        self.write_durably(&header.serialize())?;
+        self.offset.store(header.data_start(), Ordering::SeqCst);
+        self.size.store(header.data_start(), Ordering::SeqCst);
+        Ok(())
     }
-    pub fn read(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
-        // Get key-value at a given offset (provided by index)
-
-        let mut buffer = vec![0; length as usize];
-
-        // Find file offset
-        self.reader.lock()?.seek(SeekFrom::Start(offset))?;
-
-        // Read bytes
-        self.reader.lock()?.read_exact(&mut buffer)?;
-
-        // Deserialise value
-        // Return value
-        Ok(buffer)
-    }
-    pub fn append(&mut self, entry: Entry) -> Result<CommandPos> {
-        // Add key-value and return offset for index
-
-        if let Entry::Set { ref value, .. } = entry {
-            if value.is_empty() {
-                return Err(KvsError::EmptyValue);
+    fn write_durably(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        match &self.faults {
+            Some(faults) => {
+                faults.write(&mut self.file, buffer)?;
+                faults.sync(&self.file, self.file_id)
+            }
+            None => {
+                self.file.write_all(buffer)?;
+                self.file.sync_data()
             }
         }
-        // Current Segment Offset
-        let cur_offset = self.offset.load(Ordering::Acquire);
-
-        let buffer = entry.serialize();
-
-        // Write to file
-        self.writer.lock()?.write_all(&buffer)?;
-        self.writer.lock()?.flush()?;
-        self.writer.lock()?.get_ref().sync_data()?;
-
-        // Update segment offset
-        self.offset
-            .fetch_add(buffer.len() as u64, Ordering::Relaxed);
-
-        // Ensure segment size reflects file size growth
-        self.size
-            .store(self.offset.load(Ordering::Acquire), Ordering::SeqCst);
-
-        // Update log pointer
-        Ok(CommandPos {
-            file_id: self.file_id.load(Ordering::Acquire),
-            offset: cur_offset,
-            length: buffer.len() as u64,
-        })
     }
-    pub fn index(&mut self, index: &mut DashMap<String, CommandPos>) -> Result<u64> {
-        let mut stale_entries = 0;
-        let mut read_offset = 0;
-        let current_size = self.size()?;
-
-        // loop through file
-        while read_offset + 8 + 8 <= current_size {
-            // Calculate size of entry
-            let size_buffer = match self.read(read_offset, 16) {
-                Ok(e) => e,
-                Err(_) => break,
-            };
-
-            let key_size = u64::from_le_bytes(size_buffer[0..8].try_into().unwrap());
-            let value_size = u64::from_le_bytes(size_buffer[8..16].try_into().unwrap());
-
-            let entry_len = 16 + key_size + value_size;
-
-            // Read buffer (slightly larger than Entry)
-            let buffer = match self.read(read_offset, entry_len) {
-                Ok(buf) => buf,
-                Err(_) => break, // incomplete or corrupted entry
-            };
+    // Cuts the file back to `offset` and writes from there again
+    fn rewind(&mut self, offset: u64) -> Result<()> {
+        match &self.faults {
+            Some(faults) => faults.set_len(&self.file, self.file_id, offset)?,
+            None => self.file.set_len(offset)?,
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.torn = false;
+        Ok(())
+    }
+}
 
-            // Deserialize Entry
-            let slice = buffer.as_slice();
-            let entry = match Entry::deserialize(slice) {
-                Ok(e) => e,
-                Err(_) => break,
-            };
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buffer, offset)
+}
 
-            // Size of Entry
-            let mut consumed = 8u64 + 8u64; // ksz + vsz
-
-            match &entry {
-                Entry::Set { key, value } => {
-                    let entry_length = key.len() as u64 + value.len() as u64;
-                    consumed += entry_length;
-
-                    if index
-                        .insert(
-                            key.clone(),
-                            CommandPos {
-                                file_id: self.file_id.load(Ordering::Acquire),
-                                offset: read_offset,
-                                length: consumed,
-                            },
-                        )
-                        .is_some()
-                    {
-                        stale_entries += 1;
-                    }
-                }
-                Entry::Remove { key } => {
-                    consumed += key.len() as u64;
-                    if index.remove(key).is_some() {
-                        stale_entries += 1;
-                    }
-                }
+#[cfg(windows)]
+pub(crate) fn read_exact_at(
+    file: &File,
+    mut buffer: &mut [u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset)? {
+            0 => return Err(Error::from(ErrorKind::UnexpectedEof)),
+            n => {
+                buffer = &mut buffer[n..];
+                offset += n as u64;
             }
-
-            // Update read offset
-            read_offset += consumed;
         }
-
-        Ok(stale_entries)
-    }
-    pub fn size(&self) -> Result<u64> {
-        // Measure if compaction is needed
-        let binding = self.reader.lock()?;
-        let file_ref = binding.get_ref();
-        let metadata = file_ref.metadata()?;
-        let size = metadata.len();
-        Ok(size)
     }
+    Ok(())
 }
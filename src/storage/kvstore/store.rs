@@ -1,24 +1,96 @@
 #![deny(missing_docs)]
 //! In Memory key/value store.
-use super::entry::Entry;
-use super::segment::{SegmentReader, SegmentWriter};
-use crate::{KvsError, Result, StoreTrait};
+use super::crypto::Keyring;
+use super::entry::{BlobRef, Compression, Entry, entry_sizes};
+use super::fault::FaultInjector;
+use super::keydir::{DiskKeyDir, IndexMode, KeyDir};
+use super::recovery::{Repair, recover};
+use super::segment::{
+    Replay, SEGMENT_VERSION, SegmentReader, SegmentWriter, blob_ids, blob_path, index_path,
+    segment_ids,
+};
+use crate::storage::lock::DirLock;
+use crate::storage::start_backup;
+use crate::{Engine, KvsError, Result, StoreTrait};
 use dashmap::DashMap;
-use std::fs;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use tracing::{error, info};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::warn;
 
 const MAX_LOG_FILE_SIZE: u64 = 4 * 1024 * 1024; // 4 MB
-// const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1 MB
-const COMPACTION_THRESHOLD: u64 = 1024; // 1 MB
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1 MB
+const COMPRESSION_THRESHOLD: u64 = 512;
+const BLOB_THRESHOLD: u64 = 64 * 1024; // 64 KB
+const BLOB_GC_THRESHOLD: u64 = 16 * 1024 * 1024; // 16 MB
+
+/// Tuning knobs for `KvStore`
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    /// Roll over to a new segment file past this size
+    pub max_segment_bytes: u64,
+    /// Compact once the log grows past this size
+    pub compaction_threshold_bytes: u64,
+    /// Codec for new values; whatever is already written stays readable
+    pub compression: Compression,
+    /// Values shorter than this are stored as given
+    pub compression_threshold_bytes: u64,
+    /// Values at least this long go to a blob file and the log holds a
+    /// pointer to them, so compaction does not copy them
+    pub blob_threshold_bytes: u64,
+    /// Collect blob files once this many bytes in them are dead
+    pub blob_gc_threshold_bytes: u64,
+    /// Where the index of keys lives; fixed when the store is opened, and
+    /// read-only stores always index in memory
+    pub index: IndexMode,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            max_segment_bytes: MAX_LOG_FILE_SIZE,
+            compaction_threshold_bytes: COMPACTION_THRESHOLD,
+            compression: Compression::None,
+            compression_threshold_bytes: COMPRESSION_THRESHOLD,
+            blob_threshold_bytes: BLOB_THRESHOLD,
+            blob_gc_threshold_bytes: BLOB_GC_THRESHOLD,
+            index: IndexMode::Memory,
+        }
+    }
+}
+
+/// A point in the segment log: the next byte to read in `file_id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LogPosition {
+    /// Segment file
+    pub file_id: u64,
+    /// Byte offset within the segment
+    pub offset: u64,
+}
+
+/// Entries read from the segment log by `KvStore::read_log`
+#[derive(Debug)]
+pub struct LogBatch {
+    /// The requested position no longer exists, e.g. after compaction, so
+    /// reading restarted from the oldest segment and earlier state is stale
+    pub reset: bool,
+    /// Entries in log order
+    pub entries: Vec<Entry>,
+    /// Where the next read continues
+    pub next: LogPosition,
+    /// `next` is the end of the log as it stood when the batch was read
+    pub at_end: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct CommandPos {
-    pub file_id: u64, // Which file
-    pub offset: u64,  // Where in the file
-    pub length: u64,  // Number of bytes
+    pub file_id: u64,          // Which file
+    pub offset: u64,           // Where in the file
+    pub length: u64,           // Number of bytes
+    pub blob: Option<BlobRef>, // Where the value is, if not in the entry
 }
 
 /// KvStore creates HashMap of key/value pairs
@@ -27,11 +99,11 @@ pub struct CommandPos {
 ///
 /// ```
 /// use tempfile::TempDir;
-/// use kvs::{KvStore, Result};
+/// use kvs::{KvStore, Result, StoreTrait};
 ///
 /// fn main() -> Result<()> {
 ///     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-///     let mut store = KvStore::open(temp_dir.path())?;
+///     let store = KvStore::open(temp_dir.path().to_path_buf())?;
 ///
 ///     store.set("language".to_string(), "Rust".to_string())?;
 ///     assert_eq!(store.get("language".to_string())?, Some("Rust".to_string()));
@@ -45,67 +117,144 @@ pub struct CommandPos {
 pub struct KvStore {
     base_dir: PathBuf,
     readers: Arc<DashMap<String, SegmentReader>>,
-    writer: Arc<Mutex<SegmentWriter>>,
+    // None when opened read-only
+    writer: Option<Arc<Mutex<SegmentWriter>>>,
     size: Arc<AtomicU64>,
-    index: Arc<DashMap<String, CommandPos>>,
+    index: Arc<KeyDir>,
     stale_entries: Arc<AtomicU64>,
+    blobs: Arc<DashMap<u64, SegmentReader>>,
+    // Started when the first large value comes along; only locked while
+    // holding the writer lock
+    blob_writer: Arc<Mutex<Option<SegmentWriter>>>,
+    next_blob_id: Arc<AtomicU64>,
+    // Bytes of blob records nothing points at any more
+    blob_garbage: Arc<AtomicU64>,
     compaction: Arc<AtomicBool>,
+    options: Arc<LiveOptions>,
+    faults: Option<FaultInjector>,
+    keys: Option<Keyring>,
+    _lock: Arc<DirLock>,
+}
+
+// Options are read on every write and may be replaced while the store is
+// open, so each one is kept in its own atomic
+#[derive(Debug)]
+struct LiveOptions {
+    max_segment_bytes: AtomicU64,
+    compaction_threshold_bytes: AtomicU64,
+    compression: AtomicU64,
+    compression_threshold_bytes: AtomicU64,
+    blob_threshold_bytes: AtomicU64,
+    blob_gc_threshold_bytes: AtomicU64,
 }
 
 impl KvStore {
     /// Create a key/value store
     pub fn open(dir_path: PathBuf) -> Result<KvStore> {
+        KvStore::open_with_options(dir_path, KvStoreOptions::default())
+    }
+    /// Cut damaged segments in `dir_path` back to their last whole entry
+    ///
+    /// `open` does this first. Fails if the store is open anywhere else.
+    pub fn repair(dir_path: &Path) -> Result<Vec<Repair>> {
+        let _lock = DirLock::exclusive(dir_path)?;
+        recover(dir_path, None)
+    }
+    /// Like `repair`, for a store whose segments may be encrypted with `keys`
+    pub fn repair_with_keys(dir_path: &Path, keys: &Keyring) -> Result<Vec<Repair>> {
+        let _lock = DirLock::exclusive(dir_path)?;
+        recover(dir_path, Some(keys))
+    }
+    /// Create a key/value store with non-default options
+    pub fn open_with_options(dir_path: PathBuf, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_inner(dir_path, options, None, None, false)
+    }
+    /// Create a key/value store that encrypts its entries with the current
+    /// key in `keys`
+    ///
+    /// Segments written under a retired key stay readable until compaction
+    /// rewrites them.
+    pub fn open_with_keys(
+        dir_path: PathBuf,
+        options: KvStoreOptions,
+        keys: Keyring,
+    ) -> Result<KvStore> {
+        KvStore::open_inner(dir_path, options, None, Some(keys), false)
+    }
+    /// Open an existing store for reading only
+    ///
+    /// Any number of read-only stores may share a directory, but none can
+    /// while a writer has it open. Writes fail with `KvsError::ReadOnly`,
+    /// and a torn tail is skipped rather than repaired.
+    pub fn open_read_only(dir_path: PathBuf) -> Result<KvStore> {
+        KvStore::open_inner(dir_path, KvStoreOptions::default(), None, None, true)
+    }
+    /// Open an existing store whose segments may be encrypted with `keys`
+    /// for reading only
+    pub fn open_read_only_with_keys(dir_path: PathBuf, keys: Keyring) -> Result<KvStore> {
+        KvStore::open_inner(dir_path, KvStoreOptions::default(), None, Some(keys), true)
+    }
+    /// Create a key/value store whose segment I/O goes through `faults`
+    pub fn open_with_faults(
+        dir_path: PathBuf,
+        options: KvStoreOptions,
+        faults: FaultInjector,
+    ) -> Result<KvStore> {
+        KvStore::open_inner(dir_path, options, Some(faults), None, false)
+    }
+    fn open_inner(
+        dir_path: PathBuf,
+        options: KvStoreOptions,
+        faults: Option<FaultInjector>,
+        keys: Option<Keyring>,
+        read_only: bool,
+    ) -> Result<KvStore> {
+        // Another writer would append to the same segment and compact away
+        // files this one reads
+        let lock = match read_only {
+            true => DirLock::shared(&dir_path)?,
+            false => DirLock::exclusive(&dir_path)?,
+        };
+
+        // A crash can leave a torn entry behind
+        if !read_only {
+            recover(&dir_path, keys.as_ref())?;
+        }
+
         // Add segments to vector
         let readers = DashMap::new();
 
-        // Create index
+        // Create index; on disk, it is built once the active segment is known
         let mut index = DashMap::new();
+        let on_disk = options.index == IndexMode::Disk && !read_only;
+        if on_disk && keys.is_some() {
+            return Err(KvsError::Unsupported(
+                "an encrypted store indexes in memory, as index files would hold its keys in the clear"
+                    .to_owned(),
+            ));
+        }
 
         // Get all files
-        // Check directory for log files
-        let mut file_ids = fs::read_dir(&dir_path)?
-            .filter_map(|entry| {
-                let path = entry.ok()?.path();
-
-                if path.extension()?.to_str()? != "log" {
-                    return None;
-                }
-
-                let stem_str = path.file_stem()?.to_str()?;
-                stem_str.parse::<u64>().ok()
-            })
-            .collect::<Vec<u64>>();
-
-        file_ids.sort_unstable();
-
-        // Create segment for each log file
-        let active = file_ids.iter().copied().max().unwrap_or(1);
+        let file_ids = segment_ids(&dir_path)?;
 
         // Calculate size of log
         let mut size = 0;
 
-        // Calculate stale entries in log
-        let mut stale_entries = 0;
-
-        let writer = match !file_ids.is_empty() {
-            true => SegmentWriter::open(&dir_path, active)?,
-            false => SegmentWriter::new(&dir_path, active)?,
-        };
-
-        // If no files, create one
-        if file_ids.is_empty() {
-            file_ids.push(active);
-        }
+        // Count stale entries and dead blob records in log
+        let mut replay = Replay::default();
 
-        // loop newest to oldest (highest to lowest)
-        for id in file_ids {
+        // loop oldest to newest, so later entries win
+        for &id in &file_ids {
             // Create segment for file
-            let mut reader = SegmentReader::open(&dir_path, id)?;
+            let reader = SegmentReader::open(&dir_path, id, faults.clone(), keys.as_ref())?;
 
             // Update index with segment
-            let segment_stale_entries = reader.index(&mut index)?;
-
-            stale_entries += segment_stale_entries;
+            if !on_disk && let Some(damage) = reader.index(&mut index, &mut replay)? {
+                warn!(
+                    "Segment {}.log damaged at offset {} ({}): skipped bytes {}..{}",
+                    id, damage.offset, damage.reason, damage.offset, reader.size
+                );
+            }
 
             // update log size
             size += reader.size;
@@ -113,36 +262,321 @@ impl KvStore {
             readers.insert(id.to_string(), reader);
         }
 
+        // Writes continue in the newest segment unless it is encrypted
+        // differently from what the current key asks for, e.g. after a
+        // rotation; then they start a new one
+        let key_id = keys.as_ref().map_or(0, |keys| keys.current().id());
+        let active = file_ids
+            .last()
+            .and_then(|id| readers.get(&id.to_string()))
+            .filter(|reader| reader.header.key_id == key_id)
+            .map(|reader| (reader.file_id, reader.header, reader.cipher.clone()));
+        let writer = match (read_only, active) {
+            (true, _) => None,
+            (false, Some((active, header, cipher))) => Some(SegmentWriter::open(
+                &dir_path,
+                active,
+                faults.clone(),
+                header,
+                cipher,
+            )?),
+            (false, None) => {
+                let file_id = file_ids.last().map_or(1, |id| id + 1);
+                let key = keys.as_ref().map(Keyring::current);
+                let writer = SegmentWriter::new(&dir_path, file_id, faults.clone(), key)?;
+                let reader =
+                    SegmentReader::open(&dir_path, file_id, faults.clone(), keys.as_ref())?;
+                size += reader.size;
+                readers.insert(file_id.to_string(), reader);
+                Some(writer)
+            }
+        };
+
+        let index = match &writer {
+            Some(writer) if on_disk => KeyDir::Disk(DiskKeyDir::open(
+                &dir_path,
+                &readers,
+                writer.file_id,
+                &mut replay,
+            )?),
+            _ => KeyDir::Memory(index),
+        };
+
+        // Blob files are never appended to after a restart. Ids are not
+        // reused while a stale entry may still point at a collected file.
+        let blobs = DashMap::new();
+        let mut last_blob_id = replay.last_blob_id;
+        for id in blob_ids(&dir_path)? {
+            blobs.insert(id, SegmentReader::open_blob(&dir_path, id, keys.as_ref())?);
+            last_blob_id = last_blob_id.max(id);
+        }
+
         // Create Log
         Ok(KvStore {
             base_dir: dir_path,
             readers: Arc::new(readers),
-            writer: Arc::new(Mutex::new(writer)),
+            writer: writer.map(|writer| Arc::new(Mutex::new(writer))),
             size: Arc::new(AtomicU64::new(size)),
-            stale_entries: Arc::new(AtomicU64::new(stale_entries)),
+            stale_entries: Arc::new(AtomicU64::new(replay.stale_entries)),
             index: Arc::new(index),
+            blobs: Arc::new(blobs),
+            blob_writer: Arc::new(Mutex::new(None)),
+            next_blob_id: Arc::new(AtomicU64::new(last_blob_id + 1)),
+            blob_garbage: Arc::new(AtomicU64::new(replay.blob_garbage)),
             compaction: Arc::new(AtomicBool::new(false)),
+            options: Arc::new(LiveOptions {
+                max_segment_bytes: AtomicU64::new(options.max_segment_bytes),
+                compaction_threshold_bytes: AtomicU64::new(options.compaction_threshold_bytes),
+                compression: AtomicU64::new(options.compression.flag()),
+                compression_threshold_bytes: AtomicU64::new(options.compression_threshold_bytes),
+                blob_threshold_bytes: AtomicU64::new(options.blob_threshold_bytes),
+                blob_gc_threshold_bytes: AtomicU64::new(options.blob_gc_threshold_bytes),
+            }),
+            faults,
+            keys,
+            _lock: Arc::new(lock),
         })
     }
-    fn rollover(&self) -> Result<()> {
-        // Create new segment
-        let writer_ref = Arc::clone(&self.writer);
+    /// Current tuning options
+    pub fn options(&self) -> KvStoreOptions {
+        KvStoreOptions {
+            max_segment_bytes: self.options.max_segment_bytes.load(Ordering::Relaxed),
+            compaction_threshold_bytes: self
+                .options
+                .compaction_threshold_bytes
+                .load(Ordering::Relaxed),
+            compression: Compression::from_flag(self.options.compression.load(Ordering::Relaxed))
+                .unwrap_or_default(),
+            compression_threshold_bytes: self
+                .options
+                .compression_threshold_bytes
+                .load(Ordering::Relaxed),
+            blob_threshold_bytes: self.options.blob_threshold_bytes.load(Ordering::Relaxed),
+            blob_gc_threshold_bytes: self.options.blob_gc_threshold_bytes.load(Ordering::Relaxed),
+            index: self.index.mode(),
+        }
+    }
+    /// Replace the tuning options; every clone of the store sees the change
+    /// from its next write on. The index mode stays as the store was opened.
+    pub fn set_options(&self, options: KvStoreOptions) {
+        self.options
+            .max_segment_bytes
+            .store(options.max_segment_bytes, Ordering::Relaxed);
+        self.options
+            .compaction_threshold_bytes
+            .store(options.compaction_threshold_bytes, Ordering::Relaxed);
+        self.options
+            .compression
+            .store(options.compression.flag(), Ordering::Relaxed);
+        self.options
+            .compression_threshold_bytes
+            .store(options.compression_threshold_bytes, Ordering::Relaxed);
+        self.options
+            .blob_threshold_bytes
+            .store(options.blob_threshold_bytes, Ordering::Relaxed);
+        self.options
+            .blob_gc_threshold_bytes
+            .store(options.blob_gc_threshold_bytes, Ordering::Relaxed);
+    }
+    /// Read whole entries from `from` onwards, up to about `max_bytes`
+    ///
+    /// Reads never wait: an empty batch means the log has nothing past `from`
+    /// yet. Replicas use this to follow the primary's log.
+    pub fn read_log(&self, from: LogPosition, max_bytes: u64) -> Result<LogBatch> {
+        let mut reset = false;
+        let mut position = from;
+
+        // Segments are numbered in write order; compaction removes old ones
+        let mut file_ids: Vec<u64> = self
+            .readers
+            .iter()
+            .filter_map(|reader| reader.key().parse().ok())
+            .collect();
+        file_ids.sort_unstable();
+
+        let mut entries = vec![];
+        let mut read = 0;
+        let mut at_end = false;
+
+        loop {
+            let reader = match self.readers.get(&position.file_id.to_string()) {
+                Some(reader) => reader,
+                None if reset => break,
+                None => {
+                    reset = true;
+                    entries.clear();
+                    position = LogPosition {
+                        file_id: file_ids.first().copied().unwrap_or(1),
+                        offset: 0,
+                    };
+                    continue;
+                }
+            };
+            let size = reader.file.metadata()?.len();
+            position.offset = position.offset.max(reader.header.data_start());
+            if position.offset > size {
+                // Past the end of a segment this store never wrote
+                drop(reader);
+                if reset {
+                    break;
+                }
+                reset = true;
+                position = LogPosition {
+                    file_id: file_ids.first().copied().unwrap_or(1),
+                    offset: 0,
+                };
+                continue;
+            }
+
+            // The active segment may end in an entry still being written, so
+            // only whole entries are returned
+            while position.offset + 16 <= size && read < max_bytes {
+                let (key_size, value_size) = entry_sizes(&reader.read(position.offset, 16)?);
+                let length = 16 + key_size + value_size + reader.header.trailer_len();
+                if position.offset + length > size {
+                    break;
+                }
+
+                match reader.read_entry(position.offset, length)? {
+                    Entry::Blob { key, blob } => {
+                        // A collected blob file only held values that later
+                        // entries point at again
+                        if let Some(value) = self.blob_value(&blob)? {
+                            entries.push(Entry::Set { key, value });
+                        }
+                    }
+                    entry => entries.push(entry),
+                }
+                position.offset += length;
+                read += length;
+            }
+
+            // Move on once a finished segment has been read to its end
+            let next_id = file_ids.iter().copied().find(|id| *id > position.file_id);
+            match next_id {
+                Some(file_id) if position.offset >= size && read < max_bytes => {
+                    position = LogPosition { file_id, offset: 0 };
+                }
+                Some(_) => break,
+                None => {
+                    // Stopping short of `max_bytes` in the last segment means
+                    // there was nothing more to read
+                    at_end = read < max_bytes;
+                    break;
+                }
+            }
+        }
 
-        let mut writer = writer_ref.lock().map_err(|_| KvsError::LockPoisoned)?;
+        Ok(LogBatch {
+            reset,
+            entries,
+            next: position,
+            at_end,
+        })
+    }
+    /// Rewrite segments and blob files written in an older format or under
+    /// a key other than the current one, returning how many
+    ///
+    /// Such files stay readable, and compaction and blob collection rewrite
+    /// them as a matter of course; this does both now if any are left.
+    pub fn upgrade(&self) -> Result<usize> {
+        let mut writer = self.writer()?;
+        let outdated = self
+            .readers
+            .iter()
+            .filter(|reader| self.outdated(reader))
+            .count();
+        let outdated_blobs = self
+            .blobs
+            .iter()
+            .filter(|reader| self.outdated(reader))
+            .count();
+        if outdated > 0 {
+            self.compact(&mut writer)?;
+        }
+        if outdated_blobs > 0 {
+            self.collect_blobs_locked(&mut writer)?;
+        }
+        Ok(outdated + outdated_blobs)
+    }
+    /// Move the live values out of blob files that also hold dead ones,
+    /// then delete those files, returning the bytes freed
+    ///
+    /// This runs by itself once `blob_gc_threshold_bytes` of blob records
+    /// are dead. Files written in an older format or under another key are
+    /// rewritten too.
+    pub fn collect_blobs(&self) -> Result<u64> {
+        let mut writer = self.writer()?;
+        self.collect_blobs_locked(&mut writer)
+    }
+    fn outdated(&self, reader: &SegmentReader) -> bool {
+        let key_id = self.keys.as_ref().map_or(0, |keys| keys.current().id());
+        reader.header.version < SEGMENT_VERSION || reader.header.key_id != key_id
+    }
+    /// Remove every key
+    pub fn clear(&self) -> Result<()> {
+        let mut writer = self.writer()?;
+
+        self.index.for_each(&mut |key, _| {
+            let before_size = writer.offset.load(Ordering::Acquire);
+            writer.append(Entry::Remove { key: key.clone() })?;
+            let after_size = writer.offset.load(Ordering::Acquire);
+
+            self.size
+                .fetch_add(after_size - before_size, Ordering::Relaxed);
+            if let Some(pos) = self.index.remove(&key)? {
+                self.add_blob_garbage(&pos);
+            }
+            self.stale_entries.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        })?;
 
+        self.collect_blobs_if_needed(&mut writer)?;
+        if self.size.load(Ordering::Acquire) > self.options().compaction_threshold_bytes {
+            self.compact(&mut writer)?;
+        }
+
+        Ok(())
+    }
+    fn writer(&self) -> Result<MutexGuard<'_, SegmentWriter>> {
+        let writer = self.writer.as_ref().ok_or(KvsError::ReadOnly)?;
+        let mut writer = writer.lock().map_err(|_| KvsError::LockPoisoned)?;
+        // Picks up options changed since the last write
+        let options = self.options();
+        writer.compression = options.compression;
+        writer.compression_threshold = options.compression_threshold_bytes;
+        Ok(writer)
+    }
+    // Callers hold the writer lock, so it is passed in rather than re-acquired
+    fn rollover(&self, writer: &mut SegmentWriter) -> Result<()> {
+        // Create new segment
         let active_file_id = writer.file_id;
 
         let new_file_id = 1 + active_file_id;
 
-        let new_writer = SegmentWriter::new(self.base_dir.as_path(), new_file_id)?;
+        let key = self.keys.as_ref().map(Keyring::current);
+        let mut new_writer = SegmentWriter::new(
+            self.base_dir.as_path(),
+            new_file_id,
+            self.faults.clone(),
+            key,
+        )?;
+        new_writer.compression = writer.compression;
+        new_writer.compression_threshold = writer.compression_threshold;
         *writer = new_writer;
 
-        let new_reader = SegmentReader::open(self.base_dir.as_path(), new_file_id)?;
+        let new_reader = SegmentReader::open(
+            self.base_dir.as_path(),
+            new_file_id,
+            self.faults.clone(),
+            self.keys.as_ref(),
+        )?;
         self.readers.insert(new_file_id.to_string(), new_reader);
+        self.index.seal(active_file_id)?;
 
         Ok(())
     }
-    fn compact(&self) -> Result<()> {
+    fn compact(&self, writer: &mut SegmentWriter) -> Result<()> {
         self.compaction.store(true, Ordering::SeqCst);
 
         let old_reader_keys: Vec<String> = self
@@ -151,33 +585,28 @@ impl KvStore {
             .map(|entry| entry.key().clone())
             .collect();
 
-        // Get list of current files
-        let keys: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
-
         // Reset size so compaction size can be calculated
         self.size.store(0, Ordering::SeqCst);
 
         // Create new active file to write
-        self.rollover()?;
+        self.rollover(writer)?;
 
         // Loop through key_dir
-        for key in keys {
-            // get value
-            let value = self
-                .get(key.clone())
-                .map_err(|_| KvsError::KeyNotFound)?
-                .ok_or(KvsError::KeyNotFound)?;
-
-            let mut writer = self.writer.lock().map_err(|_| KvsError::KeyNotFound)?;
-
+        self.index.for_each(&mut |key, pos| {
             let before_size = writer.size.load(Ordering::Acquire);
 
-            let cmd_pos = writer
-                .append(Entry::Set {
+            // Values in blob files stay where they are; only the pointer to
+            // them is copied
+            let cmd_pos = match pos.blob {
+                Some(blob) => writer.append(Entry::Blob {
                     key: key.clone(),
-                    value,
-                })
-                .map_err(|_| KvsError::KeyNotFound)?;
+                    blob,
+                })?,
+                None => {
+                    let value = self.value_at(&pos)?.ok_or(KvsError::KeyNotFound)?;
+                    self.append_set(writer, key.clone(), value)?
+                }
+            };
 
             let after_size = writer.offset.load(Ordering::Acquire);
 
@@ -185,20 +614,27 @@ impl KvStore {
                 .fetch_add(after_size - before_size, Ordering::Relaxed);
 
             // Update index
-            self.index.insert(key, cmd_pos);
+            self.index.insert(key, cmd_pos)?;
 
             // Check file size
             let writer_size = writer.size().map_err(|_| KvsError::FileNotFound)?;
 
-            if writer_size > MAX_LOG_FILE_SIZE {
-                self.rollover()?;
+            if writer_size > self.options().max_segment_bytes {
+                self.rollover(writer)?;
             }
-        }
+            Ok(())
+        })?;
 
         // Update stale_entries
         self.stale_entries.store(0, Ordering::SeqCst);
 
         // Drop all file handles before deleting files
+        let mut old_ids: Vec<u64> = old_reader_keys
+            .iter()
+            .filter_map(|key| key.parse().ok())
+            .collect();
+        old_ids.sort_unstable();
+        self.index.forget(&old_ids)?;
         let mut old_readers: Vec<SegmentReader> = vec![];
 
         for reader_key in &old_reader_keys {
@@ -208,16 +644,177 @@ impl KvStore {
         }
         drop(old_readers);
 
-        // Remove old files (active and less)
-        for reader_key in old_reader_keys {
-            let file_name = format!("{reader_key}.log");
+        // Remove old files (active and less), oldest first: a crash part way
+        // must not leave a value behind without the removal that followed it
+        for file_id in old_ids {
+            let file_name = format!("{file_id}.log");
             fs::remove_file(self.base_dir.join(file_name))?;
+            // Written while the store indexed on disk
+            match fs::remove_file(index_path(&self.base_dir, file_id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
         }
 
         self.compaction.store(false, Ordering::SeqCst);
 
         Ok(())
     }
+    // Appends a `Set`, sending a large value to a blob file first so the
+    // pointer never reaches the log ahead of it
+    fn append_set(
+        &self,
+        writer: &mut SegmentWriter,
+        key: String,
+        value: String,
+    ) -> Result<CommandPos> {
+        if (value.len() as u64) < self.options().blob_threshold_bytes {
+            return writer.append(Entry::Set { key, value });
+        }
+        let blob = self.append_blob(key.clone(), value)?;
+        writer.append(Entry::Blob { key, blob })
+    }
+    // The record keeps its key, so a blob file can be read on its own
+    fn append_blob(&self, key: String, value: String) -> Result<BlobRef> {
+        let mut blob_writer = self
+            .blob_writer
+            .lock()
+            .map_err(|_| KvsError::LockPoisoned)?;
+        let options = self.options();
+        let writer = match blob_writer.as_mut() {
+            Some(writer) => writer,
+            None => {
+                let file_id = self.next_blob_id.fetch_add(1, Ordering::SeqCst);
+                let key = self.keys.as_ref().map(Keyring::current);
+                let writer = SegmentWriter::new_blob(self.base_dir.as_path(), file_id, key)?;
+                let reader =
+                    SegmentReader::open_blob(self.base_dir.as_path(), file_id, self.keys.as_ref())?;
+                self.blobs.insert(file_id, reader);
+                blob_writer.insert(writer)
+            }
+        };
+        writer.compression = options.compression;
+        writer.compression_threshold = options.compression_threshold_bytes;
+
+        let pos = writer.append(Entry::Set { key, value })?;
+        // Blob files roll over at the same size as segments
+        if writer.size()? > options.max_segment_bytes {
+            *blob_writer = None;
+        }
+        Ok(BlobRef {
+            file_id: pos.file_id,
+            offset: pos.offset,
+            length: pos.length,
+        })
+    }
+    // The value an index entry points at
+    fn value_at(&self, pos: &CommandPos) -> Result<Option<String>> {
+        if let Some(blob) = pos.blob {
+            return match self.blob_value(&blob)? {
+                Some(value) => Ok(Some(value)),
+                None => Err(KvsError::FileNotFound),
+            };
+        }
+
+        let reader = match self.readers.get(&pos.file_id.to_string()) {
+            Some(value) => value,
+            None => return Err(KvsError::FileNotFound),
+        };
+
+        // Has all the data (kv length, val length, key, value)
+        match reader.read_entry(pos.offset, pos.length)? {
+            Entry::Set { value, .. } => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+    // None once the blob file has been collected
+    fn blob_value(&self, blob: &BlobRef) -> Result<Option<String>> {
+        let reader = match self.blobs.get(&blob.file_id) {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        match reader.read_entry(blob.offset, blob.length)? {
+            Entry::Set { value, .. } => Ok(Some(value)),
+            _ => Err(KvsError::CorruptedLog),
+        }
+    }
+    fn add_blob_garbage(&self, replaced: &CommandPos) {
+        if let Some(blob) = replaced.blob {
+            self.blob_garbage.fetch_add(blob.length, Ordering::Relaxed);
+        }
+    }
+    fn collect_blobs_if_needed(&self, writer: &mut SegmentWriter) -> Result<()> {
+        if self.blob_garbage.load(Ordering::Acquire) > self.options().blob_gc_threshold_bytes {
+            self.collect_blobs_locked(writer)?;
+        }
+        Ok(())
+    }
+    // Liveness comes from the index, which cannot change under the writer
+    // lock. Each moved value gets a new pointer in the log before the file
+    // it came from is deleted, so a crash part way loses nothing.
+    fn collect_blobs_locked(&self, writer: &mut SegmentWriter) -> Result<u64> {
+        // The active blob file is collected too; moved values start a new one
+        *self
+            .blob_writer
+            .lock()
+            .map_err(|_| KvsError::LockPoisoned)? = None;
+
+        let mut live: HashMap<u64, Vec<(String, BlobRef)>> = HashMap::new();
+        self.index.for_each(&mut |key, pos| {
+            if let Some(blob) = pos.blob {
+                live.entry(blob.file_id).or_default().push((key, blob));
+            }
+            Ok(())
+        })?;
+        let mut file_ids: Vec<u64> = self.blobs.iter().map(|reader| *reader.key()).collect();
+        file_ids.sort_unstable();
+
+        let mut freed = 0;
+        for file_id in file_ids {
+            let (size, data_start, outdated) = match self.blobs.get(&file_id) {
+                // The reader's size is from when it was opened
+                Some(reader) => (
+                    reader.file.metadata()?.len(),
+                    reader.header.data_start(),
+                    self.outdated(&reader),
+                ),
+                None => continue,
+            };
+            let records = live.remove(&file_id).unwrap_or_default();
+            let live_bytes: u64 = records.iter().map(|(_, blob)| blob.length).sum();
+            if !outdated && data_start + live_bytes >= size {
+                continue;
+            }
+
+            for (key, blob) in records {
+                let value = self.blob_value(&blob)?.ok_or(KvsError::FileNotFound)?;
+                let moved = self.append_blob(key.clone(), value)?;
+
+                let before_size = writer.offset.load(Ordering::Acquire);
+                let cmd_pos = writer.append(Entry::Blob {
+                    key: key.clone(),
+                    blob: moved,
+                })?;
+                let after_size = writer.offset.load(Ordering::Acquire);
+                self.size
+                    .fetch_add(after_size - before_size, Ordering::Relaxed);
+                self.index.insert(key, cmd_pos)?;
+                self.stale_entries.fetch_add(1, Ordering::Relaxed);
+
+                if writer.size()? > self.options().max_segment_bytes {
+                    self.rollover(writer)?;
+                }
+            }
+
+            // Nothing points into the file any more
+            drop(self.blobs.remove(&file_id));
+            fs::remove_file(blob_path(&self.base_dir, file_id))?;
+            freed += size.saturating_sub(live_bytes);
+        }
+
+        self.blob_garbage.store(0, Ordering::SeqCst);
+        Ok(freed)
+    }
 }
 
 impl StoreTrait for KvStore {
@@ -228,16 +825,11 @@ impl StoreTrait for KvStore {
         }
 
         // add value to new file
-        let mut writer = self.writer.lock().map_err(|_| KvsError::KeyNotFound)?;
+        let mut writer = self.writer()?;
 
         let before_size = writer.size.load(Ordering::Acquire);
 
-        let cmd_pos = writer
-            .append(Entry::Set {
-                key: key.clone(),
-                value,
-            })
-            .map_err(|_| KvsError::KeyNotFound)?;
+        let cmd_pos = self.append_set(&mut writer, key.clone(), value)?;
 
         let after_size = writer.offset.load(Ordering::Acquire);
 
@@ -245,23 +837,25 @@ impl StoreTrait for KvStore {
             .fetch_add(after_size - before_size, Ordering::Relaxed);
 
         // Update index
-        if self.index.insert(key, cmd_pos).is_some() {
+        if let Some(old) = self.index.insert(key, cmd_pos)? {
             // Update stale entries for overwrite
             self.stale_entries.fetch_add(1, Ordering::Relaxed);
+            self.add_blob_garbage(&old);
         }
+        self.collect_blobs_if_needed(&mut writer)?;
 
         // Check threshold for compaction
         // Prevent recursive compaction
-        if self.size.load(Ordering::Acquire) > COMPACTION_THRESHOLD {
-            self.compact()?;
+        if self.size.load(Ordering::Acquire) > self.options().compaction_threshold_bytes {
+            self.compact(&mut writer)?;
             return Ok(());
         }
 
         // Check file size
         let writer_size = writer.size().map_err(|_| KvsError::FileNotFound)?;
 
-        if writer_size > MAX_LOG_FILE_SIZE {
-            self.rollover()?;
+        if writer_size > self.options().max_segment_bytes {
+            self.rollover(&mut writer)?;
         }
 
         Ok(())
@@ -269,43 +863,25 @@ impl StoreTrait for KvStore {
     /// Get a value from store using key
     fn get(&self, key: String) -> Result<Option<String>> {
         // Get log pointer from index
-
-        let log_pointer = match self.index.get(&key) {
-            Some(ptr) => ptr,
-            None => return Ok(None),
-        };
-
-        let file_id = log_pointer.file_id.to_string();
-
-        let readers = Arc::clone(&self.readers);
-
-        let mut reader = match readers.get_mut(&file_id) {
-            Some(value) => value,
-            None => return Err(KvsError::FileNotFound),
-        };
-
-        // Has all the data (kv length, val length, key, value)
-        let bytes = reader.read(log_pointer.offset, log_pointer.length)?;
-
-        let entry = Entry::deserialize(&bytes)?;
-
-        if let Entry::Set { value, .. } = entry {
-            Ok(Some(value))
-        } else {
-            Ok(None)
+        match self.index.get(&key)? {
+            Some(pos) => self.value_at(&pos),
+            None => Ok(None),
         }
     }
     /// Remove key/value pair from store
     fn remove(&self, key: String) -> Result<()> {
-        self.index.remove(&key).ok_or(KvsError::KeyNotFound)?;
-
-        let mut writer = self.writer.lock().map_err(|_| KvsError::KeyNotFound)?;
+        let mut writer = self.writer()?;
+        if !self.index.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
 
         let before_size = writer.offset.load(Ordering::Acquire);
 
-        writer
-            .append(Entry::Remove { key })
-            .map_err(|_| KvsError::KeyNotFound)?;
+        // The key stays readable until its removal is on disk
+        writer.append(Entry::Remove { key: key.clone() })?;
+        if let Some(pos) = self.index.remove(&key)? {
+            self.add_blob_garbage(&pos);
+        }
 
         let after_size = writer.offset.load(Ordering::Acquire);
         self.size
@@ -313,22 +889,108 @@ impl StoreTrait for KvStore {
 
         // Update stale entries for removal
         self.stale_entries.fetch_add(1, Ordering::Relaxed);
+        self.collect_blobs_if_needed(&mut writer)?;
 
         // Check threshold for compaction
-        if self.size.load(Ordering::Acquire) > COMPACTION_THRESHOLD {
-            self.compact()?;
+        if self.size.load(Ordering::Acquire) > self.options().compaction_threshold_bytes {
+            self.compact(&mut writer)?;
             return Ok(());
         }
 
         // Check file size
         let writer_size = writer.size().map_err(|_| KvsError::FileNotFound)?;
 
-        if writer_size > MAX_LOG_FILE_SIZE {
-            self.rollover()?;
+        if writer_size > self.options().max_segment_bytes {
+            self.rollover(&mut writer)?;
+        }
+
+        Ok(())
+    }
+    /// Flush the active segment to disk
+    fn flush(&self) -> Result<()> {
+        match self.writer {
+            Some(_) => self.writer()?.sync(),
+            None => Ok(()),
+        }
+    }
+    /// Snapshot the segment log and blob files into `dest`
+    ///
+    /// The writer lock freezes the segment list, since compaction needs it
+    /// too. Finished files never change again and are hard linked; the
+    /// active segment and blob file are copied up to their current ends. A
+    /// read-only store has neither.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        start_backup(dest, Engine::Kvs)?;
+
+        let mut writer = match self.writer {
+            Some(_) => Some(self.writer()?),
+            None => None,
+        };
+        let (active, end) = match writer.as_deref_mut() {
+            Some(writer) => {
+                writer.sync()?;
+                (Some(writer.file_id), writer.offset.load(Ordering::Acquire))
+            }
+            None => (None, 0),
+        };
+        let file_ids: Vec<u64> = self
+            .readers
+            .iter()
+            .filter_map(|reader| reader.key().parse().ok())
+            .collect();
+
+        for file_id in file_ids {
+            let file_name = format!("{file_id}.log");
+            let end = Some(end).filter(|_| Some(file_id) == active);
+            snapshot_file(&self.base_dir.join(&file_name), &dest.join(&file_name), end)?;
+        }
+
+        // Blob files are only written under the writer lock as well
+        let active_blob = self
+            .blob_writer
+            .lock()
+            .map_err(|_| KvsError::LockPoisoned)?
+            .as_ref()
+            .map(|writer| (writer.file_id, writer.offset.load(Ordering::Acquire)));
+        let blob_ids: Vec<u64> = self.blobs.iter().map(|reader| *reader.key()).collect();
+        for file_id in blob_ids {
+            let src = blob_path(&self.base_dir, file_id);
+            let end = active_blob
+                .filter(|(active, _)| *active == file_id)
+                .map(|(_, end)| end);
+            snapshot_file(&src, &blob_path(dest, file_id), end)?;
         }
 
         Ok(())
     }
+    /// Visit every live pair
+    ///
+    /// Keys are listed from the index up front; a key removed before its
+    /// turn is skipped.
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.index
+            .for_each(&mut |key, pos| match self.value_at(&pos)? {
+                Some(value) => visit(key, value),
+                None => Ok(()),
+            })
+    }
+}
+
+// Copies a file still being appended to up to `end`; hard links the rest
+fn snapshot_file(src: &Path, target: &Path, end: Option<u64>) -> Result<()> {
+    match end {
+        Some(end) => {
+            let mut file = File::open(src)?.take(end);
+            io::copy(&mut file, &mut File::create(target)?)?;
+        }
+        None => {
+            if fs::hard_link(src, target).is_err() {
+                // e.g. the backup is on another filesystem
+                fs::copy(src, target)?;
+            }
+        }
+    }
+    Ok(())
 }
 
 // impl Clone for KvStore {
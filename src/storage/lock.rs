@@ -0,0 +1,52 @@
+use crate::{KvsError, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+/// Lock file a store holds in its data directory while it is open
+pub const LOCK_FILE: &str = "LOCK";
+
+/// An `flock` on a data directory's `LOCK` file, released when dropped
+///
+/// One writer or any number of readers may hold it at a time, across
+/// processes and within one.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks `dir` for a store that writes to it
+    pub(crate) fn exclusive(dir: &Path) -> Result<DirLock> {
+        DirLock::acquire(dir, false)
+    }
+
+    /// Locks `dir` for a store that only reads it
+    pub(crate) fn shared(dir: &Path) -> Result<DirLock> {
+        DirLock::acquire(dir, true)
+    }
+
+    fn acquire(dir: &Path, shared: bool) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        let locked = match shared {
+            true => file.try_lock_shared(),
+            false => file.try_lock(),
+        };
+        match locked {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(KvsError::DirectoryLocked(format!(
+                "{} is already open {}",
+                dir.display(),
+                match shared {
+                    true => "for writing",
+                    false => "elsewhere",
+                }
+            ))),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
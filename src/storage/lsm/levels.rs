@@ -0,0 +1,121 @@
+use super::table::{Record, Table};
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Lists the tables of an LSM directory
+pub(super) const LEVELS_FILE: &str = "LEVELS";
+
+/// Contents of `LEVELS`: which tables make up each level, and which
+/// write-ahead log holds what no table does yet
+///
+/// Tables and logs missing from it are leftovers of a crash part way
+/// through a flush or compaction.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct Levels {
+    pub(super) wal: u64,
+    /// Table ids; level 0 newest first, deeper levels in key order
+    pub(super) levels: Vec<Vec<u64>>,
+}
+
+impl Levels {
+    pub(super) fn of(wal: u64, levels: &[Vec<Arc<Table>>]) -> Levels {
+        Levels {
+            wal,
+            levels: levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect(),
+        }
+    }
+
+    pub(super) fn load(dir: &Path) -> Result<Option<Levels>> {
+        match fs::read_to_string(dir.join(LEVELS_FILE)) {
+            Ok(text) => Ok(Some(toml::from_str(&text)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the list into `dir`, replacing the earlier one whole
+    pub(super) fn save(&self, dir: &Path) -> Result<()> {
+        let text = toml::to_string(self).map_err(|e| KvsError::Config {
+            key: LEVELS_FILE.to_owned(),
+            message: e.to_string(),
+        })?;
+        let staging = dir.join(format!("{}.tmp", LEVELS_FILE));
+        let mut file = File::create(&staging)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&staging, dir.join(LEVELS_FILE))?;
+        Ok(())
+    }
+}
+
+type Run = Box<dyn Iterator<Item = Result<Record>>>;
+
+/// Merges runs of records in key order into one, keeping each key's record
+/// from the earliest run that has it; runs are given newest first
+pub(super) struct Merge {
+    runs: Vec<Run>,
+    heads: Vec<Option<Record>>,
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+}
+
+impl Merge {
+    pub(super) fn new(mut runs: Vec<Run>) -> Result<Merge> {
+        let mut heads = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::new();
+        for (run, records) in runs.iter_mut().enumerate() {
+            let head = records.next().transpose()?;
+            if let Some((key, _)) = &head {
+                heap.push(Reverse((key.clone(), run)));
+            }
+            heads.push(head);
+        }
+        Ok(Merge { runs, heads, heap })
+    }
+
+    // Takes the head of `run` and moves the run on
+    fn advance(&mut self, run: usize) -> Result<Record> {
+        let record = self.heads[run]
+            .take()
+            .expect("every run in the heap has a head");
+        self.heads[run] = self.runs[run].next().transpose()?;
+        if let Some((key, _)) = &self.heads[run] {
+            self.heap.push(Reverse((key.clone(), run)));
+        }
+        Ok(record)
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Record>;
+
+    // Ties pop in run order, so the newest record comes first and the
+    // older ones for the same key are dropped
+    fn next(&mut self) -> Option<Result<Record>> {
+        let Reverse((key, run)) = self.heap.pop()?;
+        let record = match self.advance(run) {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        while let Some(Reverse((next, _))) = self.heap.peek() {
+            if *next != key {
+                break;
+            }
+            let Some(Reverse((_, older))) = self.heap.pop() else {
+                break;
+            };
+            if let Err(e) = self.advance(older) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(record))
+    }
+}
@@ -0,0 +1,6 @@
+mod levels;
+mod store;
+mod table;
+mod wal;
+
+pub use store::{LsmOptions, LsmStats, LsmStore};
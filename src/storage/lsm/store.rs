@@ -0,0 +1,616 @@
+use super::levels::{Levels, Merge};
+use super::table::{Record, TABLE_EXT, Table, TableBuilder, table_path};
+use super::wal::{self, WAL_EXT, Wal, wal_path};
+use crate::storage::lock::DirLock;
+use crate::storage::start_backup;
+use crate::{Engine, KvsError, Result, StoreTrait};
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+const MEMTABLE_BYTES: u64 = 4 * 1024 * 1024; // 4 MB
+const TABLE_BYTES: u64 = 2 * 1024 * 1024; // 2 MB
+const BLOCK_BYTES: u64 = 4 * 1024; // 4 KB
+const LEVEL0_TABLES: usize = 4;
+const LEVEL1_BYTES: u64 = 10 * 1024 * 1024; // 10 MB
+
+// Each level below 1 holds this many times the bytes of the one above
+const LEVEL_MULTIPLIER: u64 = 10;
+const LEVELS: usize = 7;
+
+/// Tuning knobs for `LsmStore`
+#[derive(Debug, Clone, Copy)]
+pub struct LsmOptions {
+    /// Flush the memtable to a level 0 table once this many bytes have been
+    /// written to it
+    pub memtable_bytes: u64,
+    /// Compaction starts a new table past this size
+    pub table_bytes: u64,
+    /// Bytes of records per block; a table keeps one key per block in memory
+    pub block_bytes: u64,
+    /// Merge level 0 into level 1 once it holds this many tables
+    pub level0_tables: usize,
+    /// Bytes level 1 holds before compacting into level 2; each deeper
+    /// level holds ten times more
+    pub level1_bytes: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> LsmOptions {
+        LsmOptions {
+            memtable_bytes: MEMTABLE_BYTES,
+            table_bytes: TABLE_BYTES,
+            block_bytes: BLOCK_BYTES,
+            level0_tables: LEVEL0_TABLES,
+            level1_bytes: LEVEL1_BYTES,
+        }
+    }
+}
+
+/// The shape of an `LsmStore`, and what it has written since it was opened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LsmStats {
+    /// Tables and their bytes in each level, level 0 first
+    pub levels: Vec<(usize, u64)>,
+    /// Bytes of keys and values handed to `set` and `remove`
+    pub user_bytes: u64,
+    /// Bytes appended to the write-ahead log
+    pub wal_bytes: u64,
+    /// Bytes of tables written by memtable flushes
+    pub flushed_bytes: u64,
+    /// Bytes of tables written by compactions
+    pub compacted_bytes: u64,
+}
+
+impl LsmStats {
+    /// Bytes written to disk per byte handed to the store
+    pub fn write_amplification(&self) -> f64 {
+        let written = self.wal_bytes + self.flushed_bytes + self.compacted_bytes;
+        written as f64 / self.user_bytes.max(1) as f64
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    user_bytes: AtomicU64,
+    wal_bytes: AtomicU64,
+    flushed_bytes: AtomicU64,
+    compacted_bytes: AtomicU64,
+}
+
+// What reads see. Only the holder of the log lock changes the tables.
+#[derive(Debug, Default)]
+struct State {
+    memtable: BTreeMap<String, Option<String>>,
+    // Written to the memtable since the last flush
+    memtable_bytes: u64,
+    // Level 0 newest first, where tables may overlap; deeper levels in key
+    // order, where they do not
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl State {
+    fn apply(&mut self, (key, value): Record) {
+        self.memtable_bytes += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+        self.memtable.insert(key, value);
+    }
+}
+
+/// A log-structured merge tree of string key/value pairs
+///
+/// Writes go to a write-ahead log and a sorted in-memory memtable, which is
+/// flushed to an immutable sorted table in level 0 once it fills up. Level
+/// 0 tables are merged into level 1, and each full level into the next, so
+/// every level below 0 is one sorted run of non-overlapping tables. Tables
+/// carry a bloom filter and a block index, so a lookup reads at most one
+/// block from each table it cannot rule out.
+///
+/// # Example
+///
+/// ```
+/// use tempfile::TempDir;
+/// use kvs::{LsmStore, Result, StoreTrait};
+/// use std::ops::Bound;
+///
+/// fn main() -> Result<()> {
+///     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+///     let store = LsmStore::open(temp_dir.path().to_path_buf())?;
+///
+///     store.set("b".to_string(), "2".to_string())?;
+///     store.set("a".to_string(), "1".to_string())?;
+///     let mut keys = vec![];
+///     store.scan_range(
+///         (Bound::Included("a".to_string()), Bound::Unbounded),
+///         &mut |key, _| Ok(keys.push(key)),
+///     )?;
+///     assert_eq!(keys, vec!["a", "b"]);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmStore {
+    dir: PathBuf,
+    // None when opened read-only. Held by every write, flush and compaction.
+    wal: Option<Arc<Mutex<Wal>>>,
+    state: Arc<RwLock<State>>,
+    // For tables and logs alike
+    next_id: Arc<AtomicU64>,
+    options: LsmOptions,
+    counters: Arc<Counters>,
+    _lock: Arc<DirLock>,
+}
+
+impl LsmStore {
+    /// Create an LSM store
+    pub fn open(dir_path: PathBuf) -> Result<LsmStore> {
+        LsmStore::open_with_options(dir_path, LsmOptions::default())
+    }
+    /// Create an LSM store with non-default options
+    pub fn open_with_options(dir_path: PathBuf, options: LsmOptions) -> Result<LsmStore> {
+        LsmStore::open_inner(dir_path, options, false)
+    }
+    /// Open an existing store for reading only
+    ///
+    /// Like `KvStore::open_read_only`, readers share the directory with each
+    /// other but not with a writer.
+    pub fn open_read_only(dir_path: PathBuf) -> Result<LsmStore> {
+        LsmStore::open_inner(dir_path, LsmOptions::default(), true)
+    }
+    fn open_inner(dir_path: PathBuf, options: LsmOptions, read_only: bool) -> Result<LsmStore> {
+        let lock = match read_only {
+            true => DirLock::shared(&dir_path)?,
+            false => DirLock::exclusive(&dir_path)?,
+        };
+
+        let saved = Levels::load(&dir_path)?;
+        let listed = saved.as_ref().map_or(vec![], |saved| saved.levels.clone());
+        if listed.len() > LEVELS {
+            return Err(KvsError::CorruptedLog);
+        }
+        let mut levels: Vec<Vec<Arc<Table>>> = vec![vec![]; LEVELS];
+        for (level, ids) in listed.iter().enumerate() {
+            for &id in ids {
+                levels[level].push(Arc::new(Table::open(&dir_path, id)?));
+            }
+        }
+        let wal_id = saved.as_ref().map_or(1, |saved| saved.wal);
+
+        // Ids are never reused, so count the leftovers a crash part way
+        // through a flush or compaction may have left, before removing them
+        let mut last_id = wal_id;
+        for entry in fs::read_dir(&dir_path)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some((id, ext)) = name.split_once('.') else {
+                continue;
+            };
+            let Ok(id) = id.parse::<u64>() else {
+                continue;
+            };
+            last_id = last_id.max(id);
+            let leftover = match ext {
+                TABLE_EXT => !listed.iter().flatten().any(|&listed| listed == id),
+                WAL_EXT => id != wal_id,
+                _ => ext.ends_with(".tmp"),
+            };
+            if leftover && !read_only {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let mut state = State {
+            levels,
+            ..State::default()
+        };
+        let wal = match read_only {
+            true => {
+                wal::replay(&dir_path, wal_id, &mut |record| state.apply(record))?;
+                None
+            }
+            false => {
+                let wal = Wal::open(&dir_path, wal_id, &mut |record| state.apply(record))?;
+                if saved.is_none() {
+                    Levels::of(wal_id, &[]).save(&dir_path)?;
+                }
+                Some(Arc::new(Mutex::new(wal)))
+            }
+        };
+
+        Ok(LsmStore {
+            dir: dir_path,
+            wal,
+            state: Arc::new(RwLock::new(state)),
+            next_id: Arc::new(AtomicU64::new(last_id + 1)),
+            options,
+            counters: Arc::new(Counters::default()),
+            _lock: Arc::new(lock),
+        })
+    }
+    /// Tables per level and bytes written since the store was opened
+    pub fn stats(&self) -> Result<LsmStats> {
+        let state = self.read_state()?;
+        Ok(LsmStats {
+            levels: state
+                .levels
+                .iter()
+                .map(|tables| (tables.len(), tables.iter().map(|table| table.size).sum()))
+                .collect(),
+            user_bytes: self.counters.user_bytes.load(Ordering::Relaxed),
+            wal_bytes: self.counters.wal_bytes.load(Ordering::Relaxed),
+            flushed_bytes: self.counters.flushed_bytes.load(Ordering::Relaxed),
+            compacted_bytes: self.counters.compacted_bytes.load(Ordering::Relaxed),
+        })
+    }
+    /// Writes the memtable to a level 0 table and runs any compaction that
+    /// is due, so a restart has no log to replay
+    pub fn flush_memtable(&self) -> Result<()> {
+        let mut wal = self.wal()?;
+        self.flush_locked(&mut wal)?;
+        self.compact_locked(&wal)
+    }
+    /// Remove every key
+    pub fn clear(&self) -> Result<()> {
+        let mut wal = self.wal()?;
+        let new_wal = Wal::create(&self.dir, self.next_id())?;
+        Levels::of(new_wal.id, &[]).save(&self.dir)?;
+
+        let old_levels = {
+            let mut state = self.write_state()?;
+            state.memtable.clear();
+            state.memtable_bytes = 0;
+            std::mem::replace(&mut state.levels, vec![vec![]; LEVELS])
+        };
+        let old_wal = std::mem::replace(&mut *wal, new_wal);
+        fs::remove_file(wal_path(&self.dir, old_wal.id))?;
+        for table in old_levels.iter().flatten() {
+            fs::remove_file(table_path(&self.dir, table.id))?;
+        }
+        Ok(())
+    }
+
+    fn wal(&self) -> Result<MutexGuard<'_, Wal>> {
+        let wal = self.wal.as_ref().ok_or(KvsError::ReadOnly)?;
+        wal.lock().map_err(|_| KvsError::LockPoisoned)
+    }
+    fn read_state(&self) -> Result<std::sync::RwLockReadGuard<'_, State>> {
+        self.state.read().map_err(|_| KvsError::LockPoisoned)
+    }
+    fn write_state(&self) -> Result<std::sync::RwLockWriteGuard<'_, State>> {
+        self.state.write().map_err(|_| KvsError::LockPoisoned)
+    }
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+    // Callers hold the log lock, so it is passed in rather than re-acquired
+    fn write(
+        &self,
+        wal: &mut MutexGuard<'_, Wal>,
+        key: String,
+        value: Option<String>,
+    ) -> Result<()> {
+        let written = wal.append(&key, value.as_deref())?;
+        let user_bytes = (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+        self.counters
+            .user_bytes
+            .fetch_add(user_bytes, Ordering::Relaxed);
+        self.counters
+            .wal_bytes
+            .fetch_add(written, Ordering::Relaxed);
+
+        let full = {
+            let mut state = self.write_state()?;
+            state.apply((key, value));
+            state.memtable_bytes >= self.options.memtable_bytes
+        };
+        if full {
+            self.flush_locked(wal)?;
+            self.compact_locked(wal)?;
+        }
+        Ok(())
+    }
+    // The new table and log are recorded in `LEVELS` before the memtable is
+    // dropped, so a crash part way replays the old log over the table it
+    // already went into, which changes nothing
+    fn flush_locked(&self, wal: &mut Wal) -> Result<()> {
+        let table = {
+            let state = self.read_state()?;
+            if state.memtable.is_empty() {
+                return Ok(());
+            }
+            let mut builder =
+                TableBuilder::new(&self.dir, self.next_id(), self.options.block_bytes)?;
+            for (key, value) in &state.memtable {
+                builder.add(key, value.as_deref())?;
+            }
+            Arc::new(builder.finish()?)
+        };
+        self.counters
+            .flushed_bytes
+            .fetch_add(table.size, Ordering::Relaxed);
+
+        let new_wal = Wal::create(&self.dir, self.next_id())?;
+        let mut levels = self.read_state()?.levels.clone();
+        levels[0].insert(0, table);
+        Levels::of(new_wal.id, &levels).save(&self.dir)?;
+        {
+            let mut state = self.write_state()?;
+            state.levels = levels;
+            state.memtable.clear();
+            state.memtable_bytes = 0;
+        }
+
+        let old_wal = std::mem::replace(wal, new_wal);
+        fs::remove_file(wal_path(&self.dir, old_wal.id))?;
+        Ok(())
+    }
+    // Level 0 is merged whole into level 1 once it has too many tables;
+    // after that, the oldest table of the first level past its size goes
+    // down into the next, until every level fits
+    fn compact_locked(&self, wal: &Wal) -> Result<()> {
+        loop {
+            let levels = self.read_state()?.levels.clone();
+            let picked = match levels[0].len() >= self.options.level0_tables {
+                true => Some((0, levels[0].clone())),
+                false => (1..LEVELS - 1).find_map(|level| {
+                    let bytes: u64 = levels[level].iter().map(|table| table.size).sum();
+                    let limit = self.options.level1_bytes * LEVEL_MULTIPLIER.pow(level as u32 - 1);
+                    let oldest = levels[level].iter().min_by_key(|table| table.id)?;
+                    (bytes > limit).then(|| (level, vec![Arc::clone(oldest)]))
+                }),
+            };
+            let Some((level, inputs)) = picked else {
+                return Ok(());
+            };
+            self.compact_tables(levels, level, inputs, wal)?;
+        }
+    }
+    // Merges `inputs` from `level` with the tables they overlap one level
+    // down, replacing all of them with new tables there
+    fn compact_tables(
+        &self,
+        mut levels: Vec<Vec<Arc<Table>>>,
+        level: usize,
+        inputs: Vec<Arc<Table>>,
+        wal: &Wal,
+    ) -> Result<()> {
+        let smallest = inputs.iter().map(|table| table.smallest.clone()).min();
+        let largest = inputs.iter().map(|table| table.largest.clone()).max();
+        let (Some(smallest), Some(largest)) = (smallest, largest) else {
+            return Ok(());
+        };
+        let range = (Bound::Included(smallest), Bound::Included(largest));
+        let overlapping: Vec<Arc<Table>> = levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(&range))
+            .cloned()
+            .collect();
+
+        let outputs = match (inputs.len(), overlapping.is_empty()) {
+            // Nothing to merge with, so the table moves down as it is
+            (1, true) => inputs.clone(),
+            _ => {
+                // Below the last level holding data, nothing is left for a
+                // removal to hide
+                let bottom = levels[level + 2..].iter().all(Vec::is_empty);
+                let mut runs: Vec<Box<dyn Iterator<Item = Result<Record>>>> = vec![];
+                for table in &inputs {
+                    runs.push(Box::new(table.iter(&Bound::Unbounded)));
+                }
+                let older = overlapping.clone();
+                runs.push(Box::new(
+                    older
+                        .into_iter()
+                        .flat_map(|table| table.iter(&Bound::Unbounded)),
+                ));
+                self.write_tables(Merge::new(runs)?, bottom)?
+            }
+        };
+
+        let replaced = |table: &Arc<Table>| {
+            inputs
+                .iter()
+                .chain(&overlapping)
+                .any(|old| old.id == table.id)
+        };
+        levels[level].retain(|table| !replaced(table));
+        levels[level + 1].retain(|table| !replaced(table));
+        levels[level + 1].extend(outputs.iter().cloned());
+        levels[level + 1].sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        Levels::of(wal.id, &levels).save(&self.dir)?;
+        self.write_state()?.levels = levels;
+
+        for table in inputs.iter().chain(&overlapping) {
+            if !outputs.iter().any(|output| output.id == table.id) {
+                fs::remove_file(table_path(&self.dir, table.id))?;
+            }
+        }
+        Ok(())
+    }
+    // Splits merged records into tables of about `table_bytes`
+    fn write_tables(&self, records: Merge, drop_removals: bool) -> Result<Vec<Arc<Table>>> {
+        let mut tables = vec![];
+        let mut builder: Option<TableBuilder> = None;
+        for record in records {
+            let (key, value) = record?;
+            if value.is_none() && drop_removals {
+                continue;
+            }
+            let current = match &mut builder {
+                Some(builder) => builder,
+                None => builder.insert(TableBuilder::new(
+                    &self.dir,
+                    self.next_id(),
+                    self.options.block_bytes,
+                )?),
+            };
+            current.add(&key, value.as_deref())?;
+            if current.size() >= self.options.table_bytes {
+                tables.extend(builder.take().map(TableBuilder::finish).transpose()?);
+            }
+        }
+        tables.extend(builder.map(TableBuilder::finish).transpose()?);
+
+        let written: u64 = tables.iter().map(|table| table.size).sum();
+        self.counters
+            .compacted_bytes
+            .fetch_add(written, Ordering::Relaxed);
+        Ok(tables.into_iter().map(Arc::new).collect())
+    }
+}
+
+impl StoreTrait for LsmStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        // An empty value frames the same as a removal
+        if value.is_empty() {
+            return Err(KvsError::EmptyValue);
+        }
+        let mut wal = self.wal()?;
+        self.write(&mut wal, key, Some(value))
+    }
+
+    /// Looks in the memtable, then in each table that may hold `key`, newest
+    /// first
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let tables = {
+            let state = self.read_state()?;
+            if let Some(value) = state.memtable.get(&key) {
+                return Ok(value.clone());
+            }
+            let mut tables: Vec<Arc<Table>> = state.levels[0].clone();
+            for level in &state.levels[1..] {
+                let i = level.partition_point(|table| table.largest < key);
+                if let Some(table) = level.get(i).filter(|table| table.smallest <= key) {
+                    tables.push(Arc::clone(table));
+                }
+            }
+            tables
+        };
+        for table in tables {
+            if let Some(value) = table.get(&key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut wal = self.wal()?;
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write(&mut wal, key, None)
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self.wal {
+            Some(_) => self.wal()?.sync(),
+            None => Ok(()),
+        }
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.scan_range((Bound::Unbounded, Bound::Unbounded), visit)
+    }
+
+    /// Merges the memtable and the tables the range overlaps, reading each
+    /// table a block at a time
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        visit: &mut dyn FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        if empty_range(&range) {
+            return Ok(());
+        }
+        let mut runs: Vec<Box<dyn Iterator<Item = Result<Record>>>> = vec![];
+        {
+            let state = self.read_state()?;
+            let memtable: Vec<Record> = state
+                .memtable
+                .range::<String, _>(range.clone())
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            runs.push(Box::new(memtable.into_iter().map(Ok)));
+            for table in state.levels[0]
+                .iter()
+                .filter(|table| table.overlaps(&range))
+            {
+                runs.push(Box::new(table.iter(&range.0)));
+            }
+            for level in &state.levels[1..] {
+                let tables: Vec<Arc<Table>> = level
+                    .iter()
+                    .filter(|table| table.overlaps(&range))
+                    .cloned()
+                    .collect();
+                let start = range.0.clone();
+                runs.push(Box::new(
+                    tables.into_iter().flat_map(move |table| table.iter(&start)),
+                ));
+            }
+        }
+
+        for record in Merge::new(runs)? {
+            let (key, value) = record?;
+            if !range.contains(&key) {
+                break;
+            }
+            if let Some(value) = value {
+                visit(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Links the tables into `dest` and writes the memtable there as its
+    /// log. The log lock keeps compaction from deleting a table before it
+    /// is linked; a read-only store has no compaction.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        start_backup(dest, Engine::Lsm)?;
+        let _wal = match self.wal {
+            Some(_) => Some(self.wal()?),
+            None => None,
+        };
+
+        let (memtable, levels) = {
+            let state = self.read_state()?;
+            (state.memtable.clone(), state.levels.clone())
+        };
+        for table in levels.iter().flatten() {
+            let (src, target) = (table_path(&self.dir, table.id), table_path(dest, table.id));
+            if fs::hard_link(&src, &target).is_err() {
+                // e.g. the backup is on another filesystem
+                fs::copy(&src, &target)?;
+            }
+        }
+
+        let wal_id = levels
+            .iter()
+            .flatten()
+            .map(|table| table.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut wal = Wal::create(dest, wal_id)?;
+        for (key, value) in &memtable {
+            wal.append(key, value.as_deref())?;
+        }
+        wal.sync()?;
+        Levels::of(wal_id, &levels).save(dest)
+    }
+}
+
+// `BTreeMap::range` panics on these
+fn empty_range(range: &(Bound<String>, Bound<String>)) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
@@ -0,0 +1,361 @@
+use crate::storage::bloom::Bloom;
+use crate::storage::kvstore::{entry_sizes, read_exact_at};
+use crate::{Entry, KvsError, Result};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub(super) const TABLE_EXT: &str = "sst";
+
+const TABLE_MAGIC: [u8; 8] = *b"KVSTABLE";
+
+// `[index offset u64][index length u64][bloom offset u64][bloom length u64]
+// [records u64][magic]`
+const FOOTER_LEN: u64 = 48;
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}.{TABLE_EXT}"))
+}
+
+/// A key and its value, `None` where the key was removed
+pub(super) type Record = (String, Option<String>);
+
+/// Frames a record as segments frame an entry; a removal has no value
+pub(super) fn encode_record(key: &str, value: Option<&str>, out: &mut Vec<u8>) {
+    let value = value.unwrap_or_default();
+    out.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    out.extend_from_slice(key.as_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// The record at the start of `bytes` and its length, or `None` if
+/// `bytes` stops part way through it
+pub(super) fn decode_record(bytes: &[u8]) -> io::Result<Option<(Record, usize)>> {
+    if bytes.len() < 16 {
+        return Ok(None);
+    }
+    let (key_size, value_size) = entry_sizes(&bytes[..16]);
+    let length = match key_size.checked_add(value_size) {
+        Some(body) if body <= bytes.len() as u64 - 16 => 16 + body as usize,
+        _ => return Ok(None),
+    };
+    let record = match Entry::deserialize(&bytes[..length])? {
+        Entry::Set { key, value } => (key, Some(value)),
+        Entry::Remove { key } => (key, None),
+        Entry::Blob { key, .. } => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("blob pointer for {:?} in an LSM file", key),
+            ));
+        }
+    };
+    Ok(Some((record, length)))
+}
+
+/// Writes a table from records given in key order
+///
+/// The table is written beside its final name and renamed into place by
+/// `finish`, so a table file is either whole or missing.
+#[derive(Debug)]
+pub(super) struct TableBuilder {
+    dir: PathBuf,
+    id: u64,
+    temp: PathBuf,
+    out: BufWriter<File>,
+    block_bytes: u64,
+    block: Vec<u8>,
+    smallest: Option<String>,
+    last_key: String,
+    blocks: Vec<(String, u64, u64)>,
+    hashes: Vec<u64>,
+    offset: u64,
+}
+
+impl TableBuilder {
+    pub(super) fn new(dir: &Path, id: u64, block_bytes: u64) -> Result<TableBuilder> {
+        let temp = table_path(dir, id).with_extension("sst.tmp");
+        Ok(TableBuilder {
+            dir: dir.to_path_buf(),
+            id,
+            out: BufWriter::new(File::create(&temp)?),
+            temp,
+            block_bytes,
+            block: vec![],
+            smallest: None,
+            last_key: String::new(),
+            blocks: vec![],
+            hashes: vec![],
+            offset: 0,
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        encode_record(key, value, &mut self.block);
+        self.hashes.push(Bloom::hash(key));
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_owned());
+        }
+        key.clone_into(&mut self.last_key);
+        if self.block.len() as u64 >= self.block_bytes {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, counting the open block
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let Some(smallest) = self.smallest.take() else {
+            return Err(KvsError::Compaction(
+                "an LSM table needs a record".to_owned(),
+            ));
+        };
+
+        // Index: the smallest key, then each block's last key and place
+        let mut index = vec![];
+        for (key, offset, length) in [(smallest, 0, 0)].into_iter().chain(self.blocks.drain(..)) {
+            index.extend_from_slice(&(key.len() as u64).to_le_bytes());
+            index.extend_from_slice(key.as_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&length.to_le_bytes());
+        }
+        let mut bloom = Bloom::new(self.hashes.len() as u64);
+        for &hash in &self.hashes {
+            bloom.insert_hash(hash);
+        }
+        let bloom = bloom.to_bytes();
+
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + index.len() as u64;
+        self.out.write_all(&index)?;
+        self.out.write_all(&bloom)?;
+        for field in [
+            index_offset,
+            index.len() as u64,
+            bloom_offset,
+            bloom.len() as u64,
+            self.hashes.len() as u64,
+        ] {
+            self.out.write_all(&field.to_le_bytes())?;
+        }
+        self.out.write_all(&TABLE_MAGIC)?;
+        self.out
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&self.temp, table_path(&self.dir, self.id))?;
+
+        Table::open(&self.dir, self.id)
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.out.write_all(&self.block)?;
+        let length = self.block.len() as u64;
+        self.blocks
+            .push((self.last_key.clone(), self.offset, length));
+        self.offset += length;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// A sorted, immutable table file, with its bloom filter and the last key
+/// of each data block in memory
+#[derive(Debug)]
+pub(super) struct Table {
+    pub(super) id: u64,
+    /// Bytes in the file
+    pub(super) size: u64,
+    pub(super) smallest: String,
+    pub(super) largest: String,
+    file: File,
+    bloom: Bloom,
+    // Last key, offset and length of each data block
+    blocks: Vec<(String, u64, u64)>,
+}
+
+impl Table {
+    pub(super) fn open(dir: &Path, id: u64) -> Result<Table> {
+        let file = File::open(table_path(dir, id))?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(KvsError::CorruptedLog);
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        read_exact_at(&file, &mut footer, size - FOOTER_LEN)?;
+        let field = |i: usize| u64::from_le_bytes(footer[i..i + 8].try_into().unwrap());
+        let (index_offset, index_len, bloom_offset, bloom_len) =
+            (field(0), field(8), field(16), field(24));
+        if footer[40..] != TABLE_MAGIC
+            || index_offset.checked_add(index_len) != Some(bloom_offset)
+            || bloom_offset.checked_add(bloom_len) != Some(size - FOOTER_LEN)
+        {
+            return Err(KvsError::CorruptedLog);
+        }
+
+        let mut index = vec![0; index_len as usize];
+        read_exact_at(&file, &mut index, index_offset)?;
+        let mut entries = vec![];
+        let mut rest = &index[..];
+        while !rest.is_empty() {
+            let corrupted = || KvsError::CorruptedLog;
+            let key_size = rest
+                .get(..8)
+                .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(corrupted)?;
+            let end = usize::try_from(key_size)?
+                .checked_add(24)
+                .filter(|&end| end <= rest.len())
+                .ok_or_else(corrupted)?;
+            let key = String::from_utf8(rest[8..end - 16].to_vec())?;
+            let offset = u64::from_le_bytes(rest[end - 16..end - 8].try_into().unwrap());
+            let length = u64::from_le_bytes(rest[end - 8..end].try_into().unwrap());
+            entries.push((key, offset, length));
+            rest = &rest[end..];
+        }
+
+        let mut bloom = vec![0; bloom_len as usize];
+        read_exact_at(&file, &mut bloom, bloom_offset)?;
+        let bloom = Bloom::from_bytes(&bloom).ok_or(KvsError::CorruptedLog)?;
+
+        let mut entries = entries.into_iter();
+        let smallest = entries.next().ok_or(KvsError::CorruptedLog)?.0;
+        let blocks: Vec<(String, u64, u64)> = entries.collect();
+        let largest = blocks.last().ok_or(KvsError::CorruptedLog)?.0.clone();
+        if blocks
+            .iter()
+            .any(|(_, offset, length)| offset.saturating_add(*length) > index_offset)
+        {
+            return Err(KvsError::CorruptedLog);
+        }
+
+        Ok(Table {
+            id,
+            size,
+            smallest,
+            largest,
+            file,
+            bloom,
+            blocks,
+        })
+    }
+
+    /// The table's record for `key`: `Some(None)` when it removed the key
+    pub(super) fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.smallest.as_str() || key > self.largest.as_str() || !self.bloom.contains(key)
+        {
+            return Ok(None);
+        }
+        let block = self
+            .blocks
+            .partition_point(|(last, _, _)| last.as_str() < key);
+        if block == self.blocks.len() {
+            return Ok(None);
+        }
+        let records = self.read_block(block)?;
+        Ok(records
+            .binary_search_by(|(found, _)| found.as_str().cmp(key))
+            .ok()
+            .map(|i| records[i].1.clone()))
+    }
+
+    /// Whether any key in `range` may be in the table
+    pub(super) fn overlaps(&self, range: &(Bound<String>, Bound<String>)) -> bool {
+        let after_start = match &range.0 {
+            Bound::Included(start) => self.largest >= *start,
+            Bound::Excluded(start) => self.largest > *start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match &range.1 {
+            Bound::Included(end) => self.smallest <= *end,
+            Bound::Excluded(end) => self.smallest < *end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// The table's records from `start` on, in key order
+    pub(super) fn iter(self: &Arc<Table>, start: &Bound<String>) -> TableIter {
+        let block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .blocks
+                .partition_point(|(last, _, _)| last.as_str() < key.as_str()),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table: Arc::clone(self),
+            next_block: block,
+            records: vec![].into_iter(),
+            start: start.clone(),
+        }
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Record>> {
+        let (_, offset, length) = &self.blocks[block];
+        let mut bytes = vec![0; *length as usize];
+        read_exact_at(&self.file, &mut bytes, *offset)?;
+
+        let mut records = vec![];
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let (record, length) = decode_record(rest)?.ok_or(KvsError::CorruptedLog)?;
+            records.push(record);
+            rest = &rest[length..];
+        }
+        Ok(records)
+    }
+}
+
+/// Reads a table a block at a time
+#[derive(Debug)]
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    records: std::vec::IntoIter<Record>,
+    // Records before it are skipped; only the first block holds any
+    start: Bound<String>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        loop {
+            if let Some(record) = self.records.next() {
+                let before_start = match &self.start {
+                    Bound::Included(start) => record.0 < *start,
+                    Bound::Excluded(start) => record.0 <= *start,
+                    Bound::Unbounded => false,
+                };
+                if before_start {
+                    continue;
+                }
+                self.start = Bound::Unbounded;
+                return Some(Ok(record));
+            }
+            if self.next_block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(records) => {
+                    self.records = records.into_iter();
+                    self.next_block += 1;
+                }
+                Err(e) => {
+                    self.next_block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use super::table::{Record, decode_record, encode_record};
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+pub(super) const WAL_EXT: &str = "wal";
+
+pub(super) fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}.{WAL_EXT}"))
+}
+
+/// The write-ahead log behind the memtable: each write is appended here
+/// before the memtable sees it, and replayed on open until a flush has put
+/// the memtable in a table
+///
+/// Each record is `[crc u32]` followed by the record framed as in a table.
+#[derive(Debug)]
+pub(super) struct Wal {
+    pub(super) id: u64,
+    file: File,
+}
+
+impl Wal {
+    /// Starts the empty log `id`
+    pub(super) fn create(dir: &Path, id: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(wal_path(dir, id))?;
+        Ok(Wal { id, file })
+    }
+
+    /// Replays log `id` into `visit` and continues it, dropping a torn
+    /// record a crash left at the end
+    pub(super) fn open(dir: &Path, id: u64, visit: &mut dyn FnMut(Record)) -> Result<Wal> {
+        let path = wal_path(dir, id);
+        let (whole, size) = match fs::read(&path) {
+            Ok(bytes) => (replay_bytes(&bytes, visit), bytes.len() as u64),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if whole < size {
+            warn!(
+                "{}.{} ends in a torn record at offset {}: dropped bytes {}..{}",
+                id, WAL_EXT, whole, whole, size
+            );
+            file.set_len(whole)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(whole))?;
+        Ok(Wal { id, file })
+    }
+
+    /// Appends the record, returning the bytes written
+    pub(super) fn append(&mut self, key: &str, value: Option<&str>) -> Result<u64> {
+        let mut record = vec![0; 4];
+        encode_record(key, value, &mut record);
+        let crc = crc32fast::hash(&record[4..]);
+        record[..4].copy_from_slice(&crc.to_le_bytes());
+        self.file.write_all(&record)?;
+        Ok(record.len() as u64)
+    }
+
+    pub(super) fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Replays log `id` without touching it, for a read-only store
+pub(super) fn replay(dir: &Path, id: u64, visit: &mut dyn FnMut(Record)) -> Result<()> {
+    match fs::read(wal_path(dir, id)) {
+        Ok(bytes) => {
+            replay_bytes(&bytes, visit);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Returns how many bytes hold whole records; whatever follows is a torn
+// write, as nothing is appended after it
+fn replay_bytes(bytes: &[u8], visit: &mut dyn FnMut(Record)) -> u64 {
+    let mut offset = 0;
+    while bytes.len() - offset > 4 {
+        let crc = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let Ok(Some((record, length))) = decode_record(&bytes[offset + 4..]) else {
+            break;
+        };
+        if crc32fast::hash(&bytes[offset + 4..offset + 4 + length]) != crc {
+            break;
+        }
+        visit(record);
+        offset += 4 + length;
+    }
+    offset as u64
+}
@@ -0,0 +1,171 @@
+use super::Engine;
+use crate::{KvsError, Result, StorageConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Describes what wrote a data or backup directory
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Layout version this build writes
+pub const FORMAT_VERSION: u32 = 1;
+
+// Version 0 directories only held a file naming the engine
+const LEGACY_ENGINE_FILE: &str = "engine";
+
+// Step `n` brings a directory from format version `n` to `n + 1`
+type Upgrade = fn(&Path, Engine) -> Result<()>;
+
+const UPGRADES: [Upgrade; FORMAT_VERSION as usize] = [from_legacy];
+
+/// Contents of a directory's `MANIFEST`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub engine: Engine,
+    pub format_version: u32,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    /// Options the directory was created with; backups do not record them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<ManifestOptions>,
+}
+
+/// Storage options recorded in a `Manifest`, for reference only: the
+/// server's configuration decides what is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestOptions {
+    pub max_segment_bytes: u64,
+    pub compaction_threshold_bytes: u64,
+    pub sled_cache_bytes: u64,
+}
+
+impl From<&StorageConfig> for ManifestOptions {
+    fn from(config: &StorageConfig) -> ManifestOptions {
+        ManifestOptions {
+            max_segment_bytes: config.max_segment_bytes,
+            compaction_threshold_bytes: config.compaction_threshold_bytes,
+            sled_cache_bytes: config.sled_cache_bytes,
+        }
+    }
+}
+
+impl Manifest {
+    /// A manifest for a directory created now
+    pub fn new(engine: Engine, options: Option<ManifestOptions>) -> Manifest {
+        Manifest {
+            engine,
+            format_version: FORMAT_VERSION,
+            created_at: seconds(SystemTime::now()),
+            options,
+        }
+    }
+
+    /// The manifest in `dir`, if any. A directory from before manifests
+    /// reads as format version 0.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+            Ok(text) => return Ok(Some(toml::from_str(&text)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let legacy = dir.join(LEGACY_ENGINE_FILE);
+        match fs::read_to_string(&legacy) {
+            Ok(engine) => Ok(Some(Manifest {
+                engine: engine.trim().parse()?,
+                format_version: 0,
+                created_at: seconds(fs::metadata(&legacy)?.modified()?),
+                options: None,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the manifest into `dir`, replacing any earlier one whole
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let text = toml::to_string(self).map_err(|e| KvsError::Config {
+            key: MANIFEST_FILE.to_owned(),
+            message: e.to_string(),
+        })?;
+        let staging = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&staging)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&staging, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    /// The manifest of the data directory `dir` for opening it with
+    /// `config`, creating it for a new directory and upgrading an older one
+    pub fn open(dir: &Path, config: &StorageConfig) -> Result<Manifest> {
+        let Some(mut manifest) = Manifest::load(dir)? else {
+            let manifest = Manifest::new(config.engine, Some(ManifestOptions::from(config)));
+            manifest.save(dir)?;
+            return Ok(manifest);
+        };
+
+        manifest.check(config.engine)?;
+        if manifest.format_version < FORMAT_VERSION {
+            manifest.upgrade(dir)?;
+        }
+        Ok(manifest)
+    }
+
+    /// Fails unless the directory holds `engine` data this build can read
+    pub fn check(&self, engine: Engine) -> Result<()> {
+        if self.engine != engine {
+            return Err(KvsError::WrongEngine {
+                found: self.engine,
+                wanted: engine,
+            });
+        }
+        self.check_version()
+    }
+
+    /// Fails if a newer build wrote the directory
+    pub fn check_version(&self) -> Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(KvsError::FormatVersion {
+                found: self.format_version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        Ok(())
+    }
+
+    // Each step is recorded as it finishes, so an interrupted upgrade picks
+    // up where it stopped
+    fn upgrade(&mut self, dir: &Path) -> Result<()> {
+        while self.format_version < FORMAT_VERSION {
+            UPGRADES[self.format_version as usize](dir, self.engine)?;
+            self.format_version += 1;
+            self.save(dir)?;
+            info!(
+                "Upgraded {} to format version {}",
+                dir.display(),
+                self.format_version
+            );
+        }
+
+        // Only once the manifest that replaces it is on disk
+        match fs::remove_file(dir.join(LEGACY_ENGINE_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Version 1 keeps the data exactly as version 0 laid it out
+fn from_legacy(_dir: &Path, _engine: Engine) -> Result<()> {
+    Ok(())
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
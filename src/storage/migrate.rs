@@ -0,0 +1,127 @@
+use super::{Storage, StoreTrait, sibling};
+use crate::common::hash64;
+use crate::{Engine, Entry, KvsError, Result, StorageConfig};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What `Storage::migrate` copied
+#[derive(Debug)]
+pub struct Migration {
+    pub from: Engine,
+    pub to: Engine,
+    /// Pairs copied
+    pub keys: u64,
+    /// Order-independent checksum of every pair, the same on both sides
+    pub checksum: u64,
+    /// Where the directory in the old engine was moved to; it is kept for
+    /// rolling back and can be deleted once the new engine is trusted
+    pub previous: PathBuf,
+}
+
+// Count and checksum of the pairs seen so far. Pairs are hashed one by one
+// and summed, since engines list them in different orders.
+#[derive(Default, PartialEq)]
+struct Tally {
+    keys: u64,
+    checksum: u64,
+}
+
+impl Tally {
+    fn add(&mut self, key: &str, value: &str) {
+        let entry = Entry::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        self.keys += 1;
+        self.checksum = self.checksum.wrapping_add(hash64(&entry.serialize()));
+    }
+}
+
+impl Storage {
+    /// Moves the data in `dir` to `config.engine`. The server must be stopped.
+    ///
+    /// Every pair is streamed into `<dir>.migrating` and read back to check
+    /// the count and checksum match. Only then is the new directory swapped
+    /// in, with the old one kept as `<dir>.<old engine>`. A failed migration
+    /// leaves `dir` untouched.
+    pub fn migrate(dir: &Path, config: &StorageConfig) -> Result<Migration> {
+        let from = Engine::detect(dir)?.ok_or_else(|| {
+            KvsError::Migration(format!("{} has no engine marker", dir.display()))
+        })?;
+        let to = config.engine;
+        if from == to {
+            return Err(KvsError::Migration(format!(
+                "{} already uses the {} engine",
+                dir.display(),
+                to
+            )));
+        }
+
+        let staging = sibling(dir, "migrating");
+        let previous = sibling(dir, &from.to_string());
+        if previous.exists() {
+            return Err(KvsError::Migration(format!(
+                "{} is in the way, remove it first",
+                previous.display()
+            )));
+        }
+        if staging.exists() {
+            // Left over from a failed migration
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        match copy(dir, &staging, from, config) {
+            Ok(tally) => {
+                fs::rename(dir, &previous)?;
+                fs::rename(&staging, dir)?;
+                Ok(Migration {
+                    from,
+                    to,
+                    keys: tally.keys,
+                    checksum: tally.checksum,
+                    previous,
+                })
+            }
+            Err(e) => {
+                fs::remove_dir_all(&staging)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn copy(dir: &Path, staging: &Path, from: Engine, config: &StorageConfig) -> Result<Tally> {
+    let source_config = StorageConfig {
+        engine: from,
+        ..config.clone()
+    };
+    let source = Storage::open(dir.to_path_buf(), &source_config)?;
+    let target = Storage::open(staging.to_path_buf(), config)?;
+
+    let mut copied = Tally::default();
+    source.scan(&mut |key, value| {
+        copied.add(&key, &value);
+        target.set(key, value)
+    })?;
+    target.flush()?;
+    if let Storage::Memory(store) = &target {
+        store.save(staging)?;
+    }
+    drop(target);
+
+    // Read back what reached the disk, not what the open store holds
+    let mut verified = Tally::default();
+    Storage::open(staging.to_path_buf(), config)?.scan(&mut |key, value| {
+        verified.add(&key, &value);
+        Ok(())
+    })?;
+
+    if copied != verified {
+        return Err(KvsError::Migration(format!(
+            "copied {} keys (checksum {:016x}) but read back {} (checksum {:016x})",
+            copied.keys, copied.checksum, verified.keys, verified.checksum
+        )));
+    }
+    Ok(copied)
+}
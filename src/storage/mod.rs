@@ -1,26 +1,55 @@
-use crate::{KvsError, Result};
+use crate::{KvsError, Result, StorageConfig};
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
 use std::fs;
-use std::path::PathBuf;
-use std::process::exit;
+use std::io::{self, BufRead, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
-use tracing::error;
+use std::thread;
+use std::time::Duration;
 
 // looks for file or folder with mod.rs
+mod async_store;
+mod bloom;
+mod btree;
+mod dump;
 mod kvmemory;
 mod kvsled;
 mod kvstore;
+mod lock;
+mod lsm;
+mod manifest;
+mod migrate;
 
-pub use kvmemory::KvMemory;
+pub use async_store::AsyncStore;
+pub use btree::{BTreeOptions, BTreeStats, BTreeStore};
+pub use dump::DumpFormat;
+pub use kvmemory::{KvMemory, KvMemoryOptions};
 pub use kvsled::KvSled;
-pub use kvstore::KvStore;
+pub use kvstore::{
+    BlobRef, CORRUPT_DIR, Compression, Damage, EncryptionKey, Entry, Fault, FaultInjector,
+    IndexMode, KEY_LEN, Keyring, KvStore, KvStoreOptions, LogBatch, LogInspector, LogPosition,
+    LogStats, Repair, SEGMENT_VERSION, SegmentInfo,
+};
+pub use lock::LOCK_FILE;
+pub use lsm::{LsmOptions, LsmStats, LsmStore};
+pub use manifest::{FORMAT_VERSION, MANIFEST_FILE, Manifest, ManifestOptions};
+pub use migrate::Migration;
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+const SLED_LOCK_ATTEMPTS: u32 = 50;
+const SLED_LOCK_RETRY: Duration = Duration::from_millis(20);
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Engine {
     Kvs,
     Sled,
     Memory,
+    Lsm,
+    #[value(name = "btree")]
+    BTree,
 }
 
 impl Display for Engine {
@@ -29,11 +58,20 @@ impl Display for Engine {
             Engine::Kvs => "kvs",
             Engine::Sled => "sled",
             Engine::Memory => "memory",
+            Engine::Lsm => "lsm",
+            Engine::BTree => "btree",
         };
         write!(f, "{}", s)
     }
 }
 
+impl Engine {
+    /// The engine recorded in a data or backup directory, if any.
+    pub fn detect(dir: &Path) -> Result<Option<Engine>> {
+        Ok(Manifest::load(dir)?.map(|manifest| manifest.engine))
+    }
+}
+
 impl FromStr for Engine {
     type Err = KvsError;
 
@@ -42,12 +80,15 @@ impl FromStr for Engine {
             "kvs" => Ok(Engine::Kvs),
             "sled" => Ok(Engine::Sled),
             "memory" => Ok(Engine::Memory),
+            "lsm" => Ok(Engine::Lsm),
+            "btree" => Ok(Engine::BTree),
             _ => Err(KvsError::Protocol(format!("Invalid engine: {}", s))),
         }
     }
 }
 
-pub trait StoreTrait: Clone + Send + 'static {
+// Engines are shared between worker threads by cloning, never behind a lock
+pub trait StoreTrait: Clone + Send + Sync + 'static {
     /// get the value of the given string key
     fn get(&self, key: String) -> Result<Option<String>>;
 
@@ -56,6 +97,50 @@ pub trait StoreTrait: Clone + Send + 'static {
 
     /// remove the value of the key
     fn remove(&self, key: String) -> Result<()>;
+
+    /// flush buffered writes to durable storage
+    fn flush(&self) -> Result<()>;
+
+    /// write a consistent snapshot into the new or empty directory `dest`,
+    /// while the store keeps serving
+    fn backup(&self, dest: &Path) -> Result<()>;
+
+    /// call `visit` with every key/value pair, in no particular order;
+    /// `visit` must not write to this store
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()>;
+
+    /// call `visit` with every pair whose key falls in `range`, in key
+    /// order; `visit` must not write to this store. Engines without sorted
+    /// storage sort the matches of a full `scan`.
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        visit: &mut dyn FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        let mut pairs = vec![];
+        self.scan(&mut |key, value| {
+            if range.contains(&key) {
+                pairs.push((key, value));
+            }
+            Ok(())
+        })?;
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        for (key, value) in pairs {
+            visit(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// stream every pair to `writer` as a dump, returning how many there were
+    fn export(&self, format: DumpFormat, writer: &mut dyn Write) -> Result<u64> {
+        dump::export(self, format, writer)
+    }
+
+    /// set every pair in the dump read from `reader`, in either format,
+    /// returning how many there were
+    fn import(&self, reader: &mut dyn BufRead) -> Result<u64> {
+        dump::import(self, reader)
+    }
 }
 
 #[derive(Clone)]
@@ -63,22 +148,176 @@ pub enum Storage {
     Kvs(KvStore),
     Sled(KvSled),
     Memory(KvMemory),
+    Lsm(LsmStore),
+    BTree(BTreeStore),
 }
 
 impl Storage {
     pub fn build(dir_path: PathBuf, engine: Engine) -> Result<Storage> {
-        // let _config = Config::from_file("config/config.toml")?;
+        let config = StorageConfig {
+            engine,
+            ..StorageConfig::default()
+        };
+        Storage::open(dir_path, &config)
+    }
 
-        check_engine(&dir_path, &engine)?;
+    pub fn open(dir_path: PathBuf, config: &StorageConfig) -> Result<Storage> {
+        let engine = config.engine;
+
+        Manifest::open(&dir_path, config)?;
 
         let store: Storage = match engine {
-            Engine::Kvs => Storage::Kvs(KvStore::open(dir_path)?),
-            Engine::Sled => Storage::Sled(KvSled::new(sled::open(&dir_path)?)),
-            Engine::Memory => Storage::Memory(KvMemory::new()),
+            Engine::Kvs => {
+                let options = KvStoreOptions::from(config);
+                Storage::Kvs(match config.keyring()? {
+                    Some(keys) => KvStore::open_with_keys(dir_path, options, keys)?,
+                    None => KvStore::open_with_options(dir_path, options)?,
+                })
+            }
+            Engine::Sled => Storage::Sled(KvSled::new(open_sled(&dir_path, config)?)),
+            Engine::Memory => Storage::Memory(KvMemory::load_with_options(
+                &dir_path,
+                KvMemoryOptions::from(config),
+            )?),
+            Engine::Lsm => Storage::Lsm(LsmStore::open_with_options(
+                dir_path,
+                LsmOptions::from(config),
+            )?),
+            Engine::BTree => Storage::BTree(BTreeStore::open_with_options(
+                dir_path,
+                BTreeOptions::from(config),
+            )?),
         };
 
         Ok(store)
     }
+
+    /// Opens the existing data directory `dir_path` with the engine that
+    /// wrote it, for reading only.
+    ///
+    /// Readers share the directory with each other but not with a writer.
+    /// sled has no read-only mode.
+    /// Upgrades are left for the next writer, so an older directory is read
+    /// as it is.
+    pub fn open_read_only(dir_path: PathBuf) -> Result<Storage> {
+        Storage::open_read_only_with_keys(dir_path, None)
+    }
+
+    /// Like `open_read_only`, for a KvStore whose segments may be encrypted
+    /// with `keys`
+    pub fn open_read_only_with_keys(dir_path: PathBuf, keys: Option<Keyring>) -> Result<Storage> {
+        let manifest = Manifest::load(&dir_path)?;
+        if let Some(manifest) = &manifest {
+            manifest.check_version()?;
+        }
+        match manifest.map(|manifest| manifest.engine) {
+            Some(Engine::Kvs) => Ok(Storage::Kvs(match keys {
+                Some(keys) => KvStore::open_read_only_with_keys(dir_path, keys)?,
+                None => KvStore::open_read_only(dir_path)?,
+            })),
+            Some(Engine::Memory) => Ok(Storage::Memory(KvMemory::load_read_only(&dir_path)?)),
+            Some(Engine::Lsm) => Ok(Storage::Lsm(LsmStore::open_read_only(dir_path)?)),
+            Some(Engine::BTree) => Ok(Storage::BTree(BTreeStore::open_read_only(dir_path)?)),
+            Some(Engine::Sled) => Err(KvsError::Unsupported(
+                "the sled engine cannot be opened read-only".to_owned(),
+            )),
+            None => Err(KvsError::Unsupported(format!(
+                "{} is not a data directory",
+                dir_path.display()
+            ))),
+        }
+    }
+
+    /// Removes every key.
+    pub fn clear(&self) -> Result<()> {
+        match self {
+            Storage::Kvs(store) => store.clear(),
+            Storage::Sled(store) => store.clear(),
+            Storage::Memory(store) => store.clear(),
+            Storage::Lsm(store) => store.clear(),
+            Storage::BTree(store) => store.clear(),
+        }
+    }
+
+    /// Applies the settings an open engine can change. Everything else in
+    /// `config` only takes effect when the store is reopened.
+    pub fn reconfigure(&self, config: &StorageConfig) {
+        match self {
+            Storage::Kvs(store) => store.set_options(KvStoreOptions::from(config)),
+            Storage::Sled(_) | Storage::Memory(_) | Storage::Lsm(_) | Storage::BTree(_) => {}
+        }
+    }
+
+    /// Restores the backup in `src` into the new or empty data directory
+    /// `dir`, returning the backup's engine. The server must be stopped.
+    ///
+    /// Files are copied into a sibling directory first and moved into place
+    /// at the end, so a failed restore leaves `dir` untouched.
+    pub fn restore(src: &Path, dir: &Path) -> Result<Engine> {
+        let engine = Engine::detect(src)?
+            .ok_or_else(|| KvsError::Backup(format!("{} is not a backup", src.display())))?;
+        if !is_empty_dir(dir)? {
+            return Err(KvsError::Backup(format!(
+                "{} already holds data",
+                dir.display()
+            )));
+        }
+
+        let staging = sibling(dir, "restoring");
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        copy_dir(src, &staging)?;
+
+        if dir.exists() {
+            fs::remove_dir(dir)?;
+        }
+        fs::rename(&staging, dir)?;
+        Ok(engine)
+    }
+}
+
+impl From<&StorageConfig> for KvStoreOptions {
+    fn from(config: &StorageConfig) -> KvStoreOptions {
+        KvStoreOptions {
+            max_segment_bytes: config.max_segment_bytes,
+            compaction_threshold_bytes: config.compaction_threshold_bytes,
+            compression: config.compression,
+            compression_threshold_bytes: config.compression_threshold_bytes,
+            blob_threshold_bytes: config.blob_threshold_bytes,
+            blob_gc_threshold_bytes: config.blob_gc_threshold_bytes,
+            index: config.index,
+        }
+    }
+}
+
+impl From<&StorageConfig> for LsmOptions {
+    fn from(config: &StorageConfig) -> LsmOptions {
+        LsmOptions {
+            memtable_bytes: config.lsm_memtable_bytes,
+            table_bytes: config.lsm_table_bytes,
+            ..LsmOptions::default()
+        }
+    }
+}
+
+impl From<&StorageConfig> for KvMemoryOptions {
+    fn from(config: &StorageConfig) -> KvMemoryOptions {
+        KvMemoryOptions {
+            persistent: config.memory_persistent,
+            snapshot_bytes: config.memory_snapshot_bytes,
+            snapshot_interval: Duration::from_millis(config.memory_snapshot_interval_ms),
+        }
+    }
+}
+
+impl From<&StorageConfig> for BTreeOptions {
+    fn from(config: &StorageConfig) -> BTreeOptions {
+        BTreeOptions {
+            cache_bytes: config.btree_cache_bytes,
+            checkpoint_bytes: config.btree_checkpoint_bytes,
+        }
+    }
 }
 
 impl StoreTrait for Storage {
@@ -87,6 +326,8 @@ impl StoreTrait for Storage {
             Storage::Kvs(store) => store.get(key),
             Storage::Sled(store) => store.get(key),
             Storage::Memory(store) => store.get(key),
+            Storage::Lsm(store) => store.get(key),
+            Storage::BTree(store) => store.get(key),
         }
     }
 
@@ -95,6 +336,8 @@ impl StoreTrait for Storage {
             Storage::Kvs(store) => store.set(key, val),
             Storage::Sled(store) => store.set(key, val),
             Storage::Memory(store) => store.set(key, val),
+            Storage::Lsm(store) => store.set(key, val),
+            Storage::BTree(store) => store.set(key, val),
         }
     }
 
@@ -103,28 +346,160 @@ impl StoreTrait for Storage {
             Storage::Kvs(store) => store.remove(key),
             Storage::Sled(store) => store.remove(key),
             Storage::Memory(store) => store.remove(key),
+            Storage::Lsm(store) => store.remove(key),
+            Storage::BTree(store) => store.remove(key),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            Storage::Kvs(store) => store.flush(),
+            Storage::Sled(store) => store.flush(),
+            Storage::Memory(store) => store.flush(),
+            Storage::Lsm(store) => store.flush(),
+            Storage::BTree(store) => store.flush(),
+        }
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        match self {
+            Storage::Kvs(store) => store.backup(dest),
+            Storage::Sled(store) => store.backup(dest),
+            Storage::Memory(store) => store.backup(dest),
+            Storage::Lsm(store) => store.backup(dest),
+            Storage::BTree(store) => store.backup(dest),
+        }
+    }
+
+    fn scan(&self, visit: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        match self {
+            Storage::Kvs(store) => store.scan(visit),
+            Storage::Sled(store) => store.scan(visit),
+            Storage::Memory(store) => store.scan(visit),
+            Storage::Lsm(store) => store.scan(visit),
+            Storage::BTree(store) => store.scan(visit),
+        }
+    }
+
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        visit: &mut dyn FnMut(String, String) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            Storage::Kvs(store) => store.scan_range(range, visit),
+            Storage::Sled(store) => store.scan_range(range, visit),
+            Storage::Memory(store) => store.scan_range(range, visit),
+            Storage::Lsm(store) => store.scan_range(range, visit),
+            Storage::BTree(store) => store.scan_range(range, visit),
         }
     }
 }
 
-fn check_engine(dir_path: &PathBuf, engine: &Engine) -> Result<()> {
-    let engine_str = engine.to_string();
-    let engine_file = dir_path.join("engine");
+// sled's background threads hold its file lock for a moment after the last
+// handle drops, so reopening a directory in the same process waits for them
+// before reporting the directory as locked
+fn open_sled(dir_path: &Path, config: &StorageConfig) -> Result<sled::Db> {
+    let mut attempts = 0;
+    loop {
+        let opened = sled::Config::new()
+            .path(dir_path)
+            .cache_capacity(config.sled_cache_bytes)
+            .open();
+        match opened {
+            // sled reports the held lock as a generic I/O error
+            Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => {
+                if attempts == SLED_LOCK_ATTEMPTS {
+                    return Err(KvsError::DirectoryLocked(format!(
+                        "{} is already open elsewhere",
+                        dir_path.display()
+                    )));
+                }
+                attempts += 1;
+                thread::sleep(SLED_LOCK_RETRY);
+            }
+            opened => return Ok(opened?),
+        }
+    }
+}
 
-    if !engine_file.exists() {
-        fs::write(&engine_file, &engine_str)?;
-        return Ok(());
+/// Creates the backup directory `dest` and records the engine in it, the
+/// same way a data directory does.
+pub(crate) fn start_backup(dest: &Path, engine: Engine) -> Result<()> {
+    if !is_empty_dir(dest)? {
+        return Err(KvsError::Backup(format!(
+            "{} already exists and is not empty",
+            dest.display()
+        )));
     }
+    fs::create_dir_all(dest)?;
+    Manifest::new(engine, None).save(dest)
+}
 
-    let current_engine = fs::read_to_string(&engine_file)?.trim().to_string();
+/// Resolves the `dest` of a `BACKUP` request to a directory under `root`, the
+/// server's `server.backup_root`.
+///
+/// Requests come over the network, so backups are refused while no root is
+/// set, and `dest` must be a relative path that stays inside it: no `..`, and
+/// no symlink out of it.
+pub(crate) fn backup_dest(root: Option<&Path>, dest: &str) -> Result<PathBuf> {
+    let root = root.ok_or_else(|| {
+        KvsError::Backup("backups are disabled; set server.backup_root".to_string())
+    })?;
+    let dest = Path::new(dest);
+    let plain = dest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !plain || dest.as_os_str().is_empty() {
+        return Err(KvsError::Backup(format!(
+            "{} is not a relative path inside server.backup_root",
+            dest.display()
+        )));
+    }
+
+    let root = fs::canonicalize(root)?;
+    let target = root.join(dest);
+    // Whatever already exists of the path decides where the rest is created
+    let mut existing = target.as_path();
+    while fs::symlink_metadata(existing).is_err() {
+        existing = existing.parent().unwrap_or(&root);
+    }
+    if !fs::canonicalize(existing)?.starts_with(&root) {
+        return Err(KvsError::Backup(format!(
+            "{} leads outside server.backup_root",
+            dest.display()
+        )));
+    }
+    Ok(target)
+}
 
-    if current_engine == engine_str {
-        return Ok(());
+// `<dir>.<suffix>`, next to `dir` so renames stay on one filesystem
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut path = dir.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn is_empty_dir(dir: &Path) -> Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e.into()),
     }
+}
 
-    error!(
-        "Engine mismatch: existing engine is '{}', but received '{}'",
-        current_engine, engine_str
-    );
-    exit(1);
+fn copy_dir(src: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        match entry.file_type()?.is_dir() {
+            true => copy_dir(&entry.path(), &target)?,
+            false => {
+                fs::copy(entry.path(), target)?;
+            }
+        }
+    }
+    Ok(())
 }
@@ -1,5 +1,6 @@
 use crate::Result;
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
 use tracing::info;
 
@@ -27,7 +28,8 @@ pub enum ThreadPool {
     Rayon(RayonThreadPool),
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum PoolType {
     Naive,
     Queue,
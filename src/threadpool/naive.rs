@@ -4,7 +4,7 @@ use std::thread;
 pub struct NaiveThreadPool;
 
 impl ThreadPoolTrait for NaiveThreadPool {
-    fn new(threads: u32) -> Result<NaiveThreadPool> {
+    fn new(_threads: u32) -> Result<NaiveThreadPool> {
         Ok(NaiveThreadPool)
     }
 
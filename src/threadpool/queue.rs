@@ -36,10 +36,10 @@ impl ThreadPoolTrait for QueueThreadPool {
     {
         let job = Box::new(job);
 
-        if let Some(sender) = &self.sender {
-            if let Err(err) = sender.send(job) {
-                error!("Failed to send job to worker: {err}");
-            }
+        if let Some(sender) = &self.sender
+            && let Err(err) = sender.send(job)
+        {
+            error!("Failed to send job to worker: {err}");
         }
     }
 }
@@ -52,10 +52,10 @@ impl Drop for QueueThreadPool {
         for worker in &mut self.workers {
             println!("Shutting down worker {}", worker.id);
 
-            if let Some(thread) = worker.thread.take() {
-                if let Err(err) = thread.join() {
-                    error!("Worker {} panicked: {:?}", worker.id, err);
-                }
+            if let Some(thread) = worker.thread.take()
+                && let Err(err) = thread.join()
+            {
+                error!("Worker {} panicked: {:?}", worker.id, err);
             }
         }
     }
@@ -0,0 +1,246 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    Client, ClientTrait, ClientType, Config, Engine, KvStore, KvStoreOptions, Request, Response,
+    Result, Server, ServerTrait, Storage, StoreTrait,
+};
+use predicates::str::contains;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::symlink;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Fills a store, backs it up, then keeps writing; the restored copy has the
+// state as of the backup.
+fn backup_and_restore(engine: Engine) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    let restored_dir = temp_dir.path().join("restored");
+    fs::create_dir(&data_dir)?;
+
+    let store = Storage::build(data_dir, engine)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.backup(&backup_dir)?;
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.set("late".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    assert_eq!(Storage::restore(&backup_dir, &restored_dir)?, engine);
+    let restored = Storage::build(restored_dir, engine)?;
+    assert_eq!(restored.get("key0".to_owned())?, None);
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        restored.get("key99".to_owned())?,
+        Some("value99".to_owned())
+    );
+    assert_eq!(restored.get("late".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_backup_and_restore() -> Result<()> {
+    backup_and_restore(Engine::Kvs)
+}
+
+#[test]
+fn sled_backup_and_restore() -> Result<()> {
+    backup_and_restore(Engine::Sled)
+}
+
+#[test]
+fn memory_backup_and_restore() -> Result<()> {
+    backup_and_restore(Engine::Memory)
+}
+
+#[test]
+fn lsm_backup_and_restore() -> Result<()> {
+    backup_and_restore(Engine::Lsm)
+}
+
+#[test]
+fn btree_backup_and_restore() -> Result<()> {
+    backup_and_restore(Engine::BTree)
+}
+
+// A backup taken while segments roll over and compact still holds every key.
+#[test]
+fn kvs_backup_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    fs::create_dir(&data_dir)?;
+    let store = KvStore::open_with_options(
+        data_dir,
+        KvStoreOptions {
+            max_segment_bytes: 1024,
+            compaction_threshold_bytes: 8 * 1024,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), "initial".to_owned())?;
+    }
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || {
+        for round in 0..20 {
+            for i in 0..50 {
+                writer
+                    .set(format!("key{}", i), format!("round{}", round))
+                    .unwrap();
+            }
+        }
+    });
+    thread::sleep(Duration::from_millis(5));
+    store.backup(&backup_dir)?;
+    handle.join().unwrap();
+
+    let restored = KvStore::open(backup_dir)?;
+    for i in 0..50 {
+        assert!(restored.get(format!("key{}", i))?.is_some());
+    }
+    Ok(())
+}
+
+// Backups never overwrite, and restores never mix into existing data.
+#[test]
+fn refuses_non_empty_directories() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let taken = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(taken.path().join("file"), "data")?;
+    assert!(store.backup(taken.path()).is_err());
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("backup");
+    store.backup(&backup)?;
+    assert!(Storage::restore(&backup, taken.path()).is_err());
+    assert!(Storage::restore(taken.path(), &backup_dir.path().join("new")).is_err());
+    Ok(())
+}
+
+// `kvs-admin backup` snapshots a live server and `kvs-admin restore` brings
+// it back into a new data directory.
+#[test]
+fn cli_backup_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let restored_dir = temp_dir.path().join("restored");
+    fs::create_dir(&data_dir).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4601"])
+        .env("KVS_SERVER_BACKUP_ROOT", temp_dir.path())
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4601"])
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["backup", "backup", "--addr", "127.0.0.1:4601"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Backed up"));
+
+    // A second backup to the same place is refused
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["backup", "backup", "--addr", "127.0.0.1:4601"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["restore", "backup", "--data-dir", "restored"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Restored kvs backup"));
+
+    let store = KvStore::open(restored_dir).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Backups requested over the network only write under `server.backup_root`,
+// and not at all while it is unset.
+#[test]
+fn backup_requests_stay_in_the_root() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let root = temp_dir.path().join("backups");
+    let outside = temp_dir.path().join("outside");
+    fs::create_dir(&data_dir)?;
+    fs::create_dir(&root)?;
+    fs::create_dir(&outside)?;
+    symlink(&outside, root.join("link"))?;
+
+    let addr: SocketAddr = "127.0.0.1:4602".parse().unwrap();
+    let mut config = Config::default();
+    config.server.addr = addr;
+    config.server.data_dir = Some(data_dir);
+    let backup = |dest: &str| -> Result<Response> {
+        Client::build(ClientType::Sync, addr)?.send(Request::Backup {
+            dest: dest.to_owned(),
+        })
+    };
+
+    for backup_root in [None, Some(root.clone())] {
+        config.server.backup_root = backup_root.clone();
+        let mut server = Server::from_config(&config)?;
+        let shutdown = server.shutdown();
+        let handle = thread::spawn(move || server.run().unwrap());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        if backup_root.is_none() {
+            let response = backup("nightly")?;
+            assert!(matches!(response, Response::Error(e) if e.contains("disabled")));
+        } else {
+            for dest in [
+                "../escape",
+                "nightly/../../escape",
+                outside.join("abs").to_str().unwrap(),
+            ] {
+                let response = backup(dest)?;
+                assert!(matches!(response, Response::Error(e) if e.contains("relative path")));
+            }
+            let response = backup("link/escape")?;
+            assert!(matches!(response, Response::Error(e) if e.contains("outside")));
+
+            assert!(matches!(backup("nightly/1")?, Response::Ok));
+            assert!(root.join("nightly/1").is_dir());
+        }
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+    }
+    assert!(!temp_dir.path().join("escape").exists());
+    assert_eq!(fs::read_dir(&outside)?.count(), 0);
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    EncryptionKey, Entry, KEY_LEN, Keyring, KvStore, KvStoreOptions, LogInspector, LogPosition,
+    Result, StoreTrait,
+};
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn options() -> KvStoreOptions {
+    KvStoreOptions {
+        blob_threshold_bytes: 1024,
+        ..KvStoreOptions::default()
+    }
+}
+
+fn open(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with_options(dir.to_path_buf(), options())
+}
+
+fn large(id: u32, version: u32) -> String {
+    format!("{}-{}-", id, version).repeat(400)
+}
+
+fn blob_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".blob"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Large values live in blob files and the log only points at them, so
+// compaction leaves the blob files alone.
+#[test]
+fn large_values_go_to_blob_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for id in 0..10 {
+        store.set(format!("key{}", id), large(id, 0))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let stats = LogInspector::open(temp_dir.path())?.stats()?;
+    assert_eq!(stats.live_keys, 11);
+    assert!(stats.bytes < 2048, "the log holds {} bytes", stats.bytes);
+    assert_eq!(stats.blob_files, 1);
+    assert_eq!(stats.live_blob_bytes + 24, stats.blob_bytes);
+    let blobs = blob_files(temp_dir.path());
+
+    let store = KvStore::open_with_options(
+        temp_dir.path().to_path_buf(),
+        KvStoreOptions {
+            compaction_threshold_bytes: 1,
+            ..options()
+        },
+    )?;
+    store.set("small".to_owned(), "other".to_owned())?;
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(blob_files(temp_dir.path()), blobs);
+
+    let store = open(temp_dir.path())?;
+    for id in 0..10 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(large(id, 0)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// Collection moves the values still in use and deletes the rest.
+#[test]
+fn collect_blobs_reclaims_dead_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for id in 0..10 {
+        store.set(format!("key{}", id), large(id, 0))?;
+    }
+    for id in 0..5 {
+        store.set(format!("key{}", id), large(id, 1))?;
+    }
+    store.remove("key9".to_owned())?;
+
+    assert!(store.collect_blobs()? > 0);
+    assert_eq!(store.collect_blobs()?, 0);
+    assert!(!blob_files(temp_dir.path()).contains(&"1.blob".to_owned()));
+    drop(store);
+
+    let stats = LogInspector::open(temp_dir.path())?.stats()?;
+    assert_eq!(stats.live_blob_bytes + 24, stats.blob_bytes);
+
+    let store = open(temp_dir.path())?;
+    store.set("key9".to_owned(), large(9, 2))?;
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    for id in 0..5 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(large(id, 1)));
+    }
+    for id in 5..9 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(large(id, 0)));
+    }
+    assert_eq!(store.get("key9".to_owned())?, Some(large(9, 2)));
+    Ok(())
+}
+
+// The log may still point into a collected file, so its id is not handed
+// out again.
+#[test]
+fn collected_blob_ids_are_not_reused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), large(1, 0))?;
+    store.remove("key1".to_owned())?;
+    store.collect_blobs()?;
+    drop(store);
+    assert!(blob_files(temp_dir.path()).is_empty());
+
+    let store = open(temp_dir.path())?;
+    store.set("key2".to_owned(), large(2, 0))?;
+    assert_eq!(blob_files(temp_dir.path()), vec!["2.blob".to_owned()]);
+
+    let batch = store.read_log(LogPosition::default(), u64::MAX)?;
+    let keys: Vec<String> = batch
+        .entries
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Set { key, .. } => format!("set {}", key),
+            Entry::Remove { key } => format!("remove {}", key),
+            Entry::Blob { .. } => panic!("read_log returned a blob pointer"),
+        })
+        .collect();
+    assert_eq!(keys, vec!["remove key1", "set key2"]);
+    Ok(())
+}
+
+// Overwriting the same keys keeps blob space bounded once dead bytes pass
+// the threshold.
+#[test]
+fn collection_runs_by_itself() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path().to_path_buf(),
+        KvStoreOptions {
+            blob_gc_threshold_bytes: 64 * 1024,
+            ..options()
+        },
+    )?;
+    for version in 0..50 {
+        for id in 0..4 {
+            store.set(format!("key{}", id), large(id, version))?;
+        }
+    }
+    drop(store);
+
+    let stats = LogInspector::open(temp_dir.path())?.stats()?;
+    assert!(
+        stats.blob_bytes < 4 * 64 * 1024,
+        "blob files hold {} bytes",
+        stats.blob_bytes
+    );
+    let store = open(temp_dir.path())?;
+    for id in 0..4 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(large(id, 49)));
+    }
+    Ok(())
+}
+
+// Replicas following the log get values, never pointers, before and after
+// collection.
+#[test]
+fn read_log_resolves_blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), large(1, 0))?;
+    store.set("key2".to_owned(), large(2, 0))?;
+    store.set("key1".to_owned(), large(1, 1))?;
+    store.collect_blobs()?;
+
+    let batch = store.read_log(LogPosition::default(), u64::MAX)?;
+    let mut replayed = HashMap::new();
+    for entry in batch.entries {
+        match entry {
+            Entry::Set { key, value } => replayed.insert(key, value),
+            Entry::Remove { key } => replayed.remove(&key),
+            Entry::Blob { .. } => panic!("read_log returned a blob pointer"),
+        };
+    }
+    assert_eq!(replayed.len(), 2);
+    assert_eq!(replayed["key1"], large(1, 1));
+    assert_eq!(replayed["key2"], large(2, 0));
+    Ok(())
+}
+
+// Blob files are encrypted like segments and rewritten by `upgrade` after a
+// rotation.
+#[test]
+fn blob_files_are_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = |byte: u8| EncryptionKey::new([byte; KEY_LEN]);
+    let open =
+        |keys: Keyring| KvStore::open_with_keys(temp_dir.path().to_path_buf(), options(), keys);
+
+    let store = open(Keyring::new(key(1)))?;
+    store.set("key1".to_owned(), large(1, 0))?;
+    drop(store);
+    let bytes = fs::read(temp_dir.path().join("1.blob"))?;
+    assert!(!bytes.windows(8).any(|window| window == b"1-0-1-0-"));
+
+    let store = open(Keyring::new(key(2)).retire(key(1)))?;
+    assert!(store.upgrade()? > 0);
+    drop(store);
+    assert!(!temp_dir.path().join("1.blob").exists());
+
+    let store = open(Keyring::new(key(2)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large(1, 0)));
+    Ok(())
+}
+
+// A backup carries the blob files along with the log.
+#[test]
+fn backup_includes_blob_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    fs::create_dir(&data_dir)?;
+
+    let store = open(&data_dir)?;
+    store.set("key1".to_owned(), large(1, 0))?;
+    store.set("key2".to_owned(), large(2, 0))?;
+    store.backup(&backup_dir)?;
+    store.set("key3".to_owned(), large(3, 0))?;
+    drop(store);
+
+    let store = open(&backup_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large(1, 0)));
+    assert_eq!(store.get("key2".to_owned())?, Some(large(2, 0)));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn cli_collect_blobs() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+    let store = open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "a".repeat(100_000)).unwrap();
+    store.set("key1".to_owned(), "b".repeat(100_000)).unwrap();
+    drop(store);
+
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args)
+            .args(["--data-dir", "."])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    admin(&["dump"])
+        .assert()
+        .success()
+        .stdout(contains("BLOB   \"key1\" -> 1.blob:"));
+    admin(&["collect-blobs"])
+        .assert()
+        .success()
+        .stdout(contains("Freed"));
+    admin(&["collect-blobs"])
+        .assert()
+        .success()
+        .stdout(contains("No blob file holds dead values"));
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("blob files           1"));
+}
@@ -0,0 +1,290 @@
+use kvs::{BTreeOptions, BTreeStore, Engine, KvsError, Result, Storage, StorageConfig, StoreTrait};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use tempfile::TempDir;
+
+// A pool too small for the tree, so pages are evicted and read back
+fn options() -> BTreeOptions {
+    BTreeOptions {
+        cache_bytes: 64 * 4096,
+        ..BTreeOptions::default()
+    }
+}
+
+fn open(dir: &Path) -> Result<BTreeStore> {
+    BTreeStore::open_with_options(dir.to_path_buf(), options())
+}
+
+fn contents(store: &impl StoreTrait) -> Result<BTreeMap<String, String>> {
+    let mut found = BTreeMap::new();
+    store.scan(&mut |key, value| {
+        found.insert(key, value);
+        Ok(())
+    })?;
+    Ok(found)
+}
+
+fn range(store: &impl StoreTrait, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<String>> {
+    let mut keys = vec![];
+    store.scan_range(
+        (start.map(str::to_owned), end.map(str::to_owned)),
+        &mut |key, _| {
+            keys.push(key);
+            Ok(())
+        },
+    )?;
+    Ok(keys)
+}
+
+// Overwrites and removals read back the same through splits, evictions
+// and a reopen.
+#[test]
+fn survives_splits_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let mut expected = BTreeMap::new();
+    for i in 0..10_000 {
+        let key = format!("key{:05}", (i * 7919) % 5000);
+        let value = format!("value{}{}", i, "x".repeat(50));
+        store.set(key.clone(), value.clone())?;
+        expected.insert(key, value);
+    }
+    for i in (0..5000).step_by(3) {
+        let key = format!("key{:05}", i);
+        store.remove(key.clone())?;
+        expected.remove(&key);
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.depth >= 2);
+    assert!(stats.evictions > 0);
+    assert!(stats.cache_misses > 0);
+    assert_eq!(contents(&store)?, expected);
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    for i in 0..5000 {
+        let key = format!("key{:05}", i);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+    }
+    assert_eq!(contents(&store)?, expected);
+    assert!(matches!(
+        store.remove("key00000".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Keys and values longer than a page go to overflow pages, which are freed
+// and reused when they are overwritten or removed.
+#[test]
+fn long_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let long_key = |i: usize| format!("{}{:03}", "k".repeat(3000), i);
+    for i in 0..50 {
+        store.set(long_key(i), format!("{}", i).repeat(20_000))?;
+    }
+    store.set("short".to_owned(), "v".repeat(100_000))?;
+    let pages = store.stats()?.pages;
+
+    for round in 0..3 {
+        for i in 0..50 {
+            store.set(long_key(i), format!("{}", i + round).repeat(20_000))?;
+        }
+    }
+    // Overwrites reuse the pages the values they replace gave up
+    assert!(store.stats()?.pages < pages + pages / 10);
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    for i in 0..50 {
+        assert_eq!(
+            store.get(long_key(i))?,
+            Some(format!("{}", i + 2).repeat(20_000))
+        );
+    }
+    assert_eq!(store.get("short".to_owned())?, Some("v".repeat(100_000)));
+    for i in 0..50 {
+        store.remove(long_key(i))?;
+    }
+    assert_eq!(contents(&store)?.len(), 1);
+    assert!(store.stats()?.free_pages > 0);
+    Ok(())
+}
+
+// Writes since the last checkpoint come back from the log, whether or not
+// their pages reached the page file; a torn record at its end is dropped.
+#[test]
+fn log_is_replayed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..3000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key7".to_owned())?;
+    store.flush()?;
+    assert!(store.stats()?.wal_bytes > 0);
+
+    // What a crash would leave: the files as they are, nothing checkpointed
+    let crashed = temp_dir.path().join("crashed");
+    fs::create_dir(&crashed)?;
+    for name in ["btree.db", "btree.wal"] {
+        fs::copy(temp_dir.path().join(name), crashed.join(name))?;
+    }
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(crashed.join("btree.wal"))?;
+    log.write_all(&[7; 100])?;
+    drop(log);
+
+    let recovered = open(&crashed)?;
+    assert_eq!(recovered.get("key7".to_owned())?, None);
+    for i in (0..3000).filter(|&i| i != 7) {
+        assert_eq!(
+            recovered.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    // Replaying checkpointed the log away
+    assert_eq!(fs::metadata(crashed.join("btree.wal"))?.len(), 0);
+    recovered.set("after".to_owned(), "crash".to_owned())?;
+    drop(recovered);
+    assert_eq!(
+        open(&crashed)?.get("after".to_owned())?,
+        Some("crash".to_owned())
+    );
+    Ok(())
+}
+
+// The log is checkpointed into the page file once it grows past its limit.
+#[test]
+fn checkpoints_bound_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeOptions {
+        checkpoint_bytes: 256 * 1024,
+        ..options()
+    };
+    let store = BTreeStore::open_with_options(temp_dir.path().to_path_buf(), options)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.checkpoints > 0);
+    assert!(stats.wal_bytes < 256 * 1024);
+
+    store.checkpoint()?;
+    assert_eq!(store.stats()?.wal_bytes, 0);
+    assert_eq!(fs::metadata(temp_dir.path().join("btree.wal"))?.len(), 0);
+    assert_eq!(
+        store.get("key1999".to_owned())?,
+        Some("value1999".to_owned())
+    );
+    Ok(())
+}
+
+// Range scans come back in key order with each bound honored, across many
+// leaves.
+#[test]
+fn range_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in (0..3000).rev() {
+        store.set(format!("key{:04}", i), "x".repeat(50))?;
+    }
+    store.remove("key0101".to_owned())?;
+
+    let keys = range(
+        &store,
+        Bound::Included("key0100"),
+        Bound::Excluded("key0105"),
+    )?;
+    assert_eq!(keys, vec!["key0100", "key0102", "key0103", "key0104"]);
+    let keys = range(&store, Bound::Excluded("key2997"), Bound::Unbounded)?;
+    assert_eq!(keys, vec!["key2998", "key2999"]);
+    let keys = range(&store, Bound::Unbounded, Bound::Included("key0001"))?;
+    assert_eq!(keys, vec!["key0000", "key0001"]);
+    assert!(range(&store, Bound::Included("key5"), Bound::Excluded("key1"))?.is_empty());
+
+    let all = range(&store, Bound::Unbounded, Bound::Unbounded)?;
+    assert_eq!(all.len(), 2999);
+    assert!(all.windows(2).all(|pair| pair[0] < pair[1]));
+    Ok(())
+}
+
+// Readers share the directory, see the writes still in the log without
+// replaying it, and cannot write.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let log_len = fs::metadata(temp_dir.path().join("btree.wal"))?.len();
+    assert!(log_len > 0);
+
+    let reader = BTreeStore::open_read_only(temp_dir.path().to_path_buf())?;
+    let other = BTreeStore::open_read_only(temp_dir.path().to_path_buf())?;
+    assert_eq!(reader.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(contents(&other)?.len(), 500);
+    assert!(matches!(
+        reader.set("key".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(reader.get("key".to_owned())?, None);
+    assert!(BTreeStore::open(temp_dir.path().to_path_buf()).is_err());
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("btree.wal"))?.len(),
+        log_len
+    );
+    Ok(())
+}
+
+// Clearing removes every key, and the store stays usable.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.clear()?;
+    assert!(contents(&store)?.is_empty());
+    assert_eq!(store.stats()?.pages, 2);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(contents(&store)?.len(), 1);
+    Ok(())
+}
+
+// The engine and its pool size come from the storage config.
+#[test]
+fn opened_from_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StorageConfig {
+        engine: Engine::BTree,
+        btree_cache_bytes: 1024 * 1024,
+        ..StorageConfig::default()
+    };
+    assert_eq!("btree".parse::<Engine>()?, Engine::BTree);
+    assert_eq!(Engine::BTree.to_string(), "btree");
+
+    let store = Storage::open(temp_dir.path().to_path_buf(), &config)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("btree.db").exists());
+    assert_eq!(Engine::detect(temp_dir.path())?, Some(Engine::BTree));
+
+    let store = Storage::open_read_only_with_keys(temp_dir.path().to_path_buf(), None)?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
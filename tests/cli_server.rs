@@ -31,6 +31,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on child process");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -51,6 +52,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on child process");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -70,6 +72,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on child process");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -79,12 +82,225 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+#[test]
+fn cli_pool_configuration() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args([
+            "--addr",
+            "127.0.0.1:4008",
+            "--pool",
+            "rayon",
+            "--threads",
+            "3",
+        ])
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on child process");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("rayon with 3 threads"));
+    assert!(data_dir.join("MANIFEST").exists());
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+}
+
+// Flags missing from the command line come from `--config`
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        "[server]\naddr = \"127.0.0.1:4009\"\npool = \"naive\"\nthreads = 2\n\n[storage]\nengine = \"sled\"\n",
+    )
+    .unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--threads", "7", "--config"])
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on child process");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Storage engine: sled"));
+    assert!(content.contains("127.0.0.1:4009"));
+    assert!(content.contains("naive with 7 threads"));
+}
+
+// `--print-config` shows the merged result of file, environment and flags
+#[test]
+fn cli_print_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(&config_path, "[storage]\nengine = \"sled\"\n").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--print-config", "--pool", "rayon", "--config"])
+        .arg(&config_path)
+        .env("KVS_LIMITS_MAX_CONNECTIONS", "42")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine = \"sled\""))
+        .stdout(contains("pool = \"rayon\""))
+        .stdout(contains("max_connections = 42"));
+}
+
+// `KVS_*` variables that name no config key are skipped, not rejected
+#[test]
+fn cli_unknown_env_vars() {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--print-config")
+        .env("KVS_FOO_BAR", "1")
+        .env("KVS_SERVER_BOGUS", "1")
+        .env("KVS_KEY", "00")
+        .env("KVS_LIMITS_MAX_CONNECTIONS", "42")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("max_connections = 42"));
+}
+
+// Config errors name the offending key
+#[test]
+fn cli_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(&config_path, "[server]\npool = \"fast\"\n").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("server.pool"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--print-config")
+        .env("KVS_STORAGE_MAX_SEGMENT_BYTES", "0")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("storage.max_segment_bytes"));
+}
+
+// SIGTERM should stop the server cleanly without another client connecting
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender.send(child.wait().unwrap()).unwrap();
+    });
+    let status = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not exit after SIGTERM");
+    assert!(status.success());
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Server stopped"));
+}
+
+// SIGHUP should reread the config file without dropping the server
+#[test]
+fn cli_reload_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(&config_path, "[server]\naddr = \"127.0.0.1:4010\"\n").unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    fs::write(
+        &config_path,
+        "[server]\naddr = \"127.0.0.1:4011\"\n\n[logging]\nlevel = \"debug\"\n\n[limits]\nmax_connections = 5\n",
+    )
+    .unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    // Still listening on the old address
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    child.wait().expect("failed to wait on child process");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Reloaded logging.level"));
+    assert!(content.contains("Reloaded limits.max_connections"));
+    assert!(content.contains("server.addr changed but requires a restart"));
+    assert!(content.contains("Server stopped"));
+}
+
+fn cli_access_server(engine: &str, addr: &str, server_type: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr, "--server", server_type])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -166,7 +382,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr, "--server", server_type])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -197,10 +413,20 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "127.0.0.1:4004", "sync");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "127.0.0.1:4005", "sync");
+}
+
+#[test]
+fn cli_access_async_server_kvs_engine() {
+    cli_access_server("kvs", "127.0.0.1:4006", "async");
+}
+
+#[test]
+fn cli_access_server_btree_engine() {
+    cli_access_server("btree", "127.0.0.1:4012", "sync");
 }
@@ -0,0 +1,245 @@
+use assert_cmd::prelude::*;
+use kvs::{ClusterNode, Config, Server, ServerTrait};
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Id, client address and raft address of each node
+type Nodes = [(u64, &'static str, &'static str); 3];
+
+const NODES: Nodes = [
+    (1, "127.0.0.1:4401", "127.0.0.1:4411"),
+    (2, "127.0.0.1:4402", "127.0.0.1:4412"),
+    (3, "127.0.0.1:4403", "127.0.0.1:4413"),
+];
+
+const ISOLATED_NODES: Nodes = [
+    (1, "127.0.0.1:4404", "127.0.0.1:4414"),
+    (2, "127.0.0.1:4405", "127.0.0.1:4415"),
+    (3, "127.0.0.1:4406", "127.0.0.1:4416"),
+];
+
+fn write_config(dir: &Path, nodes: &Nodes) -> PathBuf {
+    let mut config = String::from("[cluster]\nsnapshot_entries = 5\n");
+    for (id, addr, raft_addr) in nodes {
+        config += &format!(
+            "\n[[cluster.nodes]]\nid = {}\naddr = \"{}\"\nraft_addr = \"{}\"\n",
+            id, addr, raft_addr
+        );
+    }
+    let path = dir.join("cluster.toml");
+    fs::write(&path, config).unwrap();
+    path
+}
+
+fn start_node(nodes: &Nodes, id: u64, config: &Path, dir: &Path) -> Child {
+    let (_, addr, _) = nodes[id as usize - 1];
+    let data_dir = dir.join(format!("node{}", id));
+    fs::create_dir_all(&data_dir).unwrap();
+    let stderr = File::options()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("node{}.log", id)))
+        .unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--cluster", "--node-id", &id.to_string(), "--addr", addr])
+        .arg("--config")
+        .arg(config)
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .stderr(stderr)
+        .spawn()
+        .unwrap()
+}
+
+// Retries while the cluster elects a leader
+fn client(args: &[&str], addr: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    loop {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .output()
+            .unwrap();
+        if output.status.success() {
+            return String::from_utf8(output.stdout).unwrap();
+        }
+        assert!(
+            Instant::now() < deadline,
+            "kvs-client {:?} kept failing: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// The node that won the latest election, from the nodes' logs
+fn leader(dir: &Path) -> u64 {
+    let mut latest = (0, 0);
+    for id in 1..=3 {
+        let log = fs::read_to_string(dir.join(format!("node{}.log", id))).unwrap_or_default();
+        for line in log.lines() {
+            if let Some(rest) = line
+                .split(&format!("Node {} became leader for term ", id))
+                .nth(1)
+            {
+                let term: u64 = rest
+                    .split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                latest = latest.max((term, id));
+            }
+        }
+    }
+    assert_ne!(latest.1, 0, "no leader elected");
+    latest.1
+}
+
+fn stop(child: &mut Child) {
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on child process");
+}
+
+// Three processes on localhost: writes survive losing the leader, and a node
+// restarted after the log was compacted catches up from a snapshot.
+#[test]
+fn cluster_replicates_through_raft() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let config = write_config(dir, &NODES);
+
+    let mut nodes: Vec<Child> = NODES
+        .iter()
+        .map(|(id, _, _)| start_node(&NODES, *id, &config, dir))
+        .collect();
+    let addr = |id: u64| NODES[id as usize - 1].1;
+
+    // Any node accepts the request and redirects to the leader
+    client(&["set", "key0", "value0"], addr(1));
+    assert_eq!(client(&["get", "key0"], addr(3)).trim(), "value0");
+
+    let old_leader = leader(dir);
+    stop(&mut nodes[old_leader as usize - 1]);
+    let survivor = NODES
+        .iter()
+        .map(|(id, _, _)| *id)
+        .find(|id| *id != old_leader)
+        .unwrap();
+
+    // More entries than `snapshot_entries`, so the log moves past the old
+    // leader, and large enough that the snapshot takes several chunks
+    let value = |i: usize| format!("value{}{}", i, "v".repeat(100_000));
+    for i in 1..=20 {
+        client(&["set", &format!("key{}", i), &value(i)], addr(survivor));
+    }
+    client(&["rm", "key0"], addr(survivor));
+
+    nodes[old_leader as usize - 1] = start_node(&NODES, old_leader, &config, dir);
+    thread::sleep(Duration::from_secs(2));
+
+    // Without the restarted node there is no majority, so this only works if
+    // it caught up
+    stop(&mut nodes[survivor as usize - 1]);
+    for i in 1..=20 {
+        assert_eq!(
+            client(&["get", &format!("key{}", i)], addr(old_leader)).trim(),
+            value(i)
+        );
+    }
+    client(&["set", "key21", "value21"], addr(old_leader));
+    assert_eq!(
+        client(&["get", "key21"], addr(old_leader)).trim(),
+        "value21"
+    );
+
+    let log = fs::read_to_string(dir.join(format!("node{}.log", old_leader))).unwrap();
+    let removed = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key0", "--addr", addr(old_leader)])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&removed.stdout).contains("Key not found"));
+    assert!(log.contains("installing snapshot"), "{}", log);
+
+    for (i, node) in nodes.iter_mut().enumerate() {
+        if i as u64 + 1 != survivor {
+            stop(node);
+        }
+    }
+}
+
+// A leader cut off from the other nodes cannot tell whether a new one was
+// elected, so it refuses reads rather than risk answering with stale values.
+#[test]
+fn isolated_leader_refuses_reads() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let config = write_config(dir, &ISOLATED_NODES);
+
+    let mut nodes: Vec<Child> = ISOLATED_NODES
+        .iter()
+        .map(|(id, _, _)| start_node(&ISOLATED_NODES, *id, &config, dir))
+        .collect();
+    let addr = |id: u64| ISOLATED_NODES[id as usize - 1].1;
+
+    client(&["set", "key1", "value1"], addr(1));
+    let leader = leader(dir);
+    assert_eq!(client(&["get", "key1"], addr(leader)).trim(), "value1");
+
+    for (i, node) in nodes.iter_mut().enumerate() {
+        if i as u64 + 1 != leader {
+            stop(node);
+        }
+    }
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr(leader)])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("value1"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("leadership not confirmed"));
+
+    stop(&mut nodes[leader as usize - 1]);
+}
+
+// A peer's message is only buffered up to a limit, so a line that never
+// ends is cut off rather than read into memory.
+#[test]
+fn endless_raft_message_is_cut_off() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let node = ClusterNode {
+        id: 1,
+        addr: "127.0.0.1:4407".parse().unwrap(),
+        raft_addr: "127.0.0.1:4417".parse().unwrap(),
+    };
+    let mut config = Config::default();
+    config.server.addr = node.addr;
+    config.server.data_dir = Some(temp_dir.path().to_path_buf());
+    config.cluster.enabled = true;
+    config.cluster.id = 1;
+    config.cluster.nodes = vec![node.clone()];
+    let mut server = Server::from_config(&config)?;
+    let shutdown = server.shutdown();
+    let handle = thread::spawn(move || server.run().unwrap());
+
+    let mut peer = TcpStream::connect(node.raft_addr)?;
+    let chunk = vec![b'a'; 1024 * 1024];
+    let sent = (0..128).take_while(|_| peer.write_all(&chunk).is_ok()).count();
+    assert!(sent < 128, "all {} MiB were accepted", sent);
+
+    shutdown.shutdown();
+    handle.join().unwrap();
+    Ok(())
+}
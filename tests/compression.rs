@@ -0,0 +1,125 @@
+use kvs::{
+    Compression, Entry, KvStore, KvStoreOptions, LogInspector, LogPosition, Result, StoreTrait,
+};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(dir: &Path, compression: Compression) -> Result<KvStore> {
+    KvStore::open_with_options(
+        dir.to_path_buf(),
+        KvStoreOptions {
+            compression,
+            compression_threshold_bytes: 256,
+            ..KvStoreOptions::default()
+        },
+    )
+}
+
+fn document(id: u32) -> String {
+    let items: Vec<String> = (0..50)
+        .map(|i| format!(r#"{{"id":{},"name":"item{}","tags":["a","b"]}}"#, id, i))
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+fn log_bytes(dir: &Path) -> Result<u64> {
+    Ok(LogInspector::open(dir)?.stats()?.bytes)
+}
+
+// Each codec shrinks large documents and reads them back after a reopen.
+#[test]
+fn compressed_values_round_trip() -> Result<()> {
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(plain_dir.path(), Compression::None)?;
+    for id in 0..10 {
+        store.set(format!("key{}", id), document(id))?;
+    }
+    drop(store);
+    let plain = log_bytes(plain_dir.path())?;
+
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open(temp_dir.path(), compression)?;
+        for id in 0..10 {
+            store.set(format!("key{}", id), document(id))?;
+        }
+        assert_eq!(store.get("key3".to_owned())?, Some(document(3)));
+        drop(store);
+
+        assert!(log_bytes(temp_dir.path())? < plain / 2, "{:?}", compression);
+        assert!(LogInspector::open(temp_dir.path())?.verify()?.is_empty());
+
+        let store = open(temp_dir.path(), Compression::None)?;
+        for id in 0..10 {
+            assert_eq!(store.get(format!("key{}", id))?, Some(document(id)));
+        }
+    }
+    Ok(())
+}
+
+// Values under the threshold, and values compression would not shrink,
+// are stored as given.
+#[test]
+fn small_values_stay_uncompressed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), Compression::Zstd)?;
+    store.set("key1".to_owned(), "a".repeat(255))?;
+    store.set("key2".to_owned(), "xyz".to_owned())?;
+    drop(store);
+
+    let bytes = fs::read(temp_dir.path().join("1.log"))?;
+    assert!(
+        bytes
+            .windows(255)
+            .any(|window| window == "a".repeat(255).as_bytes())
+    );
+    // The last entry is followed only by its checksum
+    assert!(bytes[..bytes.len() - 4].ends_with(b"key2xyz"));
+    Ok(())
+}
+
+// Changing codecs leaves earlier values readable, through compaction and
+// through the log replicas follow.
+#[test]
+fn mixed_codecs_stay_readable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), Compression::Lz4)?;
+    store.set("key1".to_owned(), document(1))?;
+    store.set_options(KvStoreOptions {
+        compression: Compression::Zstd,
+        ..store.options()
+    });
+    store.set("key2".to_owned(), document(2))?;
+    store.set_options(KvStoreOptions {
+        compression: Compression::None,
+        ..store.options()
+    });
+    store.set("key3".to_owned(), document(3))?;
+
+    let batch = store.read_log(LogPosition::default(), u64::MAX)?;
+    let values: Vec<String> = batch
+        .entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Entry::Set { value, .. } => Some(value),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(values, vec![document(1), document(2), document(3)]);
+
+    // The next write compacts, rewriting every value with the current codec
+    store.set_options(KvStoreOptions {
+        compaction_threshold_bytes: 1,
+        ..store.options()
+    });
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = open(temp_dir.path(), Compression::Zstd)?;
+    for id in 1..=3 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(document(id)));
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use kvs::{Config, Engine, KvsError, LogFormat, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// Keys missing from the file keep their defaults.
+#[test]
+fn partial_file_uses_defaults() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &path,
+        "[storage]\nengine = \"memory\"\n\n[logging]\nformat = \"json\"\n",
+    )?;
+
+    let config = Config::from_file(&path)?;
+    assert_eq!(config.storage.engine, Engine::Memory);
+    assert_eq!(config.logging.format, LogFormat::Json);
+    assert_eq!(config.server.addr, "127.0.0.1:4000".parse().unwrap());
+    assert_eq!(config.limits.max_request_bytes, 4 * 1024 * 1024);
+
+    Ok(())
+}
+
+// The shipped example config must stay loadable.
+#[test]
+fn example_file_parses() -> Result<()> {
+    Config::from_file(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/config/config.toml"
+    ))?;
+    Ok(())
+}
+
+// Errors point at the bad key, including unknown ones.
+#[test]
+fn errors_name_the_key() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+
+    let cases = [
+        ("[server]\nthreads = \"many\"\n", "server.threads"),
+        ("[storage]\nengin = \"kvs\"\n", "storage.engin"),
+        ("[limits]\nmax_connections = 0\n", "limits.max_connections"),
+        ("[logging]\nlevel = \"kvs=loud\"\n", "logging.level"),
+        ("[tls]\nenabled = true\n", "tls.cert"),
+        (
+            "[replication]\nrole = \"replica\"\n",
+            "replication.primary_addr",
+        ),
+        (
+            "[storage]\nengine = \"sled\"\n\n[replication]\nrole = \"primary\"\nlisten_addr = \"127.0.0.1:4300\"\n",
+            "storage.engine",
+        ),
+        (
+            "[storage]\nold_encryption_key_files = [\"old.key\"]\n",
+            "storage.old_encryption_key_files",
+        ),
+        (
+            "[limits]\nmax_request_bytes = 134217728\n\n[replication]\nrole = \"replica\"\nprimary_addr = \"127.0.0.1:4300\"\n",
+            "limits.max_request_bytes",
+        ),
+        (
+            "[limits]\nmax_request_bytes = 16777216\n\n[cluster]\nenabled = true\nid = 1\n\n[[cluster.nodes]]\nid = 1\naddr = \"127.0.0.1:4000\"\nraft_addr = \"127.0.0.1:4010\"\n",
+            "limits.max_request_bytes",
+        ),
+    ];
+
+    for (contents, expected) in cases {
+        fs::write(&path, contents).unwrap();
+        match Config::from_file(&path) {
+            Err(KvsError::Config { key, .. }) => assert_eq!(key, expected, "{}", contents),
+            Err(err) => panic!("unexpected error for {:?}: {}", contents, err),
+            Ok(_) => panic!("{:?} should not parse", contents),
+        }
+    }
+}
+
+// TLS settings are checked, then refused, as the server cannot serve TLS yet.
+#[test]
+fn tls_is_not_supported_yet() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    let cert = temp_dir.path().join("cert.pem");
+    let key = temp_dir.path().join("key.pem");
+    let contents = format!(
+        "[tls]\nenabled = true\ncert = {:?}\nkey = {:?}\n",
+        cert, key
+    );
+    fs::write(&path, &contents)?;
+
+    // Missing files are reported first
+    match Config::from_file(&path) {
+        Err(KvsError::Config { key, .. }) => assert_eq!(key, "tls.cert"),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+
+    fs::write(&cert, "")?;
+    fs::write(&key, "")?;
+    match Config::from_file(&path) {
+        Err(KvsError::Config { key, message }) => {
+            assert_eq!(key, "tls.enabled");
+            assert!(message.contains("not supported yet"), "{}", message);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// The printed config reads back to the same settings.
+#[test]
+fn round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+
+    let mut config = Config::default();
+    config.storage.engine = Engine::Sled;
+    config.server.threads = Some(3);
+    fs::write(&path, config.to_toml()?)?;
+
+    let read = Config::from_file(&path)?;
+    assert_eq!(read.storage.engine, Engine::Sled);
+    assert_eq!(read.server.threads, Some(3));
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use assert_cmd::prelude::*;
+use kvs::{DumpFormat, Engine, KvMemory, KvStore, Result, Storage, StoreTrait};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn filled() -> KvMemory {
+    let store = KvMemory::new();
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value\n\"{}\"", i))
+            .unwrap();
+    }
+    store
+}
+
+// A dump in either format loads into any engine.
+#[test]
+fn export_and_import() -> Result<()> {
+    for format in [DumpFormat::Json, DumpFormat::Binary] {
+        for engine in [
+            Engine::Kvs,
+            Engine::Sled,
+            Engine::Memory,
+            Engine::Lsm,
+            Engine::BTree,
+        ] {
+            let mut dump = Vec::new();
+            assert_eq!(filled().export(format, &mut dump)?, 100);
+
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let store = Storage::build(temp_dir.path().to_path_buf(), engine)?;
+            assert_eq!(store.import(&mut dump.as_slice())?, 100);
+            for i in 0..100 {
+                assert_eq!(
+                    store.get(format!("key{}", i))?,
+                    Some(format!("value\n\"{}\"", i))
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn json_is_one_pair_per_line() -> Result<()> {
+    let store = KvMemory::new();
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let mut dump = Vec::new();
+    store.export(DumpFormat::Json, &mut dump)?;
+    assert_eq!(
+        String::from_utf8(dump).unwrap(),
+        "{\"key\":\"key\",\"value\":\"value\"}\n"
+    );
+    Ok(())
+}
+
+// Flipped bytes and cut-off dumps are refused rather than half-loaded
+// silently.
+#[test]
+fn binary_dump_detects_damage() -> Result<()> {
+    let mut dump = Vec::new();
+    filled().export(DumpFormat::Binary, &mut dump)?;
+
+    let mut corrupted = dump.clone();
+    corrupted[30] ^= 0xff;
+    let err = KvMemory::new()
+        .import(&mut corrupted.as_slice())
+        .unwrap_err();
+    assert!(err.to_string().contains("checksum mismatch in record 1"));
+
+    let truncated = &dump[..dump.len() - 20];
+    let err = KvMemory::new().import(&mut &truncated[..]).unwrap_err();
+    assert!(err.to_string().contains("truncated"));
+
+    let mut miscounted = dump.clone();
+    let len = miscounted.len();
+    miscounted[len - 8] = 99;
+    let err = KvMemory::new()
+        .import(&mut miscounted.as_slice())
+        .unwrap_err();
+    assert!(err.to_string().contains("says it holds 99"));
+    Ok(())
+}
+
+#[test]
+fn cli_export_and_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let store = Storage::build(source.clone(), Engine::Kvs).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--data-dir", "source", "--format", "binary"])
+        .args(["--output", "dump.bin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Exported 2 keys"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["import", "--data-dir", "target", "--input", "dump.bin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Imported 2 keys"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--data-dir", "target"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"key1\",\"value\":\"value1\"}"));
+
+    let store = KvStore::open(temp_dir.path().join("target")).unwrap();
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--data-dir", "missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not a data directory"));
+}
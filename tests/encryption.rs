@@ -0,0 +1,223 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    EncryptionKey, Engine, KEY_LEN, Keyring, KvStore, KvStoreOptions, KvsError, LogInspector,
+    Result, Storage, StorageConfig, StoreTrait,
+};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn key(byte: u8) -> EncryptionKey {
+    EncryptionKey::new([byte; KEY_LEN])
+}
+
+fn open(dir: &Path, keys: Keyring) -> Result<KvStore> {
+    KvStore::open_with_keys(dir.to_path_buf(), KvStoreOptions::default(), keys)
+}
+
+fn log_contents(dir: &Path) -> Vec<u8> {
+    let mut contents = vec![];
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            contents.extend(fs::read(path).unwrap());
+        }
+    }
+    contents
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+// Neither keys nor values reach the disk in the clear, and the segments
+// cannot be opened without the key.
+#[test]
+fn segments_are_unreadable_without_the_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), Keyring::new(key(1)))?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("other".to_owned())?;
+    drop(store);
+
+    let contents = log_contents(temp_dir.path());
+    assert!(!contains_bytes(&contents, b"secret-key"));
+    assert!(!contains_bytes(&contents, b"secret-value"));
+
+    for result in [
+        KvStore::open(temp_dir.path().to_path_buf()),
+        open(temp_dir.path(), Keyring::new(key(2))),
+    ] {
+        match result {
+            Err(KvsError::Encryption(_)) => {}
+            Err(e) => panic!("expected an encryption error, got {}", e),
+            Ok(_) => panic!("opened an encrypted store without its key"),
+        }
+    }
+
+    let store = open(temp_dir.path(), Keyring::new(key(1)))?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
+
+// After a rotation, segments under the old key stay readable until
+// compaction rewrites them under the new one.
+#[test]
+fn rotation_rewrites_under_the_current_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), Keyring::new(key(1)))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = open(temp_dir.path(), Keyring::new(key(2)).retire(key(1)))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.upgrade()? > 0);
+    assert_eq!(store.upgrade()?, 0);
+    drop(store);
+
+    let segments =
+        LogInspector::open_with_keys(temp_dir.path(), Keyring::new(key(2)))?.segments()?;
+    assert!(segments.iter().all(|info| info.key_id == Some(key(2).id())));
+
+    let store = open(temp_dir.path(), Keyring::new(key(2)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Turning encryption on starts a new segment; the plaintext ones are
+// rewritten by compaction.
+#[test]
+fn encryption_can_be_turned_on() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = open(temp_dir.path(), Keyring::new(key(1)))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.upgrade()?, 1);
+    drop(store);
+
+    assert!(!contains_bytes(&log_contents(temp_dir.path()), b"value1"));
+    let store = open(temp_dir.path(), Keyring::new(key(1)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A tampered entry fails authentication and counts as damage.
+#[test]
+fn tampering_is_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), Keyring::new(key(1)))?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // Flips the last byte of the ciphertext, then restamps the entry's
+    // checksum, which anyone can recompute
+    let segment = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&segment)?;
+    let mut start = 0;
+    LogInspector::open_with_keys(temp_dir.path(), Keyring::new(key(1)))?.walk(
+        1,
+        &mut |offset, _, _| {
+            start = offset as usize;
+            Ok(())
+        },
+    )?;
+    let end = bytes.len() - 4;
+    bytes[end - 1] ^= 1;
+    let crc = crc32fast::hash(&bytes[start..end]);
+    bytes[end..].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&segment, bytes)?;
+
+    let problems = LogInspector::open_with_keys(temp_dir.path(), Keyring::new(key(1)))?.verify()?;
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].1.reason.contains("authentication"));
+    Ok(())
+}
+
+// The server reads its key from the file the config names.
+#[test]
+fn storage_config_key_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir)?;
+    let key_file = temp_dir.path().join("key");
+    let (_, hex) = EncryptionKey::generate();
+    fs::write(&key_file, format!("{}\n", hex))?;
+
+    let config = StorageConfig {
+        engine: Engine::Kvs,
+        encryption_key_file: Some(key_file.clone()),
+        ..StorageConfig::default()
+    };
+    let store = Storage::open(data_dir.clone(), &config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = Storage::open(data_dir.clone(), &config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let keys = Keyring::new(EncryptionKey::from_file(&key_file)?);
+    let store = Storage::open_read_only_with_keys(data_dir, Some(keys))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_key_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let keygen = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("keygen")
+        .output()
+        .unwrap();
+    assert!(keygen.status.success());
+    fs::write(temp_dir.path().join("key"), &keygen.stdout).unwrap();
+
+    let config = StorageConfig {
+        encryption_key_file: Some(temp_dir.path().join("key")),
+        ..StorageConfig::default()
+    };
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let store = Storage::open(data_dir.clone(), &config).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args)
+            .args(["--data-dir", "data"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    admin(&["verify"])
+        .assert()
+        .failure()
+        .stderr(contains("which was not given"));
+    admin(&["dump", "--key-file", "key"])
+        .assert()
+        .success()
+        .stdout(contains("\"key1\" = \"value1\""));
+    admin(&["inspect", "--key-file", "key"])
+        .assert()
+        .success()
+        .stdout(contains("KEY"));
+}
@@ -0,0 +1,191 @@
+use kvs::{
+    Fault, FaultInjector, KvStore, KvStoreOptions, KvsError, LogInspector, Result, StoreTrait,
+};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// Small segments and an early compaction so faults also hit rollover and
+// compaction
+fn open(dir: &Path, faults: &FaultInjector) -> Result<KvStore> {
+    KvStore::open_with_faults(
+        dir.to_path_buf(),
+        KvStoreOptions {
+            max_segment_bytes: 256,
+            compaction_threshold_bytes: 2048,
+            ..KvStoreOptions::default()
+        },
+        faults.clone(),
+    )
+}
+
+// A write cut short is taken back, so the next one lands where it should
+// have and nothing of the failed one survives a reopen.
+#[test]
+fn failed_write_is_rolled_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new();
+    let store = open(temp_dir.path(), &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let size = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+    faults.inject(Fault::WriteFailsAfter(10));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(fs::metadata(temp_dir.path().join("1.log"))?.len(), size);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(LogInspector::open(temp_dir.path())?.verify()?.is_empty());
+
+    let store = open(temp_dir.path(), &faults)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A full disk refuses writes, removals included, until space frees up.
+#[test]
+fn no_space_fails_until_cleared() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new();
+    let store = open(temp_dir.path(), &faults)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    faults.inject(Fault::NoSpaceAfter(10));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    faults.clear();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = open(temp_dir.path(), &faults)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A write whose fsync failed was never acknowledged and is gone after a
+// power cut; short reads still return whole values.
+#[test]
+fn failed_sync_and_short_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new();
+    let store = open(temp_dir.path(), &faults)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    faults.inject(Fault::SyncFails(1));
+    assert!(store.set("key0".to_owned(), "lost".to_owned()).is_err());
+    drop(store);
+    faults.crash(temp_dir.path())?;
+
+    faults.inject(Fault::ShortReads(3));
+    let store = open(temp_dir.path(), &faults)?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Values each key may hold: the last acknowledged one, plus whatever failed
+// writes since then may or may not have left behind
+type Model = HashMap<String, Vec<Option<String>>>;
+
+fn record(model: &mut Model, key: String, value: Option<String>, acknowledged: bool) {
+    let allowed = model.entry(key).or_insert_with(|| vec![None]);
+    if acknowledged {
+        allowed.clear();
+    }
+    allowed.push(value);
+}
+
+fn random_fault() -> Fault {
+    match fastrand::u8(0..5) {
+        0 => Fault::WriteFailsAfter(fastrand::u64(0..40)),
+        1 => Fault::NoSpaceAfter(fastrand::u64(0..200)),
+        2 => Fault::SyncFails(fastrand::u32(1..3)),
+        3 => Fault::ShortReads(fastrand::usize(1..8)),
+        _ => Fault::PowerCutAfter(fastrand::u64(0..400)),
+    }
+}
+
+// Random writes under random faults, with a power cut every so often: after
+// each one the store must hold every acknowledged write and must not bring
+// back anything acknowledged as removed. Set KVS_FAULT_SEED to replay a run.
+#[test]
+fn crash_recovery_keeps_acknowledged_writes() -> Result<()> {
+    let seed = env::var("KVS_FAULT_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| fastrand::u64(..));
+    fastrand::seed(seed);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new();
+    let mut store = open(temp_dir.path(), &faults)?;
+    let mut model = Model::new();
+
+    for round in 0..30 {
+        for op in 0..60 {
+            if fastrand::u8(0..8) == 0 {
+                faults.inject(random_fault());
+            } else if fastrand::u8(0..4) == 0 {
+                faults.clear();
+            }
+
+            let key = format!("key{}", fastrand::u8(0..20));
+            match fastrand::u8(0..10) {
+                0..6 => {
+                    let value = format!("value{}-{}", round, op);
+                    let result = store.set(key.clone(), value.clone());
+                    record(&mut model, key, Some(value), result.is_ok());
+                }
+                6..9 => match store.remove(key.clone()) {
+                    Err(KvsError::KeyNotFound) => {}
+                    result => record(&mut model, key, None, result.is_ok()),
+                },
+                _ => {
+                    let value = store.get(key.clone())?;
+                    let allowed = model.get(&key).cloned().unwrap_or(vec![None]);
+                    assert!(
+                        allowed.contains(&value),
+                        "seed {}: {} is {:?}, expected one of {:?}",
+                        seed,
+                        key,
+                        value,
+                        allowed
+                    );
+                }
+            }
+        }
+
+        faults.clear();
+        drop(store);
+        faults.crash(temp_dir.path())?;
+        store = open(temp_dir.path(), &faults)?;
+
+        for (key, allowed) in model.iter_mut() {
+            let value = store.get(key.clone())?;
+            assert!(
+                allowed.contains(&value),
+                "seed {}, round {}: {} is {:?} after a crash, expected one of {:?}",
+                seed,
+                round,
+                key,
+                value,
+                allowed
+            );
+            *allowed = vec![value];
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsError, LogInspector, Result, SEGMENT_VERSION, StoreTrait};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+// An entry as segments lay it out, with no header in front
+fn legacy_entry(key: &str, value: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(key.as_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
+fn write_legacy_segment(dir: &Path, file_id: u64, entries: &[(&str, &str)]) {
+    let bytes: Vec<u8> = entries
+        .iter()
+        .flat_map(|(key, value)| legacy_entry(key, value))
+        .collect();
+    fs::write(dir.join(format!("{}.log", file_id)), bytes).unwrap();
+}
+
+fn versions(dir: &Path) -> Result<Vec<u32>> {
+    Ok(LogInspector::open(dir)?
+        .segments()?
+        .iter()
+        .map(|info| info.version)
+        .collect())
+}
+
+// Segments from before headers are read and appended to as they are.
+#[test]
+fn reads_and_appends_to_legacy_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_segment(
+        temp_dir.path(),
+        1,
+        &[("key1", "value1"), ("key2", "value2")],
+    );
+    write_legacy_segment(temp_dir.path(), 2, &[("key2", ""), ("key3", "value3")]);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    assert_eq!(versions(temp_dir.path())?, vec![0, 0]);
+    let inspector = LogInspector::open(temp_dir.path())?;
+    assert!(inspector.verify()?.is_empty());
+    assert_eq!(inspector.framing_only()?, vec![1, 2]);
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// `upgrade` rewrites legacy segments in the current format and leaves an
+// up-to-date store alone.
+#[test]
+fn upgrade_rewrites_legacy_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_segment(
+        temp_dir.path(),
+        1,
+        &[("key1", "value1"), ("key2", "value2")],
+    );
+
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.upgrade()?, 1);
+    assert_eq!(store.upgrade()?, 0);
+    drop(store);
+
+    let versions = versions(temp_dir.path())?;
+    assert!(!versions.is_empty());
+    assert!(versions.iter().all(|&version| version == SEGMENT_VERSION));
+
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A segment written by a newer build is refused rather than misread.
+#[test]
+fn refuses_newer_segment_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&segment)?;
+    bytes[8..12].copy_from_slice(&(SEGMENT_VERSION + 1).to_le_bytes());
+    fs::write(&segment, bytes)?;
+
+    match KvStore::open(temp_dir.path().to_path_buf()) {
+        Err(KvsError::FormatVersion { found, supported }) => {
+            assert_eq!(found, SEGMENT_VERSION + 1);
+            assert_eq!(supported, SEGMENT_VERSION);
+        }
+        Err(e) => panic!("expected a format version error, got {}", e),
+        Ok(_) => panic!("opened a segment from a newer format"),
+    }
+    Ok(())
+}
+
+// A segment cut off inside its header is rewritten as an empty one.
+#[test]
+fn repairs_torn_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let bytes = fs::read(&segment)?;
+    fs::write(&segment, &bytes[..10])?;
+
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert_eq!(versions(temp_dir.path())?, vec![SEGMENT_VERSION]);
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_upgrade() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+    write_legacy_segment(temp_dir.path(), 1, &[("key1", "value1")]);
+
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args)
+            .args(["--data-dir", "."])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    admin(&["verify"])
+        .assert()
+        .success()
+        .stdout(contains("1.log predate entry checksums"));
+    admin(&["upgrade"])
+        .assert()
+        .success()
+        .stdout(contains("Rewrote 1 segments"));
+    admin(&["upgrade"])
+        .assert()
+        .success()
+        .stdout(contains("already up to date"));
+    admin(&["verify"])
+        .assert()
+        .success()
+        .stdout(contains("predate").not());
+    assert!(
+        versions(temp_dir.path())
+            .unwrap()
+            .iter()
+            .all(|&version| version == SEGMENT_VERSION)
+    );
+}
@@ -0,0 +1,250 @@
+use kvs::{
+    EncryptionKey, IndexMode, KEY_LEN, Keyring, KvStore, KvStoreOptions, KvsError, Result,
+    StorageConfig, StoreTrait,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// Small segments, so a few hundred writes seal several of them
+fn options() -> KvStoreOptions {
+    KvStoreOptions {
+        max_segment_bytes: 4 * 1024,
+        compaction_threshold_bytes: u64::MAX,
+        index: IndexMode::Disk,
+        ..KvStoreOptions::default()
+    }
+}
+
+fn open(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with_options(dir.to_path_buf(), options())
+}
+
+fn index_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".idx"))
+        .collect();
+    names.sort();
+    names
+}
+
+fn contents(store: &KvStore) -> Result<BTreeMap<String, String>> {
+    let mut found = BTreeMap::new();
+    store.scan(&mut |key, value| {
+        found.insert(key, value);
+        Ok(())
+    })?;
+    Ok(found)
+}
+
+// Sealed segments get index files, and every key reads back the same before
+// and after a reopen.
+#[test]
+fn disk_index_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let mut expected = BTreeMap::new();
+    for i in 0..300 {
+        let key = format!("key{}", i % 100);
+        let value = format!("value{}", i);
+        store.set(key.clone(), value.clone())?;
+        expected.insert(key, value);
+    }
+    for i in (0..100).step_by(7) {
+        let key = format!("key{}", i);
+        store.remove(key.clone())?;
+        expected.remove(&key);
+    }
+    assert!(index_files(temp_dir.path()).len() > 1);
+    assert_eq!(contents(&store)?, expected);
+    assert_eq!(store.options().index, IndexMode::Disk);
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+    }
+    assert_eq!(contents(&store)?, expected);
+    assert_eq!(store.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+// A removal in a newer segment hides the value an older one still holds.
+#[test]
+fn removals_hide_older_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for i in 0..200 {
+        store.set(format!("filler{}", i), "x".repeat(20))?;
+    }
+    store.remove("key1".to_owned())?;
+    for i in 0..200 {
+        store.set(format!("filler{}", i), "y".repeat(20))?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Index files are rebuilt from their segments when missing or damaged.
+#[test]
+fn index_files_are_rebuilt() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let files = index_files(temp_dir.path());
+    assert!(files.len() >= 2);
+    fs::remove_file(temp_dir.path().join(&files[0]))?;
+    let damaged = temp_dir.path().join(&files[1]);
+    let bytes = fs::read(&damaged)?;
+    fs::write(&damaged, &bytes[..bytes.len() / 2])?;
+
+    let store = open(temp_dir.path())?;
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+    assert_eq!(index_files(temp_dir.path()), files);
+    Ok(())
+}
+
+// Compaction rewrites the live keys and drops the index files of the
+// segments it replaced.
+#[test]
+fn compaction_with_disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path().to_path_buf(),
+        KvStoreOptions {
+            compaction_threshold_bytes: 32 * 1024,
+            ..options()
+        },
+    )?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 50), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let logs = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().ends_with(".log")
+        })
+        .count();
+    assert!(index_files(temp_dir.path()).len() < logs);
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..50 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", 1950 + i))
+        );
+    }
+    assert_eq!(contents(&store)?.len(), 49);
+    Ok(())
+}
+
+// Blob pointers are kept in the index files like any other position.
+#[test]
+fn blob_values_with_disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large = |i: u32| format!("{}-", i).repeat(1000);
+    let store = KvStore::open_with_options(
+        temp_dir.path().to_path_buf(),
+        KvStoreOptions {
+            blob_threshold_bytes: 1024,
+            ..options()
+        },
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), large(i))?;
+    }
+    for i in 0..50 {
+        store.set(format!("key{}", i), large(i + 100))?;
+    }
+    assert!(store.collect_blobs()? > 0);
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(large(i + 100)));
+    }
+    for i in 50..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(large(i)));
+    }
+    Ok(())
+}
+
+// Stores indexed either way read each other's segments.
+#[test]
+fn switching_index_modes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let memory = KvStoreOptions {
+        index: IndexMode::Memory,
+        ..options()
+    };
+    let store = KvStore::open_with_options(temp_dir.path().to_path_buf(), memory)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.clear()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "again".to_owned())?;
+    }
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(contents(&store)?.len(), 100);
+    store.clear()?;
+    assert!(contents(&store)?.is_empty());
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Index files would keep keys in the clear.
+#[test]
+fn disk_index_refuses_encryption() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = Keyring::new(EncryptionKey::new([1; KEY_LEN]));
+    match KvStore::open_with_keys(temp_dir.path().to_path_buf(), options(), keys) {
+        Err(KvsError::Unsupported(_)) => {}
+        Err(e) => panic!("expected an unsupported error, got {}", e),
+        Ok(_) => panic!("indexed an encrypted store on disk"),
+    }
+}
+
+#[test]
+fn storage_config_index() {
+    let config: StorageConfig = toml::from_str("index = \"disk\"").unwrap();
+    assert_eq!(KvStoreOptions::from(&config).index, IndexMode::Disk);
+    assert_eq!(StorageConfig::default().index, IndexMode::Memory);
+}
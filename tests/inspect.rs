@@ -0,0 +1,170 @@
+use assert_cmd::prelude::*;
+use kvs::{Engine, Entry, LogInspector, Result, Storage, StoreTrait};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+// key1 is overwritten, key2 removed and key3 left alone
+fn fill(dir: &Path) -> Result<()> {
+    let store = Storage::build(dir.to_path_buf(), Engine::Kvs)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())
+}
+
+fn append(dir: &Path, bytes: &[u8]) {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(dir.join("1.log"))
+        .unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+#[test]
+fn segments_count_live_and_stale() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+
+    let inspector = LogInspector::open(temp_dir.path())?;
+    let segments = inspector.segments()?;
+    assert_eq!(segments.len(), 1);
+    let info = &segments[0];
+    assert_eq!((info.entries, info.sets, info.removes), (5, 4, 1));
+    assert_eq!((info.live, info.stale), (2, 3));
+    assert_eq!(info.damage, None);
+
+    let stats = inspector.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.key_bytes, 8);
+    assert_eq!(stats.value_bytes, 12);
+    assert_eq!(stats.live_bytes, 2 * (16 + 4 + 6 + 4));
+    Ok(())
+}
+
+#[test]
+fn walk_reports_offsets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+
+    let mut seen = vec![];
+    LogInspector::open(temp_dir.path())?.walk(1, &mut |offset, length, entry| {
+        seen.push((offset, length, matches!(entry, Entry::Remove { .. })));
+        Ok(())
+    })?;
+    assert_eq!(
+        seen,
+        vec![
+            (24, 30, false),
+            (54, 30, false),
+            (84, 30, false),
+            (114, 30, false),
+            (144, 24, true)
+        ]
+    );
+    Ok(())
+}
+
+// A torn header and an entry longer than the file are both located.
+#[test]
+fn verify_finds_damage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    assert!(LogInspector::open(temp_dir.path())?.verify()?.is_empty());
+
+    append(temp_dir.path(), &[1, 2, 3]);
+    let problems = LogInspector::open(temp_dir.path())?.verify()?;
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].0, 1);
+    assert_eq!(problems[0].1.offset, 168);
+    assert!(
+        problems[0]
+            .1
+            .reason
+            .contains("too short for an entry header")
+    );
+
+    let other = TempDir::new().expect("unable to create temporary working directory");
+    fill(other.path())?;
+    let mut header = 1000u64.to_le_bytes().to_vec();
+    header.extend_from_slice(&5u64.to_le_bytes());
+    append(other.path(), &header);
+    let problems = LogInspector::open(other.path())?.verify()?;
+    assert!(problems[0].1.reason.contains("1000 byte key"));
+    Ok(())
+}
+
+// A flipped bit inside a value leaves the framing whole, but not the
+// entry's checksum.
+#[test]
+fn verify_checks_entry_checksums() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(temp_dir.path())?;
+    let inspector = LogInspector::open(temp_dir.path())?;
+    assert!(inspector.framing_only()?.is_empty());
+    assert!(inspector.segments()?[0].checksummed);
+
+    // The last byte of key3's value, "value3"
+    let segment = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&segment)?;
+    bytes[84 + 30 - 5] ^= 1;
+    fs::write(&segment, bytes)?;
+
+    let problems = LogInspector::open(temp_dir.path())?.verify()?;
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].1.offset, 84);
+    assert!(problems[0].1.reason.contains("checksum"));
+    Ok(())
+}
+
+#[test]
+fn cli_inspect_dump_verify_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path()).unwrap();
+
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args)
+            .args(["--data-dir", "."])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    admin(&["inspect"])
+        .assert()
+        .success()
+        .stdout(contains("1.log").and(contains("STALE")));
+    admin(&["dump"])
+        .assert()
+        .success()
+        .stdout(contains("1.log:24").and(contains("REMOVE \"key2\"")));
+    admin(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains("live keys            2"));
+    admin(&["verify"])
+        .assert()
+        .success()
+        .stdout(contains("OK: 1 segments intact"));
+
+    append(temp_dir.path(), &[0; 4]);
+    admin(&["verify"])
+        .assert()
+        .failure()
+        .stdout(contains("1.log: offset 168").and(contains("interrupted write")));
+
+    let sled_dir = TempDir::new().unwrap();
+    Storage::build(sled_dir.path().to_path_buf(), Engine::Sled).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["inspect", "--data-dir"])
+        .arg(sled_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("uses the sled engine"));
+}
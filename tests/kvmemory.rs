@@ -1,4 +1,22 @@
-use kvs::{KvMemory, Result, StoreTrait};
+use kvs::{
+    Engine, KvMemory, KvMemoryOptions, KvsError, Result, Storage, StorageConfig, StoreTrait,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn persistent() -> KvMemoryOptions {
+    KvMemoryOptions {
+        persistent: true,
+        ..KvMemoryOptions::default()
+    }
+}
+
+fn aof_len(dir: &Path) -> Result<u64> {
+    Ok(fs::metadata(dir.join("memory.aof"))?.len())
+}
 
 // Should get previously stored value
 #[test]
@@ -49,3 +67,147 @@ fn remove_key() -> Result<()> {
 
     Ok(())
 }
+
+// Writes to a persistent store come back after a restart from the
+// append-only file alone; a torn record at its end is dropped.
+#[test]
+fn persistent_writes_survive_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.flush()?;
+    drop(store);
+    assert!(!temp_dir.path().join("memory.dump").exists());
+
+    let whole = aof_len(temp_dir.path())?;
+    let mut aof = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("memory.aof"))?;
+    aof.write_all(&[7; 30])?;
+    drop(aof);
+
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    assert_eq!(aof_len(temp_dir.path())?, whole);
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    store.set("after".to_owned(), "tear".to_owned())?;
+    drop(store);
+
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    assert_eq!(store.get("after".to_owned())?, Some("tear".to_owned()));
+    Ok(())
+}
+
+// Once the append-only file grows past its limit, or enough time has
+// passed, the map is snapshotted and the file emptied.
+#[test]
+fn snapshots_bound_the_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvMemoryOptions {
+        snapshot_bytes: 16 * 1024,
+        ..persistent()
+    };
+    let store = KvMemory::load_with_options(temp_dir.path(), options)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    assert!(temp_dir.path().join("memory.dump").exists());
+    assert!(aof_len(temp_dir.path())? < 16 * 1024);
+
+    store.snapshot()?;
+    assert_eq!(aof_len(temp_dir.path())?, 0);
+    drop(store);
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value1999".to_owned()));
+    drop(store);
+
+    let options = KvMemoryOptions {
+        snapshot_interval: Duration::from_millis(0),
+        ..persistent()
+    };
+    let store = KvMemory::load_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "timed".to_owned())?;
+    assert_eq!(aof_len(temp_dir.path())?, 0);
+    drop(store);
+    let store = KvMemory::load(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("timed".to_owned()));
+    Ok(())
+}
+
+// Clearing a persistent store sticks, and empty values are refused as they
+// would replay as removals.
+#[test]
+fn persistent_clear_and_empty_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.clear()?;
+    assert!(matches!(
+        store.set("key".to_owned(), String::new()),
+        Err(KvsError::EmptyValue)
+    ));
+    store.set("kept".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Readers replay the file without touching it; without persistence,
+// writes are lost on restart as before.
+#[test]
+fn read_only_and_volatile_loads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvMemory::load_with_options(temp_dir.path(), persistent())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let len = aof_len(temp_dir.path())?;
+
+    let reader = KvMemory::load_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        reader.set("key".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    drop(reader);
+    assert_eq!(aof_len(temp_dir.path())?, len);
+
+    let store = KvMemory::load(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    store.set("lost".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvMemory::load(temp_dir.path())?;
+    assert_eq!(store.get("lost".to_owned())?, None);
+    Ok(())
+}
+
+// Persistence is turned on through the storage config.
+#[test]
+fn persistent_from_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StorageConfig {
+        engine: Engine::Memory,
+        memory_persistent: true,
+        ..StorageConfig::default()
+    };
+    let store = Storage::open(temp_dir.path().to_path_buf(), &config)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = Storage::open(temp_dir.path().to_path_buf(), &config)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    let store = Storage::open_read_only(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
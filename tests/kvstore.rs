@@ -1,4 +1,4 @@
-use kvs::{KvStore, Result, StoreTrait};
+use kvs::{KvStore, KvStoreOptions, Result, StoreTrait};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -127,21 +127,59 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Options replaced on one clone should drive compaction on every clone.
+#[test]
+fn set_options_applies_to_clones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold_bytes: u64::MAX,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path().to_path_buf(), options)?;
+    let log_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    let uncompacted = log_size();
+
+    let other = store.clone();
+    other.set_options(KvStoreOptions {
+        compaction_threshold_bytes: 1024,
+        ..options
+    });
+    assert_eq!(store.options().compaction_threshold_bytes, 1024);
+
+    store.set("key".to_owned(), "last".to_owned())?;
+    assert!(log_size() < uncompacted);
+    assert_eq!(other.get("key".to_owned())?, Some("last".to_owned()));
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path().to_path_buf())?;
 
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
         });
+        handles.push(handle);
     }
     barrier.wait();
 
@@ -149,7 +187,11 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every clone
+    // has released the directory lock
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path().to_path_buf())?;
     for i in 0..1000 {
@@ -0,0 +1,136 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    Engine, KvMemory, KvStore, KvsError, LOCK_FILE, Result, Storage, StorageConfig, StoreTrait,
+};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// One writer at a time, however many clones it has, and the directory frees
+// up once the last clone is dropped.
+#[test]
+fn second_writer_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    let clone = store.clone();
+    assert!(temp_dir.path().join(LOCK_FILE).exists());
+
+    let err = KvStore::open(temp_dir.path().to_path_buf()).unwrap_err();
+    assert!(matches!(err, KvsError::DirectoryLocked(_)));
+    assert!(err.to_string().contains("already open"));
+    assert!(matches!(
+        KvStore::repair(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+
+    drop(store);
+    assert!(KvStore::open(temp_dir.path().to_path_buf()).is_err());
+    drop(clone);
+    KvStore::open(temp_dir.path().to_path_buf())?;
+
+    let memory = KvMemory::load(temp_dir.path())?;
+    assert!(matches!(
+        KvMemory::load(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    drop(memory);
+    Ok(())
+}
+
+// Readers share the directory with each other but not with a writer, refuse
+// writes, and leave a torn tail for the writer to repair.
+#[test]
+fn readers_share_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Storage::build(temp_dir.path().to_path_buf(), Engine::Kvs)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        Storage::open_read_only(temp_dir.path().to_path_buf()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    OpenOptions::new()
+        .append(true)
+        .open(&segment)?
+        .write_all(&[1, 2, 3])?;
+    let size = fs::metadata(&segment)?.len();
+
+    let first = Storage::open_read_only(temp_dir.path().to_path_buf())?;
+    let second = Storage::open_read_only(temp_dir.path().to_path_buf())?;
+    assert_eq!(first.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        first.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        first.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(fs::metadata(&segment)?.len(), size);
+
+    let config = StorageConfig {
+        engine: Engine::Kvs,
+        ..StorageConfig::default()
+    };
+    assert!(matches!(
+        Storage::open(temp_dir.path().to_path_buf(), &config),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    drop(first);
+    drop(second);
+
+    let store = Storage::open(temp_dir.path().to_path_buf(), &config)?;
+    assert_eq!(fs::metadata(&segment)?.len(), size - 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_second_server_is_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4701"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    while !temp_dir.path().join("1.log").exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4702"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already open"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--data-dir", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already open"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on child process");
+
+    // Readers do not block each other
+    let reader = Storage::open_read_only(temp_dir.path().to_path_buf()).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--data-dir", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Exported 0 keys"));
+    drop(reader);
+}
@@ -0,0 +1,273 @@
+use kvs::{Engine, KvsError, LsmOptions, LsmStore, Result, Storage, StorageConfig, StoreTrait};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use tempfile::TempDir;
+
+// Small memtables and tables, so a few thousand writes flush and compact
+// through several levels
+fn options() -> LsmOptions {
+    LsmOptions {
+        memtable_bytes: 2 * 1024,
+        table_bytes: 4 * 1024,
+        block_bytes: 512,
+        level0_tables: 2,
+        level1_bytes: 8 * 1024,
+    }
+}
+
+fn open(dir: &Path) -> Result<LsmStore> {
+    LsmStore::open_with_options(dir.to_path_buf(), options())
+}
+
+fn files(dir: &Path, ext: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(ext))
+        .collect();
+    names.sort();
+    names
+}
+
+fn range(store: &impl StoreTrait, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<String>> {
+    let mut keys = vec![];
+    store.scan_range(
+        (start.map(str::to_owned), end.map(str::to_owned)),
+        &mut |key, _| {
+            keys.push(key);
+            Ok(())
+        },
+    )?;
+    Ok(keys)
+}
+
+fn contents(store: &impl StoreTrait) -> Result<BTreeMap<String, String>> {
+    let mut found = BTreeMap::new();
+    store.scan(&mut |key, value| {
+        found.insert(key, value);
+        Ok(())
+    })?;
+    Ok(found)
+}
+
+// Overwrites and removals read back the same across flushes, compactions
+// and a reopen.
+#[test]
+fn survives_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let mut expected = BTreeMap::new();
+    for i in 0..3000 {
+        let key = format!("key{:04}", i % 500);
+        let value = format!("value{}", i);
+        store.set(key.clone(), value.clone())?;
+        expected.insert(key, value);
+    }
+    for i in (0..500).step_by(7) {
+        let key = format!("key{:04}", i);
+        store.remove(key.clone())?;
+        expected.remove(&key);
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.levels[1..].iter().any(|&(tables, _)| tables > 0));
+    assert!(stats.compacted_bytes > 0);
+    assert!(stats.write_amplification() > 1.0);
+    assert_eq!(contents(&store)?, expected);
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    for i in 0..500 {
+        let key = format!("key{:04}", i);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+    }
+    assert_eq!(contents(&store)?, expected);
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key0000".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.set("key".to_owned(), String::new()),
+        Err(KvsError::EmptyValue)
+    ));
+    Ok(())
+}
+
+// Writes still in the memtable come back from the log; a torn record at
+// its end is dropped and the log stays writable.
+#[test]
+fn log_is_replayed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    assert!(files(temp_dir.path(), ".sst").is_empty());
+    let logs = files(temp_dir.path(), ".wal");
+    assert_eq!(logs.len(), 1);
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(&logs[0]))?;
+    log.write_all(&[7; 11])?;
+    drop(log);
+
+    let store = LsmStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = LsmStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Flushing leaves an empty log and a table; the files a crash part way
+// through a flush would leave behind are removed on open.
+#[test]
+fn flush_and_leftovers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush_memtable()?;
+    assert_eq!(files(temp_dir.path(), ".sst").len(), 1);
+    assert_eq!(store.stats()?.levels[0].0, 1);
+    drop(store);
+
+    fs::write(temp_dir.path().join("90.sst"), b"half a table")?;
+    fs::write(temp_dir.path().join("91.sst.tmp"), b"partial")?;
+    fs::write(temp_dir.path().join("92.wal"), b"stale")?;
+    let store = LsmStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(files(temp_dir.path(), ".sst").len(), 1);
+    assert!(files(temp_dir.path(), ".tmp").is_empty());
+    assert_eq!(files(temp_dir.path(), ".wal").len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // New files are numbered past the leftovers
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush_memtable()?;
+    assert!(files(temp_dir.path(), ".sst").contains(&"93.sst".to_owned()));
+    Ok(())
+}
+
+// Range scans come back in key order with each bound honored, whether the
+// keys sit in the memtable or in tables.
+#[test]
+fn range_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in (0..1000).rev() {
+        store.set(format!("key{:04}", i), "x".repeat(20))?;
+    }
+    store.remove("key0101".to_owned())?;
+
+    let keys = range(
+        &store,
+        Bound::Included("key0100"),
+        Bound::Excluded("key0105"),
+    )?;
+    assert_eq!(keys, vec!["key0100", "key0102", "key0103", "key0104"]);
+    let keys = range(&store, Bound::Excluded("key0997"), Bound::Unbounded)?;
+    assert_eq!(keys, vec!["key0998", "key0999"]);
+    let keys = range(&store, Bound::Unbounded, Bound::Included("key0001"))?;
+    assert_eq!(keys, vec!["key0000", "key0001"]);
+    assert!(range(&store, Bound::Included("key5"), Bound::Excluded("key1"))?.is_empty());
+
+    let all = range(&store, Bound::Unbounded, Bound::Unbounded)?;
+    assert_eq!(all.len(), 999);
+    assert!(all.windows(2).all(|pair| pair[0] < pair[1]));
+    Ok(())
+}
+
+// Every engine answers range scans the same way.
+#[test]
+fn range_scans_agree_across_engines() -> Result<()> {
+    for engine in [
+        Engine::Kvs,
+        Engine::Sled,
+        Engine::Memory,
+        Engine::Lsm,
+        Engine::BTree,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Storage::build(temp_dir.path().to_path_buf(), engine)?;
+        for key in ["d", "a", "c", "e", "b"] {
+            store.set(key.to_owned(), key.to_uppercase())?;
+        }
+        assert_eq!(
+            range(&store, Bound::Excluded("a"), Bound::Included("d"))?,
+            vec!["b", "c", "d"],
+            "{}",
+            engine
+        );
+    }
+    Ok(())
+}
+
+// Readers share the directory with each other and cannot write.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let reader = LsmStore::open_read_only(temp_dir.path().to_path_buf())?;
+    let other = LsmStore::open_read_only(temp_dir.path().to_path_buf())?;
+    assert_eq!(reader.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(contents(&other)?.len(), 500);
+    assert!(matches!(
+        reader.set("key".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(LsmStore::open(temp_dir.path().to_path_buf()).is_err());
+    Ok(())
+}
+
+// Clearing removes every table and key, and the store stays usable.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.clear()?;
+    assert!(files(temp_dir.path(), ".sst").is_empty());
+    assert!(contents(&store)?.is_empty());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(contents(&store)?.len(), 1);
+    Ok(())
+}
+
+// The engine and its sizes come from the storage config.
+#[test]
+fn opened_from_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = StorageConfig {
+        engine: Engine::Lsm,
+        lsm_memtable_bytes: 1024,
+        ..StorageConfig::default()
+    };
+    assert_eq!("lsm".parse::<Engine>()?, Engine::Lsm);
+
+    let store = Storage::open(temp_dir.path().to_path_buf(), &config)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(!files(temp_dir.path(), ".sst").is_empty());
+    assert_eq!(Engine::detect(temp_dir.path())?, Some(Engine::Lsm));
+    Ok(())
+}
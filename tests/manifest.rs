@@ -0,0 +1,87 @@
+use kvs::{
+    Engine, FORMAT_VERSION, KvStore, KvsError, MANIFEST_FILE, Manifest, Result, Storage,
+    StorageConfig, StoreTrait,
+};
+use std::fs;
+use tempfile::TempDir;
+
+fn config(engine: Engine) -> StorageConfig {
+    StorageConfig {
+        engine,
+        max_segment_bytes: 1024,
+        ..StorageConfig::default()
+    }
+}
+
+// A new directory records what created it, and opening it with another
+// engine is an error the caller can handle.
+#[test]
+fn records_engine_and_refuses_another() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(Storage::open(
+        temp_dir.path().to_path_buf(),
+        &config(Engine::Kvs),
+    )?);
+
+    let manifest = Manifest::load(temp_dir.path())?.expect("manifest written");
+    assert_eq!(manifest.engine, Engine::Kvs);
+    assert_eq!(manifest.format_version, FORMAT_VERSION);
+    assert_eq!(manifest.options.map(|o| o.max_segment_bytes), Some(1024));
+    assert_eq!(Engine::detect(temp_dir.path())?, Some(Engine::Kvs));
+
+    let Err(err) = Storage::open(temp_dir.path().to_path_buf(), &config(Engine::Sled)) else {
+        panic!("opened kvs data with sled");
+    };
+    assert!(matches!(
+        err,
+        KvsError::WrongEngine {
+            found: Engine::Kvs,
+            wanted: Engine::Sled
+        }
+    ));
+    assert!(err.to_string().contains("holds kvs data, not sled"));
+
+    // The manifest is unchanged by the failed open
+    assert_eq!(Manifest::load(temp_dir.path())?, Some(manifest));
+    Ok(())
+}
+
+// A directory from before manifests is upgraded in place on open.
+#[test]
+fn upgrades_legacy_engine_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("engine"), "kvs")?;
+
+    let legacy = Manifest::load(temp_dir.path())?.expect("legacy engine file read");
+    assert_eq!((legacy.engine, legacy.format_version), (Engine::Kvs, 0));
+
+    let store = Storage::open(temp_dir.path().to_path_buf(), &config(Engine::Kvs))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("engine").exists());
+    let manifest = Manifest::load(temp_dir.path())?.expect("manifest written");
+    assert_eq!(manifest.format_version, FORMAT_VERSION);
+    assert_eq!(manifest.created_at, legacy.created_at);
+    Ok(())
+}
+
+#[test]
+fn refuses_newer_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut manifest = Manifest::new(Engine::Kvs, None);
+    manifest.format_version = FORMAT_VERSION + 1;
+    manifest.save(temp_dir.path())?;
+    assert!(temp_dir.path().join(MANIFEST_FILE).exists());
+
+    assert!(matches!(
+        Storage::open(temp_dir.path().to_path_buf(), &config(Engine::Kvs)),
+        Err(KvsError::FormatVersion { .. })
+    ));
+    assert!(matches!(
+        Storage::open_read_only(temp_dir.path().to_path_buf()),
+        Err(KvsError::FormatVersion { .. })
+    ));
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use assert_cmd::prelude::*;
+use kvs::{Engine, Result, Storage, StorageConfig, StoreTrait};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn config(engine: Engine) -> StorageConfig {
+    StorageConfig {
+        engine,
+        ..StorageConfig::default()
+    }
+}
+
+fn check(dir: &Path, engine: Engine) -> Result<()> {
+    let store = Storage::build(dir.to_path_buf(), engine)?;
+    for i in 1..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// Data survives kvs -> sled -> memory -> kvs, with each old directory kept.
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("data");
+    fs::create_dir(&dir)?;
+
+    let store = Storage::build(dir.clone(), Engine::Kvs)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let migration = Storage::migrate(&dir, &config(Engine::Sled))?;
+    assert_eq!(migration.keys, 199);
+    assert_eq!(migration.previous, temp_dir.path().join("data.kvs"));
+    check(&dir, Engine::Sled)?;
+    check(&migration.previous, Engine::Kvs)?;
+
+    let to_memory = Storage::migrate(&dir, &config(Engine::Memory))?;
+    check(&dir, Engine::Memory)?;
+
+    // The checksum does not depend on the engine
+    fs::remove_dir_all(&migration.previous)?;
+    let back = Storage::migrate(&dir, &config(Engine::Kvs))?;
+    assert_eq!(back.checksum, migration.checksum);
+    assert_eq!(back.checksum, to_memory.checksum);
+    check(&dir, Engine::Kvs)?;
+    Ok(())
+}
+
+// Migrating to the same engine, or over a kept directory, changes nothing.
+#[test]
+fn migrate_refusals() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("data");
+    fs::create_dir(&dir)?;
+    Storage::build(dir.clone(), Engine::Kvs)?.set("key".to_owned(), "value".to_owned())?;
+
+    assert!(Storage::migrate(&dir, &config(Engine::Kvs)).is_err());
+
+    fs::create_dir(temp_dir.path().join("data.kvs"))?;
+    assert!(Storage::migrate(&dir, &config(Engine::Sled)).is_err());
+    assert!(!temp_dir.path().join("data.migrating").exists());
+
+    let store = Storage::build(dir, Engine::Kvs)?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    fs::create_dir(&dir).unwrap();
+    Storage::build(dir.clone(), Engine::Kvs)
+        .unwrap()
+        .set("key".to_owned(), "value".to_owned())
+        .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--data-dir", "data", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 1 keys from kvs to sled"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--data-dir", "data", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already uses the sled engine"));
+
+    let store = Storage::build(dir, Engine::Sled).unwrap();
+    assert_eq!(
+        store.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
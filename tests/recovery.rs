@@ -0,0 +1,150 @@
+use assert_cmd::prelude::*;
+use kvs::{CORRUPT_DIR, KvStore, KvStoreOptions, Result, StoreTrait};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn append(path: &Path, bytes: &[u8]) {
+    let mut file = OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+// Small segments so a handful of writes spans several files
+fn open_small(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with_options(
+        dir.to_path_buf(),
+        KvStoreOptions {
+            max_segment_bytes: 64,
+            compaction_threshold_bytes: 1024 * 1024,
+            ..KvStoreOptions::default()
+        },
+    )
+}
+
+// A torn tail on the active segment is cut off, and new writes land after
+// the last whole entry instead of after the garbage.
+#[test]
+fn truncates_torn_active_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let size = fs::metadata(&segment)?.len();
+    append(&segment, &[7; 20]);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(fs::metadata(&segment)?.len(), size);
+    assert!(!temp_dir.path().join(CORRUPT_DIR).exists());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Damage in a sealed segment no longer hides the segments after it. The
+// damaged file itself is moved into `corrupt/`, untouched, and the segment is
+// rebuilt from the whole entries in front of the damage.
+#[test]
+fn quarantines_damaged_sealed_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_small(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let sealed = temp_dir.path().join("1.log");
+    let size = fs::metadata(&sealed)?.len();
+    append(&sealed, &[0xff; 40]);
+    let damaged = fs::read(&sealed)?;
+    let inode = fs::metadata(&sealed)?.ino();
+
+    let repairs = KvStore::repair(temp_dir.path())?;
+    assert_eq!(repairs.len(), 1);
+    let repair = &repairs[0];
+    assert_eq!(
+        (repair.file_id, repair.offset, repair.lost_bytes),
+        (1, size, 40)
+    );
+    let copy = repair
+        .quarantined
+        .clone()
+        .expect("sealed segment quarantined");
+    assert_eq!(copy, temp_dir.path().join(CORRUPT_DIR).join("1.log"));
+    assert_eq!(fs::metadata(&copy)?.ino(), inode);
+    assert_eq!(fs::read(&copy)?, damaged);
+    assert_ne!(fs::metadata(&sealed)?.ino(), inode);
+    assert_eq!(fs::read(&sealed)?, damaged[..size as usize]);
+    assert!(!temp_dir.path().join("1.log.tmp").exists());
+
+    let store = open_small(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // A second copy of the same segment does not overwrite the first
+    drop(store);
+    append(&sealed, &[0xff; 40]);
+    let repairs = KvStore::repair(temp_dir.path())?;
+    assert_eq!(
+        repairs[0].quarantined,
+        Some(temp_dir.path().join(CORRUPT_DIR).join("1.log.1"))
+    );
+    assert!(KvStore::repair(temp_dir.path())?.is_empty());
+    Ok(())
+}
+
+// A read-only open does not repair, so an entry header claiming impossible
+// sizes must stop the replay rather than overflow or allocate for it.
+#[test]
+fn read_only_open_skips_damage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let size = fs::metadata(&segment)?.len();
+    append(&segment, &[0xff; 40]);
+
+    let store = KvStore::open_read_only(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::metadata(&segment)?.len(), size + 40);
+    Ok(())
+}
+
+#[test]
+fn cli_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().to_path_buf()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["repair", "--data-dir", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Nothing to repair"));
+
+    append(&temp_dir.path().join("1.log"), &[1, 2, 3]);
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["repair", "--data-dir", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.log: cut at offset 54").and(contains("dropped 3 bytes")));
+}
@@ -0,0 +1,291 @@
+use kvs::{
+    Client, ClientTrait, ClientType, Config, Engine, Entry, KvStore, LogPosition, Request,
+    Response, Result, Role, Server, ServerTrait, ShutdownHandle, StoreTrait, load_position,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start(config: &Config) -> Result<(thread::JoinHandle<()>, ShutdownHandle)> {
+    let mut server = Server::from_config(config)?;
+    let shutdown = server.shutdown();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    while TcpStream::connect(config.server.addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok((handle, shutdown))
+}
+
+fn primary_config(addr: &str, listen_addr: &str, dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.server.addr = addr.parse().unwrap();
+    config.server.data_dir = Some(dir.to_path_buf());
+    config.storage.engine = Engine::Kvs;
+    config.replication.role = Role::Primary;
+    config.replication.listen_addr = Some(listen_addr.parse().unwrap());
+    config
+}
+
+fn replica_config(addr: &str, primary_addr: &str, dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.server.addr = addr.parse().unwrap();
+    config.server.data_dir = Some(dir.to_path_buf());
+    config.replication.role = Role::Replica;
+    config.replication.primary_addr = Some(primary_addr.parse().unwrap());
+    config
+}
+
+fn send(addr: SocketAddr, request: Request) -> Result<Response> {
+    Client::build(ClientType::Sync, addr)?.send(request)
+}
+
+fn set(addr: SocketAddr, key: &str, value: &str) -> Result<()> {
+    let response = send(
+        addr,
+        Request::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        },
+    )?;
+    assert!(matches!(response, Response::Ok));
+    Ok(())
+}
+
+// Polls the replica until `key` has `expected`
+fn wait_for(addr: SocketAddr, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = send(
+            addr,
+            Request::Get {
+                key: key.to_owned(),
+            },
+        )?;
+        let value = match response {
+            Response::Value(value) => Some(value),
+            _ => None,
+        };
+        if value.as_deref() == expected {
+            return Ok(());
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{} is {:?}, expected {:?}",
+            key,
+            value,
+            expected
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Writes on the primary should show up on the replica, which refuses writes.
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = primary_config("127.0.0.1:4201", "127.0.0.1:4202", primary_dir.path());
+    let replica = replica_config("127.0.0.1:4203", "127.0.0.1:4202", replica_dir.path());
+    let (_primary, _) = start(&primary)?;
+    let (_replica, _) = start(&replica)?;
+    let (primary, replica) = (primary.server.addr, replica.server.addr);
+
+    for i in 0..20 {
+        set(primary, &format!("key{}", i), &format!("value{}", i))?;
+    }
+    wait_for(replica, "key19", Some("value19"))?;
+    wait_for(replica, "key0", Some("value0"))?;
+
+    send(
+        primary,
+        Request::Remove {
+            key: "key0".to_owned(),
+        },
+    )?;
+    wait_for(replica, "key0", None)?;
+
+    let response = send(
+        replica,
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "local".to_owned(),
+        },
+    )?;
+    assert!(matches!(response, Response::Error(_)));
+    wait_for(replica, "key1", Some("value1"))?;
+
+    Ok(())
+}
+
+// A restarted replica picks up from the position it had applied.
+#[test]
+fn replica_resumes_after_restart() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = primary_config("127.0.0.1:4204", "127.0.0.1:4205", primary_dir.path());
+    let replica = replica_config("127.0.0.1:4206", "127.0.0.1:4205", replica_dir.path());
+    let (_primary, _) = start(&primary)?;
+    let (handle, shutdown) = start(&replica)?;
+    let (primary_addr, replica_addr) = (primary.server.addr, replica.server.addr);
+
+    set(primary_addr, "key1", "value1")?;
+    wait_for(replica_addr, "key1", Some("value1"))?;
+
+    shutdown.shutdown();
+    handle.join().unwrap();
+    // The follower thread notices shutdown at the next heartbeat
+    thread::sleep(Duration::from_millis(1500));
+    let position = load_position(replica_dir.path())?;
+    assert_ne!(position, LogPosition::default());
+
+    set(primary_addr, "key2", "value2")?;
+    send(
+        primary_addr,
+        Request::Remove {
+            key: "key1".to_owned(),
+        },
+    )?;
+
+    let (_replica, _) = start(&replica)?;
+    wait_for(replica_addr, "key2", Some("value2"))?;
+    wait_for(replica_addr, "key1", None)?;
+    assert!(load_position(replica_dir.path())?.offset > position.offset);
+
+    Ok(())
+}
+
+// A replica whose position is gone from the primary's log starts over, and
+// drops keys the primary no longer has.
+#[test]
+fn replica_resyncs_after_compaction() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    {
+        let store = KvStore::open(replica_dir.path().to_path_buf())?;
+        store.set("stale".to_owned(), "value".to_owned())?;
+    }
+
+    let mut primary = primary_config("127.0.0.1:4207", "127.0.0.1:4208", primary_dir.path());
+    primary.storage.compaction_threshold_bytes = 1024;
+    let replica = replica_config("127.0.0.1:4209", "127.0.0.1:4208", replica_dir.path());
+    let (_primary, _) = start(&primary)?;
+    let primary_addr = primary.server.addr;
+
+    // Overwrites push the log past the threshold, so it is compacted
+    for i in 0..100 {
+        set(primary_addr, "key", &format!("value{}", i))?;
+    }
+
+    let (_replica, _) = start(&replica)?;
+    wait_for(replica.server.addr, "key", Some("value99"))?;
+    wait_for(replica.server.addr, "stale", None)?;
+
+    Ok(())
+}
+
+// Compaction on the primary sends the replica back to the start of the log,
+// and the replica keeps serving the keys it has while it catches up.
+#[test]
+fn replica_serves_through_compaction() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut primary = primary_config("127.0.0.1:4212", "127.0.0.1:4213", primary_dir.path());
+    primary.storage.compaction_threshold_bytes = 4096;
+    let replica = replica_config("127.0.0.1:4214", "127.0.0.1:4213", replica_dir.path());
+    let (_primary, _) = start(&primary)?;
+    let (_replica, _) = start(&replica)?;
+    let (primary_addr, replica_addr) = (primary.server.addr, replica.server.addr);
+
+    for i in 0..50 {
+        set(primary_addr, &format!("key{}", i), &format!("value{}", i))?;
+    }
+    set(primary_addr, "gone", "value")?;
+    send(
+        primary_addr,
+        Request::Remove {
+            key: "gone".to_owned(),
+        },
+    )?;
+    wait_for(replica_addr, "key49", Some("value49"))?;
+
+    let reading = Arc::new(AtomicBool::new(true));
+    let reader = {
+        let reading = reading.clone();
+        thread::spawn(move || -> Result<Vec<String>> {
+            let mut missing = vec![];
+            while reading.load(Ordering::SeqCst) {
+                for i in 0..50 {
+                    let key = format!("key{}", i);
+                    let request = Request::Get { key: key.clone() };
+                    if let Response::NotFound = send(replica_addr, request)? {
+                        missing.push(key);
+                    }
+                }
+            }
+            Ok(missing)
+        })
+    };
+
+    // Overwrites push the log past the threshold again and again
+    for i in 0..1000 {
+        set(primary_addr, "churn", &format!("value{}", i))?;
+    }
+    wait_for(replica_addr, "churn", Some("value999"))?;
+    reading.store(false, Ordering::SeqCst);
+
+    let missing = reader.join().unwrap()?;
+    assert!(missing.is_empty(), "replica lost {:?}", missing);
+    wait_for(replica_addr, "gone", None)?;
+
+    Ok(())
+}
+
+// A batch whose lengths cannot be right is refused before anything is
+// allocated for it, and the replica reconnects.
+#[test]
+fn replica_refuses_oversized_batches() -> Result<()> {
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = TcpListener::bind("127.0.0.1:4210")?;
+    let replica = replica_config("127.0.0.1:4211", "127.0.0.1:4210", replica_dir.path());
+    let (_replica, _) = start(&replica)?;
+
+    let entry = Entry::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    }
+    .serialize();
+    let mut overflowing = (u64::MAX - 7).to_le_bytes().to_vec();
+    overflowing.extend_from_slice(&8u64.to_le_bytes());
+    let batches = [
+        // A payload length no batch comes near
+        (1u64 << 62, vec![]),
+        // Key and value sizes that overflow when added up
+        (overflowing.len() as u64, overflowing),
+        (entry.len() as u64, entry),
+    ];
+
+    let mut streams = vec![];
+    for (length, payload) in batches {
+        let (mut stream, _) = primary.accept()?;
+        stream.read_exact(&mut [0u8; 16])?;
+        stream.write_all(&[0, 0])?;
+        stream.write_all(&1u64.to_le_bytes())?;
+        stream.write_all(&100u64.to_le_bytes())?;
+        stream.write_all(&length.to_le_bytes())?;
+        stream.write_all(&payload)?;
+        streams.push(stream);
+    }
+
+    wait_for(replica.server.addr, "key", Some("value"))?;
+    Ok(())
+}
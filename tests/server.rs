@@ -0,0 +1,394 @@
+use kvs::{
+    Client, ClientTrait, ClientType, Config, Engine, KvStore, PoolType, Request, Response, Result,
+    Server, ServerTrait, ServerType, ShutdownHandle, StoreTrait,
+};
+use std::fs;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: SocketAddr, temp_dir: &TempDir) -> Result<thread::JoinHandle<()>> {
+    let (handle, _) = start(ServerType::Async, addr, temp_dir)?;
+    Ok(handle)
+}
+
+fn start(
+    server_type: ServerType,
+    addr: SocketAddr,
+    temp_dir: &TempDir,
+) -> Result<(thread::JoinHandle<()>, ShutdownHandle)> {
+    let mut server = Server::build(
+        server_type,
+        addr,
+        Engine::Kvs,
+        PoolType::Queue,
+        2,
+        temp_dir.path().to_path_buf(),
+    )?;
+    let shutdown = server.shutdown();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok((handle, shutdown))
+}
+
+// `run` should return soon after shutdown without another client connecting.
+fn shutdown_unblocks_accept(server_type: ServerType, addr: SocketAddr) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (handle, shutdown) = start(server_type, addr, &temp_dir)?;
+
+    let mut client = Client::build(ClientType::Sync, addr)?;
+    client.send(Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+
+    // Hold an idle connection open across shutdown
+    let _idle = TcpStream::connect(addr)?;
+
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        handle.join().unwrap();
+        done_tx.send(()).unwrap();
+    });
+
+    shutdown.shutdown();
+    done_rx
+        .recv_timeout(Duration::from_secs(3))
+        .expect("server did not stop after shutdown");
+
+    // The listener is closed once `run` returns
+    assert!(TcpStream::connect(addr).is_err());
+
+    // Data written before shutdown was flushed
+    let store = KvStore::open(temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn sync_shutdown_unblocks_accept() -> Result<()> {
+    shutdown_unblocks_accept(ServerType::Sync, "127.0.0.1:4103".parse().unwrap())
+}
+
+#[test]
+fn async_shutdown_unblocks_accept() -> Result<()> {
+    shutdown_unblocks_accept(ServerType::Async, "127.0.0.1:4104".parse().unwrap())
+}
+
+// With port 0 the system picks the port, and shutdown must still reach it.
+#[test]
+fn shutdown_with_port_zero() -> Result<()> {
+    for server_type in [ServerType::Sync, ServerType::Async] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut server = Server::build(
+            server_type,
+            "127.0.0.1:0".parse().unwrap(),
+            Engine::Kvs,
+            PoolType::Queue,
+            2,
+            temp_dir.path().to_path_buf(),
+        )?;
+        let addr = server.addr();
+        assert_ne!(addr.port(), 0);
+        let shutdown = server.shutdown();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            server.run().unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        let mut client = Client::build(ClientType::Sync, addr)?;
+        let response = client.send(Request::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+        })?;
+        assert!(matches!(response, Response::Ok));
+
+        shutdown.shutdown();
+        done_rx
+            .recv_timeout(Duration::from_secs(3))
+            .expect("server did not stop after shutdown");
+    }
+    Ok(())
+}
+
+// One async client connection should serve many requests.
+#[test]
+fn persistent_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4101".parse().unwrap();
+    let _server = start_server(addr, &temp_dir)?;
+
+    let mut client = Client::build(ClientType::Async, addr)?;
+    for i in 0..20 {
+        let response = client.send(Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })?;
+        assert!(matches!(response, Response::Ok));
+    }
+    for i in 0..20 {
+        let response = client.send(Request::Get {
+            key: format!("key{}", i),
+        })?;
+        assert!(matches!(response, Response::Value(v) if v == format!("value{}", i)));
+    }
+
+    let response = client.send(Request::Remove {
+        key: "missing".to_owned(),
+    })?;
+    assert!(matches!(response, Response::NotFound));
+
+    // An engine error is answered and the connection keeps serving. key19 is
+    // the last entry, so its checksum ends the segment.
+    let segment = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&segment)?;
+    *bytes.last_mut().unwrap() ^= 1;
+    fs::write(&segment, bytes)?;
+    let response = client.send(Request::Get {
+        key: "key19".to_owned(),
+    })?;
+    assert!(matches!(response, Response::Error(_)));
+    let response = client.send(Request::Get {
+        key: "key0".to_owned(),
+    })?;
+    assert!(matches!(response, Response::Value(v) if v == "value0"));
+
+    Ok(())
+}
+
+// Idle connections must not starve new clients of workers.
+#[test]
+fn idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4102".parse().unwrap();
+    let _server = start_server(addr, &temp_dir)?;
+
+    let idle = (0..500)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut client = Client::build(ClientType::Sync, addr)?;
+    let response = client.send(Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    })?;
+    assert!(matches!(response, Response::Ok));
+
+    drop(idle);
+    Ok(())
+}
+
+// A reload applies live settings at once and reports the ones needing a restart.
+#[test]
+fn reload_applies_live_settings() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4105".parse().unwrap();
+    let mut server = Server::build(
+        ServerType::Sync,
+        addr,
+        Engine::Kvs,
+        PoolType::Queue,
+        2,
+        temp_dir.path().to_path_buf(),
+    )?;
+    let reload = server.reload();
+    let shutdown = server.shutdown();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut config = reload.current()?;
+    config.server.addr = "127.0.0.1:4199".parse().unwrap();
+    config.server.pool = PoolType::Rayon;
+    config.server.threads = Some(4);
+    config.limits.max_request_bytes = 64;
+    config.storage.compaction_threshold_bytes = 4096;
+
+    let restart = reload.reload(&config)?;
+    assert_eq!(restart, vec!["server.addr".to_owned()]);
+
+    let current = reload.current()?;
+    assert_eq!(current.server.addr, addr);
+    assert_eq!(current.server.pool, PoolType::Rayon);
+    assert_eq!(current.server.threads, Some(4));
+    assert_eq!(current.storage.compaction_threshold_bytes, 4096);
+
+    // Served by the new pool, under the new request limit
+    let mut client = Client::build(ClientType::Sync, addr)?;
+    let response = client.send(Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    })?;
+    assert!(matches!(response, Response::Ok));
+    let response = client.send(Request::Set {
+        key: "key".to_owned(),
+        value: "v".repeat(128),
+    });
+    assert!(response.is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap();
+    Ok(())
+}
+
+// A reloaded request limit refuses oversized requests on new connections.
+#[test]
+fn reload_applies_request_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4112".parse().unwrap();
+    let mut server = Server::build(
+        ServerType::Sync,
+        addr,
+        Engine::Kvs,
+        PoolType::Queue,
+        2,
+        temp_dir.path().to_path_buf(),
+    )?;
+    let reload = server.reload();
+    let shutdown = server.shutdown();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut config = reload.current()?;
+    config.limits.max_request_bytes = 64;
+    reload.reload(&config)?;
+
+    let mut client = Client::build(ClientType::Sync, addr)?;
+    let response = client.send(Request::Set {
+        key: "key".to_owned(),
+        value: "v".repeat(128),
+    })?;
+    assert!(matches!(response, Response::Error(e) if e.contains("exceeds the limit of 64")));
+
+    shutdown.shutdown();
+    handle.join().unwrap();
+    Ok(())
+}
+
+fn start_with_limits(
+    server_type: ServerType,
+    addr: SocketAddr,
+    temp_dir: &TempDir,
+    max_connections: usize,
+    max_request_bytes: usize,
+) -> Result<(thread::JoinHandle<()>, ShutdownHandle)> {
+    let mut config = Config::default();
+    config.server.kind = server_type;
+    config.server.addr = addr;
+    config.server.threads = Some(2);
+    config.server.data_dir = Some(temp_dir.path().to_path_buf());
+    config.limits.max_connections = max_connections;
+    config.limits.max_request_bytes = max_request_bytes;
+    let mut server = Server::from_config(&config)?;
+    let shutdown = server.shutdown();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok((handle, shutdown))
+}
+
+// Messages are framed by length, so values far larger than one read come
+// back whole through either client and server.
+#[test]
+fn large_values() -> Result<()> {
+    let ports = [
+        (ServerType::Sync, ClientType::Async, 4106),
+        (ServerType::Async, ClientType::Async, 4107),
+        (ServerType::Async, ClientType::Sync, 4108),
+    ];
+    for (server_type, client_type, port) in ports {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (handle, shutdown) = start_with_limits(server_type, addr, &temp_dir, 100, 1024 * 1024)?;
+
+        let value = "v".repeat(200_000);
+        let mut client = Client::build(client_type, addr)?;
+        let response = client.send(Request::Set {
+            key: "key".to_owned(),
+            value: value.clone(),
+        })?;
+        assert!(matches!(response, Response::Ok));
+        let mut client = Client::build(client_type, addr)?;
+        let response = client.send(Request::Get {
+            key: "key".to_owned(),
+        })?;
+        assert!(matches!(response, Response::Value(v) if v == value));
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// A request over `limits.max_request_bytes` is refused with an error rather
+// than cut short.
+#[test]
+fn oversized_request_is_answered() -> Result<()> {
+    let ports = [(ServerType::Sync, 4110), (ServerType::Async, 4111)];
+    for (server_type, port) in ports {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (handle, shutdown) = start_with_limits(server_type, addr, &temp_dir, 100, 64)?;
+
+        let mut client = Client::build(ClientType::Sync, addr)?;
+        let response = client.send(Request::Set {
+            key: "key".to_owned(),
+            value: "v".repeat(128),
+        })?;
+        assert!(matches!(response, Response::Error(e) if e.contains("exceeds the limit of 64")));
+
+        shutdown.shutdown();
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// A connection over the limit is told why before it is closed.
+#[test]
+fn connection_limit_is_answered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4109".parse().unwrap();
+    let (handle, shutdown) = start_with_limits(ServerType::Async, addr, &temp_dir, 1, 1024)?;
+
+    // Retried until served, as the startup probe may still hold the only slot
+    let _held = loop {
+        let mut client = Client::build(ClientType::Async, addr)?;
+        let response = client.send(Request::Get {
+            key: "key".to_owned(),
+        });
+        if matches!(response, Ok(Response::NotFound)) {
+            break client;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // Read without writing, as the server closes the connection at once
+    let mut refused = vec![];
+    TcpStream::connect(addr)?.read_to_end(&mut refused)?;
+    let refusal = String::from_utf8_lossy(&refused[4..]);
+    assert!(refusal.contains("ERROR") && refusal.contains("connection limit of 1"));
+
+    shutdown.shutdown();
+    handle.join().unwrap();
+    Ok(())
+}
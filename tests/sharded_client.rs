@@ -0,0 +1,163 @@
+use kvs::{
+    ClientTrait, ClientType, Engine, HashRing, PoolType, Request, Response, Result, Server,
+    ServerTrait, ServerType, ShardedClient,
+};
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn keys() -> impl Iterator<Item = String> {
+    (0..10_000).map(|i| format!("key{}", i))
+}
+
+fn owners(ring: &HashRing) -> HashMap<String, SocketAddr> {
+    keys()
+        .map(|key| (key.clone(), ring.node(&key).unwrap()))
+        .collect()
+}
+
+// Adding a fourth node moves roughly a quarter of the keys, all onto it.
+#[test]
+fn adding_node_moves_few_keys() {
+    let mut ring = HashRing::default();
+    for port in 1..=3 {
+        ring.add(addr(port), 1).unwrap();
+    }
+    let before = owners(&ring);
+
+    ring.add(addr(4), 1).unwrap();
+    let after = owners(&ring);
+
+    let moved: Vec<_> = keys().filter(|key| before[key] != after[key]).collect();
+    assert!(moved.iter().all(|key| after[key] == addr(4)));
+    assert!(
+        (1_500..3_500).contains(&moved.len()),
+        "{} keys moved",
+        moved.len()
+    );
+}
+
+// Removing a node only moves the keys it owned.
+#[test]
+fn removing_node_moves_only_its_keys() {
+    let mut ring = HashRing::default();
+    for port in 1..=4 {
+        ring.add(addr(port), 1).unwrap();
+    }
+    let before = owners(&ring);
+
+    ring.remove(addr(2));
+    let after = owners(&ring);
+
+    for key in keys() {
+        if before[&key] == addr(2) {
+            assert_ne!(after[&key], addr(2));
+        } else {
+            assert_eq!(before[&key], after[&key]);
+        }
+    }
+}
+
+// A node with twice the weight owns about twice the keys.
+#[test]
+fn weights_scale_share() {
+    let mut ring = HashRing::default();
+    ring.add(addr(1), 1).unwrap();
+    ring.add(addr(2), 2).unwrap();
+
+    let heavy = owners(&ring).values().filter(|a| **a == addr(2)).count();
+    assert!(
+        (5_800..7_600).contains(&heavy),
+        "{} keys on heavy node",
+        heavy
+    );
+}
+
+// A weight that gives a server no points, or more than fit, is refused and
+// leaves the ring as it was.
+#[test]
+fn bad_weights_are_refused() -> Result<()> {
+    let mut ring = HashRing::default();
+    assert!(ring.add(addr(1), 0).is_err());
+    assert!(ring.is_empty());
+    assert!(ShardedClient::build(ClientType::Sync, &[(addr(1), 0)]).is_err());
+
+    ring.add(addr(1), 1)?;
+    assert!(ring.add(addr(1), u32::MAX).is_err());
+    assert_eq!(ring.nodes().collect::<Vec<_>>(), vec![(addr(1), 1)]);
+
+    let mut client = ShardedClient::connect(&[addr(1)])?;
+    assert!(client.add_node(addr(2), 0).is_err());
+    assert_eq!(client.node_for("key")?, addr(1));
+    Ok(())
+}
+
+// MSET/MGET fan out across servers and each key lives only on its node.
+#[test]
+fn sharded_client_routes_keys() -> Result<()> {
+    let addrs: Vec<SocketAddr> = (4501..=4503).map(addr).collect();
+    let mut shutdowns = Vec::new();
+    let mut temp_dirs = Vec::new();
+    for addr in &addrs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut server = Server::build(
+            ServerType::Sync,
+            *addr,
+            Engine::Memory,
+            PoolType::Queue,
+            2,
+            temp_dir.path().to_path_buf(),
+        )?;
+        temp_dirs.push(temp_dir);
+        shutdowns.push(server.shutdown());
+        thread::spawn(move || server.run().unwrap());
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    let mut client = ShardedClient::connect(&addrs)?;
+    let pairs: Vec<(String, String)> = (0..30)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs.clone())?;
+
+    let mut wanted: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    wanted.push("missing".to_owned());
+    let values = client.mget(wanted)?;
+    assert_eq!(values.last(), Some(&None));
+    for ((_, value), got) in pairs.iter().zip(&values) {
+        assert_eq!(got.as_ref(), Some(value));
+    }
+
+    for (key, value) in &pairs {
+        let owner = client.node_for(key)?;
+        for addr in &addrs {
+            let response = kvs::Client::connect(*addr)?.send(Request::Get { key: key.clone() })?;
+            match *addr == owner {
+                true => assert!(matches!(response, Response::Value(v) if v == *value)),
+                false => assert!(matches!(response, Response::NotFound)),
+            }
+        }
+    }
+
+    client.send(Request::Remove {
+        key: "key0".to_owned(),
+    })?;
+    let response = client.send(Request::Get {
+        key: "key0".to_owned(),
+    })?;
+    assert!(matches!(response, Response::NotFound));
+    assert!(client.remove_node(addrs[0]).is_ok());
+
+    for shutdown in shutdowns {
+        shutdown.shutdown();
+    }
+    Ok(())
+}
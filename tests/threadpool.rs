@@ -28,6 +28,7 @@ fn spawn_counter<P: ThreadPoolTrait>(pool: P) -> Result<()> {
     Ok(())
 }
 
+#[allow(dead_code)]
 fn spawn_panic_task<P: ThreadPoolTrait>() -> Result<()> {
     const TASK_NUM: usize = 1000;
 